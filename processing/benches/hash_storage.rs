@@ -0,0 +1,150 @@
+//! Compares hex and compact block hash storage: the size of the block_hash
+//! column and index, the time `compact_block_hashes.up.sql` takes to convert
+//! the blocks table, and the lookups by hash processing makes.
+//!
+//! Requires a migrated PostgreSQL database whose connection string is given in
+//! `TGI_BENCH_CONNECTION_STRING`. The database is cleared and its blocks table
+//! compacted and expanded back, so never point this at a database you care
+//! about.
+//!
+//! Run with: `cargo bench --bench hash_storage`
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::time::Instant;
+use tokio::runtime::Runtime;
+use tokio_postgres::{Client, NoTls};
+use tondi_graph_inspector_processing::database::{Database, DbTcpConfig, DbTlsConfig, DEFAULT_SCHEMA};
+use tondi_graph_inspector_processing::processing::{NewBlock, Processing};
+use tondi_graph_inspector_processing::rpc_client::BlockHash;
+
+const CONNECTION_STRING_ENV: &str = "TGI_BENCH_CONNECTION_STRING";
+const DAG_SIZE: usize = 50_000;
+const LOOKUPS: usize = 1000;
+
+const COMPACT_BLOCK_HASHES_UP: &str = include_str!("../database/optional/compact_block_hashes.up.sql");
+const COMPACT_BLOCK_HASHES_DOWN: &str = include_str!("../database/optional/compact_block_hashes.down.sql");
+
+/// Same shape as the DAG of the resync benchmarks
+fn synthetic_dag(size: usize) -> Vec<NewBlock> {
+    let hash = |i: usize| BlockHash::parse(&format!("{:064x}", i)).unwrap();
+    (0..size)
+        .map(|i| NewBlock {
+            hash: hash(i),
+            timestamp: 1_700_000_000_000 + i as i64 * 100,
+            daa_score: i as u64,
+            parent_hashes: (1..=3).filter(|d| *d <= i).map(|d| hash(i - d)).collect(),
+        })
+        .collect()
+}
+
+async fn connect(connection_string: &str) -> Database {
+    Database::connect(connection_string, None, &DbTlsConfig::default(), &DbTcpConfig::default(), None, None, DEFAULT_SCHEMA)
+        .await.expect("failed to connect to the benchmark database")
+}
+
+async fn store(database: &Database, dag: &'static [NewBlock]) {
+    let database_for_closure = database.clone();
+    database.run_in_transaction(move |tx| {
+        Box::pin(async move {
+            database_for_closure.clear(tx).await?;
+            for chunk in dag.chunks(1000) {
+                Processing::bulk_insert_blocks_and_edges_static(&database_for_closure, tx, chunk).await?;
+            }
+            Ok(())
+        })
+    }).await.expect("failed to store the DAG");
+}
+
+/// Prints the size of the block_hash column and of its index
+async fn report_sizes(client: &Client, storage: &str) {
+    let row = client.query_one(
+        r#"
+        SELECT
+            (SELECT sum(pg_column_size(block_hash))::BIGINT FROM blocks),
+            (SELECT sum(pg_relation_size(i.indexrelid))::BIGINT FROM pg_index i
+                JOIN pg_attribute a ON a.attrelid = i.indrelid AND a.attnum = ANY(i.indkey)
+                WHERE i.indrelid = 'blocks'::regclass AND a.attname = 'block_hash')
+        "#,
+        &[],
+    ).await.expect("failed to read the sizes");
+    println!(
+        "{} hashes of {} blocks: column {} bytes, index {} bytes",
+        storage, DAG_SIZE, row.get::<_, i64>(0), row.get::<_, i64>(1),
+    );
+}
+
+/// Looks every hash up on its own, as processing does for parents
+async fn lookup_each(database: &Database, hashes: &'static [BlockHash]) {
+    let database_for_closure = database.clone();
+    database.run_in_read_transaction(move |tx| {
+        let database = database_for_closure.clone();
+        Box::pin(async move {
+            for hash in hashes {
+                database.block_by_hash(tx, hash).await?.expect("the block is stored");
+            }
+            Ok(())
+        })
+    }).await.expect("failed to look the blocks up");
+}
+
+/// Looks all hashes up in one query, as the resync does
+async fn lookup_all(database: &Database, hashes: &'static [BlockHash]) {
+    let database_for_closure = database.clone();
+    let missing = database.run_in_read_transaction(move |tx| {
+        let database = database_for_closure.clone();
+        Box::pin(async move { Ok(database.missing_hashes(tx, hashes).await?) })
+    }).await.expect("failed to look the blocks up");
+    assert!(missing.is_empty());
+}
+
+fn bench_hash_storage(c: &mut Criterion) {
+    let connection_string = match std::env::var(CONNECTION_STRING_ENV) {
+        Ok(connection_string) => connection_string,
+        Err(_) => {
+            eprintln!("{} is not set; skipping hash storage benchmarks", CONNECTION_STRING_ENV);
+            return;
+        }
+    };
+
+    let runtime = Runtime::new().unwrap();
+    let client = runtime.block_on(async {
+        let (client, connection) = tokio_postgres::connect(&connection_string, NoTls).await
+            .expect("failed to connect to the benchmark database");
+        tokio::spawn(connection);
+        client.batch_execute(&format!("SET search_path TO {}", DEFAULT_SCHEMA)).await
+            .expect("failed to set the search path");
+        client
+    });
+    let dag: &'static [NewBlock] = Box::leak(synthetic_dag(DAG_SIZE).into_boxed_slice());
+    let hashes: &'static [BlockHash] = Box::leak(
+        dag.iter().step_by(DAG_SIZE / LOOKUPS).map(|block| block.hash).collect::<Vec<_>>().into_boxed_slice()
+    );
+
+    let hex_database = runtime.block_on(connect(&connection_string));
+    runtime.block_on(store(&hex_database, dag));
+    runtime.block_on(report_sizes(&client, "hex"));
+
+    let started = Instant::now();
+    runtime.block_on(client.batch_execute(COMPACT_BLOCK_HASHES_UP)).expect("failed to compact the block hashes");
+    println!("compact_block_hashes.up.sql converted {} blocks in {:?}", DAG_SIZE, started.elapsed());
+    let compact_database = runtime.block_on(connect(&connection_string));
+    runtime.block_on(report_sizes(&client, "compact"));
+
+    let mut group = c.benchmark_group("hash_storage");
+    group.sample_size(10);
+    for (storage, database) in [("compact", &compact_database), ("hex", &hex_database)] {
+        if storage == "hex" {
+            runtime.block_on(client.batch_execute(COMPACT_BLOCK_HASHES_DOWN)).expect("failed to expand the block hashes");
+        }
+        group.bench_with_input(BenchmarkId::new("block_by_hash", storage), &hashes, |b, hashes| {
+            b.to_async(&runtime).iter(|| lookup_each(database, hashes));
+        });
+        group.bench_with_input(BenchmarkId::new("missing_hashes", storage), &hashes, |b, hashes| {
+            b.to_async(&runtime).iter(|| lookup_all(database, hashes));
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_hash_storage);
+criterion_main!(benches);
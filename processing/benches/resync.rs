@@ -0,0 +1,125 @@
+//! Benchmarks the database side of the resync hot path.
+//!
+//! Requires a migrated PostgreSQL database whose connection string is given in
+//! `TGI_BENCH_CONNECTION_STRING`. The database is cleared before every
+//! iteration, so never point this at a database you care about.
+//!
+//! Run with: `cargo bench --bench resync`
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use tokio::runtime::Runtime;
+use tondi_graph_inspector_processing::database::{Database, DbTcpConfig, DbTlsConfig, DEFAULT_SCHEMA};
+use tondi_graph_inspector_processing::processing::{NewBlock, Processing};
+use tondi_graph_inspector_processing::rpc_client::BlockHash;
+
+const CONNECTION_STRING_ENV: &str = "TGI_BENCH_CONNECTION_STRING";
+const DAG_SIZES: [usize; 3] = [100, 1000, 5000];
+
+/// Generates a DAG where every block points at up to the three previous
+/// blocks, which roughly mimics the parent fan-in of a live network.
+fn synthetic_dag(size: usize) -> Vec<NewBlock> {
+    let hash = |i: usize| BlockHash::parse(&format!("{:064x}", i)).unwrap();
+    (0..size)
+        .map(|i| NewBlock {
+            hash: hash(i),
+            timestamp: 1_700_000_000_000 + i as i64 * 100,
+            daa_score: i as u64,
+            parent_hashes: (1..=3).filter(|d| *d <= i).map(|d| hash(i - d)).collect(),
+        })
+        .collect()
+}
+
+async fn clear(database: &Database) {
+    let database_for_closure = database.clone();
+    database.run_in_transaction(move |tx| {
+        Box::pin(async move { Ok(database_for_closure.clear(tx).await?) })
+    }).await.expect("failed to clear the database");
+}
+
+/// Stores the whole DAG in a single transaction, as the resync does.
+async fn store_batched(database: &Database, dag: &'static [NewBlock]) {
+    let database_for_closure = database.clone();
+    database.run_in_transaction(move |tx| {
+        Box::pin(async move {
+            for block in dag {
+                Processing::insert_block_and_edges_static(
+                    &database_for_closure, tx, &block.hash, block.timestamp, block.daa_score, &block.parent_hashes
+                ).await?;
+            }
+            Ok(())
+        })
+    }).await.expect("failed to store the DAG");
+}
+
+/// Stores the whole DAG with bulk inserts, as the resync into an empty
+/// database does.
+async fn store_bulk(database: &Database, dag: &'static [NewBlock]) {
+    let database_for_closure = database.clone();
+    database.run_in_transaction(move |tx| {
+        Box::pin(async move {
+            for chunk in dag.chunks(1000) {
+                Processing::bulk_insert_blocks_and_edges_static(&database_for_closure, tx, chunk).await?;
+            }
+            Ok(())
+        })
+    }).await.expect("failed to bulk store the DAG");
+}
+
+/// Stores every block in its own transaction, as live notifications do.
+async fn store_unbatched(database: &Database, dag: &'static [NewBlock]) {
+    for block in dag {
+        let database_for_closure = database.clone();
+        database.run_in_transaction(move |tx| {
+            Box::pin(async move {
+                Processing::insert_block_and_edges_static(
+                    &database_for_closure, tx, &block.hash, block.timestamp, block.daa_score, &block.parent_hashes
+                ).await
+            })
+        }).await.expect("failed to store block");
+    }
+}
+
+fn bench_resync(c: &mut Criterion) {
+    let connection_string = match std::env::var(CONNECTION_STRING_ENV) {
+        Ok(connection_string) => connection_string,
+        Err(_) => {
+            eprintln!("{} is not set; skipping resync benchmarks", CONNECTION_STRING_ENV);
+            return;
+        }
+    };
+
+    let runtime = Runtime::new().unwrap();
+    let database = runtime.block_on(Database::connect(&connection_string, None, &DbTlsConfig::default(), &DbTcpConfig::default(), None, None, DEFAULT_SCHEMA))
+        .expect("failed to connect to the benchmark database");
+
+    let mut group = c.benchmark_group("resync");
+    group.sample_size(10);
+    for size in DAG_SIZES {
+        let dag: &'static [NewBlock] = Box::leak(synthetic_dag(size).into_boxed_slice());
+
+        group.bench_with_input(BenchmarkId::new("batched", size), &dag, |b, dag| {
+            b.to_async(&runtime).iter(|| async {
+                clear(&database).await;
+                store_batched(&database, dag).await;
+            });
+        });
+
+        group.bench_with_input(BenchmarkId::new("bulk", size), &dag, |b, dag| {
+            b.to_async(&runtime).iter(|| async {
+                clear(&database).await;
+                store_bulk(&database, dag).await;
+            });
+        });
+
+        group.bench_with_input(BenchmarkId::new("unbatched", size), &dag, |b, dag| {
+            b.to_async(&runtime).iter(|| async {
+                clear(&database).await;
+                store_unbatched(&database, dag).await;
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_resync);
+criterion_main!(benches);
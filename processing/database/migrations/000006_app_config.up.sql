@@ -1,7 +1,7 @@
 CREATE TABLE app_config
 (
     id                 BOOLEAN    PRIMARY KEY DEFAULT TRUE,
-    tondid_version     TEXT       NOT NULL,
+    kaspad_version     TEXT       NOT NULL,
     processing_version TEXT       NOT NULL,
     CONSTRAINT unique_row CHECK (id)
 );
\ No newline at end of file
@@ -0,0 +1,8 @@
+-- Make sure the block id lists are stored as JSONB (databases created from
+-- early schemas may still have JSON columns) and index parent lookups
+ALTER TABLE blocks
+    ALTER COLUMN parent_ids TYPE JSONB USING parent_ids::JSONB,
+    ALTER COLUMN merge_set_red_ids TYPE JSONB USING merge_set_red_ids::JSONB,
+    ALTER COLUMN merge_set_blue_ids TYPE JSONB USING merge_set_blue_ids::JSONB;
+
+CREATE INDEX blocks_parent_ids_idx ON blocks USING GIN (parent_ids jsonb_path_ops);
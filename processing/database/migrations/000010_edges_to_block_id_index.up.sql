@@ -0,0 +1 @@
+CREATE INDEX edges_to_block_id_idx ON edges(to_block_id);
@@ -0,0 +1,2 @@
+ALTER TABLE blocks
+    ADD COLUMN is_header_only BOOLEAN DEFAULT FALSE NOT NULL;
@@ -0,0 +1,4 @@
+ALTER TABLE blocks
+    ADD COLUMN miner TEXT;
+
+CREATE INDEX idx_blocks_miner ON blocks (miner, daa_score) WHERE miner IS NOT NULL;
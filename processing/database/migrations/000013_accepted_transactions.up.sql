@@ -0,0 +1,8 @@
+CREATE TABLE accepted_transactions
+(
+    accepting_block_id BIGINT   NOT NULL,
+    transaction_id     CHAR(64) NOT NULL,
+    PRIMARY KEY (accepting_block_id, transaction_id)
+);
+
+CREATE INDEX idx_accepted_transactions_transaction_id ON accepted_transactions (transaction_id);
@@ -0,0 +1,8 @@
+CREATE TABLE pending_selected_parents
+(
+    block_id             BIGINT   NOT NULL,
+    selected_parent_hash CHAR(64) NOT NULL,
+    PRIMARY KEY (block_id)
+);
+
+CREATE INDEX idx_pending_selected_parents_selected_parent_hash ON pending_selected_parents (selected_parent_hash);
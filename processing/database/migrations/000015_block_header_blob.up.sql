@@ -0,0 +1,2 @@
+ALTER TABLE blocks
+    ADD COLUMN header_blob BYTEA;
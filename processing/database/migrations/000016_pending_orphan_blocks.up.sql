@@ -0,0 +1,8 @@
+CREATE TABLE pending_orphan_blocks
+(
+    block_hash  CHAR(64) NOT NULL,
+    parent_hash CHAR(64) NOT NULL,
+    PRIMARY KEY (block_hash, parent_hash)
+);
+
+CREATE INDEX idx_pending_orphan_blocks_parent_hash ON pending_orphan_blocks (parent_hash);
@@ -0,0 +1,8 @@
+CREATE TABLE pending_parent_edges
+(
+    block_id    BIGINT   NOT NULL,
+    parent_hash CHAR(64) NOT NULL,
+    PRIMARY KEY (block_id, parent_hash)
+);
+
+CREATE INDEX idx_pending_parent_edges_parent_hash ON pending_parent_edges (parent_hash);
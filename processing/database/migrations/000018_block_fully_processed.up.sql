@@ -0,0 +1,4 @@
+-- Set once a block got its selected parent and every merge set block, so
+-- processing it again can be skipped
+ALTER TABLE blocks
+    ADD COLUMN fully_processed BOOLEAN NOT NULL DEFAULT FALSE;
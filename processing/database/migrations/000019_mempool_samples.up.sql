@@ -0,0 +1,7 @@
+CREATE TABLE mempool_samples
+(
+    sampled_at        BIGINT NOT NULL PRIMARY KEY,
+    transaction_count BIGINT NOT NULL,
+    orphan_count      BIGINT NOT NULL,
+    total_fees        BIGINT NOT NULL
+);
@@ -0,0 +1,6 @@
+-- Chain block whose merge set set the color of a block, so the color can be
+-- reset when that chain block leaves the virtual selected parent chain
+ALTER TABLE blocks
+    ADD COLUMN colored_by_block_id BIGINT;
+
+CREATE INDEX blocks_colored_by_block_id_idx ON blocks (colored_by_block_id) WHERE colored_by_block_id IS NOT NULL;
@@ -0,0 +1,9 @@
+-- Notifications whose processing kept failing, retried on the next start
+CREATE TABLE failed_notifications
+(
+    kind       VARCHAR(32) NOT NULL,
+    block_hash CHAR(64)    NOT NULL,
+    error      TEXT        NOT NULL,
+    failed_at  BIGINT      NOT NULL,
+    PRIMARY KEY (kind, block_hash)
+);
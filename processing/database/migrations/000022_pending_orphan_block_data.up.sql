@@ -0,0 +1,13 @@
+-- Blocks deferred in pending_orphan_blocks, kept whole so they are stored
+-- without asking the node again once a parent arrives, and deferred_at (in
+-- milliseconds) so those whose parents never arrive expire. Rows deferred
+-- before have no data and are dropped by the first expiry sweep
+CREATE TABLE pending_orphan_block_data
+(
+    block_hash  CHAR(64) NOT NULL,
+    block       JSONB    NOT NULL,
+    deferred_at BIGINT   NOT NULL,
+    PRIMARY KEY (block_hash)
+);
+
+CREATE INDEX idx_pending_orphan_block_data_deferred_at ON pending_orphan_block_data (deferred_at);
@@ -0,0 +1,2 @@
+ALTER TABLE blocks
+    ALTER COLUMN block_hash TYPE CHAR(64) USING encode(block_hash, 'hex');
@@ -0,0 +1,10 @@
+-- Stores block hashes as 32 raw bytes instead of 64 hex characters, halving
+-- the size of the column and of its index. TGI processing detects the column
+-- type on startup and fails to reconnect when it changed meanwhile. The
+-- bundled api reads hashes as hex text and does not support compact hashes.
+-- `cargo bench --bench hash_storage` measures the difference.
+--
+-- Not part of the numbered migrations: apply it by hand, with TGI stopped,
+-- after every migration ran. It rewrites the whole blocks table.
+ALTER TABLE blocks
+    ALTER COLUMN block_hash TYPE BYTEA USING decode(block_hash, 'hex');
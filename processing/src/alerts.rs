@@ -0,0 +1,213 @@
+//! Operator alerts on notable events, POSTed as JSON to a webhook:
+//!
+//! ```json
+//! {
+//!   "kind": "sync_stalled",
+//!   "message": "No block was processed in the last 300 seconds ...",
+//!   "timestamp": 1700000000000
+//! }
+//! ```
+//!
+//! Alerts are sent in the background and the same kind is raised at most
+//! once per `ALERT_COOLDOWN`, so a flapping condition doesn't flood the
+//! webhook. Delivery is retried a few times, then dropped with a warning.
+
+use crate::events::SendFuture;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Minimum time between two alerts of the same kind
+const ALERT_COOLDOWN: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertKind {
+    SyncCompleted,
+    SyncStalled,
+    DeepReorg,
+    RpcDisconnected,
+    DatabaseConnectionLost,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Alert {
+    pub kind: AlertKind,
+    pub message: String,
+    /// Time the alert was raised, in milliseconds since the Unix epoch
+    pub timestamp: i64,
+}
+
+/// Destination of operator alerts.
+pub trait AlertSink: Send + Sync {
+    fn send(&self, alert: Alert) -> SendFuture<'_>;
+}
+
+/// Posts alerts to a webhook.
+#[cfg(feature = "webhook")]
+pub struct WebhookAlertSink {
+    client: reqwest::Client,
+    url: String,
+}
+
+#[cfg(feature = "webhook")]
+impl WebhookAlertSink {
+    const ATTEMPTS: u32 = 3;
+    const TIMEOUT: Duration = Duration::from_secs(10);
+    const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+    pub fn new(url: &str) -> Result<Self> {
+        Ok(Self {
+            client: reqwest::Client::builder().timeout(Self::TIMEOUT).build()?,
+            url: url.to_string(),
+        })
+    }
+}
+
+#[cfg(feature = "webhook")]
+impl AlertSink for WebhookAlertSink {
+    fn send(&self, alert: Alert) -> SendFuture<'_> {
+        Box::pin(async move {
+            let mut attempt = 1;
+            loop {
+                let result = self.client.post(&self.url).json(&alert).send().await
+                    .and_then(|response| response.error_for_status());
+                match result {
+                    Ok(_) => return Ok(()),
+                    Err(e) if attempt < Self::ATTEMPTS => {
+                        warn!("Alert webhook failed (attempt {}/{}), retrying: {}", attempt, Self::ATTEMPTS, e);
+                        attempt += 1;
+                        tokio::time::sleep(Self::RETRY_DELAY).await;
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        })
+    }
+}
+
+struct Alerts {
+    sink: Arc<dyn AlertSink>,
+    last_raised: Mutex<HashMap<AlertKind, Instant>>,
+}
+
+static ALERTS: OnceLock<Alerts> = OnceLock::new();
+
+/// Routes the alerts raised from now on to `sink`. Only the first call has
+/// an effect.
+pub fn init(sink: Arc<dyn AlertSink>) {
+    let _ = ALERTS.set(Alerts { sink, last_raised: Mutex::new(HashMap::new()) });
+}
+
+/// Sets up the sink selected by the configuration, if any.
+pub fn init_from_config(config: &crate::config::Config) -> Result<()> {
+    let Some(url) = config.alert_webhook() else {
+        return Ok(());
+    };
+    #[cfg(feature = "webhook")]
+    {
+        tracing::info!("Sending operator alerts to {}", url);
+        init(Arc::new(WebhookAlertSink::new(&url)?));
+        Ok(())
+    }
+    #[cfg(not(feature = "webhook"))]
+    {
+        anyhow::bail!("--alert-webhook {} requires TGI to be built with the webhook feature", url)
+    }
+}
+
+/// Sends an alert in the background. Does nothing without a sink, or when
+/// an alert of the same kind was raised within the cooldown.
+pub fn raise(kind: AlertKind, message: impl Into<String>) {
+    let Some(alerts) = ALERTS.get() else {
+        return;
+    };
+    {
+        let mut last_raised = alerts.last_raised.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        if last_raised.get(&kind).is_some_and(|raised| now.duration_since(*raised) < ALERT_COOLDOWN) {
+            return;
+        }
+        last_raised.insert(kind, now);
+    }
+    let alert = Alert {
+        kind,
+        message: message.into(),
+        timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_millis() as i64),
+    };
+    let sink = alerts.sink.clone();
+    tokio::spawn(async move {
+        if let Err(e) = sink.send(alert).await {
+            warn!("Could not deliver an operator alert: {}", e);
+        }
+    });
+}
+
+#[cfg(all(test, feature = "webhook"))]
+mod tests {
+    use super::*;
+    use axum::extract::State;
+    use axum::http::StatusCode;
+    use axum::Json;
+
+    /// Alerts POSTed to the mock webhook, which fails the first `failures`
+    /// of them
+    #[derive(Clone, Default)]
+    struct Received {
+        alerts: Arc<Mutex<Vec<Alert>>>,
+        failures: Arc<Mutex<usize>>,
+    }
+
+    async fn receive(State(received): State<Received>, Json(alert): Json<Alert>) -> StatusCode {
+        received.alerts.lock().unwrap().push(alert);
+        let mut failures = received.failures.lock().unwrap();
+        if *failures > 0 {
+            *failures -= 1;
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+        StatusCode::OK
+    }
+
+    async fn mock_webhook(failures: usize) -> (String, Received) {
+        let received = Received { failures: Arc::new(Mutex::new(failures)), ..Default::default() };
+        let router = axum::Router::new().route("/alerts", axum::routing::post(receive)).with_state(received.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/alerts", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        (url, received)
+    }
+
+    fn alert() -> Alert {
+        Alert { kind: AlertKind::DeepReorg, message: "Reorg of 12 chain blocks".to_string(), timestamp: 1700000000000 }
+    }
+
+    #[tokio::test]
+    async fn alert_is_posted_as_json() {
+        let (url, received) = mock_webhook(0).await;
+
+        WebhookAlertSink::new(&url).unwrap().send(alert()).await.unwrap();
+
+        assert_eq!(*received.alerts.lock().unwrap(), vec![alert()]);
+    }
+
+    #[tokio::test]
+    async fn failed_delivery_is_retried() {
+        let (url, received) = mock_webhook(1).await;
+
+        WebhookAlertSink::new(&url).unwrap().send(alert()).await.unwrap();
+
+        assert_eq!(*received.alerts.lock().unwrap(), vec![alert(), alert()]);
+    }
+
+    #[tokio::test]
+    async fn delivery_gives_up_after_the_last_attempt() {
+        let (url, received) = mock_webhook(usize::MAX).await;
+
+        assert!(WebhookAlertSink::new(&url).unwrap().send(alert()).await.is_err());
+
+        assert_eq!(received.alerts.lock().unwrap().len(), WebhookAlertSink::ATTEMPTS as usize);
+    }
+}
@@ -0,0 +1,386 @@
+//! Read-only HTTP JSON API over the stored blocks and edges.
+//!
+//! Endpoints:
+//! - `GET /blocks?from_height=&to_height=[&offset=&limit=]`
+//! - `GET /block/{hash}`
+//! - `GET /blocks/search?prefix=[&limit=]` (blocks whose hash starts with
+//!   `prefix`)
+//! - `GET /edges?from_height=&to_height=[&offset=&limit=]` (edges with a
+//!   block in range)
+//! - `GET /height-groups?from_height=&to_height=` (block count of every
+//!   height in range)
+//! - `GET /status`
+//! - `GET /metrics` (Prometheus text format, with the `metrics` feature)
+//!
+//! Queries run on the read connection, so with a replica the results may lag
+//! behind the processed blocks.
+
+use crate::database::{Block, Database, Edge, HeightGroup};
+use crate::rpc_client::BlockHash;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{info, warn};
+
+/// Widest height range a single request may cover
+const MAX_HEIGHT_WINDOW: u64 = 1000;
+const DEFAULT_PAGE_LIMIT: u64 = 1000;
+const MAX_PAGE_LIMIT: u64 = 10000;
+const DEFAULT_SEARCH_LIMIT: u64 = 20;
+const MAX_SEARCH_LIMIT: u64 = 100;
+
+#[derive(Debug, Deserialize)]
+struct HeightRangeQuery {
+    from_height: u64,
+    to_height: u64,
+    #[serde(default)]
+    offset: u64,
+    limit: Option<u64>,
+}
+
+impl HeightRangeQuery {
+    fn validate(&self) -> Result<u64, ApiError> {
+        if self.to_height < self.from_height {
+            return Err(ApiError::BadRequest("to_height must not be lower than from_height".to_string()));
+        }
+        if self.to_height - self.from_height > MAX_HEIGHT_WINDOW {
+            return Err(ApiError::BadRequest(format!("Height range must not exceed {} heights", MAX_HEIGHT_WINDOW)));
+        }
+        let limit = self.limit.unwrap_or(DEFAULT_PAGE_LIMIT);
+        if limit == 0 || limit > MAX_PAGE_LIMIT {
+            return Err(ApiError::BadRequest(format!("limit must be between 1 and {}", MAX_PAGE_LIMIT)));
+        }
+        Ok(limit)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct HashPrefixQuery {
+    prefix: String,
+    limit: Option<u64>,
+}
+
+enum ApiError {
+    BadRequest(String),
+    NotFound(String),
+    Internal(anyhow::Error),
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(error: anyhow::Error) -> Self {
+        ApiError::Internal(error)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            ApiError::BadRequest(message) => (StatusCode::BAD_REQUEST, message),
+            ApiError::NotFound(message) => (StatusCode::NOT_FOUND, message),
+            ApiError::Internal(error) => {
+                warn!("API request failed: {}", error);
+                (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error".to_string())
+            }
+        };
+        (status, Json(json!({ "error": message }))).into_response()
+    }
+}
+
+pub fn router(database: Database) -> Router {
+    let router = Router::new()
+        .route("/blocks", get(get_blocks))
+        .route("/blocks/search", get(search_blocks))
+        .route("/block/:hash", get(get_block))
+        .route("/edges", get(get_edges))
+        .route("/height-groups", get(get_height_groups))
+        .route("/status", get(get_status));
+    #[cfg(feature = "metrics")]
+    let router = router.route("/metrics", get(get_metrics));
+    router.with_state(database)
+}
+
+pub async fn serve(database: Database, address: &str) -> anyhow::Result<()> {
+    let listener = tokio::net::TcpListener::bind(address).await?;
+    info!("Serving the query API on {}", listener.local_addr()?);
+    axum::serve(listener, router(database)).await?;
+    Ok(())
+}
+
+async fn get_blocks(
+    State(database): State<Database>,
+    Query(query): Query<HeightRangeQuery>,
+) -> Result<Json<Vec<Block>>, ApiError> {
+    let limit = query.validate()?;
+    let database_for_closure = database.clone();
+    let blocks = database.run_in_read_transaction(move |tx| {
+        Box::pin(async move {
+            Ok(database_for_closure.blocks_between_heights(tx, query.from_height, query.to_height, query.offset, limit).await?)
+        })
+    }).await?;
+    Ok(Json(blocks))
+}
+
+async fn search_blocks(
+    State(database): State<Database>,
+    Query(query): Query<HashPrefixQuery>,
+) -> Result<Json<Vec<Block>>, ApiError> {
+    let prefix = Database::normalize_hash_prefix(&query.prefix).map_err(|e| ApiError::BadRequest(e.to_string()))?;
+    let limit = query.limit.unwrap_or(DEFAULT_SEARCH_LIMIT);
+    if limit == 0 || limit > MAX_SEARCH_LIMIT {
+        return Err(ApiError::BadRequest(format!("limit must be between 1 and {}", MAX_SEARCH_LIMIT)));
+    }
+    let database_for_closure = database.clone();
+    let blocks = database.run_in_read_transaction(move |tx| {
+        Box::pin(async move {
+            Ok(database_for_closure.blocks_by_hash_prefix(tx, &prefix, limit).await?)
+        })
+    }).await?;
+    Ok(Json(blocks))
+}
+
+async fn get_block(
+    State(database): State<Database>,
+    Path(hash): Path<String>,
+) -> Result<Json<Block>, ApiError> {
+    let hash = BlockHash::parse(&hash).map_err(|e| ApiError::BadRequest(e.to_string()))?;
+    let database_for_closure = database.clone();
+    let hash_for_closure = hash;
+    let block = database.run_in_read_transaction(move |tx| {
+        Box::pin(async move {
+            Ok(database_for_closure.block_by_hash(tx, &hash_for_closure).await?)
+        })
+    }).await?;
+    block.map(Json).ok_or_else(|| ApiError::NotFound(format!("Block {} not found", hash)))
+}
+
+async fn get_edges(
+    State(database): State<Database>,
+    Query(query): Query<HeightRangeQuery>,
+) -> Result<Json<Vec<Edge>>, ApiError> {
+    let limit = query.validate()?;
+    let database_for_closure = database.clone();
+    let edges = database.run_in_read_transaction(move |tx| {
+        Box::pin(async move {
+            Ok(database_for_closure.edges_between_heights(tx, query.from_height, query.to_height, query.offset, limit).await?)
+        })
+    }).await?;
+    Ok(Json(edges))
+}
+
+/// Block counts of the heights in range, for laying out a window of the
+/// graph. `offset` and `limit` don't apply: the range holds at most one
+/// group per height.
+async fn get_height_groups(
+    State(database): State<Database>,
+    Query(query): Query<HeightRangeQuery>,
+) -> Result<Json<Vec<HeightGroup>>, ApiError> {
+    query.validate()?;
+    let database_for_closure = database.clone();
+    let height_groups = database.run_in_read_transaction(move |tx| {
+        Box::pin(async move {
+            Ok(database_for_closure.height_groups_between_heights(tx, query.from_height, query.to_height).await?)
+        })
+    }).await?;
+    Ok(Json(height_groups))
+}
+
+/// Reports the most recently processed block, so monitoring can tell
+/// whether processing still advances.
+async fn get_status(State(database): State<Database>) -> Result<Json<serde_json::Value>, ApiError> {
+    let database_for_closure = database.clone();
+    let latest_block = database.run_in_read_transaction(move |tx| {
+        Box::pin(async move {
+            Ok(database_for_closure.latest_block(tx).await?)
+        })
+    }).await?;
+    let latest_block = latest_block.map(|block| json!({
+        "id": block.id,
+        "block_hash": block.block_hash,
+        "timestamp": block.timestamp,
+    }));
+    Ok(Json(json!({ "latest_block": latest_block })))
+}
+
+#[cfg(feature = "metrics")]
+async fn get_metrics() -> String {
+    crate::metrics::render()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::testing::TestDatabase;
+    use crate::database::COLOR_BLUE;
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn hash(n: u64) -> BlockHash {
+        BlockHash::parse(&format!("{:064x}", n)).unwrap()
+    }
+
+    /// Stores a chain of `count` blocks, one per height, with the edges
+    /// between them
+    async fn insert_chain(database: &Database, count: u64) {
+        let database_for_closure = database.clone();
+        database.run_in_transaction(move |tx| {
+            let database = database_for_closure.clone();
+            Box::pin(async move {
+                let mut parent_id = None;
+                for height in 0..count {
+                    let block = Block {
+                        id: 0,
+                        block_hash: hash(height).to_string(),
+                        timestamp: 0,
+                        parent_ids: parent_id.into_iter().collect(),
+                        daa_score: height,
+                        height,
+                        height_group_index: 0,
+                        selected_parent_id: parent_id,
+                        color: COLOR_BLUE.to_string(),
+                        is_in_virtual_selected_parent_chain: true,
+                        merge_set_red_ids: vec![],
+                        merge_set_blue_ids: vec![],
+                        is_header_only: false,
+                        miner: None,
+                    };
+                    database.insert_block(tx, &hash(height), &block).await?;
+                    let id = database.block_id_by_hash(tx, &hash(height)).await?;
+                    if let Some(parent_id) = parent_id {
+                        database.insert_edge(tx, &Edge {
+                            from_block_id: id,
+                            to_block_id: parent_id,
+                            from_height: height,
+                            to_height: height - 1,
+                            from_height_group_index: 0,
+                            to_height_group_index: 0,
+                        }).await?;
+                    }
+                    parent_id = Some(id);
+                }
+                Ok(())
+            })
+        }).await.unwrap();
+    }
+
+    async fn serve_for_test(database: Database) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router(database)).await });
+        address
+    }
+
+    /// Status code and JSON body of `GET path`
+    async fn get_json(address: SocketAddr, path: &str) -> (u16, serde_json::Value) {
+        let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        stream.write_all(format!("GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", path, address).as_bytes())
+            .await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").expect("the response has a body");
+        let status = head.split_whitespace().nth(1).and_then(|status| status.parse().ok()).expect("the response has a status");
+        (status, serde_json::from_str(body).expect("the body is JSON"))
+    }
+
+    fn block_hashes(body: &serde_json::Value) -> Vec<String> {
+        body.as_array().expect("the body is an array").iter()
+            .map(|block| block["block_hash"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn blocks_are_served_by_height_range_and_hash() {
+        let Some(test_database) = TestDatabase::create().await else { return };
+        insert_chain(&test_database.database, 3).await;
+        let address = serve_for_test(test_database.database.clone()).await;
+
+        let (status, body) = get_json(address, "/blocks?from_height=1&to_height=2").await;
+        assert_eq!(status, 200);
+        assert_eq!(block_hashes(&body), vec![hash(1).to_string(), hash(2).to_string()]);
+
+        let (status, body) = get_json(address, "/blocks?from_height=0&to_height=2&offset=1&limit=1").await;
+        assert_eq!(status, 200);
+        assert_eq!(block_hashes(&body), vec![hash(1).to_string()]);
+
+        let (status, body) = get_json(address, &format!("/block/{}", hash(2))).await;
+        assert_eq!(status, 200);
+        assert_eq!(body["height"], 2);
+
+        let (status, _) = get_json(address, &format!("/block/{}", hash(9))).await;
+        assert_eq!(status, 404);
+        let (status, _) = get_json(address, "/block/not-a-hash").await;
+        assert_eq!(status, 400);
+    }
+
+    #[tokio::test]
+    async fn edges_are_served_by_height_range() {
+        let Some(test_database) = TestDatabase::create().await else { return };
+        insert_chain(&test_database.database, 3).await;
+        let address = serve_for_test(test_database.database.clone()).await;
+
+        let (status, body) = get_json(address, "/edges?from_height=0&to_height=0").await;
+        assert_eq!(status, 200);
+        let edges = body.as_array().unwrap();
+        assert_eq!(edges.len(), 1);
+        assert_eq!((edges[0]["from_height"].as_u64(), edges[0]["to_height"].as_u64()), (Some(1), Some(0)));
+
+        let (status, body) = get_json(address, "/edges?from_height=0&to_height=2").await;
+        assert_eq!(status, 200);
+        assert_eq!(body.as_array().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn height_groups_are_served_by_height_range() {
+        let Some(test_database) = TestDatabase::create().await else { return };
+        let database_for_closure = test_database.database.clone();
+        test_database.database.run_in_transaction(move |tx| {
+            let database = database_for_closure.clone();
+            Box::pin(async move {
+                let groups = [HeightGroup { height: 1, size: 2 }, HeightGroup { height: 2, size: 1 }, HeightGroup { height: 4, size: 3 }];
+                Ok(database.bulk_upsert_height_groups(tx, &groups).await?)
+            })
+        }).await.unwrap();
+        let address = serve_for_test(test_database.database.clone()).await;
+
+        let (status, body) = get_json(address, "/height-groups?from_height=2&to_height=4").await;
+        assert_eq!(status, 200);
+        let sizes: Vec<(u64, u64)> = body.as_array().unwrap().iter()
+            .map(|group| (group["height"].as_u64().unwrap(), group["size"].as_u64().unwrap()))
+            .collect();
+        assert_eq!(sizes, vec![(2, 1), (4, 3)]);
+    }
+
+    #[tokio::test]
+    async fn oversized_windows_are_rejected() {
+        let Some(test_database) = TestDatabase::create().await else { return };
+        let address = serve_for_test(test_database.database.clone()).await;
+
+        let (status, body) = get_json(address, &format!("/blocks?from_height=0&to_height={}", MAX_HEIGHT_WINDOW + 1)).await;
+        assert_eq!(status, 400);
+        assert!(body["error"].as_str().unwrap().contains("Height range"));
+        let (status, _) = get_json(address, &format!("/edges?from_height=0&to_height=1&limit={}", MAX_PAGE_LIMIT + 1)).await;
+        assert_eq!(status, 400);
+    }
+
+    fn range(from_height: u64, to_height: u64, limit: Option<u64>) -> HeightRangeQuery {
+        HeightRangeQuery { from_height, to_height, offset: 0, limit }
+    }
+
+    #[test]
+    fn height_ranges_are_capped() {
+        assert!(matches!(range(10, 10 + MAX_HEIGHT_WINDOW, None).validate(), Ok(DEFAULT_PAGE_LIMIT)));
+        assert!(matches!(range(10, 11 + MAX_HEIGHT_WINDOW, None).validate(), Err(ApiError::BadRequest(_))));
+        assert!(matches!(range(0, u64::MAX, None).validate(), Err(ApiError::BadRequest(_))));
+        assert!(matches!(range(11, 10, None).validate(), Err(ApiError::BadRequest(_))));
+    }
+
+    #[test]
+    fn page_limits_are_capped() {
+        assert!(matches!(range(0, 1, Some(MAX_PAGE_LIMIT)).validate(), Ok(MAX_PAGE_LIMIT)));
+        assert!(matches!(range(0, 1, Some(MAX_PAGE_LIMIT + 1)).validate(), Err(ApiError::BadRequest(_))));
+        assert!(matches!(range(0, 1, Some(0)).validate(), Err(ApiError::BadRequest(_))));
+    }
+}
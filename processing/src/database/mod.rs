@@ -1,6 +1,10 @@
 mod model;
 mod operations;
+#[cfg(test)]
+pub(crate) mod testing;
+mod tls;
 
 pub use model::*;
 pub use operations::*;
+pub use tls::*;
 
@@ -18,6 +18,30 @@ pub struct Block {
     pub is_in_virtual_selected_parent_chain: bool,
     pub merge_set_red_ids: Vec<u64>,
     pub merge_set_blue_ids: Vec<u64>,
+    pub is_header_only: bool,
+    /// Hex encoded payout script public key of the coinbase, when miners
+    /// are tracked
+    pub miner: Option<String>,
+}
+
+/// New merge set color of a block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockColorUpdate {
+    pub block_id: u64,
+    pub color: String,
+    /// Chain block whose merge set holds the block. `None` when the block
+    /// turns gray
+    pub colored_by_block_id: Option<u64>,
+}
+
+impl BlockColorUpdate {
+    pub fn gray(block_id: u64) -> Self {
+        Self {
+            block_id,
+            color: COLOR_GRAY.to_string(),
+            colored_by_block_id: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -36,6 +60,84 @@ pub struct HeightGroup {
     pub size: u32,
 }
 
+/// Columns of a stored block that processing keeps for itself rather than
+/// serving them
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockState {
+    pub colored_by_block_id: Option<u64>,
+    pub header_blob: Option<Vec<u8>>,
+    pub fully_processed: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AcceptedTransaction {
+    pub accepting_block_id: u64,
+    pub transaction_id: String,
+}
+
+/// A stored block waiting for its selected parent
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingSelectedParent {
+    pub block_id: u64,
+    pub selected_parent_hash: String,
+}
+
+/// A stored block waiting for the edge to one of its parents
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingParentEdge {
+    pub block_id: u64,
+    pub parent_hash: String,
+}
+
+/// A deferred block waiting for one of its parents
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingOrphanBlock {
+    pub block_hash: String,
+    pub parent_hash: String,
+}
+
+/// A deferred block as serialized by processing
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingOrphanBlockData {
+    pub block_hash: String,
+    /// The block as JSON text
+    pub block: String,
+    /// Milliseconds since the Unix epoch
+    pub deferred_at: i64,
+}
+
+/// Extent of the stored graph. Every bound is `None` while the database is
+/// empty.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GraphBounds {
+    pub min_height: Option<u64>,
+    pub max_height: Option<u64>,
+    pub min_daa_score: Option<u64>,
+    pub max_daa_score: Option<u64>,
+    pub block_count: u64,
+    /// Highest block of the virtual selected parent chain
+    pub chain_tip_hash: Option<String>,
+}
+
+/// Rows changed by a committed transaction, across all tables
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommitSummary {
+    pub rows_inserted: u64,
+    pub rows_updated: u64,
+    pub rows_deleted: u64,
+}
+
+/// Size of the node mempool at one point in time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MempoolSample {
+    /// Milliseconds since the Unix epoch
+    pub sampled_at: i64,
+    pub transaction_count: u64,
+    pub orphan_count: u64,
+    /// Fees of the transactions, orphans excluded, in sompi
+    pub total_fees: u64,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AppConfig {
     pub id: bool,
@@ -1,54 +1,746 @@
+use crate::alerts::{self, AlertKind};
 use crate::database::model::*;
-use anyhow::{Context, Result};
+use crate::database::tls::{DbTlsConfig, DbTlsMode};
+use crate::error::{is_connection_loss, Result, TgiError};
+use crate::rpc_client::BlockHash;
 use lru::LruCache;
+use std::collections::{HashMap, HashSet};
 use std::num::NonZeroUsize;
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use tokio_postgres::{Client, NoTls, Transaction};
+use std::time::Duration;
+use tokio::sync::{Mutex, MutexGuard};
+use tokio_postgres::binary_copy::BinaryCopyInWriter;
+use tokio_postgres::types::{ToSql, Type};
+use tokio_postgres::{Client, IsolationLevel, NoTls, Row, Transaction};
+use tracing::{info, warn};
 
 const BLOCK_BASE_CACHE_CAPACITY: usize = 400000;
 
+/// Schema holding the tables unless another one is configured
+pub const DEFAULT_SCHEMA: &str = "public";
+
+/// Tables created by the migrations that TGI can't run without
+const REQUIRED_TABLES: &[&str] = &[
+    "blocks",
+    "edges",
+    "height_groups",
+    "app_config",
+    "pending_orphan_blocks",
+    "pending_orphan_block_data",
+    "pending_parent_edges",
+    "pending_selected_parents",
+    "accepted_transactions",
+    "mempool_samples",
+    "failed_notifications",
+];
+
+/// Version of the latest migration in database/migrations, which the
+/// schema_migrations table golang-migrate keeps must record.
+pub const LATEST_MIGRATION_VERSION: i64 = 22;
+
+/// Attempts at reconnecting a lost connection before the operation fails.
+/// The delay between attempts grows linearly.
+const RECONNECT_ATTEMPTS: u32 = 5;
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+const BLOCK_COLUMNS: &str = "id, block_hash, timestamp, parent_ids, daa_score, height, height_group_index, \
+    selected_parent_id, color, is_in_virtual_selected_parent_chain, merge_set_red_ids, merge_set_blue_ids, is_header_only, miner";
+
+/// `BLOCK_COLUMNS` for a database storing compact hashes
+const COMPACT_BLOCK_COLUMNS: &str = "id, encode(block_hash, 'hex') AS block_hash, timestamp, parent_ids, daa_score, height, \
+    height_group_index, selected_parent_id, color, is_in_virtual_selected_parent_chain, merge_set_red_ids, merge_set_blue_ids, \
+    is_header_only, miner";
+
+/// How `blocks.block_hash` is stored. Hashes are hex strings everywhere
+/// else, so compact hashes are converted in the queries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashStorage {
+    /// 64 hex characters, as created by the migrations
+    Hex,
+    /// 32 raw bytes, once `database/optional/compact_block_hashes.up.sql`
+    /// was applied
+    Compact,
+}
+
+impl HashStorage {
+    /// Reads the storage of the blocks table in the current schema, `schema`.
+    /// Tables that don't exist yet are created by the migrations, so as hex.
+    /// Fails with `TgiError::UnsupportedHashStorage` on any other column type
+    /// than the migrations and the optional compaction create.
+    async fn detect(client: &Client, schema: &str) -> Result<Self> {
+        let row = client.query_opt(
+            r#"
+            SELECT data_type FROM information_schema.columns
+            WHERE table_schema = current_schema() AND table_name = 'blocks' AND column_name = 'block_hash'
+            "#,
+            &[],
+        ).await?;
+        match row.map(|row| row.get::<_, String>(0)).as_deref() {
+            None | Some("character") | Some("character varying") | Some("text") => Ok(Self::Hex),
+            Some("bytea") => Ok(Self::Compact),
+            Some(data_type) => Err(TgiError::UnsupportedHashStorage {
+                schema: schema.to_string(),
+                data_type: data_type.to_string(),
+            }),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Hex => "hex",
+            Self::Compact => "compact",
+        }
+    }
+
+    /// Expression reading the hash column `column` as hex
+    fn select(self, column: &str) -> String {
+        match self {
+            Self::Hex => column.to_string(),
+            Self::Compact => format!("encode({}, 'hex')", column),
+        }
+    }
+
+    /// Expression turning the hex parameter `placeholder` into a stored hash
+    fn param(self, placeholder: &str) -> String {
+        match self {
+            Self::Hex => placeholder.to_string(),
+            Self::Compact => format!("decode({}, 'hex')", placeholder),
+        }
+    }
+
+    /// Expression turning the hex array parameter `placeholder` into an
+    /// array of stored hashes, keeping the order
+    fn array_param(self, placeholder: &str) -> String {
+        match self {
+            Self::Hex => format!("{}::CHAR(64)[]", placeholder),
+            Self::Compact => format!(
+                "ARRAY(SELECT decode(h, 'hex') FROM unnest({}::TEXT[]) WITH ORDINALITY AS u(h, ord) ORDER BY ord)",
+                placeholder,
+            ),
+        }
+    }
+
+    fn block_columns(self) -> &'static str {
+        match self {
+            Self::Hex => BLOCK_COLUMNS,
+            Self::Compact => COMPACT_BLOCK_COLUMNS,
+        }
+    }
+}
+
+/// Blocks that have parents but no selected parent, point at a missing
+/// selected parent, or reference parents that aren't stored.
+const ORPHAN_BLOCKS_CONDITION: &str = r#"
+    (b.selected_parent_id IS NULL AND jsonb_array_length(b.parent_ids) > 0)
+    OR (b.selected_parent_id IS NOT NULL
+        AND NOT EXISTS (SELECT 1 FROM blocks p WHERE p.id = b.selected_parent_id))
+    OR EXISTS (
+        SELECT 1 FROM jsonb_array_elements_text(b.parent_ids) AS parent(id)
+        LEFT JOIN blocks p ON p.id = parent.id::BIGINT
+        WHERE p.id IS NULL
+    )
+"#;
+
+fn block_from_row(row: &Row) -> Result<Block> {
+    Ok(Block {
+        id: row.get::<_, i64>(0) as u64,
+        block_hash: row.get(1),
+        timestamp: row.get(2),
+        parent_ids: serde_json::from_value(row.get(3))?,
+        daa_score: row.get::<_, i64>(4) as u64,
+        height: row.get::<_, i64>(5) as u64,
+        height_group_index: row.get::<_, i32>(6) as u32,
+        selected_parent_id: row.get::<_, Option<i64>>(7).map(|v| v as u64),
+        color: row.get(8),
+        is_in_virtual_selected_parent_chain: row.get(9),
+        merge_set_red_ids: serde_json::from_value(row.get(10))?,
+        merge_set_blue_ids: serde_json::from_value(row.get(11))?,
+        is_header_only: row.get(12),
+        miner: row.get(13),
+    })
+}
+
+fn edge_from_row(row: &Row) -> Edge {
+    Edge {
+        from_block_id: row.get::<_, i64>(0) as u64,
+        to_block_id: row.get::<_, i64>(1) as u64,
+        from_height: row.get::<_, i64>(2) as u64,
+        to_height: row.get::<_, i64>(3) as u64,
+        from_height_group_index: row.get::<_, i32>(4) as u32,
+        to_height_group_index: row.get::<_, i32>(5) as u32,
+    }
+}
+
+fn height_group_from_row(row: &Row) -> HeightGroup {
+    HeightGroup {
+        height: row.get::<_, i64>(0) as u64,
+        size: row.get::<_, i32>(1) as u32,
+    }
+}
+
+/// Which stored blocks are loaded into the block cache on startup. Misses
+/// cost a query each, so warming more blocks speeds up the first resync
+/// cycles at the cost of memory and startup time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheWarming {
+    /// Every block at or above the height
+    FromHeight(u64),
+    /// The most recently inserted blocks
+    Recent(u64),
+    /// Start with an empty cache
+    Skip,
+}
+
 #[derive(Clone)]
 struct BlockBase {
     id: u64,
     height: u64,
 }
 
+/// Whether a transaction failed with `error` because its database
+/// connection was lost
+fn lost_connection(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        matches!(cause.downcast_ref::<TgiError>(), Some(TgiError::DatabaseConnectionLost(_)))
+            || cause.downcast_ref::<tokio_postgres::Error>().is_some_and(is_connection_loss)
+    })
+}
+
+/// Whether a transaction failed with `error` after its COMMIT was sent, so
+/// it may have been committed
+fn commit_outcome_unknown(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| matches!(cause.downcast_ref::<TgiError>(), Some(TgiError::CommitOutcomeUnknown(_))))
+}
+
+/// Quotes a PostgreSQL identifier, doubling embedded quotes
+fn quote_identifier(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+/// What it takes to open the connections again after they were lost
+struct ConnectParams {
+    connection_string: String,
+    read_connection_string: Option<String>,
+    tls_config: DbTlsConfig,
+    tcp_config: DbTcpConfig,
+    statement_timeout: Option<Duration>,
+    application_name: Option<String>,
+    schema: String,
+}
+
+/// TCP settings of the database connections. Unset values keep the driver
+/// and OS defaults, and override the same settings of the connection string.
+#[derive(Debug, Clone, Default)]
+pub struct DbTcpConfig {
+    /// Idle time after which TCP keepalive probes are sent
+    pub keepalives_idle: Option<Duration>,
+    /// Time transmitted data may remain unacknowledged before the connection
+    /// is closed
+    pub tcp_user_timeout: Option<Duration>,
+}
+
+/// Ids and heights of blocks by hash. Entries written while a write
+/// transaction runs are tracked, so they can be evicted if it rolls back
+/// instead of resolving blocks that were never stored.
+struct BlockBaseCache {
+    entries: LruCache<BlockHash, BlockBase>,
+    tracked: Option<Vec<BlockHash>>,
+}
+
+impl BlockBaseCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            entries: LruCache::new(NonZeroUsize::new(capacity).unwrap()),
+            tracked: None,
+        }
+    }
+
+    fn peek(&self, block_hash: &BlockHash) -> Option<&BlockBase> {
+        self.entries.peek(block_hash)
+    }
+
+    fn put(&mut self, block_hash: BlockHash, block_base: BlockBase) {
+        if let Some(tracked) = &mut self.tracked {
+            tracked.push(block_hash);
+        }
+        self.entries.put(block_hash, block_base);
+    }
+
+    fn set_height(&mut self, block_hash: &BlockHash, height: u64) {
+        if let Some(block_base) = self.entries.peek_mut(block_hash) {
+            block_base.height = height;
+            if let Some(tracked) = &mut self.tracked {
+                tracked.push(*block_hash);
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    fn remove(&mut self, block_hashes: &[BlockHash]) {
+        for block_hash in block_hashes {
+            self.entries.pop(block_hash);
+        }
+    }
+
+    /// Starts tracking the entries a write transaction writes. Entries left
+    /// tracked by a transaction that never ended, e.g. because its future
+    /// was dropped, are evicted first.
+    fn start_tracking(&mut self) {
+        self.evict_tracked();
+        self.tracked = Some(Vec::new());
+    }
+
+    /// Keeps the entries written by the committed transaction.
+    fn stop_tracking(&mut self) {
+        self.tracked = None;
+    }
+
+    /// Evicts the entries written by the rolled back transaction.
+    fn evict_tracked(&mut self) {
+        for block_hash in self.tracked.take().unwrap_or_default() {
+            self.entries.pop(&block_hash);
+        }
+    }
+}
+
+/// Called after every transaction committed by `run_in_transaction`
+pub type CommitHook = Arc<dyn Fn(CommitSummary) + Send + Sync>;
+
 #[derive(Clone)]
 pub struct Database {
     client: Arc<Mutex<Client>>,
-    block_base_cache: Arc<Mutex<LruCache<String, BlockBase>>>,
+    read_client: Arc<Mutex<Client>>,
+    connect_params: Arc<ConnectParams>,
+    block_base_cache: Arc<Mutex<BlockBaseCache>>,
+    commit_hook: Option<CommitHook>,
+    hash_storage: HashStorage,
+    isolation_level: IsolationLevel,
+    acquire_timeout: Option<Duration>,
 }
 
 impl Database {
-    pub async fn connect(connection_string: &str) -> Result<Self> {
-        let (client, connection) = tokio_postgres::connect(connection_string, NoTls).await?;
+    /// Connects to the primary database. Read-only work goes to
+    /// `read_connection_string` when given, and to a second connection to the
+    /// primary otherwise, so reads never wait for the write connection.
+    /// `tcp_config`, `statement_timeout` and `application_name` apply to both
+    /// connections, and tables are looked up in `schema`. An application name
+    /// set by a connection string takes precedence over `application_name`.
+    pub async fn connect(
+        connection_string: &str,
+        read_connection_string: Option<&str>,
+        tls_config: &DbTlsConfig,
+        tcp_config: &DbTcpConfig,
+        statement_timeout: Option<Duration>,
+        application_name: Option<&str>,
+        schema: &str,
+    ) -> Result<Self> {
+        let client = Arc::new(Mutex::new(
+            Self::connect_client(connection_string, tls_config, tcp_config, statement_timeout, application_name, schema).await?
+        ));
+        let read_client = Arc::new(Mutex::new(
+            Self::connect_client(
+                read_connection_string.unwrap_or(connection_string),
+                tls_config, tcp_config, statement_timeout, application_name, schema,
+            ).await?
+        ));
+
+        let hash_storage = HashStorage::detect(&*client.lock().await, schema).await?;
+        if hash_storage == HashStorage::Compact {
+            info!("Block hashes are stored as compact bytes");
+        }
+
+        Ok(Self {
+            client,
+            read_client,
+            connect_params: Arc::new(ConnectParams {
+                connection_string: connection_string.to_string(),
+                read_connection_string: read_connection_string.map(str::to_string),
+                tls_config: tls_config.clone(),
+                tcp_config: tcp_config.clone(),
+                statement_timeout,
+                application_name: application_name.map(str::to_string),
+                schema: schema.to_string(),
+            }),
+            block_base_cache: Arc::new(Mutex::new(BlockBaseCache::new(BLOCK_BASE_CACHE_CAPACITY))),
+            commit_hook: None,
+            hash_storage,
+            isolation_level: IsolationLevel::ReadCommitted,
+            acquire_timeout: None,
+        })
+    }
+
+    /// Runs write transactions at `isolation_level` instead of PostgreSQL's
+    /// default READ COMMITTED. Stricter levels may fail a transaction that
+    /// conflicts with a concurrent writer, which is reported as
+    /// `TgiError::SerializationFailure`.
+    pub fn with_isolation_level(mut self, isolation_level: IsolationLevel) -> Self {
+        self.isolation_level = isolation_level;
+        self
+    }
+
+    /// Bounds how long a transaction waits for its connection while other
+    /// transactions hold it. Past it, the transaction fails with
+    /// `TgiError::AcquireTimeout` instead of hanging behind a slow one.
+    pub fn with_acquire_timeout(mut self, acquire_timeout: Option<Duration>) -> Self {
+        self.acquire_timeout = acquire_timeout;
+        self
+    }
+
+    /// Locks `client`, waiting at most the acquire timeout.
+    async fn acquire<'a>(&self, client: &'a Mutex<Client>) -> Result<MutexGuard<'a, Client>> {
+        match self.acquire_timeout {
+            Some(acquire_timeout) => tokio::time::timeout(acquire_timeout, client.lock()).await
+                .map_err(|_| TgiError::AcquireTimeout(acquire_timeout)),
+            None => Ok(client.lock().await),
+        }
+    }
 
-        // Spawn connection handler
-        tokio::spawn(async move {
-            if let Err(e) = connection.await {
-                eprintln!("Database connection error: {}", e);
+    /// Calls `hook` with the rows each write transaction changed, once it
+    /// committed. Counting the rows costs a query per transaction, which is
+    /// skipped without a hook.
+    pub fn with_commit_hook(mut self, hook: CommitHook) -> Self {
+        self.commit_hook = Some(hook);
+        self
+    }
+
+    async fn connect_client(
+        connection_string: &str,
+        tls_config: &DbTlsConfig,
+        tcp_config: &DbTcpConfig,
+        statement_timeout: Option<Duration>,
+        application_name: Option<&str>,
+        schema: &str,
+    ) -> Result<Client> {
+        let (tls_mode, connection_string) = tls_config.resolve(connection_string);
+        let mut pg_config: tokio_postgres::Config = connection_string.parse()?;
+        if let Some(application_name) = application_name {
+            if pg_config.get_application_name().is_none() {
+                pg_config.application_name(application_name);
             }
-        });
+        }
+        if let Some(keepalives_idle) = tcp_config.keepalives_idle {
+            pg_config.keepalives(true).keepalives_idle(keepalives_idle);
+        }
+        if let Some(tcp_user_timeout) = tcp_config.tcp_user_timeout {
+            pg_config.tcp_user_timeout(tcp_user_timeout);
+        }
+        let client = match tls_mode {
+            DbTlsMode::Disabled => {
+                let (client, connection) = pg_config.connect(NoTls).await?;
 
-        let cache = LruCache::new(
-            NonZeroUsize::new(BLOCK_BASE_CACHE_CAPACITY).unwrap()
-        );
+                // Spawn connection handler
+                tokio::spawn(async move {
+                    if let Err(e) = connection.await {
+                        warn!("Database connection error: {}", e);
+                    }
+                });
+                client
+            }
+            tls_mode => {
+                let connector = tls_config.connector(&tls_mode)?;
+                let (client, connection) = pg_config.connect(connector).await?;
 
-        Ok(Self {
-            client: Arc::new(Mutex::new(client)),
-            block_base_cache: Arc::new(Mutex::new(cache)),
+                // Spawn connection handler
+                tokio::spawn(async move {
+                    if let Err(e) = connection.await {
+                        warn!("Database connection error: {}", e);
+                    }
+                });
+                client
+            }
+        };
+        if let Some(statement_timeout) = statement_timeout {
+            client.batch_execute(&format!("SET statement_timeout = {}", statement_timeout.as_millis())).await?;
+        }
+        // The default search path is kept for the public schema, so existing
+        // deployments relying on it behave as before
+        if schema != DEFAULT_SCHEMA {
+            client.batch_execute(&format!("SET search_path TO {}", quote_identifier(schema))).await?;
+        }
+        Ok(client)
+    }
+
+    /// Creates the configured schema if it doesn't exist yet. Its tables are
+    /// created by the migrations, which must run with the same search path.
+    pub async fn ensure_schema(&self) -> Result<()> {
+        let client = self.client.lock().await;
+        client.batch_execute(&format!("CREATE SCHEMA IF NOT EXISTS {}", quote_identifier(&self.connect_params.schema))).await?;
+        Ok(())
+    }
+
+    /// Fails with `TgiError::MissingTables` naming the tables the migrations
+    /// haven't created yet in the configured schema, e.g. on a fresh
+    /// database, and with `TgiError::OutdatedSchema` when the latest
+    /// migration wasn't applied to it, instead of letting the first query
+    /// fail on a missing relation or column. Also fails with
+    /// `TgiError::HashStorageChanged` when the block hashes were compacted
+    /// or expanded since this `Database` connected.
+    pub async fn check_schema(&self) -> Result<()> {
+        let client = self.client.lock().await;
+        self.check_hash_storage(&client).await?;
+        let required: Vec<&str> = REQUIRED_TABLES.to_vec();
+        let rows = client.query(
+            r#"
+            SELECT required.name FROM unnest($1::TEXT[]) AS required(name)
+            WHERE NOT EXISTS (
+                SELECT 1 FROM information_schema.tables
+                WHERE table_schema = current_schema() AND table_name = required.name
+            )
+            ORDER BY required.name
+            "#,
+            &[&required],
+        ).await?;
+        let tables: Vec<String> = rows.iter().map(|row| row.get(0)).collect();
+        if !tables.is_empty() {
+            return Err(TgiError::MissingTables { schema: self.connect_params.schema.clone(), tables });
+        }
+
+        let has_versions: bool = client.query_one(
+            r#"
+            SELECT EXISTS (
+                SELECT 1 FROM information_schema.tables
+                WHERE table_schema = current_schema() AND table_name = 'schema_migrations'
+            )
+            "#,
+            &[],
+        ).await?.get(0);
+        let version = match has_versions {
+            true => client.query_opt("SELECT version, dirty FROM schema_migrations", &[]).await?
+                .map(|row| (row.get::<_, i64>(0), row.get::<_, bool>(1))),
+            false => None,
+        };
+        match version {
+            Some((version, false)) if version >= LATEST_MIGRATION_VERSION => {
+                if version > LATEST_MIGRATION_VERSION {
+                    warn!(
+                        "Database schema {} is at migration {}, newer than the latest one known, {}",
+                        self.connect_params.schema, version, LATEST_MIGRATION_VERSION
+                    );
+                }
+                Ok(())
+            }
+            version => Err(TgiError::OutdatedSchema {
+                schema: self.connect_params.schema.clone(),
+                applied: match version {
+                    Some((version, true)) => format!("migration {} (dirty)", version),
+                    Some((version, false)) => format!("migration {}", version),
+                    None => "no recorded migration".to_string(),
+                },
+                latest: LATEST_MIGRATION_VERSION,
+            }),
+        }
+    }
+
+    /// Fails when the blocks table of the schema of `client` no longer stores
+    /// hashes the way it did on connect, which every query hashes go through
+    /// depends on.
+    async fn check_hash_storage(&self, client: &Client) -> Result<()> {
+        let found = HashStorage::detect(client, &self.connect_params.schema).await?;
+        if found != self.hash_storage {
+            return Err(TgiError::HashStorageChanged {
+                schema: self.connect_params.schema.clone(),
+                connected: self.hash_storage.name(),
+                found: found.name(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Lifts the statement timeout for the rest of the transaction, for bulk
+    /// operations that legitimately run long.
+    pub async fn disable_statement_timeout(&self, tx: &Transaction<'_>) -> Result<()> {
+        tx.batch_execute("SET LOCAL statement_timeout = 0").await?;
+        Ok(())
+    }
+
+    /// Replaces `client` with a new connection if its connection was lost,
+    /// e.g. after a network failure or a database restart.
+    async fn reconnect_if_closed(&self, client: &mut Client, connection_string: &str) -> Result<()> {
+        if !client.is_closed() {
+            return Ok(());
+        }
+        self.reconnect(client, connection_string).await
+    }
+
+    /// Replaces `client` with a new connection, retrying a few times.
+    async fn reconnect(&self, client: &mut Client, connection_string: &str) -> Result<()> {
+        warn!("Database connection lost; reconnecting");
+        alerts::raise(AlertKind::DatabaseConnectionLost, "Database connection lost; reconnecting");
+        let mut attempt = 1;
+        loop {
+            match Self::connect_client(
+                connection_string,
+                &self.connect_params.tls_config,
+                &self.connect_params.tcp_config,
+                self.connect_params.statement_timeout,
+                self.connect_params.application_name.as_deref(),
+                &self.connect_params.schema,
+            ).await {
+                Ok(new_client) => {
+                    // The blocks table may have been compacted while the
+                    // database was down
+                    self.check_hash_storage(&new_client).await?;
+                    *client = new_client;
+                    info!("Reconnected to the database");
+                    return Ok(());
+                }
+                Err(e) if attempt < RECONNECT_ATTEMPTS => {
+                    warn!("Reconnecting to the database failed (attempt {}/{}): {}", attempt, RECONNECT_ATTEMPTS, e);
+                    tokio::time::sleep(RECONNECT_DELAY * attempt).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Runs `f` in a write transaction. When the connection is lost during
+    /// the transaction, e.g. by a database restart, it is reopened and a
+    /// copy of `f` runs once more, so `f` must not have effects outside the
+    /// transaction that can't be repeated. A connection lost while
+    /// committing fails the transaction instead, as it may have been
+    /// committed already.
+    pub async fn run_in_transaction<F, R>(&self, f: F) -> anyhow::Result<R>
+    where
+        F: for<'a> FnOnce(&'a Transaction<'a>) -> std::pin::Pin<Box<dyn std::future::Future<Output = anyhow::Result<R>> + Send + 'a>>
+            + Clone,
+    {
+        #[cfg(feature = "metrics")]
+        let _in_flight = crate::metrics::InFlightTransaction::start();
+        let mut client = self.acquire(&self.client).await?;
+        match self.write_transaction(&mut client, f.clone()).await {
+            Err(e) if !commit_outcome_unknown(&e) && (client.is_closed() || lost_connection(&e)) => {
+                warn!("Database connection lost during a transaction; running it again: {}", e);
+                // The connection task may not have noticed the loss yet
+                self.reconnect(&mut client, &self.connect_params.connection_string).await?;
+                self.write_transaction(&mut client, f).await
+            }
+            result => result,
+        }
+    }
+
+    /// Like `run_in_transaction`, for a closure that can only run once, e.g.
+    /// because it consumes a writer. A connection lost during the transaction
+    /// fails it.
+    pub async fn run_in_transaction_once<F, R>(&self, f: F) -> anyhow::Result<R>
+    where
+        F: for<'a> FnOnce(&'a Transaction<'a>) -> std::pin::Pin<Box<dyn std::future::Future<Output = anyhow::Result<R>> + Send + 'a>>,
+    {
+        #[cfg(feature = "metrics")]
+        let _in_flight = crate::metrics::InFlightTransaction::start();
+        let mut client = self.acquire(&self.client).await?;
+        self.write_transaction(&mut client, f).await
+    }
+
+    /// Runs `f` in a write transaction on `client`, the locked write
+    /// connection, reconnecting it first if it was lost.
+    async fn write_transaction<F, R>(&self, client: &mut Client, f: F) -> anyhow::Result<R>
+    where
+        F: for<'a> FnOnce(&'a Transaction<'a>) -> std::pin::Pin<Box<dyn std::future::Future<Output = anyhow::Result<R>> + Send + 'a>>,
+    {
+        self.reconnect_if_closed(client, &self.connect_params.connection_string).await?;
+        // Write transactions are serialized by the client lock, so only the
+        // entries of this transaction are tracked
+        self.block_base_cache.lock().await.start_tracking();
+        let committed = self.commit_transaction(client, f).await;
+        let mut cache = self.block_base_cache.lock().await;
+        match &committed {
+            Ok(_) => cache.stop_tracking(),
+            Err(_) => cache.evict_tracked(),
+        }
+        drop(cache);
+        let (result, summary) = committed?;
+        if let (Some(hook), Some(summary)) = (&self.commit_hook, summary) {
+            hook(summary);
+        }
+        Ok(result)
+    }
+
+    /// Runs `f` in a write transaction and commits it, along with the rows
+    /// it changed when there is a commit hook.
+    async fn commit_transaction<F, R>(&self, client: &mut Client, f: F) -> anyhow::Result<(R, Option<CommitSummary>)>
+    where
+        F: for<'a> FnOnce(&'a Transaction<'a>) -> std::pin::Pin<Box<dyn std::future::Future<Output = anyhow::Result<R>> + Send + 'a>>,
+    {
+        let transaction = client.build_transaction().isolation_level(self.isolation_level).start().await.map_err(TgiError::from)?;
+        let result = f(&transaction).await?;
+        let summary = match &self.commit_hook {
+            Some(_) => Some(Self::commit_summary(&transaction).await?),
+            None => None,
+        };
+        transaction.commit().await.map_err(|e| match TgiError::from(e) {
+            TgiError::DatabaseConnectionLost(e) => TgiError::CommitOutcomeUnknown(e),
+            e => e,
+        })?;
+        Ok((result, summary))
+    }
+
+    /// Rows changed so far by the transaction, from the PostgreSQL
+    /// per-transaction table statistics.
+    async fn commit_summary(tx: &Transaction<'_>) -> Result<CommitSummary> {
+        let row = tx.query_one(
+            r#"
+            SELECT COALESCE(SUM(n_tup_ins), 0)::BIGINT, COALESCE(SUM(n_tup_upd), 0)::BIGINT, COALESCE(SUM(n_tup_del), 0)::BIGINT
+            FROM pg_stat_xact_user_tables
+            "#,
+            &[],
+        ).await?;
+        Ok(CommitSummary {
+            rows_inserted: row.get::<_, i64>(0) as u64,
+            rows_updated: row.get::<_, i64>(1) as u64,
+            rows_deleted: row.get::<_, i64>(2) as u64,
         })
     }
 
-    pub async fn run_in_transaction<F, R>(&self, f: F) -> Result<R>
+    /// Like `run_in_transaction`, but in a read-only transaction on the read
+    /// connection. With a replica, results may lag behind recent writes.
+    pub async fn run_in_read_transaction<F, R>(&self, f: F) -> anyhow::Result<R>
+    where
+        F: for<'a> FnOnce(&'a Transaction<'a>) -> std::pin::Pin<Box<dyn std::future::Future<Output = anyhow::Result<R>> + Send + 'a>>
+            + Clone,
+    {
+        #[cfg(feature = "metrics")]
+        let _in_flight = crate::metrics::InFlightTransaction::start();
+        let mut client = self.acquire(&self.read_client).await?;
+        match self.read_transaction(&mut client, f.clone()).await {
+            Err(e) if client.is_closed() || lost_connection(&e) => {
+                warn!("Database connection lost during a read transaction; running it again: {}", e);
+                self.reconnect(&mut client, self.read_connection_string()).await?;
+                self.read_transaction(&mut client, f).await
+            }
+            result => result,
+        }
+    }
+
+    /// Like `run_in_read_transaction`, for a closure that can only run once.
+    /// A connection lost during the transaction fails it.
+    pub async fn run_in_read_transaction_once<F, R>(&self, f: F) -> anyhow::Result<R>
+    where
+        F: for<'a> FnOnce(&'a Transaction<'a>) -> std::pin::Pin<Box<dyn std::future::Future<Output = anyhow::Result<R>> + Send + 'a>>,
+    {
+        #[cfg(feature = "metrics")]
+        let _in_flight = crate::metrics::InFlightTransaction::start();
+        let mut client = self.acquire(&self.read_client).await?;
+        self.read_transaction(&mut client, f).await
+    }
+
+    fn read_connection_string(&self) -> &str {
+        self.connect_params.read_connection_string.as_deref().unwrap_or(&self.connect_params.connection_string)
+    }
+
+    /// Runs `f` in a read-only transaction on `client`, the locked read
+    /// connection, reconnecting it first if it was lost.
+    async fn read_transaction<F, R>(&self, client: &mut Client, f: F) -> anyhow::Result<R>
     where
-        F: for<'a> FnOnce(&'a Transaction<'a>) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<R>> + Send + 'a>>,
+        F: for<'a> FnOnce(&'a Transaction<'a>) -> std::pin::Pin<Box<dyn std::future::Future<Output = anyhow::Result<R>> + Send + 'a>>,
     {
-        let mut client = self.client.lock().await;
-        let transaction = client.transaction().await?;
+        self.reconnect_if_closed(client, self.read_connection_string()).await?;
+        let transaction = client.build_transaction().read_only(true).start().await.map_err(TgiError::from)?;
         let result = f(&transaction).await?;
-        transaction.commit().await?;
+        transaction.commit().await.map_err(TgiError::from)?;
         Ok(result)
     }
 
@@ -57,7 +749,14 @@ impl Database {
         Ok(())
     }
 
-    pub async fn does_block_exist(&self, tx: &Transaction<'_>, block_hash: &str) -> Result<bool> {
+    /// Heights of the blocks found in the cache, without querying the
+    /// database.
+    pub async fn cached_block_heights(&self, block_hashes: &[BlockHash]) -> Vec<Option<u64>> {
+        let cache = self.block_base_cache.lock().await;
+        block_hashes.iter().map(|hash| cache.peek(hash).map(|base| base.height)).collect()
+    }
+
+    pub async fn does_block_exist(&self, tx: &Transaction<'_>, block_hash: &BlockHash) -> Result<bool> {
         // Check cache first
         {
             let cache = self.block_base_cache.lock().await;
@@ -67,10 +766,8 @@ impl Database {
         }
 
         // Check database
-        let row = tx.query_opt(
-            "SELECT id, height FROM blocks WHERE block_hash = $1",
-            &[&block_hash],
-        ).await?;
+        let query = format!("SELECT id, height FROM blocks WHERE block_hash = {}", self.hash_storage.param("$1"));
+        let row = tx.query_opt(query.as_str(), &[&block_hash]).await?;
 
         if let Some(row) = row {
             let id: i64 = row.get(0);
@@ -80,29 +777,38 @@ impl Database {
                 height: height as u64,
             };
             let mut cache = self.block_base_cache.lock().await;
-            cache.put(block_hash.to_string(), block_base);
+            cache.put(*block_hash, block_base);
             Ok(true)
         } else {
             Ok(false)
         }
     }
 
-    pub async fn insert_block(&self, tx: &Transaction<'_>, block_hash: &str, block: &Block) -> Result<()> {
+    pub async fn is_empty(&self, tx: &Transaction<'_>) -> Result<bool> {
+        let row = tx.query_one("SELECT NOT EXISTS (SELECT 1 FROM blocks)", &[]).await?;
+        Ok(row.get(0))
+    }
+
+    pub async fn insert_block(&self, tx: &Transaction<'_>, block_hash: &BlockHash, block: &Block) -> Result<()> {
         let parent_ids_json = serde_json::to_value(&block.parent_ids)?;
         let merge_set_red_ids_json = serde_json::to_value(&block.merge_set_red_ids)?;
         let merge_set_blue_ids_json = serde_json::to_value(&block.merge_set_blue_ids)?;
 
-        let row = tx.query_one(
+        let query = format!(
             r#"
             INSERT INTO blocks (
                 block_hash, timestamp, parent_ids, daa_score, height, 
                 height_group_index, selected_parent_id, color, 
-                is_in_virtual_selected_parent_chain, merge_set_red_ids, merge_set_blue_ids
-            ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+                is_in_virtual_selected_parent_chain, merge_set_red_ids, merge_set_blue_ids, is_header_only, miner
+            ) VALUES ({}, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
             RETURNING id
             "#,
+            self.hash_storage.param("$1"),
+        );
+        let row = tx.query_one(
+            query.as_str(),
             &[
-                &block.block_hash,
+                block_hash,
                 &block.timestamp,
                 &parent_ids_json,
                 &(block.daa_score as i64),
@@ -113,6 +819,8 @@ impl Database {
                 &block.is_in_virtual_selected_parent_chain,
                 &merge_set_red_ids_json,
                 &merge_set_blue_ids_json,
+                &block.is_header_only,
+                &block.miner,
             ],
         ).await?;
 
@@ -122,80 +830,77 @@ impl Database {
             height: block.height,
         };
         let mut cache = self.block_base_cache.lock().await;
-        cache.put(block_hash.to_string(), block_base);
+        cache.put(*block_hash, block_base);
 
         Ok(())
     }
 
-    pub async fn get_block(&self, tx: &Transaction<'_>, id: u64) -> Result<Block> {
-        let row = tx.query_one(
-            "SELECT * FROM blocks WHERE id = $1",
-            &[&(id as i64)],
-        ).await?;
-
-        let parent_ids: serde_json::Value = row.get(2);
-        let merge_set_red_ids: serde_json::Value = row.get(10);
-        let merge_set_blue_ids: serde_json::Value = row.get(11);
-
-        Ok(Block {
-            id,
-            block_hash: row.get(1),
-            timestamp: row.get(2),
-            parent_ids: serde_json::from_value(parent_ids)?,
-            daa_score: row.get::<_, i64>(4) as u64,
-            height: row.get::<_, i64>(5) as u64,
-            height_group_index: row.get::<_, i32>(6) as u32,
-            selected_parent_id: row.get::<_, Option<i64>>(7).map(|v| v as u64),
-            color: row.get(8),
-            is_in_virtual_selected_parent_chain: row.get(9),
-            merge_set_red_ids: serde_json::from_value(merge_set_red_ids)?,
-            merge_set_blue_ids: serde_json::from_value(merge_set_blue_ids)?,
-        })
-    }
+    /// Inserts blocks with a single `COPY`, assigning them ids from the
+    /// blocks sequence. Returns the ids in the order of `blocks`. The blocks
+    /// are stored not fully processed; processing them afterwards marks
+    /// them once their selected parent and merge sets are resolved.
+    pub async fn bulk_insert_blocks(&self, tx: &Transaction<'_>, blocks: &[Block]) -> Result<Vec<u64>> {
+        let rows = tx.query(
+            "SELECT nextval(pg_get_serial_sequence('blocks', 'id')) FROM generate_series(1, $1)",
+            &[&(blocks.len() as i32)],
+        ).await?;
+        let ids: Vec<u64> = rows.iter().map(|row| row.get::<_, i64>(0) as u64).collect();
 
-    pub async fn block_id_by_hash(&self, tx: &Transaction<'_>, block_hash: &str) -> Result<u64> {
-        // Check cache first
-        {
-            let cache = self.block_base_cache.lock().await;
-            if let Some(block_base) = cache.peek(block_hash) {
-                return Ok(block_base.id);
-            }
+        let blocks: Vec<Block> = blocks.iter().zip(&ids)
+            .map(|(block, &id)| Block { id, ..block.clone() })
+            .collect();
+        self.copy_in_blocks(tx, &blocks).await?;
+
+        let mut cache = self.block_base_cache.lock().await;
+        for block in &blocks {
+            cache.put(BlockHash::parse(&block.block_hash)?, BlockBase {
+                id: block.id,
+                height: block.height,
+            });
         }
 
-        // Query database
-        let row = tx.query_one(
-            "SELECT id, height FROM blocks WHERE block_hash = $1",
-            &[&block_hash],
-        ).await
-        .with_context(|| format!("Block hash {} not found in blocks table", block_hash))?;
+        Ok(ids)
+    }
 
-        let id: i64 = row.get(0);
-        let height: i64 = row.get(1);
-        let block_base = BlockBase {
-            id: id as u64,
-            height: height as u64,
-        };
-        let mut cache = self.block_base_cache.lock().await;
-        cache.put(block_hash.to_string(), block_base);
+    pub async fn update_blocks_parent_ids(&self, tx: &Transaction<'_>, block_ids_to_parent_ids: &[(u64, Vec<u64>)]) -> Result<()> {
+        let mut ids = Vec::with_capacity(block_ids_to_parent_ids.len());
+        let mut parent_ids = Vec::with_capacity(block_ids_to_parent_ids.len());
+        for (block_id, block_parent_ids) in block_ids_to_parent_ids {
+            ids.push(*block_id as i64);
+            parent_ids.push(serde_json::to_value(block_parent_ids)?);
+        }
+        tx.execute(
+            r#"
+            UPDATE blocks SET parent_ids = v.parent_ids
+            FROM unnest($1::BIGINT[], $2::JSONB[]) AS v(id, parent_ids)
+            WHERE blocks.id = v.id
+            "#,
+            &[&ids, &parent_ids],
+        ).await?;
+        Ok(())
+    }
 
-        Ok(id as u64)
+    pub async fn get_block(&self, tx: &Transaction<'_>, id: u64) -> Result<Block> {
+        let query = format!("SELECT {} FROM blocks WHERE id = $1", self.hash_storage.block_columns());
+        let row = tx.query_one(query.as_str(), &[&(id as i64)]).await?;
+        block_from_row(&row)
     }
 
-    pub async fn block_height_by_hash(&self, tx: &Transaction<'_>, block_hash: &str) -> Result<u64> {
+    /// Returns the id and the height of a block from a single cache hit or
+    /// query, caching both.
+    pub async fn block_base_by_hash(&self, tx: &Transaction<'_>, block_hash: &BlockHash) -> Result<(u64, u64)> {
         // Check cache first
         {
             let cache = self.block_base_cache.lock().await;
             if let Some(block_base) = cache.peek(block_hash) {
-                return Ok(block_base.height);
+                return Ok((block_base.id, block_base.height));
             }
         }
 
         // Query database
-        let row = tx.query_one(
-            "SELECT id, height FROM blocks WHERE block_hash = $1",
-            &[&block_hash],
-        ).await
-        .with_context(|| format!("Block hash {} not found in blocks table", block_hash))?;
+        let query = format!("SELECT id, height FROM blocks WHERE block_hash = {}", self.hash_storage.param("$1"));
+        let row = tx.query_opt(query.as_str(), &[&block_hash]).await?
+        .ok_or_else(|| TgiError::BlockNotFound(block_hash.to_string()))?;
 
         let id: i64 = row.get(0);
         let height: i64 = row.get(1);
@@ -204,12 +909,20 @@ impl Database {
             height: height as u64,
         };
         let mut cache = self.block_base_cache.lock().await;
-        cache.put(block_hash.to_string(), block_base);
+        cache.put(*block_hash, block_base);
+
+        Ok((id as u64, height as u64))
+    }
+
+    pub async fn block_id_by_hash(&self, tx: &Transaction<'_>, block_hash: &BlockHash) -> Result<u64> {
+        Ok(self.block_base_by_hash(tx, block_hash).await?.0)
+    }
 
-        Ok(height as u64)
+    pub async fn block_height_by_hash(&self, tx: &Transaction<'_>, block_hash: &BlockHash) -> Result<u64> {
+        Ok(self.block_base_by_hash(tx, block_hash).await?.1)
     }
 
-    pub async fn block_ids_by_hashes(&self, tx: &Transaction<'_>, block_hashes: &[String]) -> Result<Vec<u64>> {
+    pub async fn block_ids_by_hashes(&self, tx: &Transaction<'_>, block_hashes: &[BlockHash]) -> Result<Vec<u64>> {
         let mut ids = Vec::with_capacity(block_hashes.len());
         for hash in block_hashes {
             ids.push(self.block_id_by_hash(tx, hash).await?);
@@ -220,65 +933,692 @@ impl Database {
     pub async fn block_ids_and_heights_by_hashes(
         &self,
         tx: &Transaction<'_>,
-        block_hashes: &[String],
+        block_hashes: &[BlockHash],
     ) -> Result<(Vec<u64>, Vec<u64>)> {
         let mut ids = Vec::with_capacity(block_hashes.len());
         let mut heights = Vec::with_capacity(block_hashes.len());
         for hash in block_hashes {
-            let id = self.block_id_by_hash(tx, hash).await?;
-            let height = self.block_height_by_hash(tx, hash).await?;
+            let (id, height) = self.block_base_by_hash(tx, hash).await?;
             ids.push(id);
             heights.push(height);
         }
         Ok((ids, heights))
     }
 
-    pub async fn update_block_selected_parent(&self, tx: &Transaction<'_>, block_id: u64, selected_parent_id: u64) -> Result<()> {
-        tx.execute(
-            "UPDATE blocks SET selected_parent_id = $1 WHERE id = $2",
-            &[&(selected_parent_id as i64), &(block_id as i64)],
-        ).await?;
-        Ok(())
+    pub async fn block_hashes_by_ids(&self, tx: &Transaction<'_>, block_ids: &[u64]) -> Result<HashMap<u64, BlockHash>> {
+        let ids: Vec<i64> = block_ids.iter().map(|&id| id as i64).collect();
+        let query = format!("SELECT id, {} FROM blocks WHERE id = ANY($1)", self.hash_storage.select("block_hash"));
+        let rows = tx.query(query.as_str(), &[&ids]).await?;
+        Ok(rows.iter().map(|row| (row.get::<_, i64>(0) as u64, row.get(1))).collect())
     }
 
-    pub async fn update_block_merge_set(
+    /// Returns the stored blocks among `block_hashes` that aren't marked as
+    /// in the virtual selected parent chain, in input order.
+    pub async fn hashes_not_in_virtual_selected_parent_chain(
         &self,
         tx: &Transaction<'_>,
-        block_id: u64,
-        merge_set_red_ids: &[u64],
-        merge_set_blue_ids: &[u64],
-    ) -> Result<()> {
-        let red_ids_json = serde_json::to_value(merge_set_red_ids)?;
-        let blue_ids_json = serde_json::to_value(merge_set_blue_ids)?;
-        tx.execute(
-            "UPDATE blocks SET merge_set_red_ids = $1, merge_set_blue_ids = $2 WHERE id = $3",
-            &[&red_ids_json, &blue_ids_json, &(block_id as i64)],
-        ).await?;
-        Ok(())
+        block_hashes: &[BlockHash],
+    ) -> Result<Vec<BlockHash>> {
+        let query = format!(
+            r#"
+            SELECT {}
+            FROM unnest({}) WITH ORDINALITY AS h(block_hash, ord)
+            JOIN blocks b ON b.block_hash = h.block_hash
+            WHERE NOT b.is_in_virtual_selected_parent_chain
+            ORDER BY h.ord
+            "#,
+            self.hash_storage.select("h.block_hash"), self.hash_storage.array_param("$1"),
+        );
+        let rows = tx.query(query.as_str(), &[&block_hashes]).await?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
     }
 
-    pub async fn update_block_is_in_virtual_selected_parent_chain(
+    /// Returns the colors of the stored blocks among `block_hashes`.
+    pub async fn block_colors_by_hashes(&self, tx: &Transaction<'_>, block_hashes: &[BlockHash]) -> Result<HashMap<BlockHash, String>> {
+        let query = format!(
+            "SELECT {}, color FROM blocks WHERE block_hash = ANY({})",
+            self.hash_storage.select("block_hash"), self.hash_storage.array_param("$1"),
+        );
+        let rows = tx.query(query.as_str(), &[&block_hashes]).await?;
+        Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    /// Hashes of the selected parents of the stored blocks among
+    /// `block_hashes`, `None` for blocks without one.
+    pub async fn selected_parent_hashes_by_hashes(
         &self,
         tx: &Transaction<'_>,
-        block_ids_to_is_in_vspc: &[(u64, bool)],
-    ) -> Result<()> {
-        for (block_id, is_in_vspc) in block_ids_to_is_in_vspc {
-            tx.execute(
-                "UPDATE blocks SET is_in_virtual_selected_parent_chain = $1 WHERE id = $2",
+        block_hashes: &[BlockHash],
+    ) -> Result<HashMap<BlockHash, Option<BlockHash>>> {
+        let query = format!(
+            "SELECT {}, {} FROM blocks b LEFT JOIN blocks p ON p.id = b.selected_parent_id WHERE b.block_hash = ANY({})",
+            self.hash_storage.select("b.block_hash"), self.hash_storage.select("p.block_hash"), self.hash_storage.array_param("$1"),
+        );
+        let rows = tx.query(query.as_str(), &[&block_hashes]).await?;
+        Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    /// Returns the hashes that aren't stored, in input order, using a single
+    /// anti-join.
+    pub async fn missing_hashes(&self, tx: &Transaction<'_>, block_hashes: &[BlockHash]) -> Result<Vec<BlockHash>> {
+        let query = format!(
+            r#"
+            SELECT {}
+            FROM unnest({}) WITH ORDINALITY AS h(block_hash, ord)
+            WHERE NOT EXISTS (SELECT 1 FROM blocks b WHERE b.block_hash = h.block_hash)
+            ORDER BY h.ord
+            "#,
+            self.hash_storage.select("h.block_hash"), self.hash_storage.array_param("$1"),
+        );
+        let rows = tx.query(query.as_str(), &[&block_hashes]).await?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    /// Returns the blocks of `block_hashes` an earlier processing completed,
+    /// see `is_block_fully_processed`.
+    pub async fn fully_processed_hashes(&self, tx: &Transaction<'_>, block_hashes: &[BlockHash]) -> Result<HashSet<BlockHash>> {
+        let query = format!(
+            "SELECT {} FROM blocks WHERE block_hash = ANY({}) AND fully_processed",
+            self.hash_storage.select("block_hash"), self.hash_storage.array_param("$1"),
+        );
+        let rows = tx.query(query.as_str(), &[&block_hashes]).await?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    /// Returns the ids of the blocks that list `parent_id` as a parent.
+    pub async fn blocks_referencing_parent(&self, tx: &Transaction<'_>, parent_id: u64) -> Result<Vec<u64>> {
+        let rows = tx.query(
+            "SELECT id FROM blocks WHERE parent_ids @> jsonb_build_array($1::BIGINT) ORDER BY id",
+            &[&(parent_id as i64)],
+        ).await?;
+        Ok(rows.iter().map(|row| row.get::<_, i64>(0) as u64).collect())
+    }
+
+    pub async fn update_block_selected_parent(&self, tx: &Transaction<'_>, block_id: u64, selected_parent_id: u64) -> Result<()> {
+        tx.execute(
+            "UPDATE blocks SET selected_parent_id = $1 WHERE id = $2",
+            &[&(selected_parent_id as i64), &(block_id as i64)],
+        ).await?;
+        Ok(())
+    }
+
+    /// Returns the id and hash of blocks with parents but no selected parent,
+    /// with an id above `after_id`, in id order.
+    pub async fn blocks_without_selected_parent(&self, tx: &Transaction<'_>, after_id: u64, limit: u64) -> Result<Vec<(u64, BlockHash)>> {
+        let query = format!(
+            r#"
+            SELECT id, {} FROM blocks
+            WHERE selected_parent_id IS NULL AND jsonb_array_length(parent_ids) > 0 AND id > $1
+            ORDER BY id
+            LIMIT $2
+            "#,
+            self.hash_storage.select("block_hash"),
+        );
+        let rows = tx.query(query.as_str(), &[&(after_id as i64), &(limit as i64)]).await?;
+        Ok(rows.iter().map(|row| (row.get::<_, i64>(0) as u64, row.get(1))).collect())
+    }
+
+    /// Records that the selected parent of a block isn't stored yet, so it
+    /// can be filled in once the parent arrives.
+    pub async fn add_pending_selected_parent(&self, tx: &Transaction<'_>, block_id: u64, selected_parent_hash: &BlockHash) -> Result<()> {
+        tx.execute(
+            "INSERT INTO pending_selected_parents (block_id, selected_parent_hash) VALUES ($1, $2) \
+            ON CONFLICT (block_id) DO UPDATE SET selected_parent_hash = EXCLUDED.selected_parent_hash",
+            &[&(block_id as i64), selected_parent_hash],
+        ).await?;
+        Ok(())
+    }
+
+    /// Forgets the selected parents the blocks `block_ids` waited for, once
+    /// they got theirs otherwise. Returns how many were waiting.
+    pub async fn remove_pending_selected_parents(&self, tx: &Transaction<'_>, block_ids: &[u64]) -> Result<u64> {
+        let ids: Vec<i64> = block_ids.iter().map(|&id| id as i64).collect();
+        let removed = tx.execute("DELETE FROM pending_selected_parents WHERE block_id = ANY($1)", &[&ids]).await?;
+        Ok(removed)
+    }
+
+    /// Sets the selected parent of every block waiting for
+    /// `selected_parent_hash` and returns how many were waiting.
+    pub async fn resolve_pending_selected_parents(
+        &self,
+        tx: &Transaction<'_>,
+        selected_parent_hash: &BlockHash,
+        selected_parent_id: u64,
+    ) -> Result<u64> {
+        let resolved = tx.execute(
+            r#"
+            WITH resolved AS (
+                DELETE FROM pending_selected_parents WHERE selected_parent_hash = $1 RETURNING block_id
+            )
+            UPDATE blocks SET selected_parent_id = $2 WHERE id IN (SELECT block_id FROM resolved)
+            "#,
+            &[selected_parent_hash, &(selected_parent_id as i64)],
+        ).await?;
+        Ok(resolved)
+    }
+
+    /// Records a block none of whose parents are stored, along with `block`,
+    /// the block as serialized by processing, so it can be stored once one
+    /// of them arrives.
+    pub async fn add_pending_orphan_block(
+        &self,
+        tx: &Transaction<'_>,
+        block_hash: &BlockHash,
+        parent_hashes: &[BlockHash],
+        block: &serde_json::Value,
+    ) -> Result<()> {
+        tx.execute(
+            r#"
+            INSERT INTO pending_orphan_blocks (block_hash, parent_hash)
+            SELECT $1, parent_hash FROM unnest($2::CHAR(64)[]) AS p(parent_hash)
+            ON CONFLICT DO NOTHING
+            "#,
+            &[block_hash, &parent_hashes],
+        ).await?;
+        let deferred_at = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as i64);
+        tx.execute(
+            "INSERT INTO pending_orphan_block_data (block_hash, block, deferred_at) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING",
+            &[block_hash, block, &deferred_at],
+        ).await?;
+        Ok(())
+    }
+
+    /// Removes and returns the orphan blocks waiting for `parent_hash`, with
+    /// the block recorded along, which blocks deferred before it was kept
+    /// lack.
+    pub async fn take_pending_orphan_blocks(
+        &self,
+        tx: &Transaction<'_>,
+        parent_hash: &BlockHash,
+    ) -> Result<Vec<(BlockHash, Option<serde_json::Value>)>> {
+        let rows = tx.query(
+            r#"
+            WITH released AS (
+                DELETE FROM pending_orphan_blocks
+                WHERE block_hash IN (SELECT block_hash FROM pending_orphan_blocks WHERE parent_hash = $1)
+                RETURNING block_hash
+            ), released_data AS (
+                DELETE FROM pending_orphan_block_data
+                WHERE block_hash IN (SELECT block_hash FROM released)
+                RETURNING block_hash, block
+            )
+            SELECT DISTINCT ON (r.block_hash) r.block_hash, d.block
+            FROM released r LEFT JOIN released_data d ON d.block_hash = r.block_hash
+            "#,
+            &[parent_hash],
+        ).await?;
+        Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    /// Drops the orphan blocks deferred before `deferred_before`, in
+    /// milliseconds since the epoch, and those deferred without their data,
+    /// and returns them.
+    pub async fn expire_pending_orphan_blocks(&self, tx: &Transaction<'_>, deferred_before: i64) -> Result<Vec<BlockHash>> {
+        let rows = tx.query(
+            r#"
+            WITH expired_data AS (
+                DELETE FROM pending_orphan_block_data WHERE deferred_at < $1
+                RETURNING block_hash
+            ), expired AS (
+                DELETE FROM pending_orphan_blocks p
+                WHERE p.block_hash IN (SELECT block_hash FROM expired_data)
+                    OR NOT EXISTS (SELECT 1 FROM pending_orphan_block_data d WHERE d.block_hash = p.block_hash)
+                RETURNING block_hash
+            )
+            SELECT block_hash FROM expired_data
+            UNION
+            SELECT block_hash FROM expired
+            "#,
+            &[&deferred_before],
+        ).await?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    /// Number of blocks waiting for one of their parents.
+    pub async fn pending_orphan_block_count(&self, tx: &Transaction<'_>) -> Result<u64> {
+        let row = tx.query_one("SELECT COUNT(DISTINCT block_hash) FROM pending_orphan_blocks", &[]).await?;
+        Ok(row.get::<_, i64>(0) as u64)
+    }
+
+    /// Records the parents of a block that weren't stored when the block
+    /// was, so the edges to them can be added once they arrive.
+    pub async fn add_pending_parent_edges(&self, tx: &Transaction<'_>, block_id: u64, parent_hashes: &[BlockHash]) -> Result<()> {
+        tx.execute(
+            r#"
+            INSERT INTO pending_parent_edges (block_id, parent_hash)
+            SELECT $1, parent_hash FROM unnest($2::CHAR(64)[]) AS p(parent_hash)
+            ON CONFLICT DO NOTHING
+            "#,
+            &[&(block_id as i64), &parent_hashes],
+        ).await?;
+        Ok(())
+    }
+
+    /// Removes and returns the ids of the stored blocks waiting for the edge
+    /// to `parent_hash`.
+    pub async fn take_pending_parent_edges(&self, tx: &Transaction<'_>, parent_hash: &BlockHash) -> Result<Vec<u64>> {
+        let rows = tx.query(
+            r#"
+            WITH released AS (
+                DELETE FROM pending_parent_edges WHERE parent_hash = $1 RETURNING block_id
+            )
+            SELECT r.block_id FROM released r JOIN blocks b ON b.id = r.block_id ORDER BY r.block_id
+            "#,
+            &[parent_hash],
+        ).await?;
+        Ok(rows.iter().map(|row| row.get::<_, i64>(0) as u64).collect())
+    }
+
+    /// Adds the parent `parent_id` to the blocks `block_ids` stored before
+    /// it, along with the edges to it.
+    pub async fn add_late_parent(&self, tx: &Transaction<'_>, block_ids: &[u64], parent_id: u64) -> Result<()> {
+        let ids: Vec<i64> = block_ids.iter().map(|&id| id as i64).collect();
+        tx.execute(
+            r#"
+            UPDATE blocks SET parent_ids = parent_ids || to_jsonb($2::BIGINT)
+            WHERE id = ANY($1) AND NOT parent_ids @> to_jsonb($2::BIGINT)
+            "#,
+            &[&ids, &(parent_id as i64)],
+        ).await?;
+        tx.execute(
+            r#"
+            INSERT INTO edges (from_block_id, to_block_id, from_height, to_height, from_height_group_index, to_height_group_index)
+            SELECT b.id, p.id, b.height, p.height, b.height_group_index, p.height_group_index
+            FROM blocks b, blocks p
+            WHERE b.id = ANY($1) AND p.id = $2
+            ON CONFLICT (from_block_id, to_block_id) DO NOTHING
+            "#,
+            &[&ids, &(parent_id as i64)],
+        ).await?;
+        Ok(())
+    }
+
+    /// The blocks to raise so that every one of `block_ids` is at least at
+    /// `min_height`, and every descendant above its raised ancestors, with
+    /// the height each has to move to. Blocks already high enough are left
+    /// out, as are their descendants unless another path reaches them.
+    pub async fn blocks_to_raise(&self, tx: &Transaction<'_>, block_ids: &[u64], min_height: u64) -> Result<Vec<(u64, u64)>> {
+        let ids: Vec<i64> = block_ids.iter().map(|&id| id as i64).collect();
+        let rows = tx.query(
+            r#"
+            WITH RECURSIVE raised (id, min_height) AS (
+                SELECT id, $2::BIGINT FROM blocks WHERE id = ANY($1) AND height < $2
+                UNION
+                SELECT e.from_block_id, r.min_height + 1
+                FROM raised r
+                JOIN edges e ON e.to_block_id = r.id
+                JOIN blocks c ON c.id = e.from_block_id
+                WHERE c.height < r.min_height + 1
+            )
+            SELECT id, MAX(min_height) FROM raised GROUP BY id ORDER BY id
+            "#,
+            &[&ids, &(min_height as i64)],
+        ).await?;
+        Ok(rows.iter().map(|row| (row.get::<_, i64>(0) as u64, row.get::<_, i64>(1) as u64)).collect())
+    }
+
+    /// Moves blocks to higher heights, given as `(block_id, height)`. Raised
+    /// blocks join the end of their new height groups, and the height groups
+    /// they left are renumbered so their indexes stay without gaps. The edges
+    /// from and to every renumbered block, the sizes of the affected height
+    /// groups and the cache follow.
+    pub async fn raise_blocks(&self, tx: &Transaction<'_>, raises: &[(u64, u64)]) -> Result<()> {
+        if raises.is_empty() {
+            return Ok(());
+        }
+        let ids: Vec<i64> = raises.iter().map(|&(id, _)| id as i64).collect();
+        let heights: Vec<i64> = raises.iter().map(|&(_, height)| height as i64).collect();
+
+        // Past the end of any height group until renumbered below
+        let query = format!(
+            r#"
+            WITH raised AS (
+                SELECT b.id, b.height AS old_height, r.height
+                FROM unnest($1::BIGINT[], $2::BIGINT[]) AS r(id, height)
+                JOIN blocks b ON b.id = r.id
+            )
+            UPDATE blocks b SET height = raised.height, height_group_index = {}
+            FROM raised WHERE b.id = raised.id
+            RETURNING raised.old_height, raised.height, {}
+            "#,
+            i32::MAX, self.hash_storage.select("b.block_hash"),
+        );
+        let rows = tx.query(query.as_str(), &[&ids, &heights]).await?;
+        let mut affected_heights: Vec<i64> = rows.iter()
+            .flat_map(|row| [row.get::<_, i64>(0), row.get::<_, i64>(1)])
+            .collect();
+        affected_heights.sort_unstable();
+        affected_heights.dedup();
+
+        tx.execute(
+            r#"
+            UPDATE blocks b SET height_group_index = renumbered.height_group_index
+            FROM (
+                SELECT id, (ROW_NUMBER() OVER (PARTITION BY height ORDER BY height_group_index, id) - 1)::INT AS height_group_index
+                FROM blocks WHERE height = ANY($1)
+            ) renumbered
+            WHERE b.id = renumbered.id AND b.height_group_index <> renumbered.height_group_index
+            "#,
+            &[&affected_heights],
+        ).await?;
+        tx.execute(
+            r#"
+            UPDATE edges e SET from_height = b.height, from_height_group_index = b.height_group_index
+            FROM blocks b
+            WHERE b.id = e.from_block_id AND b.height = ANY($1)
+                AND (e.from_height, e.from_height_group_index) IS DISTINCT FROM (b.height, b.height_group_index)
+            "#,
+            &[&affected_heights],
+        ).await?;
+        tx.execute(
+            r#"
+            UPDATE edges e SET to_height = b.height, to_height_group_index = b.height_group_index
+            FROM blocks b
+            WHERE b.id = e.to_block_id AND b.height = ANY($1)
+                AND (e.to_height, e.to_height_group_index) IS DISTINCT FROM (b.height, b.height_group_index)
+            "#,
+            &[&affected_heights],
+        ).await?;
+        // Heights without blocks have no group
+        tx.execute(
+            r#"
+            INSERT INTO height_groups (height, size)
+            SELECT height, COUNT(*) FROM blocks WHERE height = ANY($1) GROUP BY height
+            ON CONFLICT (height) DO UPDATE SET size = EXCLUDED.size
+            "#,
+            &[&affected_heights],
+        ).await?;
+        tx.execute(
+            "DELETE FROM height_groups g WHERE height = ANY($1) AND NOT EXISTS (SELECT 1 FROM blocks b WHERE b.height = g.height)",
+            &[&affected_heights],
+        ).await?;
+
+        let mut cache = self.block_base_cache.lock().await;
+        for row in &rows {
+            cache.set_height(&row.get::<_, BlockHash>(2), row.get::<_, i64>(1) as u64);
+        }
+        Ok(())
+    }
+
+    /// Ids of the stored blocks having `block_id` as a parent. Children are
+    /// looked up through the `edges_to_block_id_idx` index of migration
+    /// 000010, like the other reverse traversals (`tip_blocks`,
+    /// `block_neighbors`).
+    pub async fn block_children_ids(&self, tx: &Transaction<'_>, block_id: u64) -> Result<Vec<u64>> {
+        let rows = tx.query(
+            "SELECT from_block_id FROM edges WHERE to_block_id = $1 ORDER BY from_block_id",
+            &[&(block_id as i64)],
+        ).await?;
+        Ok(rows.iter().map(|row| row.get::<_, i64>(0) as u64).collect())
+    }
+
+    pub async fn update_block_is_header_only(&self, tx: &Transaction<'_>, block_id: u64, is_header_only: bool) -> Result<()> {
+        tx.execute(
+            "UPDATE blocks SET is_header_only = $1 WHERE id = $2",
+            &[&is_header_only, &(block_id as i64)],
+        ).await?;
+        Ok(())
+    }
+
+    /// Whether the block got its selected parent and every merge set block
+    /// by an earlier processing
+    pub async fn is_block_fully_processed(&self, tx: &Transaction<'_>, block_id: u64) -> Result<bool> {
+        let row = tx.query_one(
+            "SELECT fully_processed FROM blocks WHERE id = $1",
+            &[&(block_id as i64)],
+        ).await?;
+        Ok(row.get(0))
+    }
+
+    pub async fn set_block_fully_processed(&self, tx: &Transaction<'_>, block_id: u64) -> Result<()> {
+        tx.execute(
+            "UPDATE blocks SET fully_processed = TRUE WHERE id = $1",
+            &[&(block_id as i64)],
+        ).await?;
+        Ok(())
+    }
+
+    pub async fn update_block_miner(&self, tx: &Transaction<'_>, block_id: u64, miner: &str) -> Result<()> {
+        tx.execute(
+            "UPDATE blocks SET miner = $1 WHERE id = $2",
+            &[&miner, &(block_id as i64)],
+        ).await?;
+        Ok(())
+    }
+
+    pub async fn update_block_header_blob(&self, tx: &Transaction<'_>, block_id: u64, header_blob: &[u8]) -> Result<()> {
+        tx.execute(
+            "UPDATE blocks SET header_blob = $1 WHERE id = $2",
+            &[&header_blob, &(block_id as i64)],
+        ).await?;
+        Ok(())
+    }
+
+    /// Returns the raw header stored with `--store-headers`, or `None` if the
+    /// block was stored without it.
+    pub async fn get_header_blob(&self, tx: &Transaction<'_>, block_hash: &BlockHash) -> Result<Option<Vec<u8>>> {
+        let query = format!("SELECT header_blob FROM blocks WHERE block_hash = {}", self.hash_storage.param("$1"));
+        let row = tx.query_opt(query.as_str(), &[block_hash]).await?;
+        Ok(row.and_then(|row| row.get(0)))
+    }
+
+    /// Overwrites the header-derived fields of a block.
+    pub async fn update_block_header_fields(&self, tx: &Transaction<'_>, block_id: u64, timestamp: i64, daa_score: u64) -> Result<()> {
+        tx.execute(
+            "UPDATE blocks SET timestamp = $1, daa_score = $2 WHERE id = $3",
+            &[&timestamp, &(daa_score as i64), &(block_id as i64)],
+        ).await?;
+        Ok(())
+    }
+
+    /// Returns the most recent blocks mined by `miner`, newest first.
+    pub async fn blocks_by_miner(&self, tx: &Transaction<'_>, miner: &str, limit: u64) -> Result<Vec<Block>> {
+        let rows = tx.query(
+            &format!("SELECT {} FROM blocks WHERE miner = $1 ORDER BY daa_score DESC, id DESC LIMIT $2", self.hash_storage.block_columns()),
+            &[&miner, &(limit as i64)],
+        ).await?;
+        rows.iter().map(block_from_row).collect()
+    }
+
+    /// Records the transactions accepted by a virtual selected parent chain
+    /// block.
+    pub async fn store_accepted_transactions(
+        &self,
+        tx: &Transaction<'_>,
+        accepting_block_id: u64,
+        transaction_ids: &[String],
+    ) -> Result<()> {
+        tx.execute(
+            r#"
+            INSERT INTO accepted_transactions (accepting_block_id, transaction_id)
+            SELECT $1, transaction_id FROM unnest($2::TEXT[]) AS transaction_id
+            ON CONFLICT DO NOTHING
+            "#,
+            &[&(accepting_block_id as i64), &transaction_ids],
+        ).await?;
+        Ok(())
+    }
+
+    /// Records a notification that failed processing for good, keeping only
+    /// the latest failure per block.
+    pub async fn add_failed_notification(&self, tx: &Transaction<'_>, kind: &str, block_hash: &BlockHash, error: &str) -> Result<()> {
+        let failed_at = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as i64);
+        tx.execute(
+            "INSERT INTO failed_notifications (kind, block_hash, error, failed_at) VALUES ($1, $2, $3, $4)
+             ON CONFLICT (kind, block_hash) DO UPDATE SET error = EXCLUDED.error, failed_at = EXCLUDED.failed_at",
+            &[&kind, block_hash, &error, &failed_at],
+        ).await?;
+        Ok(())
+    }
+
+    /// Failed notifications of `kind`, oldest first, as block hashes.
+    pub async fn failed_notifications(&self, tx: &Transaction<'_>, kind: &str) -> Result<Vec<BlockHash>> {
+        let rows = tx.query(
+            "SELECT block_hash FROM failed_notifications WHERE kind = $1 ORDER BY failed_at",
+            &[&kind],
+        ).await?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    pub async fn remove_failed_notification(&self, tx: &Transaction<'_>, kind: &str, block_hash: &BlockHash) -> Result<()> {
+        tx.execute(
+            "DELETE FROM failed_notifications WHERE kind = $1 AND block_hash = $2",
+            &[&kind, block_hash],
+        ).await?;
+        Ok(())
+    }
+
+    /// Stores a mempool sample. A sample taken in the same millisecond as a
+    /// stored one is dropped.
+    pub async fn insert_mempool_sample(&self, tx: &Transaction<'_>, sample: &MempoolSample) -> Result<()> {
+        tx.execute(
+            "INSERT INTO mempool_samples (sampled_at, transaction_count, orphan_count, total_fees) VALUES ($1, $2, $3, $4)
+             ON CONFLICT (sampled_at) DO NOTHING",
+            &[
+                &sample.sampled_at,
+                &(sample.transaction_count as i64),
+                &(sample.orphan_count as i64),
+                &(sample.total_fees as i64),
+            ],
+        ).await?;
+        Ok(())
+    }
+
+    /// Forgets the transactions accepted by blocks that left the virtual
+    /// selected parent chain.
+    pub async fn remove_accepted_transactions(&self, tx: &Transaction<'_>, accepting_block_ids: &[u64]) -> Result<()> {
+        let ids: Vec<i64> = accepting_block_ids.iter().map(|&id| id as i64).collect();
+        tx.execute(
+            "DELETE FROM accepted_transactions WHERE accepting_block_id = ANY($1)",
+            &[&ids],
+        ).await?;
+        Ok(())
+    }
+
+    pub async fn accepted_transaction_ids(&self, tx: &Transaction<'_>, accepting_block_id: u64) -> Result<Vec<String>> {
+        let rows = tx.query(
+            "SELECT transaction_id FROM accepted_transactions WHERE accepting_block_id = $1 ORDER BY transaction_id",
+            &[&(accepting_block_id as i64)],
+        ).await?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    /// Returns the id of the chain block that accepted `transaction_id`.
+    pub async fn accepting_block_id(&self, tx: &Transaction<'_>, transaction_id: &str) -> Result<Option<u64>> {
+        let row = tx.query_opt(
+            "SELECT accepting_block_id FROM accepted_transactions WHERE transaction_id = $1",
+            &[&transaction_id],
+        ).await?;
+        Ok(row.map(|row| row.get::<_, i64>(0) as u64))
+    }
+
+    pub async fn count_header_only(&self, tx: &Transaction<'_>) -> Result<u64> {
+        let row = tx.query_one("SELECT COUNT(*) FROM blocks WHERE is_header_only", &[]).await?;
+        Ok(row.get::<_, i64>(0) as u64)
+    }
+
+    pub async fn update_block_merge_set(
+        &self,
+        tx: &Transaction<'_>,
+        block_id: u64,
+        merge_set_red_ids: &[u64],
+        merge_set_blue_ids: &[u64],
+    ) -> Result<()> {
+        let red_ids_json = serde_json::to_value(merge_set_red_ids)?;
+        let blue_ids_json = serde_json::to_value(merge_set_blue_ids)?;
+        tx.execute(
+            "UPDATE blocks SET merge_set_red_ids = $1, merge_set_blue_ids = $2 WHERE id = $3",
+            &[&red_ids_json, &blue_ids_json, &(block_id as i64)],
+        ).await?;
+        Ok(())
+    }
+
+    pub async fn block_merge_set_ids(&self, tx: &Transaction<'_>, block_id: u64) -> Result<(Vec<u64>, Vec<u64>)> {
+        let row = tx.query_one(
+            "SELECT merge_set_red_ids, merge_set_blue_ids FROM blocks WHERE id = $1",
+            &[&(block_id as i64)],
+        ).await?;
+        let merge_set_red_ids: serde_json::Value = row.get(0);
+        let merge_set_blue_ids: serde_json::Value = row.get(1);
+        Ok((serde_json::from_value(merge_set_red_ids)?, serde_json::from_value(merge_set_blue_ids)?))
+    }
+
+    pub async fn update_block_is_in_virtual_selected_parent_chain(
+        &self,
+        tx: &Transaction<'_>,
+        block_ids_to_is_in_vspc: &[(u64, bool)],
+    ) -> Result<()> {
+        for (block_id, is_in_vspc) in block_ids_to_is_in_vspc {
+            tx.execute(
+                "UPDATE blocks SET is_in_virtual_selected_parent_chain = $1 WHERE id = $2",
                 &[is_in_vspc, &(*block_id as i64)],
             ).await?;
         }
         Ok(())
     }
 
-    pub async fn update_block_colors(&self, tx: &Transaction<'_>, block_ids_to_colors: &[(u64, String)]) -> Result<()> {
-        for (block_id, color) in block_ids_to_colors {
-            tx.execute(
-                "UPDATE blocks SET color = $1 WHERE id = $2",
-                &[color, &(*block_id as i64)],
-            ).await?;
-        }
-        Ok(())
+    /// Applies merge set colors and returns the number of blocks that turned
+    /// red, which excludes those that already were.
+    pub async fn update_block_colors(&self, tx: &Transaction<'_>, updates: &[BlockColorUpdate]) -> Result<u64> {
+        let mut turned_red = 0;
+        for update in updates {
+            // Joining the row to itself returns its color before the update
+            let previous_color = tx.query_opt(
+                r#"
+                UPDATE blocks b SET color = $1, colored_by_block_id = $2
+                FROM blocks previous
+                WHERE b.id = $3 AND previous.id = b.id
+                RETURNING previous.color
+                "#,
+                &[&update.color, &update.colored_by_block_id.map(|id| id as i64), &(update.block_id as i64)],
+            ).await?.map(|row| row.get::<_, String>(0));
+            if update.color == COLOR_RED && previous_color.is_some_and(|color| color != COLOR_RED) {
+                turned_red += 1;
+            }
+        }
+        #[cfg(feature = "metrics")]
+        crate::metrics::record_red_blocks(turned_red);
+        Ok(turned_red)
+    }
+
+    /// Ids of the blocks colored by the merge set of one of the chain blocks
+    /// `chain_block_ids`.
+    pub async fn blocks_colored_by(&self, tx: &Transaction<'_>, chain_block_ids: &[u64]) -> Result<Vec<u64>> {
+        let ids: Vec<i64> = chain_block_ids.iter().map(|&id| id as i64).collect();
+        let rows = tx.query(
+            "SELECT id FROM blocks WHERE colored_by_block_id = ANY($1) ORDER BY id",
+            &[&ids],
+        ).await?;
+        Ok(rows.iter().map(|row| row.get::<_, i64>(0) as u64).collect())
+    }
+
+    /// Returns the red blocks between two heights, both included, ordered by
+    /// height.
+    pub async fn red_blocks_in_range(&self, tx: &Transaction<'_>, from_height: u64, to_height: u64) -> Result<Vec<Block>> {
+        let query = format!(
+            "SELECT {} FROM blocks WHERE color = $1 AND height >= $2 AND height <= $3 ORDER BY height, id",
+            self.hash_storage.block_columns(),
+        );
+        let rows = tx.query(
+            query.as_str(),
+            &[&COLOR_RED, &(from_height as i64), &(to_height as i64)],
+        ).await?;
+        rows.iter().map(block_from_row).collect()
+    }
+
+    /// Returns, for every height between `low` and `high`, both included, the
+    /// block with the largest merge set, reds and blues combined, ordered by
+    /// height. Blocks with equally large merge sets go to the lowest id.
+    pub async fn heaviest_block_per_height(&self, tx: &Transaction<'_>, low: u64, high: u64) -> Result<Vec<Block>> {
+        let query = format!(
+            "SELECT DISTINCT ON (height) {} FROM blocks WHERE height >= $1 AND height <= $2 \
+             ORDER BY height, jsonb_array_length(merge_set_red_ids) + jsonb_array_length(merge_set_blue_ids) DESC, id",
+            self.hash_storage.block_columns(),
+        );
+        let rows = tx.query(query.as_str(), &[&(low as i64), &(high as i64)]).await?;
+        rows.iter().map(block_from_row).collect()
     }
 
     pub async fn update_block_daa_scores(&self, tx: &Transaction<'_>, block_ids_to_daa_scores: &[(u64, u64)]) -> Result<()> {
@@ -291,7 +1631,7 @@ impl Database {
         Ok(())
     }
 
-    pub async fn find_latest_stored_block_index(&self, tx: &Transaction<'_>, block_hashes: &[String]) -> Result<usize> {
+    pub async fn find_latest_stored_block_index(&self, tx: &Transaction<'_>, block_hashes: &[BlockHash]) -> Result<usize> {
         // Binary search since hash array is ordered from oldest to latest
         let mut low = 0;
         let mut high = block_hashes.len();
@@ -333,90 +1673,810 @@ impl Database {
     }
 
     pub async fn highest_block_in_virtual_selected_parent_chain(&self, tx: &Transaction<'_>) -> Result<Block> {
+        let query = format!(
+            "SELECT {} FROM blocks WHERE is_in_virtual_selected_parent_chain = $1 ORDER BY height DESC LIMIT 1",
+            self.hash_storage.block_columns(),
+        );
+        let row = tx.query_one(query.as_str(), &[&true]).await?;
+        block_from_row(&row)
+    }
+
+    /// Walks the selected parent chain from `from_hash` down to `to_hash`.
+    /// Returns the hashes along the way, both ends included, or `None` if
+    /// `to_hash` isn't reached within `max_len` blocks. Fails with
+    /// `TgiError::ChainWalkLimit` when the walk comes back to a block it
+    /// visited, which only happens on corrupt data.
+    pub async fn selected_parent_path(
+        &self,
+        tx: &Transaction<'_>,
+        from_hash: &BlockHash,
+        to_hash: &BlockHash,
+        max_len: u64,
+    ) -> Result<Option<Vec<BlockHash>>> {
+        let query = format!(
+            r#"
+            WITH RECURSIVE path AS (
+                SELECT id, block_hash, selected_parent_id, 1::BIGINT AS depth
+                FROM blocks WHERE block_hash = {}
+                UNION ALL
+                SELECT b.id, b.block_hash, b.selected_parent_id, p.depth + 1
+                FROM blocks b JOIN path p ON b.id = p.selected_parent_id
+                WHERE p.block_hash <> {} AND p.depth < $3
+            )
+            SELECT {} FROM path ORDER BY depth
+            "#,
+            self.hash_storage.param("$1"), self.hash_storage.param("$2"), self.hash_storage.select("block_hash"),
+        );
+        let rows = tx.query(query.as_str(), &[from_hash, to_hash, &(max_len as i64)]).await?;
+
+        let path: Vec<BlockHash> = rows.iter().map(|row| row.get(0)).collect();
+        let mut visited = HashSet::with_capacity(path.len());
+        if !path.iter().all(|hash| visited.insert(*hash)) {
+            Err(TgiError::ChainWalkLimit { from: from_hash.to_string(), limit: max_len })
+        } else if path.last() == Some(to_hash) {
+            Ok(Some(path))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Returns the DAG tips, i.e. the blocks no stored block has as a parent,
+    /// highest first.
+    pub async fn tip_blocks(&self, tx: &Transaction<'_>, limit: u64) -> Result<Vec<Block>> {
+        let query = format!(
+            "SELECT {} FROM blocks WHERE NOT EXISTS (SELECT 1 FROM edges WHERE edges.to_block_id = blocks.id) \
+            ORDER BY height DESC, id DESC LIMIT $1",
+            self.hash_storage.block_columns(),
+        );
+        let rows = tx.query(query.as_str(), &[&(limit as i64)]).await?;
+        rows.iter().map(block_from_row).collect()
+    }
+
+    pub async fn block_by_hash(&self, tx: &Transaction<'_>, block_hash: &BlockHash) -> Result<Option<Block>> {
+        let query = format!(
+            "SELECT {} FROM blocks WHERE block_hash = {}",
+            self.hash_storage.block_columns(), self.hash_storage.param("$1"),
+        );
+        let row = tx.query_opt(query.as_str(), &[block_hash]).await?;
+        row.as_ref().map(block_from_row).transpose()
+    }
+
+    /// Checks that `prefix` is 1 to 64 hex characters and lowercases it, as
+    /// hashes are stored.
+    pub fn normalize_hash_prefix(prefix: &str) -> Result<String> {
+        if prefix.is_empty() || prefix.len() > 64 || !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(TgiError::invalid_hash(prefix, "a hash prefix must be 1 to 64 hex characters"));
+        }
+        Ok(prefix.to_ascii_lowercase())
+    }
+
+    /// Returns up to `limit` blocks whose hash starts with `prefix`, ordered
+    /// by hash.
+    pub async fn blocks_by_hash_prefix(&self, tx: &Transaction<'_>, prefix: &str, limit: u64) -> Result<Vec<Block>> {
+        let prefix = Self::normalize_hash_prefix(prefix)?;
+        // The hashes starting with the prefix are exactly those between these
+        // bounds, which the block_hash index serves with hex and compact
+        // hashes alike
+        let lowest = format!("{:0<64}", prefix);
+        let highest = format!("{:f<64}", prefix);
+        let query = format!(
+            "SELECT {} FROM blocks WHERE block_hash BETWEEN {} AND {} ORDER BY block_hash LIMIT $3",
+            self.hash_storage.block_columns(), self.hash_storage.param("$1"), self.hash_storage.param("$2"),
+        );
+        let rows = tx.query(query.as_str(), &[&lowest, &highest, &(limit as i64)]).await?;
+        rows.iter().map(block_from_row).collect()
+    }
+
+    /// Returns the parents and the children of a block, each ordered by id.
+    /// Both directions are read from the edges table: parents through its
+    /// primary key, children through `edges_to_block_id_idx`.
+    pub async fn block_neighbors(&self, tx: &Transaction<'_>, block_hash: &BlockHash) -> Result<(Vec<Block>, Vec<Block>)> {
+        let block_id = self.block_id_by_hash(tx, block_hash).await? as i64;
+
+        let parents_query = format!(
+            "SELECT {} FROM blocks WHERE id IN (SELECT to_block_id FROM edges WHERE from_block_id = $1) ORDER BY id",
+            self.hash_storage.block_columns(),
+        );
+        let parents = tx.query(parents_query.as_str(), &[&block_id]).await?
+            .iter().map(block_from_row).collect::<Result<Vec<_>>>()?;
+
+        let children_query = format!(
+            "SELECT {} FROM blocks WHERE id IN (SELECT from_block_id FROM edges WHERE to_block_id = $1) ORDER BY id",
+            self.hash_storage.block_columns(),
+        );
+        let children = tx.query(children_query.as_str(), &[&block_id]).await?
+            .iter().map(block_from_row).collect::<Result<Vec<_>>>()?;
+
+        Ok((parents, children))
+    }
+
+    pub async fn graph_bounds(&self, tx: &Transaction<'_>) -> Result<GraphBounds> {
+        let query = format!(
+            r#"
+            SELECT b.min_height, b.max_height, b.min_daa_score, b.max_daa_score, b.block_count,
+                (SELECT {} FROM blocks WHERE is_in_virtual_selected_parent_chain
+                    ORDER BY height DESC, id DESC LIMIT 1)
+            FROM (
+                SELECT MIN(height) AS min_height, MAX(height) AS max_height,
+                    MIN(daa_score) AS min_daa_score, MAX(daa_score) AS max_daa_score,
+                    COUNT(*) AS block_count
+                FROM blocks
+            ) b
+            "#,
+            self.hash_storage.select("block_hash"),
+        );
+        let row = tx.query_one(query.as_str(), &[]).await?;
+        Ok(GraphBounds {
+            min_height: row.get::<_, Option<i64>>(0).map(|v| v as u64),
+            max_height: row.get::<_, Option<i64>>(1).map(|v| v as u64),
+            min_daa_score: row.get::<_, Option<i64>>(2).map(|v| v as u64),
+            max_daa_score: row.get::<_, Option<i64>>(3).map(|v| v as u64),
+            block_count: row.get::<_, i64>(4) as u64,
+            chain_tip_hash: row.get(5),
+        })
+    }
+
+    /// Returns the most recently inserted block. Unlike
+    /// `highest_block_in_virtual_selected_parent_chain`, this follows
+    /// insertion order rather than the chain.
+    pub async fn latest_block(&self, tx: &Transaction<'_>) -> Result<Option<Block>> {
+        let query = format!("SELECT {} FROM blocks ORDER BY id DESC LIMIT 1", self.hash_storage.block_columns());
+        let row = tx.query_opt(query.as_str(), &[]).await?;
+        row.as_ref().map(block_from_row).transpose()
+    }
+
+    pub async fn blocks_between_heights(
+        &self,
+        tx: &Transaction<'_>,
+        from_height: u64,
+        to_height: u64,
+        offset: u64,
+        limit: u64,
+    ) -> Result<Vec<Block>> {
+        let query = format!(
+            "SELECT {} FROM blocks WHERE height >= $1 AND height <= $2 ORDER BY height, id OFFSET $3 LIMIT $4",
+            self.hash_storage.block_columns(),
+        );
+        let rows = tx.query(
+            query.as_str(),
+            &[&(from_height as i64), &(to_height as i64), &(offset as i64), &(limit as i64)],
+        ).await?;
+        rows.iter().map(block_from_row).collect()
+    }
+
+    /// `blocks_between_heights` paged on (height, id), so rows rewritten
+    /// between two pages don't shift the next one. `after` is the height
+    /// and id of the last block of the previous page.
+    pub async fn blocks_between_heights_after(
+        &self,
+        tx: &Transaction<'_>,
+        from_height: u64,
+        to_height: u64,
+        after: (u64, u64),
+        limit: u64,
+    ) -> Result<Vec<Block>> {
+        let query = format!(
+            "SELECT {} FROM blocks WHERE height >= $1 AND height <= $2 AND (height, id) > ($3, $4) ORDER BY height, id LIMIT $5",
+            self.hash_storage.block_columns(),
+        );
+        let rows = tx.query(
+            query.as_str(),
+            &[&(from_height as i64), &(to_height as i64), &(after.0 as i64), &(after.1 as i64), &(limit as i64)],
+        ).await?;
+        rows.iter().map(block_from_row).collect()
+    }
+
+    /// Virtual selected parent chain blocks above `after`, the height and
+    /// id of the last block of the previous page, ordered by height.
+    pub async fn chain_blocks_after(&self, tx: &Transaction<'_>, after: (u64, u64), limit: u64) -> Result<Vec<Block>> {
+        let query = format!(
+            "SELECT {} FROM blocks WHERE is_in_virtual_selected_parent_chain AND (height, id) > ($1, $2) ORDER BY height, id LIMIT $3",
+            self.hash_storage.block_columns(),
+        );
+        let rows = tx.query(query.as_str(), &[&(after.0 as i64), &(after.1 as i64), &(limit as i64)]).await?;
+        rows.iter().map(block_from_row).collect()
+    }
+
+    /// Edges with a block between `from_height` and `to_height`, including
+    /// those leaving the range. A child is always higher than its parent, so
+    /// both height indexes are only scanned over the range, and an edge
+    /// spanning the whole range without a block in it isn't returned.
+    pub async fn edges_between_heights(
+        &self,
+        tx: &Transaction<'_>,
+        from_height: u64,
+        to_height: u64,
+        offset: u64,
+        limit: u64,
+    ) -> Result<Vec<Edge>> {
+        let rows = tx.query(
+            r#"
+            SELECT from_block_id, to_block_id, from_height, to_height, from_height_group_index, to_height_group_index
+            FROM edges
+            WHERE from_height BETWEEN $1 AND $2 OR to_height BETWEEN $1 AND $2
+            ORDER BY to_height, from_block_id, to_block_id
+            OFFSET $3 LIMIT $4
+            "#,
+            &[&(from_height as i64), &(to_height as i64), &(offset as i64), &(limit as i64)],
+        ).await?;
+        Ok(rows.iter().map(edge_from_row).collect())
+    }
+
+    pub async fn orphan_blocks(&self, tx: &Transaction<'_>, limit: u64) -> Result<Vec<Block>> {
+        let query = format!(
+            "SELECT {} FROM blocks b WHERE {} ORDER BY b.id LIMIT $1",
+            self.hash_storage.block_columns(), ORPHAN_BLOCKS_CONDITION,
+        );
+        let rows = tx.query(query.as_str(), &[&(limit as i64)]).await?;
+        rows.iter().map(block_from_row).collect()
+    }
+
+    pub async fn orphan_block_count(&self, tx: &Transaction<'_>) -> Result<u64> {
+        let query = format!("SELECT COUNT(*) FROM blocks b WHERE {}", ORPHAN_BLOCKS_CONDITION);
+        let row = tx.query_one(query.as_str(), &[]).await?;
+        Ok(row.get::<_, i64>(0) as u64)
+    }
+
+    pub async fn height_group_size(&self, tx: &Transaction<'_>, height: u64) -> Result<u32> {
+        let row = tx.query_opt(
+            "SELECT size FROM height_groups WHERE height = $1",
+            &[&(height as i64)],
+        ).await?;
+        Ok(row.map(|r| r.get::<_, i32>(0) as u32).unwrap_or(0))
+    }
+
+    pub async fn height_group(&self, tx: &Transaction<'_>, height: u64) -> Result<Option<HeightGroup>> {
+        let row = tx.query_opt(
+            "SELECT height, size FROM height_groups WHERE height = $1",
+            &[&(height as i64)],
+        ).await?;
+        Ok(row.as_ref().map(height_group_from_row))
+    }
+
+    /// Height groups between two heights, both included, in height order.
+    /// Heights without blocks have no group.
+    pub async fn height_groups_between_heights(
+        &self,
+        tx: &Transaction<'_>,
+        from_height: u64,
+        to_height: u64,
+    ) -> Result<Vec<HeightGroup>> {
+        let rows = tx.query(
+            "SELECT height, size FROM height_groups WHERE height >= $1 AND height <= $2 ORDER BY height",
+            &[&(from_height as i64), &(to_height as i64)],
+        ).await?;
+        Ok(rows.iter().map(height_group_from_row).collect())
+    }
+
+    pub async fn block_height(&self, tx: &Transaction<'_>, block_id: u64) -> Result<u64> {
         let row = tx.query_one(
-            "SELECT * FROM blocks WHERE is_in_virtual_selected_parent_chain = $1 ORDER BY height DESC LIMIT 1",
-            &[&true],
+            "SELECT height FROM blocks WHERE id = $1",
+            &[&(block_id as i64)],
+        ).await?;
+        Ok(row.get::<_, i64>(0) as u64)
+    }
+
+    pub async fn block_height_group_index(&self, tx: &Transaction<'_>, block_id: u64) -> Result<u32> {
+        let row = tx.query_one(
+            "SELECT height_group_index FROM blocks WHERE id = $1",
+            &[&(block_id as i64)],
+        ).await?;
+        Ok(row.get::<_, i32>(0) as u32)
+    }
+
+    /// Inserts an edge, or refreshes the heights and height group indexes of
+    /// an existing edge between the same blocks, as they change when a block
+    /// is reprocessed. Unchanged edges aren't rewritten.
+    pub async fn insert_edge(&self, tx: &Transaction<'_>, edge: &Edge) -> Result<()> {
+        tx.execute(
+            r#"
+            INSERT INTO edges (from_block_id, to_block_id, from_height, to_height, from_height_group_index, to_height_group_index)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT (from_block_id, to_block_id) DO UPDATE SET
+                from_height = EXCLUDED.from_height,
+                to_height = EXCLUDED.to_height,
+                from_height_group_index = EXCLUDED.from_height_group_index,
+                to_height_group_index = EXCLUDED.to_height_group_index
+            WHERE (edges.from_height, edges.to_height, edges.from_height_group_index, edges.to_height_group_index)
+                IS DISTINCT FROM (EXCLUDED.from_height, EXCLUDED.to_height, EXCLUDED.from_height_group_index, EXCLUDED.to_height_group_index)
+            "#,
+            &[
+                &(edge.from_block_id as i64),
+                &(edge.to_block_id as i64),
+                &(edge.from_height as i64),
+                &(edge.to_height as i64),
+                &(edge.from_height_group_index as i32),
+                &(edge.to_height_group_index as i32),
+            ],
+        ).await?;
+        Ok(())
+    }
+
+    pub async fn insert_or_update_height_group(&self, tx: &Transaction<'_>, height_group: &HeightGroup) -> Result<()> {
+        tx.execute(
+            r#"
+            INSERT INTO height_groups (height, size)
+            VALUES ($1, $2)
+            ON CONFLICT (height) DO UPDATE SET size = EXCLUDED.size
+            "#,
+            &[&(height_group.height as i64), &(height_group.size as i32)],
+        ).await?;
+        Ok(())
+    }
+
+    /// Stores the sizes of many height groups in a single statement. A height
+    /// given more than once keeps its largest size.
+    pub async fn bulk_upsert_height_groups(&self, tx: &Transaction<'_>, groups: &[HeightGroup]) -> Result<()> {
+        if groups.is_empty() {
+            return Ok(());
+        }
+        let heights: Vec<i64> = groups.iter().map(|group| group.height as i64).collect();
+        let sizes: Vec<i32> = groups.iter().map(|group| group.size as i32).collect();
+        tx.execute(
+            r#"
+            INSERT INTO height_groups (height, size)
+            SELECT height, MAX(size) FROM unnest($1::BIGINT[], $2::INT[]) AS g(height, size)
+            GROUP BY height
+            ON CONFLICT (height) DO UPDATE SET size = EXCLUDED.size
+            "#,
+            &[&heights, &sizes],
+        ).await?;
+        Ok(())
+    }
+
+    pub async fn blocks_after_id(&self, tx: &Transaction<'_>, after_id: u64, limit: u64) -> Result<Vec<Block>> {
+        let query = format!("SELECT {} FROM blocks WHERE id > $1 ORDER BY id LIMIT $2", self.hash_storage.block_columns());
+        let rows = tx.query(query.as_str(), &[&(after_id as i64), &(limit as i64)]).await?;
+        rows.iter().map(block_from_row).collect()
+    }
+
+    /// `blocks_after_id` along with the state of every block.
+    pub async fn blocks_with_state_after_id(&self, tx: &Transaction<'_>, after_id: u64, limit: u64) -> Result<Vec<(Block, BlockState)>> {
+        let query = format!(
+            "SELECT {}, colored_by_block_id, header_blob, fully_processed FROM blocks WHERE id > $1 ORDER BY id LIMIT $2",
+            self.hash_storage.block_columns(),
+        );
+        let rows = tx.query(query.as_str(), &[&(after_id as i64), &(limit as i64)]).await?;
+        rows.iter().map(|row| {
+            let state = BlockState {
+                colored_by_block_id: row.get::<_, Option<i64>>(14).map(|id| id as u64),
+                header_blob: row.get(15),
+                fully_processed: row.get(16),
+            };
+            Ok((block_from_row(row)?, state))
+        }).collect()
+    }
+
+    pub async fn edges_after(&self, tx: &Transaction<'_>, after: (u64, u64), limit: u64) -> Result<Vec<Edge>> {
+        let rows = tx.query(
+            r#"
+            SELECT from_block_id, to_block_id, from_height, to_height, from_height_group_index, to_height_group_index
+            FROM edges
+            WHERE (from_block_id, to_block_id) > ($1, $2)
+            ORDER BY from_block_id, to_block_id
+            LIMIT $3
+            "#,
+            &[&(after.0 as i64), &(after.1 as i64), &(limit as i64)],
+        ).await?;
+        Ok(rows.iter().map(edge_from_row).collect())
+    }
+
+    pub async fn height_groups_from(&self, tx: &Transaction<'_>, min_height: u64, limit: u64) -> Result<Vec<HeightGroup>> {
+        let rows = tx.query(
+            "SELECT height, size FROM height_groups WHERE height >= $1 ORDER BY height LIMIT $2",
+            &[&(min_height as i64), &(limit as i64)],
+        ).await?;
+        Ok(rows.iter().map(|row| HeightGroup {
+            height: row.get::<_, i64>(0) as u64,
+            size: row.get::<_, i32>(1) as u32,
+        }).collect())
+    }
+
+    /// Bulk loads blocks, keeping their ids. Call `reset_block_id_sequence`
+    /// once all blocks are loaded.
+    pub async fn copy_in_blocks(&self, tx: &Transaction<'_>, blocks: &[Block]) -> Result<()> {
+        let state = BlockState::default();
+        self.copy_in_block_rows(tx, blocks.iter().map(|block| (block, &state))).await
+    }
+
+    /// `copy_in_blocks` for blocks exported along with their state.
+    pub async fn copy_in_blocks_with_state(&self, tx: &Transaction<'_>, blocks: &[(Block, BlockState)]) -> Result<()> {
+        self.copy_in_block_rows(tx, blocks.iter().map(|(block, state)| (block, state))).await
+    }
+
+    async fn copy_in_block_rows<'b>(
+        &self,
+        tx: &Transaction<'_>,
+        blocks: impl Iterator<Item = (&'b Block, &'b BlockState)>,
+    ) -> Result<()> {
+        let sink = tx.copy_in(
+            "COPY blocks (id, block_hash, timestamp, parent_ids, daa_score, height, height_group_index, \
+            selected_parent_id, color, is_in_virtual_selected_parent_chain, merge_set_red_ids, merge_set_blue_ids, \
+            is_header_only, miner, colored_by_block_id, header_blob, fully_processed) FROM STDIN BINARY"
+        ).await?;
+        let hash_type = match self.hash_storage {
+            HashStorage::Hex => Type::BPCHAR,
+            HashStorage::Compact => Type::BYTEA,
+        };
+        let writer = BinaryCopyInWriter::new(sink, &[
+            Type::INT8, hash_type, Type::INT8, Type::JSONB, Type::INT8, Type::INT8, Type::INT4,
+            Type::INT8, Type::TEXT, Type::BOOL, Type::JSONB, Type::JSONB, Type::BOOL, Type::TEXT,
+            Type::INT8, Type::BYTEA, Type::BOOL,
+        ]);
+        tokio::pin!(writer);
+        for (block, state) in blocks {
+            let compact_hash;
+            let block_hash: &(dyn ToSql + Sync) = match self.hash_storage {
+                HashStorage::Hex => &block.block_hash,
+                HashStorage::Compact => {
+                    compact_hash = hex::decode(&block.block_hash)
+                        .map_err(|e| TgiError::invalid_hash(&block.block_hash, e))?;
+                    &compact_hash
+                }
+            };
+            writer.as_mut().write(&[
+                &(block.id as i64),
+                block_hash,
+                &block.timestamp,
+                &serde_json::to_value(&block.parent_ids)?,
+                &(block.daa_score as i64),
+                &(block.height as i64),
+                &(block.height_group_index as i32),
+                &block.selected_parent_id.map(|id| id as i64),
+                &block.color,
+                &block.is_in_virtual_selected_parent_chain,
+                &serde_json::to_value(&block.merge_set_red_ids)?,
+                &serde_json::to_value(&block.merge_set_blue_ids)?,
+                &block.is_header_only,
+                &block.miner,
+                &state.colored_by_block_id.map(|id| id as i64),
+                &state.header_blob,
+                &state.fully_processed,
+            ]).await?;
+        }
+        writer.finish().await?;
+        Ok(())
+    }
+
+    pub async fn copy_in_edges(&self, tx: &Transaction<'_>, edges: &[Edge]) -> Result<()> {
+        let sink = tx.copy_in(
+            "COPY edges (from_block_id, to_block_id, from_height, to_height, from_height_group_index, to_height_group_index) \
+            FROM STDIN BINARY"
+        ).await?;
+        let writer = BinaryCopyInWriter::new(sink, &[
+            Type::INT8, Type::INT8, Type::INT8, Type::INT8, Type::INT4, Type::INT4,
+        ]);
+        tokio::pin!(writer);
+        for edge in edges {
+            writer.as_mut().write(&[
+                &(edge.from_block_id as i64),
+                &(edge.to_block_id as i64),
+                &(edge.from_height as i64),
+                &(edge.to_height as i64),
+                &(edge.from_height_group_index as i32),
+                &(edge.to_height_group_index as i32),
+            ]).await?;
+        }
+        writer.finish().await?;
+        Ok(())
+    }
+
+    pub async fn copy_in_height_groups(&self, tx: &Transaction<'_>, height_groups: &[HeightGroup]) -> Result<()> {
+        let sink = tx.copy_in("COPY height_groups (height, size) FROM STDIN BINARY").await?;
+        let writer = BinaryCopyInWriter::new(sink, &[Type::INT8, Type::INT4]);
+        tokio::pin!(writer);
+        for height_group in height_groups {
+            writer.as_mut().write(&[&(height_group.height as i64), &(height_group.size as i32)]).await?;
+        }
+        writer.finish().await?;
+        Ok(())
+    }
+
+    pub async fn accepted_transactions_after(
+        &self,
+        tx: &Transaction<'_>,
+        after: (u64, &str),
+        limit: u64,
+    ) -> Result<Vec<AcceptedTransaction>> {
+        let rows = tx.query(
+            r#"
+            SELECT accepting_block_id, transaction_id
+            FROM accepted_transactions
+            WHERE (accepting_block_id, transaction_id) > ($1, $2)
+            ORDER BY accepting_block_id, transaction_id
+            LIMIT $3
+            "#,
+            &[&(after.0 as i64), &after.1, &(limit as i64)],
+        ).await?;
+        Ok(rows.iter().map(|row| AcceptedTransaction {
+            accepting_block_id: row.get::<_, i64>(0) as u64,
+            transaction_id: row.get(1),
+        }).collect())
+    }
+
+    pub async fn copy_in_accepted_transactions(&self, tx: &Transaction<'_>, accepted_transactions: &[AcceptedTransaction]) -> Result<()> {
+        let sink = tx.copy_in("COPY accepted_transactions (accepting_block_id, transaction_id) FROM STDIN BINARY").await?;
+        let writer = BinaryCopyInWriter::new(sink, &[Type::INT8, Type::BPCHAR]);
+        tokio::pin!(writer);
+        for accepted_transaction in accepted_transactions {
+            writer.as_mut().write(&[
+                &(accepted_transaction.accepting_block_id as i64),
+                &accepted_transaction.transaction_id,
+            ]).await?;
+        }
+        writer.finish().await?;
+        Ok(())
+    }
+
+    pub async fn pending_selected_parents_after(
+        &self,
+        tx: &Transaction<'_>,
+        after_block_id: u64,
+        limit: u64,
+    ) -> Result<Vec<PendingSelectedParent>> {
+        let rows = tx.query(
+            "SELECT block_id, selected_parent_hash FROM pending_selected_parents WHERE block_id > $1 ORDER BY block_id LIMIT $2",
+            &[&(after_block_id as i64), &(limit as i64)],
+        ).await?;
+        Ok(rows.iter().map(|row| PendingSelectedParent {
+            block_id: row.get::<_, i64>(0) as u64,
+            selected_parent_hash: row.get(1),
+        }).collect())
+    }
+
+    pub async fn insert_pending_selected_parents(&self, tx: &Transaction<'_>, pending: &[PendingSelectedParent]) -> Result<()> {
+        let block_ids: Vec<i64> = pending.iter().map(|row| row.block_id as i64).collect();
+        let selected_parent_hashes: Vec<&str> = pending.iter().map(|row| row.selected_parent_hash.as_str()).collect();
+        tx.execute(
+            r#"
+            INSERT INTO pending_selected_parents (block_id, selected_parent_hash)
+            SELECT * FROM unnest($1::BIGINT[], $2::CHAR(64)[])
+            "#,
+            &[&block_ids, &selected_parent_hashes],
+        ).await?;
+        Ok(())
+    }
+
+    pub async fn pending_parent_edges_after(
+        &self,
+        tx: &Transaction<'_>,
+        after: (u64, &str),
+        limit: u64,
+    ) -> Result<Vec<PendingParentEdge>> {
+        let rows = tx.query(
+            r#"
+            SELECT block_id, parent_hash
+            FROM pending_parent_edges
+            WHERE (block_id, parent_hash) > ($1, $2)
+            ORDER BY block_id, parent_hash
+            LIMIT $3
+            "#,
+            &[&(after.0 as i64), &after.1, &(limit as i64)],
+        ).await?;
+        Ok(rows.iter().map(|row| PendingParentEdge {
+            block_id: row.get::<_, i64>(0) as u64,
+            parent_hash: row.get(1),
+        }).collect())
+    }
+
+    pub async fn insert_pending_parent_edges(&self, tx: &Transaction<'_>, pending: &[PendingParentEdge]) -> Result<()> {
+        let block_ids: Vec<i64> = pending.iter().map(|row| row.block_id as i64).collect();
+        let parent_hashes: Vec<&str> = pending.iter().map(|row| row.parent_hash.as_str()).collect();
+        tx.execute(
+            r#"
+            INSERT INTO pending_parent_edges (block_id, parent_hash)
+            SELECT * FROM unnest($1::BIGINT[], $2::CHAR(64)[])
+            "#,
+            &[&block_ids, &parent_hashes],
+        ).await?;
+        Ok(())
+    }
+
+    pub async fn pending_orphan_blocks_after(
+        &self,
+        tx: &Transaction<'_>,
+        after: (&str, &str),
+        limit: u64,
+    ) -> Result<Vec<PendingOrphanBlock>> {
+        let rows = tx.query(
+            r#"
+            SELECT block_hash, parent_hash
+            FROM pending_orphan_blocks
+            WHERE (block_hash, parent_hash) > ($1, $2)
+            ORDER BY block_hash, parent_hash
+            LIMIT $3
+            "#,
+            &[&after.0, &after.1, &(limit as i64)],
         ).await?;
+        Ok(rows.iter().map(|row| PendingOrphanBlock {
+            block_hash: row.get(0),
+            parent_hash: row.get(1),
+        }).collect())
+    }
 
-        let id: i64 = row.get(0);
-        let parent_ids: serde_json::Value = row.get(2);
-        let merge_set_red_ids: serde_json::Value = row.get(10);
-        let merge_set_blue_ids: serde_json::Value = row.get(11);
-
-        Ok(Block {
-            id: id as u64,
-            block_hash: row.get(1),
-            timestamp: row.get(2),
-            parent_ids: serde_json::from_value(parent_ids)?,
-            daa_score: row.get::<_, i64>(4) as u64,
-            height: row.get::<_, i64>(5) as u64,
-            height_group_index: row.get::<_, i32>(6) as u32,
-            selected_parent_id: row.get::<_, Option<i64>>(7).map(|v| v as u64),
-            color: row.get(8),
-            is_in_virtual_selected_parent_chain: row.get(9),
-            merge_set_red_ids: serde_json::from_value(merge_set_red_ids)?,
-            merge_set_blue_ids: serde_json::from_value(merge_set_blue_ids)?,
-        })
+    pub async fn insert_pending_orphan_blocks(&self, tx: &Transaction<'_>, pending: &[PendingOrphanBlock]) -> Result<()> {
+        let block_hashes: Vec<&str> = pending.iter().map(|row| row.block_hash.as_str()).collect();
+        let parent_hashes: Vec<&str> = pending.iter().map(|row| row.parent_hash.as_str()).collect();
+        tx.execute(
+            r#"
+            INSERT INTO pending_orphan_blocks (block_hash, parent_hash)
+            SELECT * FROM unnest($1::CHAR(64)[], $2::CHAR(64)[])
+            "#,
+            &[&block_hashes, &parent_hashes],
+        ).await?;
+        Ok(())
     }
 
-    pub async fn height_group_size(&self, tx: &Transaction<'_>, height: u64) -> Result<u32> {
-        let row = tx.query_opt(
-            "SELECT size FROM height_groups WHERE height = $1",
-            &[&(height as i64)],
+    pub async fn pending_orphan_block_data_after(
+        &self,
+        tx: &Transaction<'_>,
+        after_block_hash: &str,
+        limit: u64,
+    ) -> Result<Vec<PendingOrphanBlockData>> {
+        let rows = tx.query(
+            r#"
+            SELECT block_hash, block::TEXT, deferred_at
+            FROM pending_orphan_block_data
+            WHERE block_hash > $1
+            ORDER BY block_hash
+            LIMIT $2
+            "#,
+            &[&after_block_hash, &(limit as i64)],
         ).await?;
-        Ok(row.map(|r| r.get::<_, i32>(0) as u32).unwrap_or(0))
+        Ok(rows.iter().map(|row| PendingOrphanBlockData {
+            block_hash: row.get(0),
+            block: row.get(1),
+            deferred_at: row.get(2),
+        }).collect())
     }
 
-    pub async fn block_height(&self, tx: &Transaction<'_>, block_id: u64) -> Result<u64> {
-        let row = tx.query_one(
-            "SELECT height FROM blocks WHERE id = $1",
-            &[&(block_id as i64)],
+    pub async fn insert_pending_orphan_block_data(&self, tx: &Transaction<'_>, pending: &[PendingOrphanBlockData]) -> Result<()> {
+        let block_hashes: Vec<&str> = pending.iter().map(|row| row.block_hash.as_str()).collect();
+        let blocks: Vec<&str> = pending.iter().map(|row| row.block.as_str()).collect();
+        let deferred_ats: Vec<i64> = pending.iter().map(|row| row.deferred_at).collect();
+        tx.execute(
+            r#"
+            INSERT INTO pending_orphan_block_data (block_hash, block, deferred_at)
+            SELECT block_hash, block::JSONB, deferred_at FROM unnest($1::CHAR(64)[], $2::TEXT[], $3::BIGINT[])
+                AS d(block_hash, block, deferred_at)
+            "#,
+            &[&block_hashes, &blocks, &deferred_ats],
         ).await?;
-        Ok(row.get::<_, i64>(0) as u64)
+        Ok(())
     }
 
-    pub async fn block_height_group_index(&self, tx: &Transaction<'_>, block_id: u64) -> Result<u32> {
-        let row = tx.query_one(
-            "SELECT height_group_index FROM blocks WHERE id = $1",
-            &[&(block_id as i64)],
+    /// Moves the blocks id sequence past the highest stored id, so blocks
+    /// inserted after a bulk load don't collide with the loaded ones.
+    pub async fn reset_block_id_sequence(&self, tx: &Transaction<'_>) -> Result<()> {
+        tx.execute(
+            "SELECT setval(pg_get_serial_sequence('blocks', 'id'), COALESCE(MAX(id), 0) + 1, false) FROM blocks",
+            &[],
         ).await?;
-        Ok(row.get::<_, i32>(0) as u32)
+        Ok(())
     }
 
-    pub async fn insert_edge(&self, tx: &Transaction<'_>, edge: &Edge) -> Result<()> {
+    /// Recomputes the height groups from the stored blocks. Blocks get
+    /// height group indexes in id order within their height, and the edges
+    /// are updated to match.
+    pub async fn rebuild_height_groups(&self, tx: &Transaction<'_>) -> Result<()> {
         tx.execute(
             r#"
-            INSERT INTO edges (from_block_id, to_block_id, from_height, to_height, from_height_group_index, to_height_group_index)
-            VALUES ($1, $2, $3, $4, $5, $6)
-            ON CONFLICT (from_block_id, to_block_id) DO NOTHING
+            UPDATE blocks SET height_group_index = r.height_group_index
+            FROM (
+                SELECT id, (ROW_NUMBER() OVER (PARTITION BY height ORDER BY id) - 1)::INT AS height_group_index
+                FROM blocks
+            ) r
+            WHERE blocks.id = r.id AND blocks.height_group_index <> r.height_group_index
             "#,
-            &[
-                &(edge.from_block_id as i64),
-                &(edge.to_block_id as i64),
-                &(edge.from_height as i64),
-                &(edge.to_height as i64),
-                &(edge.from_height_group_index as i32),
-                &(edge.to_height_group_index as i32),
-            ],
+            &[],
+        ).await?;
+        tx.execute(
+            r#"
+            UPDATE edges SET from_height_group_index = b.height_group_index
+            FROM blocks b
+            WHERE b.id = edges.from_block_id AND edges.from_height_group_index <> b.height_group_index
+            "#,
+            &[],
+        ).await?;
+        tx.execute(
+            r#"
+            UPDATE edges SET to_height_group_index = b.height_group_index
+            FROM blocks b
+            WHERE b.id = edges.to_block_id AND edges.to_height_group_index <> b.height_group_index
+            "#,
+            &[],
+        ).await?;
+        tx.execute("TRUNCATE TABLE height_groups", &[]).await?;
+        tx.execute(
+            "INSERT INTO height_groups (height, size) SELECT height, COUNT(*) FROM blocks GROUP BY height",
+            &[],
         ).await?;
         Ok(())
     }
 
-    pub async fn insert_or_update_height_group(&self, tx: &Transaction<'_>, height_group: &HeightGroup) -> Result<()> {
+    /// Deletes the blocks below `daa_score` that aren't in the virtual
+    /// selected parent chain, along with everything referencing them, and
+    /// returns how many were deleted. Chain blocks are kept so the chain
+    /// stays connected down to the original anchor. Surviving blocks drop
+    /// the deleted ids from their parents, merge sets and colored by
+    /// pointers, and height groups are rebuilt so their indexes stay
+    /// contiguous. Heights are kept rather than re-anchored at the pruning
+    /// point: the kept chain still leads down to the original anchor, so
+    /// they stay consistent, and links to heights stay valid.
+    pub async fn prune_below_daa_score(&self, tx: &Transaction<'_>, daa_score: u64) -> Result<u64> {
+        let query = format!(
+            "DELETE FROM blocks WHERE daa_score < $1 AND NOT is_in_virtual_selected_parent_chain RETURNING id, {}",
+            self.hash_storage.select("block_hash"),
+        );
+        let rows = tx.query(query.as_str(), &[&(daa_score as i64)]).await?;
+        let pruned_ids: Vec<i64> = rows.iter().map(|row| row.get(0)).collect();
+        let pruned_hashes: Vec<String> = rows.iter().map(|row| row.get(1)).collect();
+        if pruned_ids.is_empty() {
+            return Ok(0);
+        }
+
+        tx.execute(
+            "DELETE FROM edges WHERE from_block_id = ANY($1) OR to_block_id = ANY($1)",
+            &[&pruned_ids],
+        ).await?;
+        tx.execute("DELETE FROM pending_selected_parents WHERE block_id = ANY($1)", &[&pruned_ids]).await?;
+        tx.execute(
+            "DELETE FROM pending_parent_edges WHERE block_id = ANY($1) OR parent_hash = ANY($2)",
+            &[&pruned_ids, &pruned_hashes],
+        ).await?;
+        let rows = tx.query(
+            "DELETE FROM pending_orphan_blocks WHERE block_hash = ANY($1) OR parent_hash = ANY($1) RETURNING block_hash",
+            &[&pruned_hashes],
+        ).await?;
+        let mut released_hashes: Vec<String> = rows.iter().map(|row| row.get(0)).collect();
+        released_hashes.extend(pruned_hashes.iter().cloned());
+        // Blocks deferred on a pruned block keep their data as long as they
+        // still wait for another parent
         tx.execute(
             r#"
-            INSERT INTO height_groups (height, size)
-            VALUES ($1, $2)
-            ON CONFLICT (height) DO UPDATE SET size = EXCLUDED.size
+            DELETE FROM pending_orphan_block_data d
+            WHERE d.block_hash = ANY($1)
+                AND NOT EXISTS (SELECT 1 FROM pending_orphan_blocks o WHERE o.block_hash = d.block_hash)
             "#,
-            &[&(height_group.height as i64), &(height_group.size as i32)],
+            &[&released_hashes],
         ).await?;
-        Ok(())
+        tx.execute("DELETE FROM accepted_transactions WHERE accepting_block_id = ANY($1)", &[&pruned_ids]).await?;
+        tx.execute(
+            "UPDATE blocks SET selected_parent_id = NULL WHERE selected_parent_id = ANY($1)",
+            &[&pruned_ids],
+        ).await?;
+        tx.execute(
+            "UPDATE blocks SET colored_by_block_id = NULL WHERE colored_by_block_id = ANY($1)",
+            &[&pruned_ids],
+        ).await?;
+        for column in ["parent_ids", "merge_set_red_ids", "merge_set_blue_ids"] {
+            let query = format!(
+                r#"
+                UPDATE blocks SET {column} = COALESCE((
+                    SELECT jsonb_agg(r.id::BIGINT ORDER BY r.ord)
+                    FROM jsonb_array_elements_text(blocks.{column}) WITH ORDINALITY AS r(id, ord)
+                    WHERE NOT r.id::BIGINT = ANY($1)
+                ), '[]'::jsonb)
+                WHERE EXISTS (
+                    SELECT 1 FROM jsonb_array_elements_text(blocks.{column}) AS r(id)
+                    WHERE r.id::BIGINT = ANY($1)
+                )
+                "#,
+            );
+            tx.execute(query.as_str(), &[&pruned_ids]).await?;
+        }
+        self.rebuild_height_groups(tx).await?;
+
+        // The surviving blocks keep their ids and heights, so only the
+        // pruned ones are evicted
+        let pruned_hashes = pruned_hashes.iter().map(|hash| BlockHash::parse(hash)).collect::<Result<Vec<_>>>()?;
+        self.block_base_cache.lock().await.remove(&pruned_hashes);
+        Ok(pruned_ids.len() as u64)
     }
 
     pub async fn get_app_config(&self, tx: &Transaction<'_>) -> Result<AppConfig> {
         let row = tx.query_one(
-            "SELECT * FROM app_config WHERE id = $1",
+            "SELECT id, tondid_version, processing_version, network FROM app_config WHERE id = $1",
             &[&true],
         ).await?;
 
@@ -451,21 +2511,40 @@ impl Database {
         tx.execute("TRUNCATE TABLE blocks", &[]).await?;
         tx.execute("TRUNCATE TABLE edges", &[]).await?;
         tx.execute("TRUNCATE TABLE height_groups", &[]).await?;
+        tx.execute("TRUNCATE TABLE accepted_transactions", &[]).await?;
+        tx.execute("TRUNCATE TABLE pending_selected_parents", &[]).await?;
+        tx.execute("TRUNCATE TABLE pending_orphan_blocks", &[]).await?;
+        tx.execute("TRUNCATE TABLE pending_orphan_block_data", &[]).await?;
+        tx.execute("TRUNCATE TABLE pending_parent_edges", &[]).await?;
         Ok(())
     }
 
-    pub async fn load_cache(&self, tx: &Transaction<'_>, min_height: u64) -> Result<()> {
-        let rows = tx.query(
-            "SELECT id, block_hash, height FROM blocks WHERE height >= $1",
-            &[&(min_height as i64)],
-        ).await?;
+    /// Replaces the block cache content with the blocks selected by
+    /// `warming` and returns how many were loaded.
+    pub async fn load_cache(&self, tx: &Transaction<'_>, warming: CacheWarming) -> Result<usize> {
+        let rows = match warming {
+            CacheWarming::FromHeight(min_height) => tx.query(
+                format!("SELECT id, {}, height FROM blocks WHERE height >= $1", self.hash_storage.select("block_hash")).as_str(),
+                &[&(min_height as i64)],
+            ).await?,
+            // Oldest first, so the most recent blocks are the last evicted
+            CacheWarming::Recent(count) => tx.query(
+                format!(
+                    "SELECT id, {}, height FROM (SELECT id, block_hash, height FROM blocks ORDER BY id DESC LIMIT $1) r ORDER BY id",
+                    self.hash_storage.select("block_hash"),
+                ).as_str(),
+                &[&(count as i64)],
+            ).await?,
+            CacheWarming::Skip => vec![],
+        };
 
         let mut cache = self.block_base_cache.lock().await;
         cache.clear();
 
+        let loaded = rows.len();
         for row in rows {
             let id: i64 = row.get(0);
-            let block_hash: String = row.get(1);
+            let block_hash: BlockHash = row.get(1);
             let height: i64 = row.get(2);
             cache.put(block_hash, BlockBase {
                 id: id as u64,
@@ -473,7 +2552,900 @@ impl Database {
             });
         }
 
-        Ok(())
+        Ok(loaded)
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicI64, Ordering};
+
+    fn block_base(id: u64) -> BlockBase {
+        BlockBase { id, height: id }
+    }
+
+    fn hash(n: u64) -> BlockHash {
+        BlockHash::parse(&format!("{:064x}", n)).unwrap()
+    }
+
+    #[test]
+    fn rolled_back_entries_are_evicted() {
+        let mut cache = BlockBaseCache::new(10);
+        let (committed, rolled_back) = (hash(1), hash(2));
+        cache.put(committed, block_base(1));
+        cache.start_tracking();
+        cache.put(rolled_back, block_base(2));
+        cache.set_height(&committed, 5);
+        cache.evict_tracked();
+        assert!(cache.peek(&rolled_back).is_none());
+        // A height update may be rolled back as well
+        assert!(cache.peek(&committed).is_none());
+    }
+
+    #[test]
+    fn committed_entries_are_kept() {
+        let mut cache = BlockBaseCache::new(10);
+        cache.start_tracking();
+        cache.put(hash(1), block_base(1));
+        cache.stop_tracking();
+        cache.start_tracking();
+        cache.evict_tracked();
+        assert_eq!(cache.peek(&hash(1)).map(|base| base.id), Some(1));
+    }
+
+    #[test]
+    fn hash_prefix_is_lowercased() {
+        assert_eq!(Database::normalize_hash_prefix("00AbC").unwrap(), "00abc");
+        assert_eq!(Database::normalize_hash_prefix(&"F".repeat(64)).unwrap(), "f".repeat(64));
+    }
+
+    #[test]
+    fn invalid_hash_prefixes_are_rejected() {
+        for prefix in ["", "0x12", "12g4", &"0".repeat(65)] {
+            assert!(
+                matches!(Database::normalize_hash_prefix(prefix), Err(TgiError::InvalidHash { .. })),
+                "{:?} should be rejected", prefix,
+            );
+        }
+    }
+
+    #[test]
+    fn unfinished_transaction_entries_are_evicted() {
+        let mut cache = BlockBaseCache::new(10);
+        cache.start_tracking();
+        cache.put(hash(1), block_base(1));
+        cache.start_tracking();
+        assert!(cache.peek(&hash(1)).is_none());
+    }
+
+    async fn reported_application_name(connection_string: &str, application_name: Option<&str>) -> Option<String> {
+        let test_database = crate::database::testing::TestDatabase::create_empty().await?;
+        let connection_string = format!("{} {}", test_database.connection_string, connection_string);
+        let database = Database::connect(
+            &connection_string, None, &DbTlsConfig::default(), &DbTcpConfig::default(), None, application_name,
+            &test_database.schema,
+        ).await.unwrap();
+        let client = database.client.lock().await;
+        let row = client.query_one(
+            "SELECT application_name FROM pg_stat_activity WHERE pid = pg_backend_pid()", &[],
+        ).await.unwrap();
+        Some(row.get(0))
+    }
+
+    #[tokio::test]
+    async fn sessions_report_the_application_name() {
+        let Some(reported) = reported_application_name("", Some("tgi-processing/1.0.0/tondi-mainnet")).await else {
+            return;
+        };
+        assert_eq!(reported, "tgi-processing/1.0.0/tondi-mainnet");
+    }
+
+    #[tokio::test]
+    async fn connection_string_application_name_wins() {
+        let Some(reported) = reported_application_name("application_name='dba tool'", Some("tgi-processing")).await else {
+            return;
+        };
+        assert_eq!(reported, "dba tool");
+    }
+
+    #[tokio::test]
+    async fn transactions_run_at_the_configured_isolation_level() {
+        let Some(test_database) = crate::database::testing::TestDatabase::create_empty().await else {
+            return;
+        };
+        let database = test_database.database.with_isolation_level(IsolationLevel::Serializable);
+        let isolation_level = database.run_in_transaction(|tx| Box::pin(async move {
+            Ok(tx.query_one("SHOW transaction_isolation", &[]).await?.get::<_, String>(0))
+        })).await.unwrap();
+        assert_eq!(isolation_level, "serializable");
+    }
+
+    /// Terminates the backend with the process id `pid` from `client`, like
+    /// a database restart would.
+    async fn terminate_backend(client: &Client, pid: i32) {
+        client.execute("SELECT pg_terminate_backend($1)", &[&pid]).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn queries_resume_after_the_connection_was_killed() {
+        let Some(test_database) = crate::database::testing::TestDatabase::create_empty().await else {
+            return;
+        };
+        let database = test_database.database.clone();
+        let pid: i32 = database.run_in_transaction(|tx| Box::pin(async move {
+            Ok(tx.query_one("SELECT pg_backend_pid()", &[]).await?.get(0))
+        })).await.unwrap();
+        terminate_backend(&test_database.client().await, pid).await;
+        // Let the connection task notice
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let new_pid: i32 = database.run_in_transaction(|tx| Box::pin(async move {
+            Ok(tx.query_one("SELECT pg_backend_pid()", &[]).await?.get(0))
+        })).await.unwrap();
+        assert_ne!(new_pid, pid);
+    }
+
+    #[tokio::test]
+    async fn transaction_interrupted_by_a_lost_connection_runs_again() {
+        let Some(test_database) = crate::database::testing::TestDatabase::create_empty().await else {
+            return;
+        };
+        let killer = Arc::new(test_database.client().await);
+        killer.batch_execute("CREATE TABLE probes (attempt BIGINT NOT NULL)").await.unwrap();
+        let attempts = Arc::new(AtomicI64::new(0));
+        let attempts_for_closure = attempts.clone();
+        test_database.database.run_in_transaction(move |tx| {
+            let attempts = attempts_for_closure.clone();
+            let killer = killer.clone();
+            Box::pin(async move {
+                let attempt = attempts.fetch_add(1, Ordering::SeqCst) + 1;
+                tx.execute("INSERT INTO probes (attempt) VALUES ($1)", &[&attempt]).await?;
+                if attempt == 1 {
+                    let pid: i32 = tx.query_one("SELECT pg_backend_pid()", &[]).await?.get(0);
+                    terminate_backend(&killer, pid).await;
+                }
+                tx.query_one("SELECT 1", &[]).await?;
+                Ok(())
+            })
+        }).await.unwrap();
+
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        let attempts: Vec<i64> = test_database.client().await
+            .query("SELECT attempt FROM probes", &[]).await.unwrap()
+            .iter().map(|row| row.get(0)).collect();
+        assert_eq!(attempts, vec![2]);
+    }
+
+    #[tokio::test]
+    async fn transaction_that_can_only_run_once_fails_on_a_lost_connection() {
+        let Some(test_database) = crate::database::testing::TestDatabase::create_empty().await else {
+            return;
+        };
+        let killer = test_database.client().await;
+        let result = test_database.database.run_in_transaction_once(move |tx| Box::pin(async move {
+            let pid: i32 = tx.query_one("SELECT pg_backend_pid()", &[]).await?.get(0);
+            terminate_backend(&killer, pid).await;
+            tx.query_one("SELECT 1", &[]).await?;
+            Ok(())
+        })).await;
+        assert!(result.is_err());
+        // The next transaction gets a new connection
+        test_database.database.run_in_transaction(|tx| Box::pin(async move {
+            tx.query_one("SELECT 1", &[]).await?;
+            Ok(())
+        })).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn transaction_whose_commit_lost_the_connection_does_not_run_again() {
+        let Some(test_database) = crate::database::testing::TestDatabase::create_empty().await else {
+            return;
+        };
+        let killer = Arc::new(test_database.client().await);
+        let attempts = Arc::new(AtomicI64::new(0));
+        let attempts_for_closure = attempts.clone();
+        let result = test_database.database.run_in_transaction(move |tx| {
+            let attempts = attempts_for_closure.clone();
+            let killer = killer.clone();
+            Box::pin(async move {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                let pid: i32 = tx.query_one("SELECT pg_backend_pid()", &[]).await?.get(0);
+                terminate_backend(&killer, pid).await;
+                // Let the connection task notice, so only the COMMIT fails
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                Ok(())
+            })
+        }).await;
+
+        let error = result.unwrap_err();
+        assert!(matches!(error.downcast_ref::<TgiError>(), Some(TgiError::CommitOutcomeUnknown(_))), "{:#}", error);
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn transaction_waiting_past_the_acquire_timeout_fails() {
+        let Some(test_database) = crate::database::testing::TestDatabase::create_empty().await else {
+            return;
+        };
+        let database = test_database.database.with_acquire_timeout(Some(Duration::from_millis(100)));
+        let (held, hold) = tokio::sync::oneshot::channel::<()>();
+        let (release, released) = tokio::sync::oneshot::channel::<()>();
+        let holder = tokio::spawn({
+            let database = database.clone();
+            async move {
+                database.run_in_transaction_once(move |_| Box::pin(async move {
+                    let _ = held.send(());
+                    let _ = released.await;
+                    Ok(())
+                })).await
+            }
+        });
+        hold.await.unwrap();
+
+        let result = database.run_in_transaction(|tx| Box::pin(async move {
+            tx.query_one("SELECT 1", &[]).await?;
+            Ok(())
+        })).await;
+
+        assert!(matches!(result.unwrap_err().downcast_ref::<TgiError>(), Some(TgiError::AcquireTimeout(_))));
+        release.send(()).unwrap();
+        holder.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn lost_rpc_connection_does_not_run_the_transaction_again() {
+        let Some(test_database) = crate::database::testing::TestDatabase::create_empty().await else {
+            return;
+        };
+        let attempts = Arc::new(AtomicI64::new(0));
+        let attempts_for_closure = attempts.clone();
+        let result: anyhow::Result<()> = test_database.database.run_in_transaction(move |_| {
+            let attempts = attempts_for_closure.clone();
+            Box::pin(async move {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(TgiError::rpc("GetBlock", "Client disconnected").into())
+            })
+        }).await;
+
+        assert!(matches!(result.unwrap_err().downcast_ref::<TgiError>(), Some(TgiError::ConnectionLost(_))));
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn reads_without_a_replica_do_not_wait_for_a_write_transaction() {
+        let Some(test_database) = crate::database::testing::TestDatabase::create_empty().await else {
+            return;
+        };
+        let database = test_database.database.clone();
+        test_database.database.run_in_transaction(move |tx| {
+            let database = database.clone();
+            Box::pin(async move {
+                tx.query_one("SELECT 1", &[]).await?;
+                let read = database.run_in_read_transaction(|tx| Box::pin(async move {
+                    Ok(tx.query_one("SELECT 1", &[]).await?.get::<_, i32>(0))
+                }));
+                let read = tokio::time::timeout(Duration::from_secs(5), read).await
+                    .expect("the read waited for the write transaction")?;
+                assert_eq!(read, 1);
+                Ok(())
+            })
+        }).await.unwrap();
+    }
+
+    /// Stores a chain of blocks, each the selected parent of the next one
+    async fn insert_selected_parent_chain(database: &Database, hashes: &[BlockHash]) {
+        let hashes_for_closure = hashes.to_vec();
+        let database_for_closure = database.clone();
+        database.run_in_transaction(move |tx| {
+            let hashes = hashes_for_closure.clone();
+            let database = database_for_closure.clone();
+            Box::pin(async move {
+                let mut selected_parent_id = None;
+                for (height, hash) in hashes.iter().enumerate() {
+                    let block = Block {
+                        id: 0,
+                        block_hash: hash.to_string(),
+                        timestamp: 0,
+                        parent_ids: selected_parent_id.into_iter().collect(),
+                        daa_score: height as u64,
+                        height: height as u64,
+                        height_group_index: 0,
+                        selected_parent_id,
+                        color: COLOR_BLUE.to_string(),
+                        is_in_virtual_selected_parent_chain: true,
+                        merge_set_red_ids: vec![],
+                        merge_set_blue_ids: vec![],
+                        is_header_only: false,
+                        miner: None,
+                    };
+                    database.insert_block(tx, hash, &block).await?;
+                    selected_parent_id = Some(database.block_id_by_hash(tx, hash).await?);
+                }
+                Ok(())
+            })
+        }).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn selected_parent_path_walks_down_to_an_ancestor() {
+        let Some(test_database) = crate::database::testing::TestDatabase::create().await else {
+            return;
+        };
+        let database = &test_database.database;
+        let hashes: Vec<BlockHash> = (0..4).map(hash).collect();
+        insert_selected_parent_chain(database, &hashes).await;
+
+        let mut tx_client = test_database.client().await;
+        let tx = tx_client.transaction().await.unwrap();
+        let path = database.selected_parent_path(&tx, &hashes[3], &hashes[0], 10).await.unwrap();
+        assert_eq!(path, Some(hashes.iter().rev().cloned().collect()));
+        // Longer than max_len
+        assert_eq!(database.selected_parent_path(&tx, &hashes[3], &hashes[0], 3).await.unwrap(), None);
+        // Not an ancestor
+        assert_eq!(database.selected_parent_path(&tx, &hashes[0], &hashes[3], 10).await.unwrap(), None);
+        assert_eq!(database.selected_parent_path(&tx, &hashes[3], &hash(9), 10).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn missing_hashes_keep_the_input_order() {
+        let Some(test_database) = crate::database::testing::TestDatabase::create().await else {
+            return;
+        };
+        let database = &test_database.database;
+        insert_selected_parent_chain(database, &[hash(1), hash(2), hash(3)]).await;
+
+        let mut tx_client = test_database.client().await;
+        let tx = tx_client.transaction().await.unwrap();
+        let hashes = [hash(7), hash(1), hash(5), hash(3), hash(4), hash(2)];
+        assert_eq!(database.missing_hashes(&tx, &hashes).await.unwrap(), vec![hash(7), hash(5), hash(4)]);
+        assert!(database.missing_hashes(&tx, &[hash(2), hash(1)]).await.unwrap().is_empty());
+        assert!(database.missing_hashes(&tx, &[]).await.unwrap().is_empty());
+    }
+
+    async fn stored_hashes(database: &Database, hashes: &[BlockHash]) -> Vec<BlockHash> {
+        let hashes_for_closure = hashes.to_vec();
+        let database_for_closure = database.clone();
+        let missing = database.run_in_read_transaction(move |tx| {
+            let hashes = hashes_for_closure.clone();
+            let database = database_for_closure.clone();
+            Box::pin(async move { Ok(database.missing_hashes(tx, &hashes).await?) })
+        }).await.unwrap();
+        hashes.iter().filter(|hash| !missing.contains(hash)).copied().collect()
+    }
+
+    #[tokio::test]
+    async fn blocks_of_one_schema_are_invisible_from_another() {
+        let Some(mainnet) = crate::database::testing::TestDatabase::create().await else {
+            return;
+        };
+        let Some(testnet) = crate::database::testing::TestDatabase::create().await else {
+            return;
+        };
+        insert_selected_parent_chain(&mainnet.database, &[hash(1), hash(2)]).await;
+        insert_selected_parent_chain(&testnet.database, &[hash(3)]).await;
+
+        let hashes = [hash(1), hash(2), hash(3)];
+        assert_eq!(stored_hashes(&mainnet.database, &hashes).await, vec![hash(1), hash(2)]);
+        assert_eq!(stored_hashes(&testnet.database, &hashes).await, vec![hash(3)]);
+    }
+
+    #[tokio::test]
+    async fn ensure_schema_creates_the_configured_schema() {
+        let Some(test_database) = crate::database::testing::TestDatabase::create_empty().await else {
+            return;
+        };
+        let schema = format!("{}_network", test_database.schema);
+        let database = Database::connect(
+            &test_database.connection_string, None, &DbTlsConfig::default(), &DbTcpConfig::default(), None, None, &schema,
+        ).await.unwrap();
+
+        database.ensure_schema().await.unwrap();
+        database.ensure_schema().await.unwrap();
+
+        let client = test_database.client().await;
+        let row = client.query_one("SELECT EXISTS (SELECT 1 FROM pg_namespace WHERE nspname = $1)", &[&schema]).await.unwrap();
+        assert!(row.get::<_, bool>(0));
+        client.batch_execute(&format!("DROP SCHEMA {}", schema)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reads_survive_added_and_reordered_columns() {
+        let Some(test_database) = crate::database::testing::TestDatabase::create().await else {
+            return;
+        };
+        insert_selected_parent_chain(&test_database.database, &[hash(0), hash(1), hash(2)]).await;
+        let app_config = AppConfig {
+            id: true,
+            tondid_version: "1.0.0".to_string(),
+            processing_version: "2.0.0".to_string(),
+            network: "tondi-mainnet".to_string(),
+        };
+        let database_for_closure = test_database.database.clone();
+        test_database.database.run_in_transaction(move |tx| {
+            let database = database_for_closure.clone();
+            let app_config = app_config.clone();
+            Box::pin(async move { Ok(database.store_app_config(tx, &app_config).await?) })
+        }).await.unwrap();
+        // A later migration adding columns and recreating existing ones,
+        // which moves them last
+        test_database.client().await.batch_execute(
+            r#"
+            ALTER TABLE blocks ADD COLUMN note TEXT;
+            ALTER TABLE blocks RENAME COLUMN height TO old_height;
+            ALTER TABLE blocks ADD COLUMN height BIGINT;
+            UPDATE blocks SET height = old_height;
+            ALTER TABLE blocks DROP COLUMN old_height;
+            ALTER TABLE app_config ADD COLUMN note TEXT;
+            ALTER TABLE app_config RENAME COLUMN network TO old_network;
+            ALTER TABLE app_config ADD COLUMN network TEXT;
+            UPDATE app_config SET network = old_network;
+            ALTER TABLE app_config DROP COLUMN old_network;
+            "#,
+        ).await.unwrap();
+
+        let database = test_database.connect().await;
+        let database_for_closure = database.clone();
+        let (by_id, by_hash, chain_tip, app_config) = database.run_in_read_transaction(move |tx| {
+            let database = database_for_closure.clone();
+            Box::pin(async move {
+                let id = database.block_id_by_hash(tx, &hash(1)).await?;
+                Ok((
+                    database.get_block(tx, id).await?,
+                    database.block_by_hash(tx, &hash(1)).await?,
+                    database.highest_block_in_virtual_selected_parent_chain(tx).await?,
+                    database.get_app_config(tx).await?,
+                ))
+            })
+        }).await.unwrap();
+
+        assert_eq!((by_id.block_hash.as_str(), by_id.height), (hash(1).to_string().as_str(), 1));
+        let by_hash = by_hash.expect("the block is stored");
+        assert_eq!((by_hash.id, by_hash.height, by_hash.color.as_str()), (by_id.id, 1, COLOR_BLUE));
+        assert_eq!((chain_tip.block_hash, chain_tip.height), (hash(2).to_string(), 2));
+        assert_eq!(app_config.network, "tondi-mainnet");
+        assert_eq!(app_config.processing_version, "2.0.0");
+    }
+
+    #[tokio::test]
+    async fn commit_hook_reports_the_rows_each_committed_transaction_changed() {
+        let Some(test_database) = crate::database::testing::TestDatabase::create_empty().await else {
+            return;
+        };
+        test_database.client().await.batch_execute("CREATE TABLE rows (n INT)").await.unwrap();
+        let summaries = Arc::new(std::sync::Mutex::new(vec![]));
+        let summaries_for_hook = summaries.clone();
+        let database = test_database.database.clone()
+            .with_commit_hook(Arc::new(move |summary| summaries_for_hook.lock().unwrap().push(summary)));
+
+        database.run_in_transaction(|tx| Box::pin(async move {
+            tx.batch_execute("INSERT INTO rows VALUES (1), (2), (3); UPDATE rows SET n = n + 10 WHERE n < 3; DELETE FROM rows WHERE n = 3")
+                .await?;
+            Ok(())
+        })).await.unwrap();
+        let failed: anyhow::Result<()> = database.run_in_transaction(|tx| Box::pin(async move {
+            tx.batch_execute("INSERT INTO rows VALUES (4)").await?;
+            anyhow::bail!("rolled back")
+        })).await;
+        assert!(failed.is_err());
+
+        assert_eq!(
+            *summaries.lock().unwrap(),
+            vec![CommitSummary { rows_inserted: 3, rows_updated: 2, rows_deleted: 1 }],
+        );
+    }
+
+    #[tokio::test]
+    async fn heaviest_block_of_each_height_has_the_largest_merge_set() {
+        let Some(test_database) = crate::database::testing::TestDatabase::create().await else {
+            return;
+        };
+        let database_for_closure = test_database.database.clone();
+        let heaviest = test_database.database.run_in_transaction(move |tx| {
+            let database = database_for_closure.clone();
+            Box::pin(async move {
+                // (block, height, merge set reds, merge set blues)
+                let blocks: [(u64, u64, usize, usize); 7] = [
+                    (1, 1, 0, 1), (2, 1, 1, 2), (3, 1, 0, 3),
+                    (4, 2, 0, 0),
+                    (5, 3, 2, 1), (6, 3, 0, 2),
+                    (7, 5, 0, 9),
+                ];
+                for (n, height, reds, blues) in blocks {
+                    let block = Block {
+                        id: 0,
+                        block_hash: hash(n).to_string(),
+                        timestamp: 0,
+                        parent_ids: vec![],
+                        daa_score: height,
+                        height,
+                        height_group_index: 0,
+                        selected_parent_id: None,
+                        color: COLOR_BLUE.to_string(),
+                        is_in_virtual_selected_parent_chain: false,
+                        merge_set_red_ids: vec![0; reds],
+                        merge_set_blue_ids: vec![0; blues],
+                        is_header_only: false,
+                        miner: None,
+                    };
+                    database.insert_block(tx, &hash(n), &block).await?;
+                }
+                Ok(database.heaviest_block_per_height(tx, 1, 3).await?)
+            })
+        }).await.unwrap();
+
+        // Blocks 2 and 3 tie at height 1, where the one stored first wins
+        let hashes: Vec<String> = heaviest.into_iter().map(|block| block.block_hash).collect();
+        assert_eq!(hashes, vec![hash(2).to_string(), hash(4).to_string(), hash(5).to_string()]);
+    }
+
+    #[tokio::test]
+    async fn height_groups_are_read_one_at_a_time_or_by_range() {
+        let Some(test_database) = crate::database::testing::TestDatabase::create().await else {
+            return;
+        };
+        let database_for_closure = test_database.database.clone();
+        let (single, absent, range) = test_database.database.run_in_transaction(move |tx| {
+            let database = database_for_closure.clone();
+            Box::pin(async move {
+                for (height, size) in [(1, 1), (2, 4), (4, 2), (7, 3)] {
+                    database.insert_or_update_height_group(tx, &HeightGroup { height, size }).await?;
+                }
+                Ok((
+                    database.height_group(tx, 4).await?,
+                    database.height_group(tx, 3).await?,
+                    database.height_groups_between_heights(tx, 2, 4).await?,
+                ))
+            })
+        }).await.unwrap();
+
+        assert_eq!(single.map(|group| (group.height, group.size)), Some((4, 2)));
+        assert!(absent.is_none());
+        let sizes: Vec<(u64, u32)> = range.iter().map(|group| (group.height, group.size)).collect();
+        assert_eq!(sizes, vec![(2, 4), (4, 2)]);
+    }
+
+    #[tokio::test]
+    async fn height_groups_of_a_batch_are_upserted_at_once() {
+        let Some(test_database) = crate::database::testing::TestDatabase::create().await else {
+            return;
+        };
+        let database_for_closure = test_database.database.clone();
+        let groups = test_database.database.run_in_transaction(move |tx| {
+            let database = database_for_closure.clone();
+            Box::pin(async move {
+                database.bulk_upsert_height_groups(tx, &[HeightGroup { height: 3, size: 5 }]).await?;
+                database.bulk_upsert_height_groups(tx, &[
+                    HeightGroup { height: 1, size: 1 },
+                    HeightGroup { height: 2, size: 2 },
+                    HeightGroup { height: 2, size: 3 },
+                    HeightGroup { height: 3, size: 6 },
+                    HeightGroup { height: 5, size: 1 },
+                ]).await?;
+                database.bulk_upsert_height_groups(tx, &[]).await?;
+                Ok(database.height_groups_between_heights(tx, 0, 10).await?)
+            })
+        }).await.unwrap();
+
+        let sizes: Vec<(u64, u32)> = groups.iter().map(|group| (group.height, group.size)).collect();
+        assert_eq!(sizes, vec![(1, 1), (2, 3), (3, 6), (5, 1)]);
+    }
+
+    #[tokio::test]
+    async fn one_lookup_caches_both_the_id_and_the_height() {
+        let Some(test_database) = crate::database::testing::TestDatabase::create().await else {
+            return;
+        };
+        let database = &test_database.database;
+        insert_selected_parent_chain(database, &[hash(0), hash(1)]).await;
+        database.block_base_cache.lock().await.clear();
+
+        let mut tx_client = test_database.client().await;
+        let tx = tx_client.transaction().await.unwrap();
+        let (id, height) = database.block_base_by_hash(&tx, &hash(1)).await.unwrap();
+        assert_eq!(height, 1);
+        // Any further query would see the new row
+        test_database.client().await
+            .execute("UPDATE blocks SET id = id + 100, height = 99 WHERE block_hash = $1", &[&hash(1).to_string()])
+            .await.unwrap();
+        assert_eq!(database.block_id_by_hash(&tx, &hash(1)).await.unwrap(), id);
+        assert_eq!(database.block_height_by_hash(&tx, &hash(1)).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn conflicting_edge_insert_refreshes_the_heights() {
+        let Some(test_database) = crate::database::testing::TestDatabase::create().await else {
+            return;
+        };
+        let database = &test_database.database;
+        insert_selected_parent_chain(database, &[hash(0), hash(1)]).await;
+        let database_for_closure = database.clone();
+        database.run_in_transaction(move |tx| {
+            let database = database_for_closure.clone();
+            Box::pin(async move {
+                let (parent_id, child_id) = (database.block_id_by_hash(tx, &hash(0)).await?, database.block_id_by_hash(tx, &hash(1)).await?);
+                let mut edge = Edge {
+                    from_block_id: child_id,
+                    to_block_id: parent_id,
+                    from_height: 1,
+                    to_height: 0,
+                    from_height_group_index: 0,
+                    to_height_group_index: 0,
+                };
+                database.insert_edge(tx, &edge).await?;
+                // The child is reprocessed higher, e.g. once another parent
+                // arrived
+                edge.from_height = 3;
+                edge.from_height_group_index = 2;
+                database.insert_edge(tx, &edge).await?;
+                Ok(())
+            })
+        }).await.unwrap();
+
+        let client = test_database.client().await;
+        let rows = client.query("SELECT from_height, to_height, from_height_group_index, to_height_group_index FROM edges", &[])
+            .await.unwrap();
+        let edges: Vec<(i64, i64, i32, i32)> = rows.iter().map(|row| (row.get(0), row.get(1), row.get(2), row.get(3))).collect();
+        assert_eq!(edges, vec![(3, 0, 2, 0)]);
+    }
+
+    #[tokio::test]
+    async fn selected_parent_path_detects_cycles() {
+        let Some(test_database) = crate::database::testing::TestDatabase::create().await else {
+            return;
+        };
+        let database = &test_database.database;
+        let hashes: Vec<BlockHash> = (0..4).map(hash).collect();
+        insert_selected_parent_chain(database, &hashes).await;
+
+        let mut tx_client = test_database.client().await;
+        let tx = tx_client.transaction().await.unwrap();
+
+        // Block 0 pointing back at block 2 closes a cycle the walk would
+        // otherwise follow until max_len
+        tx.execute(
+            "UPDATE blocks SET selected_parent_id = (SELECT id FROM blocks WHERE block_hash = $1) WHERE block_hash = $2",
+            &[&hashes[2], &hashes[0]],
+        ).await.unwrap();
+        let unknown = hash(u64::MAX);
+        match database.selected_parent_path(&tx, &hashes[3], &unknown, 1000).await {
+            Err(TgiError::ChainWalkLimit { from, limit }) => {
+                assert_eq!(from, hashes[3].to_string());
+                assert_eq!(limit, 1000);
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn latest_migration_version_matches_the_migrations() {
+        let versions: Vec<i64> = crate::database::testing::TestDatabase::migrations().into_iter()
+            .map(|(version, _)| version).collect();
+        assert_eq!(versions.last().copied(), Some(LATEST_MIGRATION_VERSION));
+    }
+
+    #[tokio::test]
+    async fn fresh_database_lacks_every_table() {
+        let Some(test_database) = crate::database::testing::TestDatabase::create_empty().await else {
+            return;
+        };
+        match test_database.database.check_schema().await {
+            Err(TgiError::MissingTables { schema, tables }) => {
+                assert_eq!(schema, test_database.schema);
+                let mut expected: Vec<String> = REQUIRED_TABLES.iter().map(|table| table.to_string()).collect();
+                expected.sort();
+                assert_eq!(tables, expected);
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn migrated_database_passes_the_schema_check() {
+        let Some(test_database) = crate::database::testing::TestDatabase::create().await else {
+            return;
+        };
+        test_database.database.check_schema().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn outdated_or_dirty_migrations_fail_the_schema_check() {
+        let Some(test_database) = crate::database::testing::TestDatabase::create().await else {
+            return;
+        };
+        let client = test_database.client().await;
+        for (statement, applied) in [
+            ("UPDATE schema_migrations SET version = version - 1", format!("migration {}", LATEST_MIGRATION_VERSION - 1)),
+            ("UPDATE schema_migrations SET version = version + 1, dirty = true", format!("migration {} (dirty)", LATEST_MIGRATION_VERSION)),
+            ("DELETE FROM schema_migrations", "no recorded migration".to_string()),
+            ("DROP TABLE schema_migrations", "no recorded migration".to_string()),
+        ] {
+            client.batch_execute(statement).await.unwrap();
+            match test_database.database.check_schema().await {
+                Err(TgiError::OutdatedSchema { applied: found, latest, .. }) => {
+                    assert_eq!(found, applied, "{}", statement);
+                    assert_eq!(latest, LATEST_MIGRATION_VERSION);
+                }
+                result => panic!("unexpected result {:?} after {}", result, statement),
+            }
+        }
+    }
+
+    const COMPACT_BLOCK_HASHES_UP: &str = include_str!("../../database/optional/compact_block_hashes.up.sql");
+    const COMPACT_BLOCK_HASHES_DOWN: &str = include_str!("../../database/optional/compact_block_hashes.down.sql");
+
+    fn chain_block(block_hash: BlockHash, height: u64, selected_parent_id: Option<u64>) -> Block {
+        Block {
+            id: 0,
+            block_hash: block_hash.to_string(),
+            timestamp: 0,
+            parent_ids: selected_parent_id.into_iter().collect(),
+            daa_score: height,
+            height,
+            height_group_index: 0,
+            selected_parent_id,
+            color: COLOR_BLUE.to_string(),
+            is_in_virtual_selected_parent_chain: true,
+            merge_set_red_ids: vec![],
+            merge_set_blue_ids: vec![],
+            is_header_only: false,
+            miner: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn compact_hashes_are_stored_as_bytes_and_read_as_hex() {
+        let Some(test_database) = crate::database::testing::TestDatabase::create().await else {
+            return;
+        };
+        let client = test_database.client().await;
+        client.batch_execute(COMPACT_BLOCK_HASHES_UP).await.unwrap();
+        let database = test_database.connect().await;
+        assert_eq!(database.hash_storage, HashStorage::Compact);
+        database.check_schema().await.unwrap();
+
+        let database_for_closure = database.clone();
+        let (stored, missing, hashes_by_ids, path) = database.run_in_transaction(move |tx| {
+            let database = database_for_closure.clone();
+            Box::pin(async move {
+                database.insert_block(tx, &hash(0), &chain_block(hash(0), 0, None)).await?;
+                let genesis_id = database.block_id_by_hash(tx, &hash(0)).await?;
+                let ids = database.bulk_insert_blocks(tx, &[chain_block(hash(1), 1, Some(genesis_id))]).await?;
+                let stored = database.block_by_hash(tx, &hash(1)).await?;
+                let missing = database.missing_hashes(tx, &[hash(0), hash(2), hash(1)]).await?;
+                let hashes_by_ids = database.block_hashes_by_ids(tx, &[genesis_id, ids[0]]).await?;
+                let path = database.selected_parent_path(tx, &hash(1), &hash(0), 10).await?;
+                Ok((stored, missing, hashes_by_ids, path))
+            })
+        }).await.unwrap();
+
+        assert_eq!(stored.map(|block| block.block_hash), Some(hash(1).to_string()));
+        assert_eq!(missing, vec![hash(2)]);
+        assert_eq!(hashes_by_ids.into_values().collect::<HashSet<_>>(), HashSet::from([hash(0), hash(1)]));
+        assert_eq!(path, Some(vec![hash(1), hash(0)]));
+        let stored_length: i32 = client.query_one("SELECT max(length(block_hash)) FROM blocks", &[]).await.unwrap().get(0);
+        assert_eq!(stored_length, 32);
+    }
+
+    fn edge(from_block_id: u64, from_height: u64, to_block_id: u64, to_height: u64) -> Edge {
+        Edge { from_block_id, to_block_id, from_height, to_height, from_height_group_index: 0, to_height_group_index: 0 }
+    }
+
+    #[tokio::test]
+    async fn edges_between_heights_are_those_with_a_block_in_range() {
+        let Some(test_database) = crate::database::testing::TestDatabase::create().await else {
+            return;
+        };
+        let below = edge(2, 2, 1, 1);
+        let leaving_below = edge(3, 3, 2, 2);
+        let inside = edge(4, 4, 3, 3);
+        let leaving_above = edge(6, 6, 4, 4);
+        let spanning = edge(7, 7, 1, 1);
+        let above = edge(7, 7, 6, 6);
+        let database = test_database.database.clone();
+        let edges = test_database.database.run_in_transaction(move |tx| {
+            let database = database.clone();
+            Box::pin(async move {
+                for edge in [&below, &leaving_below, &inside, &leaving_above, &spanning, &above] {
+                    database.insert_edge(tx, edge).await?;
+                }
+                Ok(database.edges_between_heights(tx, 3, 5, 0, 100).await?)
+            })
+        }).await.unwrap();
+
+        let edges: Vec<(u64, u64)> = edges.iter().map(|edge| (edge.from_block_id, edge.to_block_id)).collect();
+        assert_eq!(edges, vec![(3, 2), (4, 3), (6, 4)]);
+    }
+
+    #[tokio::test]
+    async fn changed_hash_storage_fails_the_schema_check() {
+        let Some(test_database) = crate::database::testing::TestDatabase::create().await else {
+            return;
+        };
+        let client = test_database.client().await;
+        client.batch_execute(COMPACT_BLOCK_HASHES_UP).await.unwrap();
+        match test_database.database.check_schema().await {
+            Err(TgiError::HashStorageChanged { connected: "hex", found: "compact", .. }) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        let compact_database = test_database.connect().await;
+        client.batch_execute(COMPACT_BLOCK_HASHES_DOWN).await.unwrap();
+        match compact_database.check_schema().await {
+            Err(TgiError::HashStorageChanged { connected: "compact", found: "hex", .. }) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+        test_database.database.check_schema().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn unsupported_hash_column_fails_to_connect() {
+        let Some(test_database) = crate::database::testing::TestDatabase::create_empty().await else {
+            return;
+        };
+        test_database.client().await.batch_execute("CREATE TABLE blocks (block_hash BIGINT)").await.unwrap();
+        match Database::connect(
+            &test_database.connection_string, None, &DbTlsConfig::default(), &DbTcpConfig::default(), None, None,
+            &test_database.schema,
+        ).await {
+            Err(TgiError::UnsupportedHashStorage { schema, data_type }) => {
+                assert_eq!(schema, test_database.schema);
+                assert_eq!(data_type, "bigint");
+            }
+            result => panic!("unexpected result {:?}", result.map(|_| ())),
+        }
+    }
+
+    fn colored_block(n: u64, color: &str) -> Block {
+        Block {
+            id: 0,
+            block_hash: format!("{:064x}", n),
+            timestamp: n as i64,
+            parent_ids: vec![],
+            daa_score: n,
+            height: n,
+            height_group_index: 0,
+            selected_parent_id: None,
+            color: color.to_string(),
+            is_in_virtual_selected_parent_chain: false,
+            merge_set_red_ids: vec![],
+            merge_set_blue_ids: vec![],
+            is_header_only: false,
+            miner: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn only_blocks_turning_red_are_counted() {
+        let Some(test_database) = crate::database::testing::TestDatabase::create().await else {
+            return;
+        };
+        let database_for_closure = test_database.database.clone();
+        let (turned_red, colors) = test_database.database.run_in_transaction(move |tx| {
+            let database = database_for_closure.clone();
+            Box::pin(async move {
+                let blocks = [
+                    colored_block(1, COLOR_GRAY),
+                    colored_block(2, COLOR_BLUE),
+                    colored_block(3, COLOR_RED),
+                    colored_block(4, COLOR_GRAY),
+                ];
+                let ids = database.bulk_insert_blocks(tx, &blocks).await?;
+                let new_colors = [COLOR_RED, COLOR_RED, COLOR_RED, COLOR_BLUE];
+                let updates: Vec<_> = ids.iter().zip(new_colors).map(|(&block_id, color)| BlockColorUpdate {
+                    block_id,
+                    color: color.to_string(),
+                    colored_by_block_id: None,
+                }).collect();
+                let turned_red = database.update_block_colors(tx, &updates).await?;
+                let rows = tx.query("SELECT color FROM blocks ORDER BY height", &[]).await?;
+                Ok((turned_red, rows.iter().map(|row| row.get::<_, String>(0)).collect::<Vec<_>>()))
+            })
+        }).await.unwrap();
+
+        // The gray and the blue block turned red, the red one stayed red
+        assert_eq!(turned_red, 2);
+        assert_eq!(colors, vec![COLOR_RED, COLOR_RED, COLOR_RED, COLOR_BLUE]);
+    }
+}
@@ -0,0 +1,106 @@
+//! PostgreSQL databases for the tests exercising the database layer.
+//!
+//! Requires a PostgreSQL database whose key/value connection string is given in
+//! `TGI_TEST_CONNECTION_STRING`; the tests needing it are skipped when it is
+//! unset. Every test database is a fresh schema migrated like
+//! `database/migration.go` does, so tests don't see each other's rows. The
+//! schemas are left behind, so never point this at a database you care
+//! about.
+
+use crate::database::{Database, DbTcpConfig, DbTlsConfig};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio_postgres::{Client, NoTls};
+
+const CONNECTION_STRING_ENV: &str = "TGI_TEST_CONNECTION_STRING";
+
+static NEXT_SCHEMA: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) struct TestDatabase {
+    pub database: Database,
+    pub connection_string: String,
+    pub schema: String,
+}
+
+impl TestDatabase {
+    /// Creates and migrates a fresh schema, or returns `None` when no test
+    /// database is configured.
+    pub async fn create() -> Option<Self> {
+        let test_database = Self::create_empty().await?;
+        let client = test_database.client().await;
+        let mut versions = vec![];
+        for (version, migration) in Self::migrations() {
+            client.batch_execute(&migration).await.expect("failed to migrate the test database");
+            versions.push(version);
+        }
+        // Recorded like golang-migrate does
+        client.batch_execute(
+            "CREATE TABLE schema_migrations (version BIGINT PRIMARY KEY, dirty BOOLEAN NOT NULL)"
+        ).await.expect("failed to create schema_migrations");
+        client.execute(
+            "INSERT INTO schema_migrations (version, dirty) VALUES ($1, false)",
+            &[&versions.last().copied().unwrap_or_default()],
+        ).await.expect("failed to record the migration version");
+        Some(test_database)
+    }
+
+    /// Creates a fresh schema without running the migrations, or returns
+    /// `None` when no test database is configured.
+    pub async fn create_empty() -> Option<Self> {
+        let Ok(connection_string) = std::env::var(CONNECTION_STRING_ENV) else {
+            eprintln!("{} is not set; skipping the database test", CONNECTION_STRING_ENV);
+            return None;
+        };
+        let schema = format!(
+            "tgi_test_{}_{}",
+            std::process::id(),
+            NEXT_SCHEMA.fetch_add(1, Ordering::Relaxed),
+        );
+        let (client, connection) = tokio_postgres::connect(&connection_string, NoTls).await
+            .expect("failed to connect to the test database");
+        tokio::spawn(connection);
+        client.batch_execute(&format!("DROP SCHEMA IF EXISTS {schema} CASCADE; CREATE SCHEMA {schema}")).await
+            .expect("failed to create the test schema");
+        let database = Database::connect(
+            &connection_string, None, &DbTlsConfig::default(), &DbTcpConfig::default(), None, None, &schema,
+        ).await.expect("failed to connect to the test schema");
+        Some(Self { database, connection_string, schema })
+    }
+
+    /// Another `Database` on the test schema, which picks up changes made to
+    /// it through `client` since `database` connected.
+    pub async fn connect(&self) -> Database {
+        Database::connect(
+            &self.connection_string, None, &DbTlsConfig::default(), &DbTcpConfig::default(), None, None, &self.schema,
+        ).await.expect("failed to connect to the test schema")
+    }
+
+    /// A separate connection to the test schema, e.g. to inspect rows or
+    /// inject failures behind the back of `database`.
+    pub async fn client(&self) -> Client {
+        let (client, connection) = tokio_postgres::connect(&self.connection_string, NoTls).await
+            .expect("failed to connect to the test database");
+        tokio::spawn(connection);
+        client.batch_execute(&format!("SET search_path TO {}", self.schema)).await
+            .expect("failed to set the search path");
+        client
+    }
+
+    /// The `.up.sql` migrations with their versions, in order.
+    pub fn migrations() -> Vec<(i64, String)> {
+        let directory = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("database/migrations");
+        let mut paths: Vec<PathBuf> = std::fs::read_dir(&directory).expect("failed to list the migrations")
+            .map(|entry| entry.expect("failed to list the migrations").path())
+            .filter(|path| path.to_string_lossy().ends_with(".up.sql"))
+            .collect();
+        paths.sort();
+        paths.into_iter()
+            .map(|path| {
+                let file_name = path.file_name().unwrap().to_string_lossy().into_owned();
+                let version = file_name.split('_').next().and_then(|version| version.parse().ok())
+                    .expect("migration file names start with their version");
+                (version, std::fs::read_to_string(&path).expect("failed to read a migration"))
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,66 @@
+use crate::error::{Result, TgiError};
+use native_tls::{Certificate, TlsConnector};
+use postgres_native_tls::MakeTlsConnector;
+use std::fs;
+
+/// TLS settings for the PostgreSQL connection.
+///
+/// TLS is used when `enabled` is set or when the connection string asks for it
+/// via `sslmode=require`, `sslmode=verify-ca` or `sslmode=verify-full`.
+#[derive(Debug, Clone, Default)]
+pub struct DbTlsConfig {
+    pub enabled: bool,
+    /// PEM encoded CA certificate used to verify the server certificate
+    pub ca_cert_path: Option<String>,
+}
+
+pub(super) enum DbTlsMode {
+    Disabled,
+    /// Encrypt the connection without verifying the server certificate
+    Unverified,
+    /// Encrypt the connection and verify the server certificate
+    Verified,
+}
+
+impl DbTlsConfig {
+    /// Resolves the TLS mode and returns a connection string `tokio-postgres`
+    /// can parse, since it only understands `disable`, `prefer` and `require`.
+    pub(super) fn resolve(&self, connection_string: &str) -> (DbTlsMode, String) {
+        let ssl_mode = ssl_mode(connection_string);
+        let mode = match ssl_mode {
+            Some("verify-ca") | Some("verify-full") => DbTlsMode::Verified,
+            _ if self.ca_cert_path.is_some() && (self.enabled || ssl_mode == Some("require")) => DbTlsMode::Verified,
+            Some("require") => DbTlsMode::Unverified,
+            _ if self.enabled => DbTlsMode::Unverified,
+            _ => DbTlsMode::Disabled,
+        };
+        let connection_string = connection_string
+            .replace("sslmode=verify-ca", "sslmode=require")
+            .replace("sslmode=verify-full", "sslmode=require");
+        (mode, connection_string)
+    }
+
+    pub(super) fn connector(&self, mode: &DbTlsMode) -> Result<MakeTlsConnector> {
+        let mut builder = TlsConnector::builder();
+        if let Some(path) = &self.ca_cert_path {
+            let pem = fs::read(path)
+                .map_err(|e| TgiError::Tls(format!("Failed to read CA certificate {}: {}", path, e)))?;
+            let certificate = Certificate::from_pem(&pem)
+                .map_err(|e| TgiError::Tls(format!("Invalid CA certificate {}: {}", path, e)))?;
+            builder.add_root_certificate(certificate);
+        }
+        if let DbTlsMode::Unverified = mode {
+            builder.danger_accept_invalid_certs(true);
+        }
+        let connector = builder.build()
+            .map_err(|e| TgiError::Tls(format!("Failed to build TLS connector: {}", e)))?;
+        Ok(MakeTlsConnector::new(connector))
+    }
+}
+
+fn ssl_mode(connection_string: &str) -> Option<&str> {
+    let start = connection_string.find("sslmode=")? + "sslmode=".len();
+    let value = &connection_string[start..];
+    let end = value.find(|c: char| c == '&' || c.is_whitespace()).unwrap_or(value.len());
+    Some(&value[..end])
+}
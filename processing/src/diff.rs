@@ -0,0 +1,120 @@
+//! Field by field comparison of a stored block with the node view of it,
+//! for investigating divergence reports.
+
+use crate::database::{Database, COLOR_BLUE, COLOR_GRAY, COLOR_RED};
+use crate::rpc_client::{BlockHash, RpcClient};
+use anyhow::{Context, Result};
+use std::collections::{HashSet, VecDeque};
+use tondi_rpc_core::model::RpcBlock;
+use tracing::info;
+
+/// Descendants fetched from the node while looking for the chain block
+/// merging the compared block
+const MERGING_BLOCK_SEARCH_LIMIT: usize = 50;
+
+fn print_field(name: &str, stored: &str, node: &str) -> bool {
+    let matches = stored == node;
+    let marker = if matches { "  " } else { "!!" };
+    println!("{} {:<22} stored: {}", marker, name, stored);
+    println!("{} {:<22} node:   {}", marker, "", node);
+    matches
+}
+
+fn sorted_hashes(hashes: impl IntoIterator<Item = String>) -> String {
+    let mut hashes: Vec<String> = hashes.into_iter().collect();
+    hashes.sort();
+    format!("[{}]", hashes.join(", "))
+}
+
+/// Derives the color of a block the way TGI does, from the merge set of the
+/// chain block merging it, found by walking its descendants on the node.
+/// Gray while no chain block merged it yet.
+async fn node_color(rpc_client: &RpcClient, block: &RpcBlock) -> Result<String> {
+    let block_hash = block.header.hash;
+    let mut queue: VecDeque<_> = block.verbose_data.iter().flat_map(|vd| vd.children_hashes.iter().copied()).collect();
+    let mut visited = HashSet::new();
+    while let Some(hash) = queue.pop_front() {
+        if visited.len() >= MERGING_BLOCK_SEARCH_LIMIT {
+            break;
+        }
+        if !visited.insert(hash) {
+            continue;
+        }
+        let descendant = rpc_client.get_block(hash, false).await?.block;
+        let Some(verbose_data) = descendant.verbose_data else { continue };
+        if verbose_data.is_chain_block {
+            if verbose_data.merge_set_blues_hashes.contains(&block_hash) {
+                return Ok(COLOR_BLUE.to_string());
+            }
+            if verbose_data.merge_set_reds_hashes.contains(&block_hash) {
+                return Ok(COLOR_RED.to_string());
+            }
+        }
+        queue.extend(verbose_data.children_hashes.iter().copied());
+    }
+    Ok(COLOR_GRAY.to_string())
+}
+
+/// Prints the stored and node values of every compared field, flagging
+/// mismatches with `!!`. Returns whether everything matched.
+pub async fn diff_block(database: &Database, rpc_client: &RpcClient, block_hash: &str) -> Result<bool> {
+    let block_hash = BlockHash::parse(block_hash)?;
+    info!("Comparing block {} with the node", block_hash);
+
+    let database_for_closure = database.clone();
+    let hash_for_closure = block_hash;
+    let stored = database.run_in_read_transaction(move |tx| {
+        let database = database_for_closure.clone();
+        Box::pin(async move {
+            let Some(block) = database.block_by_hash(tx, &hash_for_closure).await? else {
+                return Ok(None);
+            };
+            let mut ids = block.parent_ids.clone();
+            ids.extend(block.selected_parent_id);
+            let hashes = database.block_hashes_by_ids(tx, &ids).await?;
+            Ok(Some((block, hashes)))
+        })
+    }).await?;
+    let Some((stored_block, stored_hashes)) = stored else {
+        anyhow::bail!("Block {} is not stored in the database", block_hash);
+    };
+
+    let node_block = rpc_client.get_block(block_hash, false).await
+        .with_context(|| format!("Could not fetch block {} from the node", block_hash))?.block;
+    let verbose_data = node_block.verbose_data.as_ref()
+        .with_context(|| format!("The node returned block {} without verbose data", block_hash))?;
+
+    let stored_hash = |id: &u64| stored_hashes.get(id).map(|hash| hash.to_string()).unwrap_or_else(|| format!("<missing id {}>", id));
+    let mut all_match = true;
+    println!("Block {}", block_hash);
+    all_match &= print_field(
+        "parents",
+        &sorted_hashes(stored_block.parent_ids.iter().map(stored_hash)),
+        &sorted_hashes(node_block.header.direct_parents().iter().map(|hash| hash.to_string())),
+    );
+    all_match &= print_field(
+        "selected parent",
+        &stored_block.selected_parent_id.as_ref().map(stored_hash).unwrap_or_else(|| "none".to_string()),
+        &verbose_data.selected_parent_hash.to_string(),
+    );
+    all_match &= print_field("color", &stored_block.color, &node_color(rpc_client, &node_block).await?);
+    all_match &= print_field(
+        "chain membership",
+        &stored_block.is_in_virtual_selected_parent_chain.to_string(),
+        &verbose_data.is_chain_block.to_string(),
+    );
+    all_match &= print_field(
+        "header only",
+        &stored_block.is_header_only.to_string(),
+        &verbose_data.is_header_only.to_string(),
+    );
+    all_match &= print_field("daa score", &stored_block.daa_score.to_string(), &node_block.header.daa_score.to_string());
+    all_match &= print_field("timestamp", &stored_block.timestamp.to_string(), &node_block.header.timestamp.to_string());
+
+    if all_match {
+        println!("No mismatch found");
+    } else {
+        println!("Mismatching fields are flagged with !!");
+    }
+    Ok(all_match)
+}
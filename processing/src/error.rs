@@ -0,0 +1,206 @@
+use thiserror::Error;
+use tokio_postgres::error::SqlState;
+
+pub type Result<T> = std::result::Result<T, TgiError>;
+
+/// Errors surfaced by the RPC and database layers.
+///
+/// The processing tier converts these into `anyhow::Error` at its boundary,
+/// but callers that need to branch on the failure kind (e.g. retry logic)
+/// can match on the variants directly.
+#[derive(Debug, Error)]
+pub enum TgiError {
+    #[error("Block {0} not found")]
+    BlockNotFound(String),
+
+    #[error("Invalid hash format {hash}: {message}")]
+    InvalidHash { hash: String, message: String },
+
+    #[error("Invalid hash format for {} hashes: {}", .0.len(), .0.join(", "))]
+    InvalidHashes(Vec<String>),
+
+    #[error("Failed to connect to Tondi RPC server: {0}")]
+    RpcConnect(String),
+
+    #[error("Invalid RPC server address {address:?}: {reason} (expected host:port, optionally prefixed with grpc://)")]
+    InvalidRpcAddress { address: String, reason: String },
+
+    #[error("{method} RPC call failed: {message}")]
+    Rpc { method: &'static str, message: String },
+
+    #[error("Connection to the Tondi RPC server lost: {0}")]
+    ConnectionLost(String),
+
+    #[error("Walking at most {limit} blocks down the selected parent chain from {from} came back to a visited block; possible cycle detected")]
+    ChainWalkLimit { from: String, limit: u64 },
+
+    #[error("Waited {0:?} for the database connection held by other transactions (see --db-acquire-timeout)")]
+    AcquireTimeout(std::time::Duration),
+
+    #[error("Database schema {schema} lacks the tables {}; apply the migrations of database/migrations to it before starting TGI", .tables.join(", "))]
+    MissingTables { schema: String, tables: Vec<String> },
+
+    #[error("Database schema {schema} has {applied} applied, but TGI needs migration {latest}; apply the migrations of database/migrations to it before starting TGI")]
+    OutdatedSchema { schema: String, applied: String, latest: i64 },
+
+    #[error("Database schema {schema} stores block hashes as {data_type}; TGI supports CHAR(64) as created by the migrations, and BYTEA as created by database/optional/compact_block_hashes.up.sql")]
+    UnsupportedHashStorage { schema: String, data_type: String },
+
+    #[error("Database schema {schema} stores block hashes as {found} since TGI connected to it, when they were {connected}; restart TGI after applying or reverting database/optional/compact_block_hashes")]
+    HashStorageChanged { schema: String, connected: &'static str, found: &'static str },
+
+    #[error("Database connection lost: {0}")]
+    DatabaseConnectionLost(tokio_postgres::Error),
+
+    #[error("Database connection lost while committing; the transaction may or may not have been committed: {0}")]
+    CommitOutcomeUnknown(tokio_postgres::Error),
+
+    #[error("Database TLS error: {0}")]
+    Tls(String),
+
+    #[error("Database statement timed out (see --db-statement-timeout): {0}")]
+    StatementTimeout(tokio_postgres::Error),
+
+    #[error("Database transaction could not be serialized with concurrent ones (see --db-isolation-level): {0}")]
+    SerializationFailure(tokio_postgres::Error),
+
+    #[error("Database constraint violated: {0}")]
+    DbConstraint(tokio_postgres::Error),
+
+    #[error("Database error: {0}")]
+    Database(tokio_postgres::Error),
+
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+}
+
+impl TgiError {
+    /// Builds an error from a failed RPC call, classifying the well-known
+    /// node responses so callers don't have to parse messages themselves.
+    pub fn rpc(method: &'static str, error: impl std::fmt::Display) -> Self {
+        let message = error.to_string();
+        let lowercase = message.to_lowercase();
+        if lowercase.contains("not connected") || lowercase.contains("disconnected") {
+            TgiError::ConnectionLost(message)
+        } else {
+            TgiError::Rpc { method, message }
+        }
+    }
+
+    /// Builds an error from a failed `GetBlock` call for `hash`.
+    pub fn get_block(hash: &str, error: impl std::fmt::Display) -> Self {
+        Self::block_lookup("GetBlock", hash, error)
+    }
+
+    /// Builds an error from a failed `GetBlocks` call starting at `low_hash`.
+    pub fn get_blocks(low_hash: &str, error: impl std::fmt::Display) -> Self {
+        Self::block_lookup("GetBlocks", low_hash, error)
+    }
+
+    /// Classifies the failure of a call looking `hash` up, telling a block
+    /// unknown to the node from other failures.
+    fn block_lookup(method: &'static str, hash: &str, error: impl std::fmt::Display) -> Self {
+        let error = Self::rpc(method, error);
+        match &error {
+            TgiError::Rpc { message, .. } if message.to_lowercase().contains("not found") => {
+                TgiError::BlockNotFound(hash.to_string())
+            }
+            _ => error,
+        }
+    }
+
+    pub fn invalid_hash(hash: &str, error: impl std::fmt::Display) -> Self {
+        TgiError::InvalidHash {
+            hash: hash.to_string(),
+            message: error.to_string(),
+        }
+    }
+
+    /// Short name of the failure kind, used as a metrics label.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TgiError::BlockNotFound(_) => "block_not_found",
+            TgiError::InvalidHash { .. } | TgiError::InvalidHashes(_) => "invalid_hash",
+            TgiError::RpcConnect(_) => "rpc_connect",
+            TgiError::InvalidRpcAddress { .. } => "invalid_rpc_address",
+            TgiError::Rpc { .. } => "rpc",
+            TgiError::ConnectionLost(_) => "connection_lost",
+            TgiError::ChainWalkLimit { .. } => "chain_walk_limit",
+            TgiError::AcquireTimeout(_) => "acquire_timeout",
+            TgiError::MissingTables { .. } => "missing_tables",
+            TgiError::OutdatedSchema { .. } => "outdated_schema",
+            TgiError::UnsupportedHashStorage { .. } => "unsupported_hash_storage",
+            TgiError::HashStorageChanged { .. } => "hash_storage_changed",
+            TgiError::DatabaseConnectionLost(_) => "database_connection_lost",
+            TgiError::CommitOutcomeUnknown(_) => "commit_outcome_unknown",
+            TgiError::Tls(_) => "tls",
+            TgiError::StatementTimeout(_) => "statement_timeout",
+            TgiError::SerializationFailure(_) => "serialization_failure",
+            TgiError::DbConstraint(_) => "db_constraint",
+            TgiError::Database(_) => "database",
+            TgiError::Serialization(_) => "serialization",
+        }
+    }
+
+    /// Whether retrying the failed operation may succeed.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            TgiError::ConnectionLost(_)
+                | TgiError::DatabaseConnectionLost(_)
+                | TgiError::RpcConnect(_)
+                | TgiError::SerializationFailure(_)
+        )
+    }
+}
+
+/// Whether `error` means the database connection is gone: it was closed, or
+/// the server is terminating it, e.g. on shutdown.
+pub fn is_connection_loss(error: &tokio_postgres::Error) -> bool {
+    error.is_closed()
+        || matches!(error.code(), Some(code) if *code == SqlState::ADMIN_SHUTDOWN || *code == SqlState::CRASH_SHUTDOWN)
+}
+
+impl From<tokio_postgres::Error> for TgiError {
+    fn from(error: tokio_postgres::Error) -> Self {
+        if is_connection_loss(&error) {
+            return TgiError::DatabaseConnectionLost(error);
+        }
+        match error.code() {
+            Some(code)
+                if *code == SqlState::UNIQUE_VIOLATION
+                    || *code == SqlState::FOREIGN_KEY_VIOLATION
+                    || *code == SqlState::CHECK_VIOLATION
+                    || *code == SqlState::NOT_NULL_VIOLATION =>
+            {
+                TgiError::DbConstraint(error)
+            }
+            Some(code) if *code == SqlState::QUERY_CANCELED => TgiError::StatementTimeout(error),
+            Some(code) if *code == SqlState::T_R_SERIALIZATION_FAILURE => TgiError::SerializationFailure(error),
+            _ => TgiError::Database(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_blocks_are_classified_as_not_found() {
+        let hash = "00".repeat(32);
+        for message in [format!("Block {} not found", hash), "block not found".to_string(), "Not Found".to_string()] {
+            assert!(matches!(TgiError::get_block(&hash, &message), TgiError::BlockNotFound(h) if h == hash), "{}", message);
+            assert!(matches!(TgiError::get_blocks(&hash, &message), TgiError::BlockNotFound(h) if h == hash), "{}", message);
+        }
+    }
+
+    #[test]
+    fn other_failures_keep_their_kind() {
+        let hash = "00".repeat(32);
+        assert!(matches!(TgiError::get_block(&hash, "Client disconnected"), TgiError::ConnectionLost(_)));
+        assert!(matches!(TgiError::get_block(&hash, "internal error"), TgiError::Rpc { method: "GetBlock", .. }));
+        // Only block lookups tell unknown blocks apart
+        assert!(matches!(TgiError::rpc("GetSink", "sink not found"), TgiError::Rpc { .. }));
+    }
+}
@@ -0,0 +1,102 @@
+//! Forwarding of processed blocks to external consumers.
+//!
+//! Every block added by a live notification is published as a JSON encoded
+//! `BlockEvent` once the transaction storing it committed:
+//!
+//! ```json
+//! {
+//!   "block_hash": "<64 hex characters>",
+//!   "timestamp": 1700000000000,
+//!   "daa_score": 123456,
+//!   "parent_hashes": ["<64 hex characters>", "..."]
+//! }
+//! ```
+//!
+//! Delivery is best effort: a failed send is logged and not retried.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::pin::Pin;
+use tondi_rpc_core::model::RpcBlock;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlockEvent {
+    pub block_hash: String,
+    /// Block timestamp in milliseconds
+    pub timestamp: i64,
+    pub daa_score: u64,
+    pub parent_hashes: Vec<String>,
+}
+
+impl From<&RpcBlock> for BlockEvent {
+    fn from(block: &RpcBlock) -> Self {
+        Self {
+            block_hash: block.header.hash.to_string(),
+            timestamp: block.header.timestamp as i64,
+            daa_score: block.header.daa_score,
+            parent_hashes: block.header.direct_parents().iter().map(|h| h.to_string()).collect(),
+        }
+    }
+}
+
+pub type SendFuture<'a> = Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
+
+/// Destination of processed-block events.
+pub trait BlockEventSink: Send + Sync {
+    fn send(&self, event: BlockEvent) -> SendFuture<'_>;
+}
+
+/// Drops every event. Used when no sink is configured.
+pub struct NoopEventSink;
+
+impl BlockEventSink for NoopEventSink {
+    fn send(&self, _event: BlockEvent) -> SendFuture<'_> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Publishes events to a NATS subject.
+#[cfg(feature = "nats")]
+pub struct NatsEventSink {
+    client: async_nats::Client,
+    subject: String,
+}
+
+#[cfg(feature = "nats")]
+impl NatsEventSink {
+    pub async fn connect(url: &str, subject: &str) -> Result<Self> {
+        let client = async_nats::connect(url).await?;
+        Ok(Self {
+            client,
+            subject: subject.to_string(),
+        })
+    }
+}
+
+#[cfg(feature = "nats")]
+impl BlockEventSink for NatsEventSink {
+    fn send(&self, event: BlockEvent) -> SendFuture<'_> {
+        Box::pin(async move {
+            let payload = serde_json::to_vec(&event)?;
+            self.client.publish(self.subject.clone(), payload.into()).await?;
+            Ok(())
+        })
+    }
+}
+
+/// Builds the sink selected by the configuration.
+pub async fn sink_from_config(config: &crate::config::Config) -> Result<Box<dyn BlockEventSink>> {
+    let Some(url) = config.nats_url() else {
+        return Ok(Box::new(NoopEventSink));
+    };
+    #[cfg(feature = "nats")]
+    {
+        tracing::info!("Publishing processed blocks to NATS subject {} at {}", config.nats_subject(), url);
+        Ok(Box::new(NatsEventSink::connect(&url, &config.nats_subject()).await?))
+    }
+    #[cfg(not(feature = "nats"))]
+    {
+        anyhow::bail!("--nats-url {} requires TGI to be built with the nats feature", url)
+    }
+}
@@ -0,0 +1,671 @@
+//! Compact binary export and import of the database: the blocks along with
+//! the state processing keeps for them, the edges, the height groups, the
+//! accepted transactions and the pending tables, so an imported database
+//! picks up processing where the exported one left it.
+//!
+//! A stream starts with the `TGIB` magic and a little-endian `u16` format
+//! version. Records follow, each made of a one byte tag, a little-endian
+//! `u32` payload length and a bincode payload. The stream ends with a
+//! trailer record holding the number of records of every kind, which the
+//! import checks before committing.
+//!
+//! Exports read every table in pages of `PAGE_SIZE` rows, keyed on the
+//! primary key, and write each page before fetching the next, so memory use
+//! doesn't grow with the size of the database.
+//!
+//! With the `sqlite` feature, the blocks, edges and height groups can also
+//! be exported to a SQLite database mirroring the PostgreSQL schema, for
+//! tools that don't talk to PostgreSQL. JSON columns are stored as text
+//! there.
+
+use crate::database::{
+    AcceptedTransaction, Block, BlockState, Database, Edge, HeightGroup, PendingOrphanBlock, PendingOrphanBlockData,
+    PendingParentEdge, PendingSelectedParent,
+};
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio_postgres::Transaction;
+use tracing::info;
+
+const MAGIC: &[u8; 4] = b"TGIB";
+const FORMAT_VERSION: u16 = 4;
+
+const RECORD_BLOCK: u8 = 1;
+const RECORD_EDGE: u8 = 2;
+const RECORD_HEIGHT_GROUP: u8 = 3;
+const RECORD_ACCEPTED_TRANSACTION: u8 = 4;
+const RECORD_PENDING_SELECTED_PARENT: u8 = 5;
+const RECORD_PENDING_PARENT_EDGE: u8 = 6;
+const RECORD_PENDING_ORPHAN_BLOCK: u8 = 7;
+const RECORD_PENDING_ORPHAN_BLOCK_DATA: u8 = 8;
+const RECORD_TRAILER: u8 = 0xFF;
+
+const PAGE_SIZE: u64 = 10000;
+
+/// Pages read ahead of the SQLite writer
+#[cfg(feature = "sqlite")]
+const SQLITE_PAGES_IN_FLIGHT: usize = 2;
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct RecordCounts {
+    blocks: u64,
+    edges: u64,
+    height_groups: u64,
+    accepted_transactions: u64,
+    pending_selected_parents: u64,
+    pending_parent_edges: u64,
+    pending_orphan_blocks: u64,
+    pending_orphan_block_data: u64,
+}
+
+impl RecordCounts {
+    fn pending_rows(&self) -> u64 {
+        self.pending_selected_parents + self.pending_parent_edges + self.pending_orphan_blocks + self.pending_orphan_block_data
+    }
+}
+
+async fn write_record<T: Serialize>(writer: &mut (impl AsyncWrite + Unpin), tag: u8, record: &T) -> Result<()> {
+    let payload = bincode::serialize(record)?;
+    writer.write_all(&[tag]).await?;
+    writer.write_all(&(payload.len() as u32).to_le_bytes()).await?;
+    writer.write_all(&payload).await?;
+    Ok(())
+}
+
+async fn write_records<T: Serialize>(writer: &mut (impl AsyncWrite + Unpin), tag: u8, records: &[T]) -> Result<()> {
+    for record in records {
+        write_record(writer, tag, record).await?;
+    }
+    Ok(())
+}
+
+async fn read_payload<T: DeserializeOwned>(reader: &mut (impl AsyncRead + Unpin)) -> Result<T> {
+    let mut length = [0u8; 4];
+    reader.read_exact(&mut length).await?;
+    let mut payload = vec![0u8; u32::from_le_bytes(length) as usize];
+    reader.read_exact(&mut payload).await?;
+    Ok(bincode::deserialize(&payload)?)
+}
+
+pub async fn export_binary(database: &Database, path: &str) -> Result<()> {
+    info!("Exporting the database to {}", path);
+
+    let file = File::create(path).await.with_context(|| format!("Could not create export file {}", path))?;
+    let mut writer = BufWriter::new(file);
+    writer.write_all(MAGIC).await?;
+    writer.write_all(&FORMAT_VERSION.to_le_bytes()).await?;
+
+    let database_for_closure = database.clone();
+    let counts = database.run_in_read_transaction_once(move |tx| {
+        let database = database_for_closure.clone();
+        Box::pin(async move {
+            let mut counts = RecordCounts::default();
+
+            let mut after_id = 0;
+            loop {
+                let blocks = database.blocks_with_state_after_id(tx, after_id, PAGE_SIZE).await?;
+                let Some((last, _)) = blocks.last() else { break };
+                after_id = last.id;
+                write_records(&mut writer, RECORD_BLOCK, &blocks).await?;
+                counts.blocks += blocks.len() as u64;
+            }
+
+            let mut after = (0, 0);
+            loop {
+                let edges = database.edges_after(tx, after, PAGE_SIZE).await?;
+                let Some(last) = edges.last() else { break };
+                after = (last.from_block_id, last.to_block_id);
+                write_records(&mut writer, RECORD_EDGE, &edges).await?;
+                counts.edges += edges.len() as u64;
+            }
+
+            let mut min_height = 0;
+            loop {
+                let height_groups = database.height_groups_from(tx, min_height, PAGE_SIZE).await?;
+                let Some(last) = height_groups.last() else { break };
+                min_height = last.height + 1;
+                write_records(&mut writer, RECORD_HEIGHT_GROUP, &height_groups).await?;
+                counts.height_groups += height_groups.len() as u64;
+            }
+
+            let mut after = (0, String::new());
+            loop {
+                let accepted_transactions = database.accepted_transactions_after(tx, (after.0, &after.1), PAGE_SIZE).await?;
+                let Some(last) = accepted_transactions.last() else { break };
+                after = (last.accepting_block_id, last.transaction_id.clone());
+                write_records(&mut writer, RECORD_ACCEPTED_TRANSACTION, &accepted_transactions).await?;
+                counts.accepted_transactions += accepted_transactions.len() as u64;
+            }
+
+            export_pending_rows(&database, tx, &mut writer, &mut counts).await?;
+
+            write_record(&mut writer, RECORD_TRAILER, &counts).await?;
+            writer.flush().await?;
+            Ok(counts)
+        })
+    }).await?;
+
+    info!(
+        "Exported {} blocks, {} edges, {} height groups, {} accepted transactions and {} pending rows",
+        counts.blocks, counts.edges, counts.height_groups, counts.accepted_transactions, counts.pending_rows()
+    );
+    Ok(())
+}
+
+/// Writes the rows of the tables tracking what processing still waits for.
+async fn export_pending_rows(
+    database: &Database,
+    tx: &Transaction<'_>,
+    writer: &mut (impl AsyncWrite + Unpin),
+    counts: &mut RecordCounts,
+) -> Result<()> {
+    let mut after_block_id = 0;
+    loop {
+        let pending = database.pending_selected_parents_after(tx, after_block_id, PAGE_SIZE).await?;
+        let Some(last) = pending.last() else { break };
+        after_block_id = last.block_id;
+        write_records(writer, RECORD_PENDING_SELECTED_PARENT, &pending).await?;
+        counts.pending_selected_parents += pending.len() as u64;
+    }
+
+    let mut after = (0, String::new());
+    loop {
+        let pending = database.pending_parent_edges_after(tx, (after.0, &after.1), PAGE_SIZE).await?;
+        let Some(last) = pending.last() else { break };
+        after = (last.block_id, last.parent_hash.clone());
+        write_records(writer, RECORD_PENDING_PARENT_EDGE, &pending).await?;
+        counts.pending_parent_edges += pending.len() as u64;
+    }
+
+    let mut after = (String::new(), String::new());
+    loop {
+        let pending = database.pending_orphan_blocks_after(tx, (&after.0, &after.1), PAGE_SIZE).await?;
+        let Some(last) = pending.last() else { break };
+        after = (last.block_hash.clone(), last.parent_hash.clone());
+        write_records(writer, RECORD_PENDING_ORPHAN_BLOCK, &pending).await?;
+        counts.pending_orphan_blocks += pending.len() as u64;
+    }
+
+    let mut after_block_hash = String::new();
+    loop {
+        let pending = database.pending_orphan_block_data_after(tx, &after_block_hash, PAGE_SIZE).await?;
+        let Some(last) = pending.last() else { break };
+        after_block_hash = last.block_hash.clone();
+        write_records(writer, RECORD_PENDING_ORPHAN_BLOCK_DATA, &pending).await?;
+        counts.pending_orphan_block_data += pending.len() as u64;
+    }
+    Ok(())
+}
+
+/// Takes the buffered rows once they fill a page, or whatever is left once
+/// the stream ended.
+fn take_page<T>(rows: &mut Vec<T>, stream_ended: bool) -> Option<Vec<T>> {
+    if rows.len() as u64 >= PAGE_SIZE || (stream_ended && !rows.is_empty()) {
+        Some(std::mem::take(rows))
+    } else {
+        None
+    }
+}
+
+/// Rows read from an export stream and not loaded yet
+#[derive(Default)]
+struct ImportBuffers {
+    blocks: Vec<(Block, BlockState)>,
+    edges: Vec<Edge>,
+    height_groups: Vec<HeightGroup>,
+    accepted_transactions: Vec<AcceptedTransaction>,
+    pending_selected_parents: Vec<PendingSelectedParent>,
+    pending_parent_edges: Vec<PendingParentEdge>,
+    pending_orphan_blocks: Vec<PendingOrphanBlock>,
+    pending_orphan_block_data: Vec<PendingOrphanBlockData>,
+}
+
+impl ImportBuffers {
+    /// Loads the full pages, or every buffered row once the stream ended,
+    /// adding the loaded rows to `counts`.
+    async fn load(&mut self, database: &Database, tx: &Transaction<'_>, stream_ended: bool, counts: &mut RecordCounts) -> Result<()> {
+        if let Some(blocks) = take_page(&mut self.blocks, stream_ended) {
+            database.copy_in_blocks_with_state(tx, &blocks).await?;
+            counts.blocks += blocks.len() as u64;
+        }
+        if let Some(edges) = take_page(&mut self.edges, stream_ended) {
+            database.copy_in_edges(tx, &edges).await?;
+            counts.edges += edges.len() as u64;
+        }
+        if let Some(height_groups) = take_page(&mut self.height_groups, stream_ended) {
+            database.copy_in_height_groups(tx, &height_groups).await?;
+            counts.height_groups += height_groups.len() as u64;
+        }
+        if let Some(accepted_transactions) = take_page(&mut self.accepted_transactions, stream_ended) {
+            database.copy_in_accepted_transactions(tx, &accepted_transactions).await?;
+            counts.accepted_transactions += accepted_transactions.len() as u64;
+        }
+        if let Some(pending) = take_page(&mut self.pending_selected_parents, stream_ended) {
+            database.insert_pending_selected_parents(tx, &pending).await?;
+            counts.pending_selected_parents += pending.len() as u64;
+        }
+        if let Some(pending) = take_page(&mut self.pending_parent_edges, stream_ended) {
+            database.insert_pending_parent_edges(tx, &pending).await?;
+            counts.pending_parent_edges += pending.len() as u64;
+        }
+        if let Some(pending) = take_page(&mut self.pending_orphan_blocks, stream_ended) {
+            database.insert_pending_orphan_blocks(tx, &pending).await?;
+            counts.pending_orphan_blocks += pending.len() as u64;
+        }
+        if let Some(pending) = take_page(&mut self.pending_orphan_block_data, stream_ended) {
+            database.insert_pending_orphan_block_data(tx, &pending).await?;
+            counts.pending_orphan_block_data += pending.len() as u64;
+        }
+        Ok(())
+    }
+}
+
+pub async fn import_binary(database: &Database, path: &str) -> Result<()> {
+    info!("Importing {} into the database", path);
+
+    let file = File::open(path).await.with_context(|| format!("Could not open import file {}", path))?;
+    let mut reader = BufReader::new(file);
+
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic).await?;
+    if &magic != MAGIC {
+        anyhow::bail!("{} is not a TGI binary export", path);
+    }
+    let mut version = [0u8; 2];
+    reader.read_exact(&mut version).await?;
+    let version = u16::from_le_bytes(version);
+    if version != FORMAT_VERSION {
+        anyhow::bail!("Unsupported export format version {} (expected {})", version, FORMAT_VERSION);
+    }
+
+    let database_for_closure = database.clone();
+    let counts = database.run_in_transaction_once(move |tx| {
+        let database = database_for_closure.clone();
+        Box::pin(async move {
+            if !database.is_empty(tx).await? {
+                anyhow::bail!("The database already contains blocks; import requires an empty database");
+            }
+            database.disable_statement_timeout(tx).await?;
+
+            let mut counts = RecordCounts::default();
+            let mut buffers = ImportBuffers::default();
+            loop {
+                let mut tag = [0u8; 1];
+                reader.read_exact(&mut tag).await
+                    .context("Export stream ended before its trailer")?;
+                match tag[0] {
+                    RECORD_BLOCK => buffers.blocks.push(read_payload(&mut reader).await?),
+                    RECORD_EDGE => buffers.edges.push(read_payload(&mut reader).await?),
+                    RECORD_HEIGHT_GROUP => buffers.height_groups.push(read_payload(&mut reader).await?),
+                    RECORD_ACCEPTED_TRANSACTION => buffers.accepted_transactions.push(read_payload(&mut reader).await?),
+                    RECORD_PENDING_SELECTED_PARENT => buffers.pending_selected_parents.push(read_payload(&mut reader).await?),
+                    RECORD_PENDING_PARENT_EDGE => buffers.pending_parent_edges.push(read_payload(&mut reader).await?),
+                    RECORD_PENDING_ORPHAN_BLOCK => buffers.pending_orphan_blocks.push(read_payload(&mut reader).await?),
+                    RECORD_PENDING_ORPHAN_BLOCK_DATA => buffers.pending_orphan_block_data.push(read_payload(&mut reader).await?),
+                    RECORD_TRAILER => {
+                        let expected_counts: RecordCounts = read_payload(&mut reader).await?;
+                        buffers.load(&database, tx, true, &mut counts).await?;
+                        if counts != expected_counts {
+                            anyhow::bail!("Imported {:?} but the export trailer lists {:?}", counts, expected_counts);
+                        }
+                        break;
+                    }
+                    tag => anyhow::bail!("Unknown record tag {} in export stream", tag),
+                }
+                buffers.load(&database, tx, false, &mut counts).await?;
+            }
+
+            database.reset_block_id_sequence(tx).await?;
+            Ok(counts)
+        })
+    }).await?;
+
+    info!(
+        "Imported {} blocks, {} edges, {} height groups, {} accepted transactions and {} pending rows",
+        counts.blocks, counts.edges, counts.height_groups, counts.accepted_transactions, counts.pending_rows()
+    );
+    Ok(())
+}
+
+#[cfg(feature = "sqlite")]
+const SQLITE_SCHEMA: &str = r#"
+CREATE TABLE blocks
+(
+    id                                  INTEGER PRIMARY KEY,
+    block_hash                          TEXT    NOT NULL UNIQUE,
+    timestamp                           INTEGER NOT NULL,
+    parent_ids                          TEXT    NOT NULL,
+    daa_score                           INTEGER NOT NULL,
+    height                              INTEGER NOT NULL,
+    height_group_index                  INTEGER NOT NULL,
+    selected_parent_id                  INTEGER NULL,
+    color                               TEXT    NOT NULL,
+    is_in_virtual_selected_parent_chain INTEGER NOT NULL,
+    merge_set_red_ids                   TEXT    NOT NULL,
+    merge_set_blue_ids                  TEXT    NOT NULL,
+    is_header_only                      INTEGER NOT NULL,
+    miner                               TEXT    NULL
+);
+CREATE INDEX idx_blocks_height ON blocks (height);
+
+CREATE TABLE edges
+(
+    from_block_id           INTEGER NOT NULL,
+    to_block_id             INTEGER NOT NULL,
+    from_height             INTEGER NOT NULL,
+    to_height               INTEGER NOT NULL,
+    from_height_group_index INTEGER NOT NULL,
+    to_height_group_index   INTEGER NOT NULL,
+    PRIMARY KEY (from_block_id, to_block_id)
+);
+CREATE INDEX idx_edges_to_block_id ON edges (to_block_id);
+
+CREATE TABLE height_groups
+(
+    height INTEGER PRIMARY KEY,
+    size   INTEGER NOT NULL
+);
+"#;
+
+#[cfg(feature = "sqlite")]
+fn write_sqlite_blocks(connection: &rusqlite::Connection, blocks: &[Block]) -> Result<()> {
+    let mut insert = connection.prepare_cached(
+        "INSERT INTO blocks (id, block_hash, timestamp, parent_ids, daa_score, height, height_group_index, \
+        selected_parent_id, color, is_in_virtual_selected_parent_chain, merge_set_red_ids, merge_set_blue_ids, \
+        is_header_only, miner) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+    )?;
+    for block in blocks {
+        insert.execute(rusqlite::params![
+            block.id as i64,
+            block.block_hash,
+            block.timestamp,
+            serde_json::to_string(&block.parent_ids)?,
+            block.daa_score as i64,
+            block.height as i64,
+            block.height_group_index,
+            block.selected_parent_id.map(|id| id as i64),
+            block.color,
+            block.is_in_virtual_selected_parent_chain,
+            serde_json::to_string(&block.merge_set_red_ids)?,
+            serde_json::to_string(&block.merge_set_blue_ids)?,
+            block.is_header_only,
+            block.miner,
+        ])?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "sqlite")]
+fn write_sqlite_edges(connection: &rusqlite::Connection, edges: &[Edge]) -> Result<()> {
+    let mut insert = connection.prepare_cached(
+        "INSERT INTO edges (from_block_id, to_block_id, from_height, to_height, from_height_group_index, \
+        to_height_group_index) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+    )?;
+    for edge in edges {
+        insert.execute(rusqlite::params![
+            edge.from_block_id as i64,
+            edge.to_block_id as i64,
+            edge.from_height as i64,
+            edge.to_height as i64,
+            edge.from_height_group_index,
+            edge.to_height_group_index,
+        ])?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "sqlite")]
+fn write_sqlite_height_groups(connection: &rusqlite::Connection, height_groups: &[HeightGroup]) -> Result<()> {
+    let mut insert = connection.prepare_cached("INSERT INTO height_groups (height, size) VALUES (?1, ?2)")?;
+    for height_group in height_groups {
+        insert.execute(rusqlite::params![height_group.height as i64, height_group.size])?;
+    }
+    Ok(())
+}
+
+/// A page of rows for the SQLite writer
+#[cfg(feature = "sqlite")]
+enum SqlitePage {
+    Blocks(Vec<Block>),
+    Edges(Vec<Edge>),
+    HeightGroups(Vec<HeightGroup>),
+    /// Every page was sent; commits the export
+    Finished,
+}
+
+/// Creates the SQLite database at `path` and writes the pages received from
+/// `pages` to it in a single SQLite transaction. Runs on a blocking thread,
+/// as rusqlite does its I/O synchronously. Returns whether the export was
+/// committed, which it isn't when `pages` closes before `Finished`.
+#[cfg(feature = "sqlite")]
+fn write_sqlite(path: &str, mut pages: tokio::sync::mpsc::Receiver<SqlitePage>) -> Result<bool> {
+    if std::path::Path::new(path).exists() {
+        anyhow::bail!("SQLite export file {} already exists", path);
+    }
+    let mut connection = rusqlite::Connection::open(path)
+        .with_context(|| format!("Could not create SQLite export file {}", path))?;
+    connection.execute_batch(SQLITE_SCHEMA)?;
+    let transaction = connection.transaction()?;
+    while let Some(page) = pages.blocking_recv() {
+        match page {
+            SqlitePage::Blocks(blocks) => write_sqlite_blocks(&transaction, &blocks)?,
+            SqlitePage::Edges(edges) => write_sqlite_edges(&transaction, &edges)?,
+            SqlitePage::HeightGroups(height_groups) => write_sqlite_height_groups(&transaction, &height_groups)?,
+            SqlitePage::Finished => {
+                transaction.commit()?;
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(feature = "sqlite")]
+async fn send_sqlite_page(sender: &tokio::sync::mpsc::Sender<SqlitePage>, page: SqlitePage) -> Result<()> {
+    sender.send(page).await.map_err(|_| anyhow::anyhow!("The SQLite writer stopped"))
+}
+
+/// Writes the blocks, edges and height groups to a new SQLite database at
+/// `path`, one page at a time, in a single SQLite transaction. The pages are
+/// read here and written by `write_sqlite` on a blocking thread, at most
+/// `SQLITE_PAGES_IN_FLIGHT` pages apart.
+pub async fn export_sqlite(database: &Database, path: &str) -> Result<()> {
+    #[cfg(feature = "sqlite")]
+    {
+        info!("Exporting the database to SQLite file {}", path);
+        let (sender, receiver) = tokio::sync::mpsc::channel(SQLITE_PAGES_IN_FLIGHT);
+        let path_for_writer = path.to_string();
+        let writer = tokio::task::spawn_blocking(move || write_sqlite(&path_for_writer, receiver));
+
+        let database_for_closure = database.clone();
+        let read = database.run_in_read_transaction_once(move |tx| {
+            let database = database_for_closure.clone();
+            Box::pin(async move {
+                let mut counts = RecordCounts::default();
+                let send = |page| send_sqlite_page(&sender, page);
+
+                let mut after_id = 0;
+                loop {
+                    let blocks = database.blocks_after_id(tx, after_id, PAGE_SIZE).await?;
+                    let Some(last) = blocks.last() else { break };
+                    after_id = last.id;
+                    counts.blocks += blocks.len() as u64;
+                    send(SqlitePage::Blocks(blocks)).await?;
+                }
+
+                let mut after = (0, 0);
+                loop {
+                    let edges = database.edges_after(tx, after, PAGE_SIZE).await?;
+                    let Some(last) = edges.last() else { break };
+                    after = (last.from_block_id, last.to_block_id);
+                    counts.edges += edges.len() as u64;
+                    send(SqlitePage::Edges(edges)).await?;
+                }
+
+                let mut min_height = 0;
+                loop {
+                    let height_groups = database.height_groups_from(tx, min_height, PAGE_SIZE).await?;
+                    let Some(last) = height_groups.last() else { break };
+                    min_height = last.height + 1;
+                    counts.height_groups += height_groups.len() as u64;
+                    send(SqlitePage::HeightGroups(height_groups)).await?;
+                }
+
+                send(SqlitePage::Finished).await?;
+                Ok(counts)
+            })
+        }).await;
+
+        // A writer failure is what stopped the reading, if anything did;
+        // otherwise the writer only stopped because the reading failed
+        let committed = writer.await.context("The SQLite writer panicked")??;
+        let counts = read?;
+        if !committed {
+            anyhow::bail!("The SQLite export to {} was interrupted", path);
+        }
+
+        info!(
+            "Exported {} blocks, {} edges and {} height groups to SQLite",
+            counts.blocks, counts.edges, counts.height_groups
+        );
+        Ok(())
+    }
+    #[cfg(not(feature = "sqlite"))]
+    {
+        let _ = database;
+        anyhow::bail!("--export-sqlite {} requires TGI to be built with the sqlite feature", path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::testing::TestDatabase;
+    use crate::database::COLOR_BLUE;
+
+    fn block(n: u64, parent_ids: Vec<u64>) -> Block {
+        Block {
+            id: 0,
+            block_hash: format!("{:064x}", n),
+            timestamp: n as i64,
+            parent_ids,
+            daa_score: n,
+            height: n,
+            height_group_index: 0,
+            selected_parent_id: None,
+            color: COLOR_BLUE.to_string(),
+            is_in_virtual_selected_parent_chain: true,
+            merge_set_red_ids: vec![],
+            merge_set_blue_ids: vec![],
+            is_header_only: false,
+            miner: None,
+        }
+    }
+
+    /// Every row of `table` as JSON, in a stable order
+    async fn table_rows(test_database: &TestDatabase, table: &str) -> Vec<String> {
+        test_database.client().await
+            .query(&format!("SELECT row_to_json(t)::TEXT FROM {} t ORDER BY 1", table), &[]).await
+            .expect("failed to read the table")
+            .iter().map(|row| row.get(0)).collect()
+    }
+
+    #[tokio::test]
+    async fn binary_import_restores_every_exported_table() {
+        let Some(exported) = TestDatabase::create().await else { return };
+        let database = &exported.database;
+        let database_for_closure = database.clone();
+        database.run_in_transaction(move |tx| {
+            let database = database_for_closure.clone();
+            Box::pin(async move {
+                let ids = database.bulk_insert_blocks(tx, &[block(0, vec![])]).await?;
+                let child_ids = database.bulk_insert_blocks(tx, &[block(1, ids.clone())]).await?;
+                database.copy_in_edges(tx, &[Edge {
+                    from_block_id: child_ids[0],
+                    to_block_id: ids[0],
+                    from_height: 1,
+                    to_height: 0,
+                    from_height_group_index: 0,
+                    to_height_group_index: 0,
+                }]).await?;
+                database.bulk_upsert_height_groups(tx, &[HeightGroup { height: 0, size: 1 }, HeightGroup { height: 1, size: 1 }]).await?;
+                Ok(())
+            })
+        }).await.unwrap();
+        exported.client().await.batch_execute(
+            r#"
+            UPDATE blocks SET colored_by_block_id = (SELECT MIN(id) FROM blocks), header_blob = '\x0102', fully_processed = TRUE
+                WHERE height = 1;
+            INSERT INTO accepted_transactions SELECT MIN(id), repeat('a', 64) FROM blocks;
+            INSERT INTO pending_selected_parents SELECT MAX(id), repeat('b', 64) FROM blocks;
+            INSERT INTO pending_parent_edges SELECT MAX(id), repeat('c', 64) FROM blocks;
+            INSERT INTO pending_orphan_blocks VALUES (repeat('d', 64), repeat('e', 64));
+            INSERT INTO pending_orphan_block_data VALUES (repeat('d', 64), '{"hash": "d"}', 5);
+            "#,
+        ).await.expect("failed to add the rows processing keeps");
+
+        let path = std::env::temp_dir().join(format!("{}.tgib", exported.schema));
+        let path = path.to_str().unwrap();
+        export_binary(database, path).await.expect("the export should succeed");
+        let Some(imported) = TestDatabase::create().await else { return };
+        import_binary(&imported.database, path).await.expect("the import should succeed");
+        std::fs::remove_file(path).unwrap();
+
+        let tables = [
+            "blocks", "edges", "height_groups", "accepted_transactions", "pending_selected_parents",
+            "pending_parent_edges", "pending_orphan_blocks", "pending_orphan_block_data",
+        ];
+        for table in tables {
+            let rows = table_rows(&exported, table).await;
+            assert!(!rows.is_empty(), "{}", table);
+            assert_eq!(table_rows(&imported, table).await, rows, "{}", table);
+        }
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn sqlite_export_writes_every_table() {
+        let Some(test_database) = TestDatabase::create().await else { return };
+        let database = &test_database.database;
+        let database_for_closure = database.clone();
+        database.run_in_transaction(move |tx| {
+            let database = database_for_closure.clone();
+            Box::pin(async move {
+                let ids = database.bulk_insert_blocks(tx, &[block(0, vec![])]).await?;
+                let child_ids = database.bulk_insert_blocks(tx, &[block(1, ids.clone())]).await?;
+                database.copy_in_edges(tx, &[Edge {
+                    from_block_id: child_ids[0],
+                    to_block_id: ids[0],
+                    from_height: 1,
+                    to_height: 0,
+                    from_height_group_index: 0,
+                    to_height_group_index: 0,
+                }]).await?;
+                database.bulk_upsert_height_groups(tx, &[HeightGroup { height: 0, size: 1 }, HeightGroup { height: 1, size: 1 }]).await?;
+                Ok(())
+            })
+        }).await.unwrap();
+
+        let path = std::env::temp_dir().join(format!("{}.sqlite", test_database.schema));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        export_sqlite(database, path).await.expect("the export should succeed");
+
+        let connection = rusqlite::Connection::open(path).unwrap();
+        for (table, expected) in [("blocks", 2), ("edges", 1), ("height_groups", 2)] {
+            let count: i64 = connection.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| row.get(0)).unwrap();
+            assert_eq!(count, expected, "{}", table);
+        }
+        let parent_ids: String = connection.query_row("SELECT parent_ids FROM blocks WHERE height = 1", [], |row| row.get(0)).unwrap();
+        assert_eq!(parent_ids, format!("[{}]", connection.query_row("SELECT id FROM blocks WHERE height = 0", [], |row| row.get::<_, i64>(0)).unwrap()));
+        drop(connection);
+
+        // An existing file is left alone
+        let error = export_sqlite(database, path).await.expect_err("the export should not overwrite the file");
+        assert!(error.to_string().contains("already exists"), "{}", error);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,15 @@
+pub mod alerts;
+pub mod api;
+pub mod config;
+pub mod database;
+pub mod diff;
+pub mod error;
+pub mod events;
+pub mod export;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod processing;
+pub mod rpc_bench;
+pub mod rpc_client;
+pub mod verify;
+pub mod version;
@@ -1,37 +1,144 @@
-mod config;
-mod database;
-mod processing;
-mod rpc_client;
-mod version;
-
 use anyhow::Result;
-use tracing::{info, error};
+use std::sync::Mutex;
+use tondi_graph_inspector_processing::{alerts, api, config, database, diff, export, processing, rpc_bench, rpc_client, verify, version};
+use tracing::{info, error, warn};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+/// Logs to stdout, and to the log file when the configuration has a log
+/// directory. Missing directories are created.
+fn init_logging(config: &config::Config) -> Result<()> {
+    let file_layer = config.open_log_file()?
+        .map(|log_file| tracing_subscriber::fmt::layer().with_ansi(false).with_writer(Mutex::new(log_file)));
+
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::EnvFilter::from_default_env())
+        .with(tracing_subscriber::fmt::layer())
+        .with(file_layer)
+        .init();
+    Ok(())
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Initialize tracing
-    tracing_subscriber::fmt()
-        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-        .init();
+    let config = match config::Config::load()? {
+        config::LoadOutcome::Run(config) => *config,
+        config::LoadOutcome::Print(text) => {
+            print!("{}", text);
+            return Ok(());
+        }
+    };
 
     println!("=================================================");
     println!("Tondi Graph Inspector (TGI)   -   Processing Tier");
     println!("=================================================");
-
-    let config = config::Config::load()?;
+    init_logging(&config)?;
+    if let Some(log_path) = config.log_path() {
+        info!("Logging to {}", log_path.display());
+    }
 
     info!("Application version {}", version::VERSION);
     info!("Network {}", config.network());
+    if config.rpcserver_port_of_other_network() {
+        warn!(
+            "RPC server {} uses the default port of the other network; check that the node runs {}",
+            config.rpcserver(), config.network()
+        );
+    }
+    alerts::init_from_config(&config)?;
+
+    if let Some(calls) = config.commands.benchmark_rpc {
+        let rpc_client = rpc_client::RpcClient::new(config.rpcserver(), 1000, &config.rpc_connect_options()).await?;
+        rpc_bench::benchmark_rpc(&rpc_client, calls.max(1), config.rpc_max_concurrency).await?;
+        return Ok(());
+    }
+
+    let database = database::Database::connect(
+        &config.connection_string,
+        config.read_connection_string.as_deref(),
+        &config.db_tls_config(),
+        &config.db_tcp_config(),
+        config.db_statement_timeout(),
+        Some(&config.db_application_name()),
+        &config.schema,
+    ).await?
+        .with_isolation_level(config.db_isolation_level())
+        .with_acquire_timeout(config.db_acquire_timeout());
+    if config.schema != database::DEFAULT_SCHEMA {
+        info!("Using database schema {}", config.schema);
+        database.ensure_schema().await?;
+    }
+
+    if config.verify() {
+        verify::verify_database(&database).await?;
+        let rpc_client = rpc_client::RpcClient::new(config.rpcserver(), 1000, &config.rpc_connect_options()).await?;
+        verify::verify_virtual_chain(&database, &rpc_client, config.verify_concurrency(), config.repair()).await?;
+        return Ok(());
+    }
+
+    if config.reindex_height_groups() {
+        info!("Rebuilding height groups");
+        let database_for_closure = database.clone();
+        database.run_in_transaction(move |tx| {
+            Box::pin(async move { Ok(database_for_closure.rebuild_height_groups(tx).await?) })
+        }).await?;
+        info!("Finished rebuilding height groups");
+        return Ok(());
+    }
+
+    if let Some(block_hash) = &config.commands.diff_block {
+        let rpc_client = rpc_client::RpcClient::new(config.rpcserver(), 1000, &config.rpc_connect_options()).await?;
+        diff::diff_block(&database, &rpc_client, block_hash).await?;
+        return Ok(());
+    }
+
+    if config.reprocess() {
+        let rpc_client = rpc_client::RpcClient::new(config.rpcserver(), 1000, &config.rpc_connect_options()).await?;
+        let (from_height, to_height) = config.reprocess_heights();
+        processing::Processing::reprocess(
+            &database, &rpc_client, from_height, to_height, config.block_processing_options()
+        ).await?;
+        return Ok(());
+    }
+
+    if config.enforce_selected_parent() {
+        let rpc_client = rpc_client::RpcClient::new(config.rpcserver(), 1000, &config.rpc_connect_options()).await?;
+        processing::Processing::backfill_selected_parents(&database, &rpc_client).await?;
+        return Ok(());
+    }
+
+    if let Some(path) = &config.commands.export_binary {
+        export::export_binary(&database, path).await?;
+        return Ok(());
+    }
+
+    if let Some(path) = &config.commands.export_sqlite {
+        export::export_sqlite(&database, path).await?;
+        return Ok(());
+    }
+
+    if let Some(path) = &config.commands.import_binary {
+        export::import_binary(&database, path).await?;
+        return Ok(());
+    }
 
-    let database = database::Database::connect(&config.connection_string).await?;
+    if let Some(api_addr) = config.api_addr.clone() {
+        let database = database.clone();
+        tokio::spawn(async move {
+            if let Err(e) = api::serve(database, &api_addr).await {
+                error!("Query API stopped: {}", e);
+            }
+        });
+    }
 
-    let rpc_client = rpc_client::RpcClient::new(config.rpcserver(), 1000).await?;
+    let rpc_client = rpc_client::RpcClient::new(config.rpcserver(), 1000, &config.rpc_connect_options()).await?;
 
-    let _processing = processing::Processing::new(config, database, rpc_client).await?;
+    let processing = processing::Processing::new(config, database, rpc_client).await?;
 
     // Keep the process running
     tokio::signal::ctrl_c().await?;
     info!("Shutting down...");
+    processing.shutdown().await;
 
     Ok(())
 }
@@ -0,0 +1,304 @@
+//! Process-wide metrics, rendered in the Prometheus text format by the
+//! `/metrics` endpoint of the query API. Only built with the `metrics`
+//! feature.
+
+use crate::error::TgiError;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Upper bounds, in seconds, of the latency histogram buckets
+const LATENCY_BUCKETS: [f64; 12] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+struct Histogram {
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Self {
+            buckets: LATENCY_BUCKETS.iter().map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        if let Some(index) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, output: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(output, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, bound, cumulative);
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(output, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, count);
+        let _ = writeln!(output, "{}_sum{{{}}} {}", name, labels, sum);
+        let _ = writeln!(output, "{}_count{{{}}} {}", name, labels, count);
+    }
+}
+
+/// Block processing latency, indexed by `[is_new][with_dependencies]`
+fn block_processing_histograms() -> &'static [[Histogram; 2]; 2] {
+    static HISTOGRAMS: OnceLock<[[Histogram; 2]; 2]> = OnceLock::new();
+    HISTOGRAMS.get_or_init(|| {
+        [[Histogram::new(), Histogram::new()], [Histogram::new(), Histogram::new()]]
+    })
+}
+
+#[derive(Default)]
+struct RpcMetrics {
+    latencies: BTreeMap<&'static str, Histogram>,
+    /// Failed calls, by method and error kind
+    errors: BTreeMap<(&'static str, &'static str), u64>,
+    /// Retried calls, by method and outcome
+    retries: BTreeMap<(&'static str, &'static str), u64>,
+}
+
+fn rpc_metrics() -> &'static Mutex<RpcMetrics> {
+    static RPC_METRICS: OnceLock<Mutex<RpcMetrics>> = OnceLock::new();
+    RPC_METRICS.get_or_init(Default::default)
+}
+
+/// Final outcome of an RPC call that needed more than one attempt
+pub enum RetryOutcome {
+    /// A later attempt succeeded
+    Recovered,
+    /// Every attempt failed
+    Exhausted,
+}
+
+/// Records one RPC call. Calls are counted by the latency histogram, so
+/// the error rate of a method is its error count over the histogram count.
+pub fn record_rpc_call(method: &'static str, elapsed: Duration, error: Option<&TgiError>) {
+    let mut metrics = rpc_metrics().lock().unwrap_or_else(|e| e.into_inner());
+    metrics.latencies.entry(method).or_insert_with(Histogram::new).observe(elapsed);
+    if let Some(error) = error {
+        *metrics.errors.entry((method, error.kind())).or_default() += 1;
+    }
+}
+
+pub fn record_rpc_retry(method: &'static str, outcome: RetryOutcome) {
+    let outcome = match outcome {
+        RetryOutcome::Recovered => "recovered",
+        RetryOutcome::Exhausted => "exhausted",
+    };
+    let mut metrics = rpc_metrics().lock().unwrap_or_else(|e| e.into_inner());
+    *metrics.retries.entry((method, outcome)).or_default() += 1;
+}
+
+fn failed_notifications() -> &'static Mutex<BTreeMap<&'static str, u64>> {
+    static FAILED_NOTIFICATIONS: OnceLock<Mutex<BTreeMap<&'static str, u64>>> = OnceLock::new();
+    FAILED_NOTIFICATIONS.get_or_init(Default::default)
+}
+
+/// Counts a notification given up on after every processing attempt failed.
+pub fn record_failed_notification(kind: &'static str) {
+    let mut failed_notifications = failed_notifications().lock().unwrap_or_else(|e| e.into_inner());
+    *failed_notifications.entry(kind).or_default() += 1;
+}
+
+static RED_BLOCKS: AtomicU64 = AtomicU64::new(0);
+
+/// Counts blocks that turned red from gray or blue. A block recolored red
+/// after a reorg is counted again only if it wasn't red in between.
+pub fn record_red_blocks(count: u64) {
+    RED_BLOCKS.fetch_add(count, Ordering::Relaxed);
+}
+
+/// Blocks the node returned incomplete, by whether fetching them again
+/// completed them
+static INCOMPLETE_BLOCKS: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+
+/// Counts a block the node returned without its verbose data
+pub fn record_incomplete_block(recovered: bool) {
+    INCOMPLETE_BLOCKS[recovered as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+static PENDING_ORPHAN_BLOCKS: AtomicU64 = AtomicU64::new(0);
+static EXPIRED_ORPHAN_BLOCKS: AtomicU64 = AtomicU64::new(0);
+
+/// Records the blocks waiting for one of their parents after a sweep, and
+/// those the sweep dropped because their parents never arrived.
+pub fn record_pending_orphan_blocks(pending: u64, expired: usize) {
+    PENDING_ORPHAN_BLOCKS.store(pending, Ordering::Relaxed);
+    EXPIRED_ORPHAN_BLOCKS.fetch_add(expired as u64, Ordering::Relaxed);
+}
+
+/// When a block was last processed, or processing started
+static LAST_PROGRESS: Mutex<Option<Instant>> = Mutex::new(None);
+
+pub fn record_progress(at: Instant) {
+    *LAST_PROGRESS.lock().unwrap_or_else(|e| e.into_inner()) = Some(at);
+}
+
+static IN_FLIGHT_TRANSACTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Counts a database transaction as in flight until dropped.
+pub struct InFlightTransaction;
+
+impl InFlightTransaction {
+    pub fn start() -> Self {
+        IN_FLIGHT_TRANSACTIONS.fetch_add(1, Ordering::Relaxed);
+        Self
+    }
+}
+
+impl Drop for InFlightTransaction {
+    fn drop(&mut self) {
+        IN_FLIGHT_TRANSACTIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Latest resync estimate: blocks per second, then the seconds remaining in
+/// the cycle and overall, as f64 bits. Estimates are NaN while the rate is
+/// unknown.
+static RESYNC_ETA: [AtomicU64; 3] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+
+pub fn record_resync_eta(blocks_per_second: f64, cycle: Option<Duration>, overall: Option<Duration>) {
+    let seconds = |eta: Option<Duration>| eta.map_or(f64::NAN, |eta| eta.as_secs_f64());
+    for (gauge, value) in RESYNC_ETA.iter().zip([blocks_per_second, seconds(cycle), seconds(overall)]) {
+        gauge.store(value.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// Times the processing of one block and records it when dropped, so early
+/// returns and failures are measured too.
+pub struct BlockProcessingTimer {
+    started: Instant,
+    is_new: bool,
+    with_dependencies: bool,
+}
+
+impl BlockProcessingTimer {
+    pub fn start(with_dependencies: bool) -> Self {
+        Self {
+            started: Instant::now(),
+            is_new: false,
+            with_dependencies,
+        }
+    }
+
+    /// Marks the block as inserted by this processing rather than already
+    /// stored.
+    pub fn set_new(&mut self, is_new: bool) {
+        self.is_new = is_new;
+    }
+}
+
+impl Drop for BlockProcessingTimer {
+    fn drop(&mut self) {
+        block_processing_histograms()[self.is_new as usize][self.with_dependencies as usize]
+            .observe(self.started.elapsed());
+    }
+}
+
+pub fn render() -> String {
+    const NAME: &str = "tgi_block_processing_seconds";
+    let mut output = String::new();
+    let _ = writeln!(output, "# HELP {} Time spent processing a block, including database and RPC calls", NAME);
+    let _ = writeln!(output, "# TYPE {} histogram", NAME);
+    for (is_new, histograms) in block_processing_histograms().iter().enumerate() {
+        for (with_dependencies, histogram) in histograms.iter().enumerate() {
+            let labels = format!(
+                "block=\"{}\",path=\"{}\"",
+                if is_new == 1 { "new" } else { "existing" },
+                if with_dependencies == 1 { "dependencies" } else { "static" },
+            );
+            histogram.render(&mut output, NAME, &labels);
+        }
+    }
+
+    const SINCE_PROGRESS_NAME: &str = "tgi_seconds_since_last_progress";
+    let last_progress = *LAST_PROGRESS.lock().unwrap_or_else(|e| e.into_inner());
+    let _ = writeln!(output, "# HELP {} Seconds since a block was last processed, or processing started", SINCE_PROGRESS_NAME);
+    let _ = writeln!(output, "# TYPE {} gauge", SINCE_PROGRESS_NAME);
+    let _ = writeln!(output, "{} {}", SINCE_PROGRESS_NAME, last_progress.map_or(f64::NAN, |at| at.elapsed().as_secs_f64()));
+
+    const IN_FLIGHT_NAME: &str = "tgi_database_transactions_in_flight";
+    let _ = writeln!(output, "# HELP {} Database transactions waiting for or holding a connection", IN_FLIGHT_NAME);
+    let _ = writeln!(output, "# TYPE {} gauge", IN_FLIGHT_NAME);
+    let _ = writeln!(output, "{} {}", IN_FLIGHT_NAME, IN_FLIGHT_TRANSACTIONS.load(Ordering::Relaxed));
+
+    let counts = crate::processing::ProcessedBlockCounts::current();
+    const PROCESSED_NAME: &str = "tgi_blocks_processed_total";
+    let _ = writeln!(output, "# HELP {} Processed blocks, by whether they were already stored", PROCESSED_NAME);
+    let _ = writeln!(output, "# TYPE {} counter", PROCESSED_NAME);
+    let _ = writeln!(output, "{}{{block=\"new\"}} {}", PROCESSED_NAME, counts.new);
+    let _ = writeln!(output, "{}{{block=\"existing\"}} {}", PROCESSED_NAME, counts.existing);
+
+    const RED_BLOCKS_NAME: &str = "tgi_red_blocks_total";
+    let _ = writeln!(output, "# HELP {} Blocks that turned red from gray or blue", RED_BLOCKS_NAME);
+    let _ = writeln!(output, "# TYPE {} counter", RED_BLOCKS_NAME);
+    let _ = writeln!(output, "{} {}", RED_BLOCKS_NAME, RED_BLOCKS.load(Ordering::Relaxed));
+
+    const INCOMPLETE_BLOCKS_NAME: &str = "tgi_incomplete_blocks_total";
+    let _ = writeln!(output, "# HELP {} Blocks the node returned without verbose data, by whether a retry completed them", INCOMPLETE_BLOCKS_NAME);
+    let _ = writeln!(output, "# TYPE {} counter", INCOMPLETE_BLOCKS_NAME);
+    for (outcome, count) in ["left_incomplete", "recovered"].iter().zip(&INCOMPLETE_BLOCKS) {
+        let _ = writeln!(output, "{}{{outcome=\"{}\"}} {}", INCOMPLETE_BLOCKS_NAME, outcome, count.load(Ordering::Relaxed));
+    }
+
+    const PENDING_ORPHAN_BLOCKS_NAME: &str = "tgi_pending_orphan_blocks";
+    let _ = writeln!(output, "# HELP {} Blocks deferred until one of their parents is stored, as of the last sweep", PENDING_ORPHAN_BLOCKS_NAME);
+    let _ = writeln!(output, "# TYPE {} gauge", PENDING_ORPHAN_BLOCKS_NAME);
+    let _ = writeln!(output, "{} {}", PENDING_ORPHAN_BLOCKS_NAME, PENDING_ORPHAN_BLOCKS.load(Ordering::Relaxed));
+
+    const EXPIRED_ORPHAN_BLOCKS_NAME: &str = "tgi_expired_orphan_blocks_total";
+    let _ = writeln!(output, "# HELP {} Deferred blocks dropped because none of their parents was stored in time", EXPIRED_ORPHAN_BLOCKS_NAME);
+    let _ = writeln!(output, "# TYPE {} counter", EXPIRED_ORPHAN_BLOCKS_NAME);
+    let _ = writeln!(output, "{} {}", EXPIRED_ORPHAN_BLOCKS_NAME, EXPIRED_ORPHAN_BLOCKS.load(Ordering::Relaxed));
+
+    const RESYNC_NAMES: [(&str, &str); 3] = [
+        ("tgi_resync_blocks_per_second", "Blocks stored per second since the current resync cycle started"),
+        ("tgi_resync_cycle_eta_seconds", "Estimated seconds until the current resync cycle is stored"),
+        ("tgi_resync_eta_seconds", "Coarse estimate of the seconds until the resync reaches the node tip"),
+    ];
+    for ((name, help), gauge) in RESYNC_NAMES.iter().zip(&RESYNC_ETA) {
+        let _ = writeln!(output, "# HELP {} {}", name, help);
+        let _ = writeln!(output, "# TYPE {} gauge", name);
+        let _ = writeln!(output, "{} {}", name, f64::from_bits(gauge.load(Ordering::Relaxed)));
+    }
+
+    const FAILED_NOTIFICATIONS_NAME: &str = "tgi_failed_notifications_total";
+    let _ = writeln!(output, "# HELP {} Notifications dead-lettered after every processing attempt failed", FAILED_NOTIFICATIONS_NAME);
+    let _ = writeln!(output, "# TYPE {} counter", FAILED_NOTIFICATIONS_NAME);
+    for (kind, count) in failed_notifications().lock().unwrap_or_else(|e| e.into_inner()).iter() {
+        let _ = writeln!(output, "{}{{kind=\"{}\"}} {}", FAILED_NOTIFICATIONS_NAME, kind, count);
+    }
+
+    let rpc_metrics = rpc_metrics().lock().unwrap_or_else(|e| e.into_inner());
+    const RPC_NAME: &str = "tgi_rpc_call_seconds";
+    let _ = writeln!(output, "# HELP {} Latency of RPC calls to the node, failed ones included", RPC_NAME);
+    let _ = writeln!(output, "# TYPE {} histogram", RPC_NAME);
+    for (method, histogram) in &rpc_metrics.latencies {
+        histogram.render(&mut output, RPC_NAME, &format!("method=\"{}\"", method));
+    }
+
+    const RPC_ERRORS_NAME: &str = "tgi_rpc_errors_total";
+    let _ = writeln!(output, "# HELP {} Failed RPC calls by error kind", RPC_ERRORS_NAME);
+    let _ = writeln!(output, "# TYPE {} counter", RPC_ERRORS_NAME);
+    for ((method, kind), count) in &rpc_metrics.errors {
+        let _ = writeln!(output, "{}{{method=\"{}\",kind=\"{}\"}} {}", RPC_ERRORS_NAME, method, kind, count);
+    }
+
+    const RPC_RETRIES_NAME: &str = "tgi_rpc_retries_total";
+    let _ = writeln!(output, "# HELP {} Retried RPC calls by final outcome", RPC_RETRIES_NAME);
+    let _ = writeln!(output, "# TYPE {} counter", RPC_RETRIES_NAME);
+    for ((method, outcome), count) in &rpc_metrics.retries {
+        let _ = writeln!(output, "{}{{method=\"{}\",outcome=\"{}\"}} {}", RPC_RETRIES_NAME, method, outcome, count);
+    }
+    output
+}
@@ -1,30 +1,78 @@
 use crate::database::Database;
+use crate::error::TgiError;
 use crate::rpc_client::RpcClient;
+use super::log_throttle::warn_throttled;
 use tondi_rpc_core::model::RpcBlock;
-use tondi_hashes::Hash;
+use crate::rpc_client::BlockHash;
 use anyhow::Result;
 use std::collections::HashMap;
 use tokio_postgres::Transaction;
 use tracing::warn;
 
-const MAX_SUPPORTED_MISSING_DEPENDENCIES: usize = 600;
+pub(super) const MAX_SUPPORTED_MISSING_DEPENDENCIES: usize = 600;
+
+/// What to do when a block has more missing dependencies than a batch
+/// supports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DependencyOverflowPolicy {
+    /// Fail, so the process exits and restarts from scratch
+    #[default]
+    Abort,
+    /// Drop the block and its dependencies and carry on
+    Skip,
+    /// Drop the batch and resync the range the database is missing
+    Resync,
+}
+
+/// Outcome of collecting a block and its missing dependencies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Collection {
+    /// The batch holds the block and all its missing dependencies
+    Complete,
+    /// There were too many dependencies; the batch was emptied
+    Skipped,
+    /// There were too many dependencies; the batch was emptied and the
+    /// missing range must be resynced
+    ResyncNeeded,
+}
+
+/// A parent the node doesn't know yet, returned instead of ignoring the parent
+/// when the batch waits for missing parents. Failing rolls the transaction
+/// back, so the caller waits for the node outside of it and collects the
+/// block again.
+#[derive(Debug, thiserror::Error)]
+#[error("Parent {parent_hash} of block {block_hash} is not known to the node yet")]
+pub struct ParentNotYetFound {
+    pub parent_hash: BlockHash,
+    pub block_hash: BlockHash,
+}
 
 pub struct Batch {
     database: Database,
     rpc_client: RpcClient,
-    blocks: Vec<(String, RpcBlock)>,
-    hashes: HashMap<String, usize>, // hash -> index in blocks
+    blocks: Vec<(BlockHash, RpcBlock)>,
+    hashes: HashMap<BlockHash, usize>, // hash -> index in blocks
     pruning_block: Option<RpcBlock>,
+    overflow_policy: DependencyOverflowPolicy,
+    wait_for_missing_parents: bool,
 }
 
 impl Batch {
-    pub fn new(database: Database, rpc_client: RpcClient, pruning_block: Option<RpcBlock>) -> Self {
+    pub fn new(
+        database: Database,
+        rpc_client: RpcClient,
+        pruning_block: Option<RpcBlock>,
+        overflow_policy: DependencyOverflowPolicy,
+        wait_for_missing_parents: bool,
+    ) -> Self {
         Self {
             database,
             rpc_client,
             blocks: Vec::new(),
             hashes: HashMap::new(),
             pruning_block,
+            overflow_policy,
+            wait_for_missing_parents,
         }
     }
 
@@ -36,13 +84,13 @@ impl Batch {
         }
     }
 
-    pub fn has(&self, hash: &str) -> bool {
+    pub fn has(&self, hash: &BlockHash) -> bool {
         self.hashes.contains_key(hash)
     }
 
-    pub fn add(&mut self, hash: String, block: RpcBlock) {
+    pub fn add(&mut self, hash: BlockHash, block: RpcBlock) {
         if !self.has(&hash) && self.in_scope(&block) {
-            self.hashes.insert(hash.clone(), self.blocks.len());
+            self.hashes.insert(hash, self.blocks.len());
             self.blocks.push((hash, block));
         }
     }
@@ -51,7 +99,7 @@ impl Batch {
         self.blocks.is_empty()
     }
 
-    pub fn pop(&mut self) -> Option<(String, RpcBlock)> {
+    pub fn pop(&mut self) -> Option<(BlockHash, RpcBlock)> {
         self.blocks.pop().map(|(hash, block)| {
             self.hashes.remove(&hash);
             (hash, block)
@@ -61,10 +109,10 @@ impl Batch {
     pub async fn collect_block_and_dependencies(
         &mut self,
         tx: &Transaction<'_>,
-        hash: &str,
+        hash: &BlockHash,
         block: &RpcBlock,
-    ) -> Result<()> {
-        self.add(hash.to_string(), block.clone());
+    ) -> Result<Collection> {
+        self.add(*hash, block.clone());
         
         let mut i = 0;
         while i < self.blocks.len() {
@@ -72,42 +120,87 @@ impl Batch {
             self.collect_direct_dependencies(tx, &item_hash, &item_block).await?;
             
             if self.blocks.len() > MAX_SUPPORTED_MISSING_DEPENDENCIES {
-                anyhow::bail!(
-                    "More than {} missing dependencies found! TGI is out of sync with the node. Terminating the process so it can restart from scratch.",
-                    MAX_SUPPORTED_MISSING_DEPENDENCIES
-                );
+                return self.overflow(hash);
             }
             i += 1;
         }
-        Ok(())
+        Ok(Collection::Complete)
+    }
+
+    fn overflow(&mut self, hash: &BlockHash) -> Result<Collection> {
+        match self.overflow_policy {
+            DependencyOverflowPolicy::Abort => anyhow::bail!(
+                "More than {} missing dependencies found! TGI is out of sync with the node. Terminating the process so it can restart from scratch.",
+                MAX_SUPPORTED_MISSING_DEPENDENCIES
+            ),
+            DependencyOverflowPolicy::Skip => {
+                warn!(
+                    "More than {} missing dependencies found for block {}; skipping it",
+                    MAX_SUPPORTED_MISSING_DEPENDENCIES, hash
+                );
+                self.clear();
+                Ok(Collection::Skipped)
+            }
+            DependencyOverflowPolicy::Resync => {
+                warn!(
+                    "More than {} missing dependencies found for block {}; resyncing the missing range",
+                    MAX_SUPPORTED_MISSING_DEPENDENCIES, hash
+                );
+                self.clear();
+                Ok(Collection::ResyncNeeded)
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.blocks.clear();
+        self.hashes.clear();
     }
 
     async fn collect_direct_dependencies(
         &mut self,
         tx: &Transaction<'_>,
-        hash: &str,
+        hash: &BlockHash,
         block: &RpcBlock,
     ) -> Result<()> {
         let parent_hashes = block.header.direct_parents();
-        for parent_hash in parent_hashes {
-            let parent_hash_str = parent_hash.to_string();
-            let parent_exists = self.database.does_block_exist(tx, &parent_hash_str).await?;
+        for &parent_hash in parent_hashes {
+            let parent_hash = BlockHash::from(parent_hash);
+            let parent_exists = self.database.does_block_exist(tx, &parent_hash).await?;
             if !parent_exists {
-                match self.rpc_client.get_block(&parent_hash_str, false).await {
-                    Ok(rpc_block) => {
-                        self.add(parent_hash_str.clone(), rpc_block.block);
-                        warn!("Missing parent {} of {} registered for processing", parent_hash_str, hash);
+                match self.fetch_missing_parent(parent_hash, hash).await? {
+                    Some(parent_block) => {
+                        self.add(parent_hash, parent_block);
+                        warn_throttled("missing_parent_registered", || {
+                            format!("Missing parent {} of {} registered for processing", parent_hash, hash)
+                        });
                     }
-                    Err(e) => {
-                        // We ignore the `block not found` error.
-                        // In this case the parent is out the node scope so we have no way
+                    None => {
+                        // The parent is out the node scope so we have no way
                         // to include it in the batch
-                        warn!("Parent {} for block {} not found by Tondi domain consensus; the missing dependency is ignored: {}", parent_hash_str, hash, e);
+                        warn_throttled("missing_parent_ignored", || {
+                            format!("Parent {} for block {} not found by Tondi domain consensus; the missing dependency is ignored", parent_hash, hash)
+                        });
                     }
                 }
             }
         }
         Ok(())
     }
-}
 
+    /// Fetches a parent missing from the database. Returns `None` when the
+    /// node doesn't know the parent, meaning it was pruned or is otherwise out
+    /// of the node scope, or `ParentNotYetFound` instead when the batch waits
+    /// for missing parents. Other RPC failures are returned rather than
+    /// mistaken for a missing parent.
+    async fn fetch_missing_parent(&self, parent_hash: BlockHash, block_hash: &BlockHash) -> Result<Option<RpcBlock>> {
+        match self.rpc_client.get_block(parent_hash, false).await {
+            Ok(rpc_block) => Ok(Some(rpc_block.block)),
+            Err(TgiError::BlockNotFound(_)) if self.wait_for_missing_parents => {
+                Err(ParentNotYetFound { parent_hash, block_hash: *block_hash }.into())
+            }
+            Err(TgiError::BlockNotFound(_)) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
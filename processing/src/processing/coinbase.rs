@@ -0,0 +1,48 @@
+//! Parsing of coinbase transaction payloads.
+//!
+//! A coinbase payload starts with the blue score (`u64`), the subsidy
+//! (`u64`), the version of the miner's payout script public key (`u16`),
+//! all little-endian, followed by the script length (`u8`) and the script
+//! itself. Whatever follows is free-form extra data set by the miner.
+
+const BLUE_SCORE_LENGTH: usize = 8;
+const SUBSIDY_LENGTH: usize = 8;
+const SCRIPT_VERSION_LENGTH: usize = 2;
+const SCRIPT_LENGTH_OFFSET: usize = BLUE_SCORE_LENGTH + SUBSIDY_LENGTH + SCRIPT_VERSION_LENGTH;
+
+/// Returns the miner of a block, as the hex encoded payout script public key
+/// in its coinbase payload. `None` if the payload is truncated.
+pub fn miner_from_coinbase_payload(payload: &[u8]) -> Option<String> {
+    let script_length = *payload.get(SCRIPT_LENGTH_OFFSET)? as usize;
+    let script_start = SCRIPT_LENGTH_OFFSET + 1;
+    let script = payload.get(script_start..script_start + script_length)?;
+    Some(hex::encode(script))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(script: &[u8]) -> Vec<u8> {
+        let mut payload = vec![0; SCRIPT_LENGTH_OFFSET];
+        payload.push(script.len() as u8);
+        payload.extend_from_slice(script);
+        payload
+    }
+
+    #[test]
+    fn miner_is_the_payout_script_whatever_follows_it() {
+        let mut with_extra_data = payload(&[0x20, 0xab, 0xcd]);
+        with_extra_data.extend_from_slice(b"/miner-software/");
+        assert_eq!(miner_from_coinbase_payload(&payload(&[0x20, 0xab, 0xcd])).as_deref(), Some("20abcd"));
+        assert_eq!(miner_from_coinbase_payload(&with_extra_data).as_deref(), Some("20abcd"));
+    }
+
+    #[test]
+    fn truncated_payloads_have_no_miner() {
+        let complete = payload(&[0x20, 0xab, 0xcd]);
+        assert_eq!(miner_from_coinbase_payload(&complete[..complete.len() - 1]), None);
+        assert_eq!(miner_from_coinbase_payload(&complete[..SCRIPT_LENGTH_OFFSET]), None);
+        assert_eq!(miner_from_coinbase_payload(&[]), None);
+    }
+}
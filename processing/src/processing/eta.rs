@@ -0,0 +1,158 @@
+//! Time remaining estimates of a resync, from the rate blocks were stored at
+//! since the current cycle started.
+//!
+//! The estimate of the current cycle is reliable: its block hashes are known
+//! up front. Later cycles only load the blocks the node added in the meantime,
+//! so the overall estimate is coarse. It takes the DAA score gap between the
+//! last stored block and the virtual as the number of blocks left, which
+//! ignores the blocks the node keeps adding while TGI catches up.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Progress of the blocks stored by one resync cycle
+pub struct ResyncProgress {
+    started: Instant,
+    total: usize,
+    virtual_daa_score: u64,
+}
+
+/// Estimated time remaining at some point of a resync cycle
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResyncEta {
+    /// Blocks stored per second since the cycle started
+    pub blocks_per_second: f64,
+    /// Until the last block of the current cycle is stored
+    pub cycle: Option<Duration>,
+    /// Until the virtual DAA score known at the start of the cycle is reached
+    pub overall: Option<Duration>,
+}
+
+impl ResyncProgress {
+    /// Starts timing a cycle storing `total` blocks while the node virtual is
+    /// at `virtual_daa_score`.
+    pub fn start(total: usize, virtual_daa_score: u64) -> Self {
+        Self {
+            started: Instant::now(),
+            total,
+            virtual_daa_score,
+        }
+    }
+
+    /// Estimates the time remaining after `added` blocks of the cycle were
+    /// stored, the last of them at `daa_score`.
+    pub fn eta(&self, added: usize, daa_score: u64) -> ResyncEta {
+        ResyncEta::from_rate(
+            rate(added, self.started.elapsed()),
+            self.total.saturating_sub(added) as u64,
+            self.virtual_daa_score.saturating_sub(daa_score),
+        )
+    }
+}
+
+impl ResyncEta {
+    /// Estimates the time to store `cycle_remaining` more blocks in the
+    /// current cycle and `overall_remaining` more in total at
+    /// `blocks_per_second`. Without a rate yet, there is no estimate.
+    pub fn from_rate(blocks_per_second: f64, cycle_remaining: u64, overall_remaining: u64) -> Self {
+        let remaining = |blocks: u64| {
+            (blocks_per_second > 0.0).then(|| Duration::from_secs_f64(blocks as f64 / blocks_per_second))
+        };
+        Self {
+            blocks_per_second,
+            cycle: remaining(cycle_remaining),
+            // The cycle blocks are part of the overall remainder, whatever
+            // the DAA score gap says
+            overall: remaining(overall_remaining.max(cycle_remaining)),
+        }
+    }
+
+    /// Publishes the estimate as metrics
+    pub fn record(&self) {
+        #[cfg(feature = "metrics")]
+        crate::metrics::record_resync_eta(self.blocks_per_second, self.cycle, self.overall);
+    }
+}
+
+impl fmt::Display for ResyncEta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.1} blocks/s", self.blocks_per_second)?;
+        if let Some(cycle) = self.cycle {
+            write!(f, ", cycle ETA {}", FormattedDuration(cycle))?;
+        }
+        if let Some(overall) = self.overall {
+            write!(f, ", overall ETA ~{}", FormattedDuration(overall))?;
+        }
+        Ok(())
+    }
+}
+
+/// Blocks per second, 0 until time passed
+pub fn rate(count: usize, elapsed: Duration) -> f64 {
+    let seconds = elapsed.as_secs_f64();
+    if seconds > 0.0 {
+        count as f64 / seconds
+    } else {
+        0.0
+    }
+}
+
+/// Formats a duration as hours, minutes and seconds, e.g. `2h05m09s`
+struct FormattedDuration(Duration);
+
+impl fmt::Display for FormattedDuration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let seconds = self.0.as_secs();
+        let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+        if hours > 0 {
+            write!(f, "{}h{:02}m{:02}s", hours, minutes, seconds)
+        } else if minutes > 0 {
+            write!(f, "{}m{:02}s", minutes, seconds)
+        } else {
+            write!(f, "{}s", seconds)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remaining_blocks_at_the_rate() {
+        let eta = ResyncEta::from_rate(10.0, 100, 1000);
+        assert_eq!(eta.cycle, Some(Duration::from_secs(10)));
+        assert_eq!(eta.overall, Some(Duration::from_secs(100)));
+    }
+
+    #[test]
+    fn overall_covers_at_least_the_cycle() {
+        let eta = ResyncEta::from_rate(10.0, 100, 20);
+        assert_eq!(eta.overall, Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn no_estimate_without_a_rate() {
+        let eta = ResyncEta::from_rate(0.0, 100, 1000);
+        assert_eq!((eta.cycle, eta.overall), (None, None));
+        assert_eq!(rate(100, Duration::ZERO), 0.0);
+    }
+
+    #[test]
+    fn rate_is_per_second() {
+        assert_eq!(rate(50, Duration::from_secs(10)), 5.0);
+    }
+
+    #[test]
+    fn formats_hours_minutes_and_seconds() {
+        assert_eq!(FormattedDuration(Duration::from_secs(7509)).to_string(), "2h05m09s");
+        assert_eq!(FormattedDuration(Duration::from_secs(69)).to_string(), "1m09s");
+        assert_eq!(FormattedDuration(Duration::from_secs(9)).to_string(), "9s");
+    }
+
+    #[test]
+    fn displays_the_estimates() {
+        let eta = ResyncEta::from_rate(10.0, 100, 1000);
+        assert_eq!(eta.to_string(), "10.0 blocks/s, cycle ETA 10s, overall ETA ~1m40s");
+    }
+}
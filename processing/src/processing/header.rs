@@ -0,0 +1,39 @@
+//! Raw block headers stored with `--store-headers`.
+//!
+//! The header is stored as the bincode encoding of the `RpcHeader` returned
+//! by the node, which keeps every header field, so fields derived from it
+//! can be recomputed without contacting the node again.
+
+use anyhow::{Context, Result};
+use tondi_rpc_core::model::RpcHeader;
+
+pub fn encode_header(header: &RpcHeader) -> Result<Vec<u8>> {
+    Ok(bincode::serialize(header)?)
+}
+
+pub fn decode_header(blob: &[u8]) -> Result<RpcHeader> {
+    bincode::deserialize(blob).context("Stored header blob is not a valid header")
+}
+
+/// Block fields derived from its header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderFields {
+    /// Block timestamp in milliseconds
+    pub timestamp: i64,
+    pub daa_score: u64,
+    pub blue_score: u64,
+    pub parent_hashes: Vec<String>,
+}
+
+impl HeaderFields {
+    /// Re-derives the header fields of a block from its stored header blob
+    pub fn from_blob(blob: &[u8]) -> Result<Self> {
+        let header = decode_header(blob)?;
+        Ok(Self {
+            timestamp: header.timestamp as i64,
+            daa_score: header.daa_score,
+            blue_score: header.blue_score,
+            parent_hashes: header.direct_parents().iter().map(|h| h.to_string()).collect(),
+        })
+    }
+}
@@ -0,0 +1,76 @@
+//! Rate limiting of warnings that fire once per block, so a storm of them,
+//! e.g. while far out of sync, doesn't bury everything else in the logs.
+
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Minimum time between two warnings with the same key
+const THROTTLE_WINDOW: Duration = Duration::from_secs(10);
+
+struct KeyState {
+    last_logged: Instant,
+    suppressed: u64,
+}
+
+fn key_states() -> &'static Mutex<HashMap<&'static str, KeyState>> {
+    static KEY_STATES: OnceLock<Mutex<HashMap<&'static str, KeyState>>> = OnceLock::new();
+    KEY_STATES.get_or_init(Default::default)
+}
+
+/// Logs the warning built by `message` unless one with the same `key` was
+/// logged less than `THROTTLE_WINDOW` ago. Suppressed warnings are counted
+/// and reported with the next one logged.
+pub fn warn_throttled(key: &'static str, message: impl FnOnce() -> String) {
+    let Some(suppressed) = admit(key, Instant::now()) else {
+        return;
+    };
+    if suppressed > 0 {
+        warn!("{} ({} similar warnings suppressed)", message(), suppressed);
+    } else {
+        warn!("{}", message());
+    }
+}
+
+/// Returns how many warnings with `key` were suppressed since the last one
+/// logged, or `None` when the warning at `now` is suppressed as well.
+fn admit(key: &'static str, now: Instant) -> Option<u64> {
+    let mut key_states = key_states().lock().unwrap_or_else(|e| e.into_inner());
+    match key_states.get_mut(key) {
+        Some(state) if now.duration_since(state.last_logged) < THROTTLE_WINDOW => {
+            state.suppressed += 1;
+            None
+        }
+        Some(state) => {
+            state.last_logged = now;
+            Some(std::mem::take(&mut state.suppressed))
+        }
+        None => {
+            key_states.insert(key, KeyState { last_logged: now, suppressed: 0 });
+            Some(0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn warnings_within_the_window_are_suppressed_and_counted() {
+        let start = Instant::now();
+        assert_eq!(admit("test_suppressed", start), Some(0));
+        assert_eq!(admit("test_suppressed", start + Duration::from_secs(1)), None);
+        assert_eq!(admit("test_suppressed", start + Duration::from_secs(2)), None);
+        assert_eq!(admit("test_suppressed", start + THROTTLE_WINDOW), Some(2));
+        assert_eq!(admit("test_suppressed", start + THROTTLE_WINDOW * 2), Some(0));
+    }
+
+    #[test]
+    fn keys_are_throttled_apart() {
+        let start = Instant::now();
+        assert_eq!(admit("test_first_key", start), Some(0));
+        assert_eq!(admit("test_second_key", start), Some(0));
+    }
+}
@@ -1,16 +1,316 @@
 mod batch;
+mod coinbase;
+mod eta;
+mod header;
+mod log_throttle;
+mod mode;
+#[cfg(all(test, feature = "mock-rpc"))]
+mod tests;
 
 use crate::config::Config;
-use crate::database::{Database, Block, Edge, HeightGroup, AppConfig};
-use crate::rpc_client::{RpcClient, GetBlockDagInfoResponse};
+use crate::database::{Database, Block, BlockColorUpdate, Edge, HeightGroup, AppConfig, CacheWarming, MempoolSample, COLOR_BLUE, COLOR_RED};
+use crate::error::TgiError;
+use crate::alerts::{self, AlertKind};
+use crate::events::{self, BlockEvent, BlockEventSink};
+use crate::rpc_client::{BlockHash, RpcClient, GetBlockDagInfoResponse};
 use crate::rpc_client::types::{BlockAddedNotification, VirtualChainChangedNotification};
 use anyhow::{Context, Result};
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use tracing::{debug, info, warn};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, watch, Mutex};
+use tokio::task::{JoinHandle, JoinSet};
+use tracing::{debug, error, info, warn};
 use tondi_rpc_core::model::RpcBlock;
-use tondi_hashes::Hash;
+use mode::ProcessingMode;
+use eta::ResyncProgress;
+
+pub use batch::DependencyOverflowPolicy;
+
+/// Number of blocks stored per bulk insert when syncing into an empty database
+const BULK_SYNC_CHUNK_SIZE: usize = 1000;
+
+/// Interval between checks of the node sync state while it is in IBD
+const IBD_POLL_INTERVAL: Duration = Duration::from_secs(3);
+
+/// Number of stored blocks recomputed per transaction by `reprocess`
+const REPROCESS_BATCH_SIZE: u64 = 500;
+
+/// Number of blocks missing their selected parent looked up per transaction
+/// by `backfill_selected_parents`
+const SELECTED_PARENT_BACKFILL_BATCH_SIZE: u64 = 500;
+
+/// Chain blocks of a virtual chain change updated at a time, so a deep reorg
+/// doesn't build one huge update. Live changes commit every chunk on its own,
+/// with a `ResumeMarker` left until the last one commits
+const VIRTUAL_CHAIN_CHUNK_SIZE: usize = 1000;
+
+/// Virtual chain changes removing more chain blocks than this raise a deep
+/// reorg alert
+const DEEP_REORG_ALERT_THRESHOLD: usize = 10;
+
+/// Attempts at recomputing the merge set colors of a batch of virtual chain
+/// changes before giving up on it
+const COLOR_UPDATE_ATTEMPTS: u32 = 3;
+const COLOR_UPDATE_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Time between two checks of whether held back colorings got their
+/// confirmations, when no virtual chain change arrives in between
+const COLORING_RECHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time between two samples of the node mempool size with --track-mempool
+const MEMPOOL_SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Blocks deferred until one of their parents is stored are dropped once
+/// none was within `PENDING_ORPHAN_TTL`, checked every
+/// `PENDING_ORPHAN_SWEEP_INTERVAL`
+const PENDING_ORPHAN_TTL: Duration = Duration::from_secs(60 * 60);
+const PENDING_ORPHAN_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Attempts at processing a notification before it is dead-lettered in the
+/// failed_notifications table. The delay grows with every attempt
+const NOTIFICATION_ATTEMPTS: u32 = 3;
+const NOTIFICATION_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Delay before fetching an incomplete block again, growing with every
+/// retry of --incomplete-block-retries
+const INCOMPLETE_BLOCK_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// A parent referenced by a freshly arrived block may briefly be unknown to
+/// the node, so a block whose parent is missing is processed this many times
+/// before the parent is considered out of the node scope
+const MISSING_PARENT_ATTEMPTS: u32 = 3;
+const MISSING_PARENT_RETRY_DELAY: Duration = Duration::from_millis(200);
+
+/// Kinds of the failed_notifications rows
+const BLOCK_ADDED_NOTIFICATION: &str = "block_added";
+const VIRTUAL_CHAIN_CHANGED_NOTIFICATION: &str = "virtual_chain_changed";
+/// Chain blocks whose merge set coloring failed every attempt, keyed by the
+/// added and the removed chain blocks of the given up color updates
+const COLOR_UPDATE_ADDED: &str = "color_update_added";
+const COLOR_UPDATE_REMOVED: &str = "color_update_removed";
+
+static NEW_BLOCK_COUNT: AtomicU64 = AtomicU64::new(0);
+static EXISTING_BLOCK_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// Where a resync stands between two of its transactions
+#[derive(Clone)]
+struct ResyncState {
+    virtual_daa_score: u64,
+    pruning_block: RpcBlock,
+    low_hash: BlockHash,
+    keep_database: bool,
+    vspc_cycle: u64,
+    /// The cycle stopped to commit a part of its blocks, if any
+    interrupted_cycle: Option<InterruptedCycle>,
+}
+
+/// A resync cycle stopped to commit a part of the blocks it loaded, so the
+/// next transaction carries on from there
+#[derive(Clone)]
+struct InterruptedCycle {
+    /// Blocks loaded by the cycle
+    hashes: Vec<BlockHash>,
+    mode: ProcessingMode,
+    /// Index in `hashes` of the first block not processed yet
+    next_index: usize,
+}
+
+/// What the resync does after a cycle
+enum ResyncCycleOutcome {
+    /// Runs the next cycle in the same transaction
+    Continue,
+    /// Commits, then carries on with the interrupted cycle
+    Commit,
+    /// Commits and stops
+    Finished,
+}
+
+/// Blocks processed since startup, split by whether they were already
+/// stored. Many already stored blocks during a resync mean it re-processes
+/// blocks it could have skipped.
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessedBlockCounts {
+    pub new: u64,
+    pub existing: u64,
+}
+
+impl ProcessedBlockCounts {
+    pub fn current() -> Self {
+        Self {
+            new: NEW_BLOCK_COUNT.load(Ordering::Relaxed),
+            existing: EXISTING_BLOCK_COUNT.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Counts since `earlier`
+    pub fn since(self, earlier: Self) -> Self {
+        Self {
+            new: self.new - earlier.new,
+            existing: self.existing - earlier.existing,
+        }
+    }
+
+    /// Share of the processed blocks that were already stored
+    pub fn existing_ratio(self) -> f64 {
+        let total = self.new + self.existing;
+        if total == 0 {
+            0.0
+        } else {
+            self.existing as f64 / total as f64
+        }
+    }
+}
+
+/// Settings that control how a single block is processed
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BlockProcessingOptions {
+    /// Fail instead of skipping merge set blocks missing from the database
+    pub strict_merge_set: bool,
+    /// Blocks above this height are not stored
+    pub max_height: Option<u64>,
+    /// Fetch block transactions to record the miner of every block
+    pub track_miners: bool,
+    /// What to do with a block that has too many missing dependencies
+    pub dependency_overflow_policy: DependencyOverflowPolicy,
+    /// Store the raw header of every block to re-derive header fields offline
+    pub store_headers: bool,
+    /// Process stored blocks again even when an earlier processing already
+    /// set their selected parent and merge sets
+    pub reprocess_complete_blocks: bool,
+    /// Times a block lacking verbose data is fetched again before it is left
+    /// incomplete
+    pub incomplete_block_retries: u32,
+    /// Fail with `ParentNotYetFound` on a parent the node doesn't know
+    /// instead of ignoring it, see `with_missing_parent_retries`
+    pub wait_for_missing_parents: bool,
+    /// The network genesis block. Without it, any block without parents is
+    /// taken for the genesis
+    pub genesis_hash: Option<BlockHash>,
+}
+
+impl BlockProcessingOptions {
+    /// Whether stored blocks an earlier processing completed are skipped.
+    /// Miners need the transactions checked again
+    pub fn skips_complete_blocks(&self) -> bool {
+        !self.reprocess_complete_blocks && !self.track_miners
+    }
+}
+
+/// Settings of a resync, see `Config` for each of them
+#[derive(Debug, Clone, Default)]
+pub struct ResyncOptions {
+    pub clear_db: bool,
+    pub resync: bool,
+    pub abort_if_pruned: bool,
+    pub vspc_threshold: usize,
+    pub tip_threshold: usize,
+    pub prefetch_blocks: usize,
+    pub resync_from: Option<String>,
+    pub disable_coloring: bool,
+    pub cache_warm_blocks: Option<u64>,
+    pub parallel_fetch: bool,
+    /// Most blocks processed in one transaction, without limit when `None`
+    pub transaction_blocks: Option<usize>,
+    pub block_processing: BlockProcessingOptions,
+}
+
+/// Settings of the virtual chain worker, see `Config` for each of them
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VirtualChainOptions {
+    pub track_accepted_transactions: bool,
+    pub disable_coloring: bool,
+    pub color_batch_size: usize,
+    pub coloring_confirmations: u64,
+}
+
+/// A block that is about to be stored, as described by the node
+pub struct NewBlock {
+    pub hash: BlockHash,
+    pub timestamp: i64,
+    pub daa_score: u64,
+    pub parent_hashes: Vec<BlockHash>,
+}
+
+/// Merge set of a chain block added to the virtual selected parent chain
+#[derive(Clone)]
+struct ChainMergeSet {
+    chain_block_hash: BlockHash,
+    blue_hashes: Vec<BlockHash>,
+    red_hashes: Vec<BlockHash>,
+}
+
+/// Virtual selected parent chain fetched from the node ahead of the
+/// transaction storing it
+struct VirtualChainResync {
+    removed_hashes: Vec<BlockHash>,
+    added_hashes: Vec<BlockHash>,
+    merge_sets: Vec<ChainMergeSet>,
+}
+
+/// Records that a block was processed, for the stall watchdog and the
+/// metrics.
+async fn record_progress(last_progress: &Mutex<Instant>) {
+    let now = Instant::now();
+    *last_progress.lock().await = now;
+    #[cfg(feature = "metrics")]
+    crate::metrics::record_progress(now);
+}
+
+/// Tells whether the node advanced since the previous stall check. The
+/// first check only records where the node is, as there is nothing to
+/// compare it with.
+#[derive(Default)]
+struct StallCheck {
+    last_virtual_daa_score: Option<u64>,
+}
+
+impl StallCheck {
+    fn node_advanced(&mut self, virtual_daa_score: u64) -> bool {
+        let advanced = self.last_virtual_daa_score.is_some_and(|score| virtual_daa_score > score);
+        self.last_virtual_daa_score = Some(virtual_daa_score);
+        advanced
+    }
+}
+
+/// Merge set coloring still pending for a stored virtual chain change
+struct ColorUpdate {
+    removed_block_ids: Vec<u64>,
+    added_chain_block_hashes: Vec<BlockHash>,
+}
+
+/// A part of a virtual chain change stored in its own transaction
+enum VirtualChainPart {
+    Chain { removed_hashes: Vec<BlockHash>, added_hashes: Vec<BlockHash> },
+    AcceptedTransactions(Vec<(BlockHash, Vec<String>)>),
+}
+
+/// The failed_notifications row a transaction of a virtual chain change
+/// stored in several transactions adds or removes, so a change interrupted
+/// between two of them is replayed from its start on the next start
+#[derive(Clone, Copy)]
+enum ResumeMarker {
+    None,
+    Add(BlockHash),
+    Remove(BlockHash),
+}
+
+impl ResumeMarker {
+    async fn store(self, database: &Database, tx: &tokio_postgres::Transaction<'_>) -> Result<()> {
+        match self {
+            ResumeMarker::None => {}
+            ResumeMarker::Add(block_hash) => {
+                database.add_failed_notification(tx, VIRTUAL_CHAIN_CHANGED_NOTIFICATION, &block_hash, "interrupted").await?;
+            }
+            ResumeMarker::Remove(block_hash) => {
+                database.remove_failed_notification(tx, VIRTUAL_CHAIN_CHANGED_NOTIFICATION, &block_hash).await?;
+            }
+        }
+        Ok(())
+    }
+}
 
 pub struct Processing {
     config: Config,
@@ -18,10 +318,26 @@ pub struct Processing {
     rpc_client: Arc<RpcClient>,
     app_config: Arc<Mutex<AppConfig>>,
     syncing: Arc<Mutex<bool>>,
+    last_progress: Arc<Mutex<Instant>>,
+    event_sink: Arc<dyn BlockEventSink>,
+    shutdown: watch::Sender<bool>,
+    virtual_chain_worker: Mutex<Option<JoinHandle<()>>>,
 }
 
 impl Processing {
     pub async fn new(config: Config, database: Database, rpc_client: RpcClient) -> Result<Self> {
+        let event_sink = events::sink_from_config(&config).await?;
+        Self::new_with_event_sink(config, database, rpc_client, event_sink.into()).await
+    }
+
+    /// Like `new`, but publishes processed blocks to the given sink instead of
+    /// the one selected by the configuration.
+    pub async fn new_with_event_sink(
+        config: Config,
+        database: Database,
+        rpc_client: RpcClient,
+        event_sink: Arc<dyn BlockEventSink>,
+    ) -> Result<Self> {
         let app_config = Arc::new(Mutex::new(AppConfig {
             id: true,
             tondid_version: "unknown".to_string(),
@@ -29,12 +345,19 @@ impl Processing {
             network: config.network(),
         }));
 
+        let started = Instant::now();
+        #[cfg(feature = "metrics")]
+        crate::metrics::record_progress(started);
         let processing = Self {
             config,
             database,
             rpc_client: Arc::new(rpc_client),
             app_config,
             syncing: Arc::new(Mutex::new(false)),
+            last_progress: Arc::new(Mutex::new(started)),
+            event_sink,
+            shutdown: watch::channel(false).0,
+            virtual_chain_worker: Mutex::new(None),
         };
 
         processing.init().await?;
@@ -42,15 +365,571 @@ impl Processing {
         Ok(processing)
     }
 
+    /// Stops the virtual chain worker once it stored the changes it holds
+    /// back, so they aren't lost when the process exits.
+    pub async fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+        if let Some(virtual_chain_worker) = self.virtual_chain_worker.lock().await.take() {
+            if let Err(e) = virtual_chain_worker.await {
+                error!("Virtual chain worker failed: {}", e);
+            }
+        }
+    }
+
     async fn init(&self) -> Result<()> {
+        self.database.check_schema().await?;
+        if self.config.genesis_hash().is_none() {
+            warn!("--genesis-hash is not set; any block without parents is taken for the genesis block");
+        }
         self.update_rpc_client_version().await?;
         self.register_app_config().await?;
         self.wait_for_synced_rpc_client().await?;
         self.resync_database().await?;
+        self.retry_failed_notifications().await?;
         self.initialize_consensus_events_handler().await?;
+        self.start_stall_watchdog();
+        self.start_pruning_task();
+        self.start_mempool_sampler();
+        self.start_orphan_sweeper();
+        Ok(())
+    }
+
+    /// Processes the added blocks, replays the virtual chain changes and
+    /// recomputes the merge set colors whose notification or coloring failed
+    /// for good in an earlier run.
+    async fn retry_failed_notifications(&self) -> Result<()> {
+        let database_for_closure = self.database.clone();
+        let failed_hashes = self.database.run_in_read_transaction(move |tx| {
+            Box::pin(async move {
+                Ok(database_for_closure.failed_notifications(tx, BLOCK_ADDED_NOTIFICATION).await?)
+            })
+        }).await?;
+        if !failed_hashes.is_empty() {
+            info!("Retrying {} block added notifications that failed before", failed_hashes.len());
+        }
+        let options = self.config.block_processing_options();
+        for block_hash in failed_hashes {
+            let block = match self.rpc_client.get_block(&block_hash, options.track_miners).await {
+                Ok(response) => Some(Self::fetch_complete_block(&self.rpc_client, response.block, options).await?),
+                Err(TgiError::BlockNotFound(_)) => {
+                    warn!("Failed block {} is no longer known to the node; dropping it", block_hash);
+                    None
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let block = Arc::new(block);
+            Self::with_missing_parent_retries(options, |options| {
+                let database_for_closure = self.database.clone();
+                let rpc_client_for_closure = self.rpc_client.clone();
+                let block = block.clone();
+                self.database.run_in_transaction(move |tx| {
+                    Box::pin(async move {
+                        if let Some(block) = block.as_ref() {
+                            Self::process_block_and_dependencies_static(
+                                &database_for_closure, tx, &rpc_client_for_closure, &block_hash, block, None, options
+                            ).await?;
+                        }
+                        database_for_closure.remove_failed_notification(tx, BLOCK_ADDED_NOTIFICATION, &block_hash).await?;
+                        Ok(())
+                    })
+                })
+            }).await?;
+        }
+
+        let database_for_closure = self.database.clone();
+        let failed_hashes = self.database.run_in_read_transaction(move |tx| {
+            Box::pin(async move {
+                Ok(database_for_closure.failed_notifications(tx, VIRTUAL_CHAIN_CHANGED_NOTIFICATION).await?)
+            })
+        }).await?;
+        if !failed_hashes.is_empty() {
+            info!("Replaying {} virtual chain changed notifications that failed before", failed_hashes.len());
+        }
+        for block_hash in failed_hashes {
+            let replayed = Self::replay_virtual_chain_change(
+                &self.database, &self.rpc_client, &block_hash,
+                self.config.track_accepted_transactions(), self.config.disable_coloring(),
+            ).await;
+            if let Err(e) = replayed {
+                warn!("Could not replay the virtual chain change from block {}; retrying on the next start: {}", block_hash, e);
+                continue;
+            }
+            let database_for_closure = self.database.clone();
+            self.database.run_in_transaction(move |tx| {
+                Box::pin(async move {
+                    Ok(database_for_closure.remove_failed_notification(tx, VIRTUAL_CHAIN_CHANGED_NOTIFICATION, &block_hash).await?)
+                })
+            }).await?;
+        }
+
+        if !self.config.disable_coloring() {
+            if let Err(e) = Self::retry_failed_color_updates(&self.database, &self.rpc_client).await {
+                warn!("Could not recompute the merge set colors that failed before; retrying on the next start: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Replays a failed virtual chain change, recorded under the chain block
+    /// it starts from, by fetching the virtual chain from that block again.
+    /// The fetched change also covers every later change, which is stored
+    /// again harmlessly.
+    async fn replay_virtual_chain_change(
+        database: &Database,
+        rpc_client: &RpcClient,
+        start_hash: &BlockHash,
+        track_accepted_transactions: bool,
+        disable_coloring: bool,
+    ) -> Result<()> {
+        let response = rpc_client.get_virtual_chain_from_block(start_hash, track_accepted_transactions).await?;
+        // The start block is only listed when it left the chain since
+        let start_hash = start_hash.as_rpc_hash();
+        let mut added_chain_block_hashes = response.added_chain_block_hashes;
+        if !response.removed_chain_block_hashes.contains(&start_hash) {
+            added_chain_block_hashes.insert(0, start_hash);
+        }
+        let notification = VirtualChainChangedNotification {
+            removed_chain_block_hashes: Arc::new(response.removed_chain_block_hashes),
+            added_chain_block_hashes: Arc::new(added_chain_block_hashes),
+            accepted_transaction_ids: Arc::new(response.accepted_transaction_ids),
+        };
+
+        let color_updates = Self::process_virtual_chain_changed_notification(
+            database, notification, track_accepted_transactions
+        ).await?;
+        if !disable_coloring && !color_updates.is_empty() {
+            Self::apply_color_updates(database, rpc_client, Arc::new(color_updates)).await?;
+        }
+        Ok(())
+    }
+
+    /// Resyncs the virtual selected parent chain whenever no block was
+    /// processed within the stall timeout while the node kept advancing.
+    fn start_stall_watchdog(&self) {
+        let stall_timeout = match self.config.stall_timeout() {
+            Some(stall_timeout) => stall_timeout,
+            None => return,
+        };
+        let database = self.database.clone();
+        let rpc_client = self.rpc_client.clone();
+        let last_progress = self.last_progress.clone();
+        let disable_coloring = self.config.disable_coloring();
+        tokio::spawn(async move {
+            let mut stall_check = StallCheck::default();
+            loop {
+                tokio::time::sleep(stall_timeout / 2).await;
+
+                let elapsed = last_progress.lock().await.elapsed();
+                if elapsed < stall_timeout {
+                    continue;
+                }
+
+                let virtual_daa_score = match rpc_client.get_block_dag_info().await {
+                    Ok(dag_info) => dag_info.virtual_daa_score,
+                    Err(e) => {
+                        warn!("Stall watchdog could not reach the node: {}", e);
+                        continue;
+                    }
+                };
+                if !stall_check.node_advanced(virtual_daa_score) {
+                    continue;
+                }
+
+                let message = format!(
+                    "No block was processed in the last {} seconds while the node kept advancing; resyncing the virtual selected parent chain",
+                    elapsed.as_secs()
+                );
+                error!("{}", message);
+                alerts::raise(AlertKind::SyncStalled, message);
+                // The node is asked before the transaction starts, so the
+                // write connection isn't held across RPC calls
+                let chain = match Self::fetch_virtual_selected_parent_chain(&rpc_client, disable_coloring).await {
+                    Ok(chain) => Arc::new(chain),
+                    Err(e) => {
+                        warn!("Stall watchdog failed to fetch the virtual selected parent chain: {}", e);
+                        continue;
+                    }
+                };
+                let database_for_closure = database.clone();
+                let result = database.run_in_transaction(move |tx| {
+                    let chain = chain.clone();
+                    Box::pin(async move {
+                        Self::store_virtual_selected_parent_chain(&database_for_closure, tx, &chain, disable_coloring).await
+                    })
+                }).await;
+                match result {
+                    Ok(()) => record_progress(&last_progress).await,
+                    Err(e) => warn!("Stall watchdog failed to resync the virtual selected parent chain: {}", e),
+                }
+            }
+        });
+    }
+
+    /// Follows the node pruning point, deleting the blocks it pruned except
+    /// for the virtual selected parent chain.
+    fn start_pruning_task(&self) {
+        let prune_interval = match self.config.prune_interval() {
+            Some(prune_interval) => prune_interval,
+            None => return,
+        };
+        let database = self.database.clone();
+        let rpc_client = self.rpc_client.clone();
+        tokio::spawn(async move {
+            let mut last_pruning_point = None;
+            loop {
+                tokio::time::sleep(prune_interval).await;
+
+                let pruning_point_hash = match rpc_client.get_block_dag_info().await {
+                    Ok(dag_info) => BlockHash::from(dag_info.pruning_point_hash),
+                    Err(e) => {
+                        warn!("Pruning task could not reach the node: {}", e);
+                        continue;
+                    }
+                };
+                if last_pruning_point.as_ref() == Some(&pruning_point_hash) {
+                    continue;
+                }
+
+                let database_for_closure = database.clone();
+                let pruning_point_for_closure = pruning_point_hash;
+                let result = database.run_in_transaction(move |tx| {
+                    Box::pin(async move {
+                        // A pruning point that isn't stored yet is handled on a later pass
+                        let Some(pruning_block) = database_for_closure.block_by_hash(tx, &pruning_point_for_closure).await? else {
+                            return Ok(None);
+                        };
+                        let pruned = database_for_closure.prune_below_daa_score(tx, pruning_block.daa_score).await?;
+                        Ok(Some(pruned))
+                    })
+                }).await;
+                match result {
+                    Ok(Some(pruned)) => {
+                        info!("Pruned {} blocks below the pruning point {}", pruned, pruning_point_hash);
+                        last_pruning_point = Some(pruning_point_hash);
+                    }
+                    Ok(None) => debug!("Pruning point {} is not stored yet; pruning postponed", pruning_point_hash),
+                    Err(e) => warn!("Failed to prune blocks below the pruning point {}: {}", pruning_point_hash, e),
+                }
+            }
+        });
+    }
+
+    /// Records the size of the node mempool every `MEMPOOL_SAMPLE_INTERVAL`.
+    fn start_mempool_sampler(&self) {
+        if !self.config.track_mempool() {
+            return;
+        }
+        let database = self.database.clone();
+        let rpc_client = self.rpc_client.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(MEMPOOL_SAMPLE_INTERVAL).await;
+                match Self::sample_mempool(&database, &rpc_client).await {
+                    Ok(sample) => debug!(
+                        "Mempool holds {} transactions and {} orphans", sample.transaction_count, sample.orphan_count
+                    ),
+                    Err(e) => warn!("Failed to sample the mempool: {}", e),
+                }
+            }
+        });
+    }
+
+    /// Drops the deferred blocks none of whose parents arrived within
+    /// `PENDING_ORPHAN_TTL`, every `PENDING_ORPHAN_SWEEP_INTERVAL`.
+    fn start_orphan_sweeper(&self) {
+        let database = self.database.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(PENDING_ORPHAN_SWEEP_INTERVAL).await;
+                if let Err(e) = Self::sweep_pending_orphan_blocks(&database, PENDING_ORPHAN_TTL).await {
+                    warn!("Failed to sweep the deferred blocks: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Drops the deferred blocks older than `ttl` and returns them.
+    async fn sweep_pending_orphan_blocks(database: &Database, ttl: Duration) -> Result<Vec<BlockHash>> {
+        let deferred_before = SystemTime::now().checked_sub(ttl).unwrap_or(UNIX_EPOCH)
+            .duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_millis() as i64);
+        let database_for_closure = database.clone();
+        let (expired, pending) = database.run_in_transaction(move |tx| {
+            Box::pin(async move {
+                let expired = database_for_closure.expire_pending_orphan_blocks(tx, deferred_before).await?;
+                let pending = database_for_closure.pending_orphan_block_count(tx).await?;
+                Ok((expired, pending))
+            })
+        }).await?;
+        if !expired.is_empty() {
+            warn!(
+                "Dropped {} deferred blocks none of whose parents was stored within {:?}, e.g. {}",
+                expired.len(), ttl, expired[0]
+            );
+        }
+        debug!("{} blocks are deferred until one of their parents is stored", pending);
+        #[cfg(feature = "metrics")]
+        crate::metrics::record_pending_orphan_blocks(pending, expired.len());
+        Ok(expired)
+    }
+
+    /// Counts the transactions and orphans in the node mempool and stores
+    /// them as a sample taken now.
+    async fn sample_mempool(database: &Database, rpc_client: &RpcClient) -> Result<MempoolSample> {
+        let entries = rpc_client.get_mempool_entries(true, false).await
+            .context("Mempool sampler could not reach the node")?;
+        let orphan_count = entries.iter().filter(|entry| entry.is_orphan).count() as u64;
+        let sample = MempoolSample {
+            sampled_at: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_millis() as i64),
+            transaction_count: entries.len() as u64 - orphan_count,
+            orphan_count,
+            total_fees: entries.iter().filter(|entry| !entry.is_orphan).map(|entry| entry.fee).sum(),
+        };
+
+        let database_for_closure = database.clone();
+        let sample_for_closure = sample;
+        database.run_in_transaction(move |tx| {
+            Box::pin(async move {
+                Ok(database_for_closure.insert_mempool_sample(tx, &sample_for_closure).await?)
+            })
+        }).await?;
+        Ok(sample)
+    }
+
+    /// Recomputes the selected parents, merge sets and colors of the stored
+    /// blocks between two heights, in height order and one batch per
+    /// transaction, from the verbose data of the node. Blocks aren't inserted
+    /// or removed. Blocks are read in pages keyed on (height, id), so pages
+    /// aren't shifted by the rows rewritten in between. Every block in the
+    /// range is colored again, from the merge sets of the chain blocks in it
+    /// or, for blocks near its top, above it.
+    pub async fn reprocess(
+        database: &Database,
+        rpc_client: &RpcClient,
+        from_height: u64,
+        to_height: u64,
+        options: BlockProcessingOptions,
+    ) -> Result<()> {
+        info!("Reprocessing stored blocks between heights {} and {}", from_height, to_height);
+        let options = BlockProcessingOptions { reprocess_complete_blocks: true, ..options };
+        let mut after = (from_height, 0);
+        let mut reprocessed = 0;
+        // Blocks of the range no merge set colored yet
+        let mut uncolored = HashSet::new();
+        loop {
+            let database_for_closure = database.clone();
+            let blocks = database.run_in_read_transaction(move |tx| {
+                Box::pin(async move {
+                    Ok(database_for_closure.blocks_between_heights_after(tx, from_height, to_height, after, REPROCESS_BATCH_SIZE).await?)
+                })
+            }).await?;
+            let Some(last) = blocks.last() else { break };
+            after = (last.height, last.id);
+            reprocessed += blocks.len();
+            uncolored.extend(blocks.iter().map(|block| block.id));
+
+            let database_for_closure = database.clone();
+            let blocks_for_closure = blocks.clone();
+            let rederived = database.run_in_transaction(move |tx| {
+                Box::pin(async move {
+                    Ok(Self::rederive_header_fields(&database_for_closure, tx, &blocks_for_closure).await?)
+                })
+            }).await?;
+            if rederived > 0 {
+                debug!("Re-derived header fields of {} blocks from their stored headers", rederived);
+            }
+
+            let mut rpc_blocks = Vec::with_capacity(blocks.len());
+            let mut merge_sets = Vec::new();
+            for block in &blocks {
+                let rpc_block = match rpc_client.get_block(&block.block_hash, options.track_miners).await {
+                    Ok(rpc_block_resp) => Self::fetch_complete_block(rpc_client, rpc_block_resp.block, options).await?,
+                    Err(TgiError::BlockNotFound(_)) => {
+                        warn!("Block {} is no longer known to the node; not reprocessed", block.block_hash);
+                        continue;
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                if block.is_in_virtual_selected_parent_chain {
+                    if let Some(verbose_data) = &rpc_block.verbose_data {
+                        merge_sets.push(ChainMergeSet {
+                            chain_block_hash: rpc_block.header.hash.into(),
+                            blue_hashes: verbose_data.merge_set_blues_hashes.iter().map(|&hash| hash.into()).collect(),
+                            red_hashes: verbose_data.merge_set_reds_hashes.iter().map(|&hash| hash.into()).collect(),
+                        });
+                    }
+                }
+                rpc_blocks.push(rpc_block);
+            }
+
+            let rpc_blocks = Arc::new(rpc_blocks);
+            let database_for_closure = database.clone();
+            let rpc_client_for_closure = rpc_client.clone();
+            let colored = database.run_in_transaction(move |tx| {
+                Box::pin(async move {
+                    for rpc_block in rpc_blocks.iter() {
+                        Self::process_block_static(&database_for_closure, tx, &rpc_client_for_closure, rpc_block, None, options, false, false).await?;
+                    }
+                    let block_colors = Self::color_chain_merge_sets(&database_for_closure, tx, &[], &merge_sets).await?;
+                    let color_updates: Vec<BlockColorUpdate> = block_colors.into_values().collect();
+                    database_for_closure.update_block_colors(tx, &color_updates).await?;
+                    Ok(color_updates.into_iter().map(|update| update.block_id).collect::<Vec<_>>())
+                })
+            }).await?;
+            for block_id in colored {
+                uncolored.remove(&block_id);
+            }
+            info!("Reprocessed {} blocks", reprocessed);
+        }
+
+        Self::recolor_from_chain_above(database, rpc_client, to_height, uncolored).await?;
+        info!("Finished reprocessing {} blocks", reprocessed);
+        Ok(())
+    }
+
+    /// Colors the `uncolored` blocks of a reprocessed range up to `to_height`
+    /// from the merge sets of the chain blocks above it, which merge the
+    /// blocks near its top. Blocks no stored chain block merges turn gray.
+    async fn recolor_from_chain_above(
+        database: &Database,
+        rpc_client: &RpcClient,
+        to_height: u64,
+        mut uncolored: HashSet<u64>,
+    ) -> Result<()> {
+        let mut after = (to_height, i64::MAX as u64);
+        while !uncolored.is_empty() {
+            let database_for_closure = database.clone();
+            let chain_blocks = database.run_in_read_transaction(move |tx| {
+                Box::pin(async move {
+                    Ok(database_for_closure.chain_blocks_after(tx, after, REPROCESS_BATCH_SIZE).await?)
+                })
+            }).await?;
+            let Some(last) = chain_blocks.last() else { break };
+            after = (last.height, last.id);
+            let chain_block_hashes = chain_blocks.iter()
+                .map(|block| BlockHash::parse(&block.block_hash))
+                .collect::<Result<Vec<_>, _>>()?;
+            let merge_sets = Self::fetch_chain_merge_sets(rpc_client, &chain_block_hashes).await?;
+
+            let database_for_closure = database.clone();
+            let uncolored_for_closure = Arc::new(uncolored.clone());
+            let colored = database.run_in_transaction(move |tx| {
+                Box::pin(async move {
+                    let block_colors = Self::color_chain_merge_sets(&database_for_closure, tx, &[], &merge_sets).await?;
+                    let color_updates: Vec<BlockColorUpdate> = block_colors.into_values()
+                        .filter(|update| uncolored_for_closure.contains(&update.block_id))
+                        .collect();
+                    database_for_closure.update_block_colors(tx, &color_updates).await?;
+                    Ok(color_updates.into_iter().map(|update| update.block_id).collect::<Vec<_>>())
+                })
+            }).await?;
+            for block_id in colored {
+                uncolored.remove(&block_id);
+            }
+        }
+
+        if !uncolored.is_empty() {
+            debug!("{} reprocessed blocks aren't merged by any stored chain block; turning them gray", uncolored.len());
+            let color_updates: Vec<BlockColorUpdate> = uncolored.into_iter().map(BlockColorUpdate::gray).collect();
+            let database_for_closure = database.clone();
+            database.run_in_transaction(move |tx| {
+                Box::pin(async move {
+                    database_for_closure.update_block_colors(tx, &color_updates).await?;
+                    Ok(())
+                })
+            }).await?;
+        }
+        Ok(())
+    }
+
+    /// Sets the selected parent of the stored blocks that have parents but
+    /// none, whenever the node-reported selected parent is stored by now.
+    /// Blocks the node no longer knows, or whose selected parent isn't
+    /// stored, are left alone and counted as unresolvable.
+    pub async fn backfill_selected_parents(database: &Database, rpc_client: &RpcClient) -> Result<()> {
+        info!("Backfilling missing selected parents");
+        let mut after_id = 0;
+        let mut resolved = 0;
+        let mut unresolvable = 0;
+        loop {
+            let database_for_closure = database.clone();
+            let blocks = database.run_in_read_transaction(move |tx| {
+                Box::pin(async move {
+                    Ok(database_for_closure.blocks_without_selected_parent(tx, after_id, SELECTED_PARENT_BACKFILL_BATCH_SIZE).await?)
+                })
+            }).await?;
+            let Some((last_id, _)) = blocks.last() else {
+                break;
+            };
+            after_id = *last_id;
+
+            let mut selected_parents = Vec::with_capacity(blocks.len());
+            for (block_id, block_hash) in blocks {
+                let verbose_data = match rpc_client.get_block(&block_hash, false).await {
+                    Ok(response) => response.block.verbose_data,
+                    Err(TgiError::BlockNotFound(_)) => None,
+                    Err(e) => return Err(e.into()),
+                };
+                match verbose_data {
+                    Some(verbose_data) => selected_parents.push((block_id, block_hash, BlockHash::from(verbose_data.selected_parent_hash))),
+                    None => {
+                        debug!("The node has no verbose data for block {}; its selected parent is unresolvable", block_hash);
+                        unresolvable += 1;
+                    }
+                }
+            }
+
+            let database_for_closure = database.clone();
+            let (batch_resolved, batch_unresolvable) = database.run_in_transaction(move |tx| {
+                Box::pin(async move {
+                    let mut resolved_ids = Vec::new();
+                    let mut unresolvable = 0;
+                    for (block_id, block_hash, selected_parent_hash) in &selected_parents {
+                        match database_for_closure.block_id_by_hash(tx, selected_parent_hash).await {
+                            Ok(selected_parent_id) => {
+                                database_for_closure.update_block_selected_parent(tx, *block_id, selected_parent_id).await?;
+                                resolved_ids.push(*block_id);
+                            }
+                            Err(TgiError::BlockNotFound(_)) => {
+                                debug!("Selected parent {} of block {} is not stored", selected_parent_hash, block_hash);
+                                unresolvable += 1;
+                            }
+                            Err(e) => return Err(e.into()),
+                        }
+                    }
+                    // Blocks that got their selected parent wait for it no more
+                    database_for_closure.remove_pending_selected_parents(tx, &resolved_ids).await?;
+                    Ok((resolved_ids.len(), unresolvable))
+                })
+            }).await?;
+            resolved += batch_resolved;
+            unresolvable += batch_unresolvable;
+            info!("Backfilled {} selected parents so far, {} unresolvable", resolved, unresolvable);
+        }
+        info!("Finished backfilling: {} selected parents set, {} blocks still without one", resolved, unresolvable);
         Ok(())
     }
 
+    /// Rewrites the header-derived fields of the blocks stored with their
+    /// raw header, without contacting the node. Returns how many blocks had
+    /// one.
+    async fn rederive_header_fields(
+        database: &Database,
+        tx: &tokio_postgres::Transaction<'_>,
+        blocks: &[Block],
+    ) -> Result<usize> {
+        let mut rederived = 0;
+        for block in blocks {
+            let Some(header_blob) = database.get_header_blob(tx, &BlockHash::parse(&block.block_hash)?).await? else {
+                continue;
+            };
+            let fields = header::HeaderFields::from_blob(&header_blob)
+                .with_context(|| format!("Could not decode stored header of block {}", block.block_hash))?;
+            database.update_block_header_fields(tx, block.id, fields.timestamp, fields.daa_score).await?;
+            rederived += 1;
+        }
+        Ok(rederived)
+    }
+
     async fn update_rpc_client_version(&self) -> Result<()> {
         let info = self.rpc_client.get_info().await?;
         let mut app_config = self.app_config.lock().await;
@@ -72,7 +951,7 @@ impl Processing {
             let app_config = app_config_clone.clone();
             let database = database.clone();
             Box::pin(async move {
-                database.store_app_config(tx, &app_config).await
+                Ok(database.store_app_config(tx, &app_config).await?)
             })
         }).await?;
 
@@ -80,10 +959,19 @@ impl Processing {
         Ok(())
     }
 
+    /// Waits for the node to finish IBD. When its header and block counts
+    /// stop moving for the configured number of polls, the likely cause is
+    /// logged and, if configured, waiting is abandoned.
     async fn wait_for_synced_rpc_client(&self) -> Result<()> {
+        Self::wait_for_synced_node_static(&self.rpc_client, self.config.ibd_stall_polls(), self.config.abort_on_ibd_stall()).await
+    }
+
+    async fn wait_for_synced_node_static(rpc_client: &RpcClient, stall_polls: u32, abort_on_stall: bool) -> Result<()> {
         let mut cycle = 0;
+        let mut last_counts = None;
+        let mut polls_without_progress = 0;
         loop {
-            let info = self.rpc_client.get_info().await?;
+            let info = rpc_client.get_info().await?;
             if info.is_synced {
                 info!("Node is synced");
                 return Ok(());
@@ -91,7 +979,39 @@ impl Processing {
             if cycle == 0 {
                 info!("Waiting for the node to finish IBD...");
             }
-            tokio::time::sleep(tokio::time::Duration::from_secs(3)).await;
+
+            let dag_info = rpc_client.get_block_dag_info().await?;
+            let counts = (dag_info.header_count, dag_info.block_count);
+            if last_counts == Some(counts) {
+                polls_without_progress += 1;
+            } else {
+                polls_without_progress = 0;
+            }
+            last_counts = Some(counts);
+
+            if stall_polls > 0 && polls_without_progress == stall_polls {
+                let peer_count = rpc_client.get_connected_peer_info().await?.peer_info.len();
+                let cause = if peer_count == 0 {
+                    "the node has no peers; check its network connectivity and --connect/--addpeer settings".to_string()
+                } else {
+                    format!("the node has {} peers but none of them sends it new data", peer_count)
+                };
+                if abort_on_stall {
+                    anyhow::bail!(
+                        "The node IBD made no progress in {} polls ({} headers, {} blocks): {}",
+                        polls_without_progress, counts.0, counts.1, cause
+                    );
+                }
+                let message = format!(
+                    "The node IBD made no progress in {} polls ({} headers, {} blocks): {}",
+                    polls_without_progress, counts.0, counts.1, cause
+                );
+                error!("{}. Still waiting", message);
+                alerts::raise(AlertKind::SyncStalled, message);
+                polls_without_progress = 0;
+            }
+
+            tokio::time::sleep(IBD_POLL_INTERVAL).await;
             cycle += 1;
         }
     }
@@ -101,289 +1021,1197 @@ impl Processing {
         *syncing = true;
         drop(syncing);
 
-        let rpc_client = self.rpc_client.clone();
-        let database = self.database.clone();
-        let config_clear_db = self.config.clear_db();
-        let config_resync = self.config.resync();
-
-        self.database.run_in_transaction(move |tx| {
-            Box::pin(async move {
-                info!("Resyncing database");
-                
-                let dag_info = rpc_client.get_block_dag_info().await?;
-                let pruning_point_hash_str = dag_info.pruning_point_hash.to_string();
-                
-                let pruning_block_resp = rpc_client.get_block(&pruning_point_hash_str, false).await?;
-                let pruning_block = pruning_block_resp.block;
-                
-                let has_pruning_block = database.does_block_exist(tx, &pruning_point_hash_str).await?;
-                
-                let mut low_hash = pruning_point_hash_str.clone();
-                let mut keep_database = has_pruning_block && !config_clear_db;
-                
-                if keep_database {
-                    info!("Pruning point {} already in the database", pruning_point_hash_str);
-                    info!("Database kept");
-                    
-                    let pruning_block_height = database.block_height_by_hash(tx, &pruning_point_hash_str).await?;
-                    
-                    info!("Loading cache");
-                    database.load_cache(tx, pruning_block_height).await?;
-                    info!("Cache loaded from the database");
-                    
-                    info!("Searching for an optimal sync starting point");
-                    low_hash = Self::find_optimal_sync_starting_block(
-                        &database, tx, &rpc_client, &pruning_point_hash_str, 
-                        pruning_block.header.daa_score
-                    ).await?;
-                    if low_hash != pruning_point_hash_str {
-                        info!("Optimal sync starting point set at {}", low_hash);
-                    } else {
-                        info!("Sync starting point set at the pruning point");
-                    }
-                } else {
-                    database.clear(tx).await?;
-                    info!("Database cleared");
-                    
-                    let pruning_database_block = Block {
-                        id: 0,
-                        block_hash: pruning_point_hash_str.clone(),
-                        timestamp: pruning_block.header.timestamp as i64,
-                        parent_ids: vec![],
-                        daa_score: pruning_block.header.daa_score,
-                        height: 0,
-                        height_group_index: 0,
-                        selected_parent_id: None,
-                        color: "gray".to_string(),
-                        is_in_virtual_selected_parent_chain: true,
-                        merge_set_red_ids: vec![],
-                        merge_set_blue_ids: vec![],
-                    };
-                    database.insert_block(tx, &pruning_point_hash_str, &pruning_database_block).await?;
-                    
-                    let height_group = HeightGroup {
-                        height: 0,
-                        size: 1,
-                    };
-                    database.insert_or_update_height_group(tx, &height_group).await?;
-                    info!("Pruning point {} has been added to the database", pruning_point_hash_str);
-                }
+        Self::resync_database_static(&self.database, &self.rpc_client, self.config.resync_options()).await?;
 
-                let mut vspc_cycle = 0;
-                loop {
-                    info!("Cycle {} - Load node blocks", vspc_cycle);
-                    let hashes = Self::get_hashes_to_selected_tip(
-                        &rpc_client, &low_hash, dag_info.virtual_daa_score, 
-                        pruning_block.header.daa_score
-                    ).await?;
-                    info!("Cycle {} - Node blocks loaded", vspc_cycle);
-
-                    let mut start_index = 0;
-                    if keep_database && vspc_cycle == 0 {
-                        info!("Cycle {} - Syncing {} blocks with the database", vspc_cycle, hashes.len());
-                        if !config_resync {
-                            start_index = database.find_latest_stored_block_index(tx, &hashes).await?;
-                            info!("Cycle {} - First {} blocks already exist in the database", vspc_cycle, start_index);
-                            start_index = start_index.saturating_sub(3000usize);
-                        }
-                    } else {
-                        info!("Cycle {} - Adding {} blocks to the database", vspc_cycle, hashes.len());
-                    }
+        let mut syncing = self.syncing.lock().await;
+        *syncing = false;
+        Ok(())
+    }
 
-                    let total_to_add = hashes.len() - start_index;
-                    for i in start_index..hashes.len() {
-                        let block_hash = &hashes[i];
-                        let rpc_block_resp = rpc_client.get_block(block_hash, false).await?;
-                        let rpc_block = rpc_block_resp.block;
-                        
-                        if config_resync || (i - start_index) >= 6000 {
-                            Self::process_block_static(&database, tx, &rpc_client, &rpc_block, None).await?;
-                        } else {
-                            Self::process_block_and_dependencies_static(
-                                &database, tx, &rpc_client, block_hash, &rpc_block, Some(&pruning_block)
-                            ).await?;
-                        }
-                        
-                        let added_count = i + 1 - start_index;
-                        if added_count % 1000 == 0 || added_count == total_to_add {
-                            info!("Cycle {} - Added {}/{} blocks to the database", vspc_cycle, added_count, total_to_add);
+    /// Syncs the database with the node, one cycle of blocks loaded from the
+    /// node after another. The whole resync is a single transaction unless
+    /// `options.transaction_blocks` limits how many blocks a transaction
+    /// processes.
+    async fn resync_database_static(database: &Database, rpc_client: &Arc<RpcClient>, options: ResyncOptions) -> Result<()> {
+        let mut state: Option<ResyncState> = None;
+        loop {
+            let database_for_closure = database.clone();
+            let rpc_client = rpc_client.clone();
+            let options = options.clone();
+            state = database.run_in_transaction(move |tx| {
+                Box::pin(async move {
+                    let database = database_for_closure;
+                    let mut state = match state {
+                        Some(state) => state,
+                        None => Self::start_resync(&database, tx, &rpc_client, &options).await?,
+                    };
+                    loop {
+                        match Self::resync_cycle(&database, tx, &rpc_client, &options, &mut state).await? {
+                            ResyncCycleOutcome::Continue => {}
+                            ResyncCycleOutcome::Commit => return Ok(Some(state)),
+                            ResyncCycleOutcome::Finished => return Ok(None),
                         }
                     }
+                })
+            }).await?;
+            if state.is_none() {
+                break;
+            }
+        }
 
-                    if hashes.len() < 20 {
-                        Self::resync_virtual_selected_parent_chain_static(&database, tx, &rpc_client, true).await?;
-                        vspc_cycle += 1;
-                    }
+        info!("Finished resyncing database");
+        #[cfg(feature = "metrics")]
+        crate::metrics::record_resync_eta(0.0, Some(Duration::ZERO), Some(Duration::ZERO));
+        alerts::raise(AlertKind::SyncCompleted, "Finished resyncing database");
+        Ok(())
+    }
+
+    /// Finds where the resync starts: the database is kept when it holds the
+    /// node pruning point, and cleared down to it otherwise.
+    async fn start_resync(
+        database: &Database,
+        tx: &tokio_postgres::Transaction<'_>,
+        rpc_client: &RpcClient,
+        options: &ResyncOptions,
+    ) -> Result<ResyncState> {
+        info!("Resyncing database");
+
+        let dag_info = rpc_client.get_block_dag_info().await?;
+        let pruning_point_hash = BlockHash::from(dag_info.pruning_point_hash);
+        let resync_from = options.resync_from.as_deref().map(BlockHash::parse).transpose()?;
+
+        let pruning_block_resp = rpc_client.get_block(pruning_point_hash, false).await?;
+        let pruning_block = pruning_block_resp.block;
+
+        let has_pruning_block = database.does_block_exist(tx, &pruning_point_hash).await?;
+
+        let mut low_hash = pruning_point_hash;
+        let keep_database = has_pruning_block && !options.clear_db;
+        let pruning_point_is_genesis = Self::is_genesis(&pruning_block, options.block_processing.genesis_hash);
+        if pruning_point_is_genesis {
+            info!("Pruning point {} is the genesis block", pruning_point_hash);
+        }
+
+        // A populated database that lacks the pruning point means the node
+        // pruned past every block TGI stored, so they can't be synced anymore
+        if !has_pruning_block && !options.clear_db && !database.is_empty(tx).await? {
+            if options.abort_if_pruned {
+                anyhow::bail!(
+                    "The node pruned past the blocks stored in the database (pruning point {} is missing). \
+                    Restart with --clear-db to sync from the new pruning point",
+                    pruning_point_hash
+                );
+            }
+            warn!(
+                "The node pruned past the blocks stored in the database (pruning point {} is missing); \
+                clearing the database and syncing from the new pruning point",
+                pruning_point_hash
+            );
+        }
+
+        if let Some(resync_from) = resync_from {
+            if !keep_database {
+                anyhow::bail!(
+                    "--resync-from requires a database that already contains the pruning point {}",
+                    pruning_point_hash
+                );
+            }
+            let resync_from_block = match rpc_client.get_block(resync_from, false).await {
+                Ok(resync_from_block_resp) => resync_from_block_resp.block,
+                Err(TgiError::BlockNotFound(_)) => anyhow::bail!(
+                    "Block {} passed to --resync-from is not known to the node", resync_from
+                ),
+                Err(e) => return Err(e.into()),
+            };
+            if resync_from_block.header.daa_score < pruning_block.header.daa_score {
+                anyhow::bail!(
+                    "Block {} passed to --resync-from precedes the pruning point {}",
+                    resync_from, pruning_point_hash
+                );
+            }
+        }
+
+        if keep_database {
+            info!("Pruning point {} already in the database", pruning_point_hash);
+            info!("Database kept");
+
+            let pruning_block_height = database.block_height_by_hash(tx, &pruning_point_hash).await?;
+
+            let cache_warming = match options.cache_warm_blocks {
+                None => CacheWarming::FromHeight(pruning_block_height),
+                Some(0) => CacheWarming::Skip,
+                Some(count) => CacheWarming::Recent(count),
+            };
+            info!("Loading cache ({:?})", cache_warming);
+            let loaded = database.load_cache(tx, cache_warming).await?;
+            info!("Cache loaded with {} blocks from the database", loaded);
+
+            if let Some(resync_from) = resync_from {
+                low_hash = resync_from;
+            } else if !pruning_point_is_genesis {
+                // Nothing precedes genesis, so there is no better starting point to find
+                info!("Searching for an optimal sync starting point");
+                low_hash = Self::find_optimal_sync_starting_block(
+                    database, tx, rpc_client, &pruning_point_hash, 
+                    pruning_block.header.daa_score
+                ).await?;
+            }
+            if options.resync_from.is_some() {
+                info!("Sync starting point forced at {}", low_hash);
+            } else if low_hash != pruning_point_hash {
+                info!("Optimal sync starting point set at {}", low_hash);
+            } else {
+                info!("Sync starting point set at the pruning point");
+            }
+        } else {
+            database.clear(tx).await?;
+            info!("Database cleared");
+
+            let pruning_database_block = Block {
+                id: 0,
+                block_hash: pruning_point_hash.to_string(),
+                timestamp: pruning_block.header.timestamp as i64,
+                parent_ids: vec![],
+                daa_score: pruning_block.header.daa_score,
+                height: 0,
+                height_group_index: 0,
+                selected_parent_id: None,
+                color: "gray".to_string(),
+                is_in_virtual_selected_parent_chain: true,
+                merge_set_red_ids: vec![],
+                merge_set_blue_ids: vec![],
+                is_header_only: false,
+                miner: None,
+            };
+            database.insert_block(tx, &pruning_point_hash, &pruning_database_block).await?;
+
+            let height_group = HeightGroup {
+                height: 0,
+                size: 1,
+            };
+            database.insert_or_update_height_group(tx, &height_group).await?;
+            info!("Pruning point {} has been added to the database", pruning_point_hash);
+        }
+        Ok(ResyncState {
+            virtual_daa_score: dag_info.virtual_daa_score,
+            pruning_block,
+            low_hash,
+            keep_database,
+            vspc_cycle: 0,
+            interrupted_cycle: None,
+        })
+    }
+
+    /// Runs a resync cycle, or carries on with the one `state` says was
+    /// interrupted: loads the blocks from `state.low_hash` up to the node
+    /// tip and processes at most `options.transaction_blocks` of them.
+    async fn resync_cycle(
+        database: &Database,
+        tx: &tokio_postgres::Transaction<'_>,
+        rpc_client: &Arc<RpcClient>,
+        options: &ResyncOptions,
+        state: &mut ResyncState,
+    ) -> Result<ResyncCycleOutcome> {
+        let vspc_cycle = state.vspc_cycle;
+        let virtual_daa_score = state.virtual_daa_score;
+        let (hashes, mode, start_index) = match state.interrupted_cycle.take() {
+            Some(InterruptedCycle { hashes, mode, next_index }) => {
+                info!("Cycle {} - Resuming at block {}/{}", vspc_cycle, next_index, hashes.len());
+                (hashes, mode, next_index)
+            }
+            None => {
+                info!("Cycle {} - Load node blocks", vspc_cycle);
+                let hashes = Self::get_hashes_to_selected_tip(
+                    database, rpc_client, &state.low_hash, virtual_daa_score,
+                    state.pruning_block.header.daa_score
+                ).await?;
+                info!("Cycle {} - Node blocks loaded", vspc_cycle);
+
+                let mode = ProcessingMode::for_resync_cycle(
+                    state.keep_database, options.resync, options.block_processing.max_height.is_some()
+                );
+                debug!("Cycle {} - Processing mode {:?}", vspc_cycle, mode);
 
-                    if vspc_cycle > 1 && hashes.len() < 10 {
-                        info!("Cycle {} - Almost at tip with last {} blocks added, stopping resync", vspc_cycle, hashes.len());
-                        break;
+                let mut start_index = 0;
+                if state.keep_database && vspc_cycle == 0 {
+                    info!("Cycle {} - Syncing {} blocks with the database", vspc_cycle, hashes.len());
+                    // A forced starting point re-pulls its whole range
+                    if mode.skips_stored_blocks() && options.resync_from.is_none() {
+                        start_index = database.find_latest_stored_block_index(tx, &hashes).await?;
+                        info!("Cycle {} - First {} blocks already exist in the database", vspc_cycle, start_index);
+                        start_index = ProcessingMode::resume_index(start_index);
                     }
+                } else {
+                    info!("Cycle {} - Adding {} blocks to the database", vspc_cycle, hashes.len());
+                }
+                (hashes, mode, start_index)
+            }
+        };
+        let block_processing_options = mode.block_processing_options(options.block_processing);
+        let end_index = match mode.transaction_block_limit(options.transaction_blocks) {
+            Some(max_blocks) => hashes.len().min(start_index + max_blocks),
+            None => hashes.len(),
+        };
 
-                    keep_database = true;
+        if let Some(chunk_size) = mode.bulk_insert_chunk_size() {
+            Self::bulk_sync_blocks_static(
+                database, tx, rpc_client, &hashes[start_index..end_index], chunk_size, vspc_cycle, options.parallel_fetch,
+                virtual_daa_score, block_processing_options
+            ).await?;
+        } else {
+            let counts_at_start = ProcessedBlockCounts::current();
+            // Completed blocks are skipped before the prefetcher fetches them
+            let mut to_add = hashes[start_index..end_index].to_vec();
+            if block_processing_options.skips_complete_blocks() {
+                let complete = database.fully_processed_hashes(tx, &to_add).await?;
+                if !complete.is_empty() {
+                    to_add.retain(|hash| !complete.contains(hash));
+                    EXISTING_BLOCK_COUNT.fetch_add(complete.len() as u64, Ordering::Relaxed);
+                    info!("Cycle {} - Skipping {} blocks fully processed before", vspc_cycle, complete.len());
                 }
+            }
+            let total_to_add = to_add.len();
+            let progress = ResyncProgress::start(total_to_add, virtual_daa_score);
+            let mut prefetched_blocks = Self::spawn_block_prefetcher(
+                rpc_client.clone(), to_add.clone(), options.prefetch_blocks, block_processing_options,
+            );
+            for (offset, block_hash) in to_add.iter().enumerate() {
+                let rpc_block = match prefetched_blocks.recv().await {
+                    Some(Ok(rpc_block)) => rpc_block,
+                    Some(Err(TgiError::BlockNotFound(_))) => anyhow::bail!(
+                        "Block {} was pruned by the node while resyncing. Restart to sync from the new pruning point",
+                        block_hash
+                    ),
+                    Some(Err(e)) => return Err(e.into()),
+                    None => anyhow::bail!("Block prefetching stopped before block {}", block_hash),
+                };
 
-                info!("Finished resyncing database");
-                Ok(())
-            })
-        }).await?;
+                if mode.collects_dependencies(offset) {
+                    Self::process_block_and_dependencies_static(
+                        database, tx, rpc_client, block_hash, &rpc_block, Some(&state.pruning_block), block_processing_options
+                    ).await?;
+                } else {
+                    Self::process_block_static(database, tx, rpc_client, &rpc_block, None, block_processing_options, false, false).await?;
+                }
 
-        let mut syncing = self.syncing.lock().await;
-        *syncing = false;
-        Ok(())
+                let added_count = offset + 1;
+                if added_count % 1000 == 0 || added_count == total_to_add {
+                    let counts = ProcessedBlockCounts::current().since(counts_at_start);
+                    let eta = progress.eta(added_count, rpc_block.header.daa_score);
+                    eta.record();
+                    info!(
+                        "Cycle {} - Added {}/{} blocks to the database ({} already stored, {:.1}%), {}",
+                        vspc_cycle, added_count, total_to_add, counts.existing, counts.existing_ratio() * 100.0, eta
+                    );
+                }
+            }
+        }
+
+        if end_index < hashes.len() {
+            info!(
+                "Cycle {} - Committing after block {}/{}, the rest follows in a new transaction",
+                vspc_cycle, end_index, hashes.len()
+            );
+            state.interrupted_cycle = Some(InterruptedCycle { hashes, mode, next_index: end_index });
+            return Ok(ResyncCycleOutcome::Commit);
+        }
+
+        let sink_hash = BlockHash::from(rpc_client.get_sink().await?.sink);
+        let reached_sink = hashes.last() == Some(&sink_hash);
+        let reached_max_height = match block_processing_options.max_height {
+            Some(max_height) => database.height_group_size(tx, max_height).await? > 0,
+            None => false,
+        };
+
+        if hashes.len() < options.vspc_threshold || reached_sink || reached_max_height {
+            Self::resync_virtual_selected_parent_chain_static(database, tx, rpc_client, true, options.disable_coloring).await?;
+            state.vspc_cycle += 1;
+        }
+
+        if reached_sink {
+            info!("Cycle {} - Reached the node sink {}, stopping resync", state.vspc_cycle, sink_hash);
+            return Ok(ResyncCycleOutcome::Finished);
+        }
+
+        if reached_max_height {
+            info!("Cycle {} - Reached the maximum height, stopping resync", state.vspc_cycle);
+            return Ok(ResyncCycleOutcome::Finished);
+        }
+
+        if state.vspc_cycle > 1 && hashes.len() < options.tip_threshold {
+            info!("Cycle {} - Almost at tip with last {} blocks added, stopping resync", state.vspc_cycle, hashes.len());
+            return Ok(ResyncCycleOutcome::Finished);
+        }
+
+        state.keep_database = true;
+        Ok(ResyncCycleOutcome::Continue)
+    }
+
+    /// Fetches `hashes` from the node in order on a separate task, so the
+    /// next blocks are fetched while the current one is stored. At most
+    /// `capacity` fetched blocks wait in the channel: the task pauses until
+    /// the consumer catches up, and stops after the first error or once the
+    /// receiver is dropped. Incomplete blocks are fetched again as `options`
+    /// say, see `fetch_complete_block`.
+    fn spawn_block_prefetcher(
+        rpc_client: Arc<RpcClient>,
+        hashes: Vec<BlockHash>,
+        capacity: usize,
+        options: BlockProcessingOptions,
+    ) -> mpsc::Receiver<Result<RpcBlock, TgiError>> {
+        let (sender, receiver) = mpsc::channel(capacity);
+        tokio::spawn(async move {
+            for block_hash in hashes {
+                let result = match rpc_client.get_block(block_hash, false).await {
+                    Ok(response) => Self::fetch_complete_block(&rpc_client, response.block, options).await,
+                    Err(e) => Err(e),
+                };
+                let failed = result.is_err();
+                if sender.send(result).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+        receiver
+    }
+
+    fn is_genesis(block: &RpcBlock, genesis_hash: Option<BlockHash>) -> bool {
+        match genesis_hash {
+            Some(genesis_hash) => BlockHash::from(block.header.hash) == genesis_hash,
+            None => block.header.direct_parents().is_empty(),
+        }
     }
 
     async fn find_optimal_sync_starting_block(
         database: &Database,
         tx: &tokio_postgres::Transaction<'_>,
         rpc_client: &RpcClient,
-        pruning_point_hash: &str,
+        pruning_point_hash: &BlockHash,
         pruning_point_daa_score: u64,
-    ) -> Result<String> {
+    ) -> Result<BlockHash> {
         // Simplified version - in full implementation would search backwards
-        Ok(pruning_point_hash.to_string())
+        Ok(*pruning_point_hash)
     }
 
+    /// Returns the node's block hashes from `low_hash` to the selected tip,
+    /// oldest first. The resync relies on that order, so it is checked
+    /// against the cached heights and restored if the node broke it.
     async fn get_hashes_to_selected_tip(
+        database: &Database,
         rpc_client: &RpcClient,
-        low_hash: &str,
+        low_hash: &BlockHash,
         virtual_daa_score: u64,
         _pruning_point_daa_score: u64,
-    ) -> Result<Vec<String>> {
-        let response = rpc_client.get_blocks(low_hash, false, false).await?;
-        Ok(response.block_hashes.iter().map(|h| h.to_string()).collect())
+    ) -> Result<Vec<BlockHash>> {
+        let response = match rpc_client.get_blocks(low_hash, false, false).await {
+            Ok(response) => response,
+            // A starting point taken from the database may have been pruned
+            // by the node since
+            Err(TgiError::BlockNotFound(_)) => {
+                let pruning_point_hash = BlockHash::from(rpc_client.get_block_dag_info().await?.pruning_point_hash);
+                if pruning_point_hash == *low_hash {
+                    anyhow::bail!("The node doesn't know its own pruning point {}", low_hash);
+                }
+                warn!(
+                    "Block {} is not known to the node; loading the blocks from the pruning point {} instead",
+                    low_hash, pruning_point_hash
+                );
+                rpc_client.get_blocks(&pruning_point_hash, false, false).await?
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let hashes: Vec<BlockHash> = response.block_hashes.iter().map(|&hash| hash.into()).collect();
+
+        let heights = database.cached_block_heights(&hashes).await;
+        let known_heights: Vec<u64> = heights.into_iter().flatten().collect();
+        if known_heights.windows(2).all(|pair| pair[0] <= pair[1]) {
+            return Ok(hashes);
+        }
+
+        warn!("The node returned {} block hashes out of topological order; sorting them", hashes.len());
+        let mut parents = HashMap::with_capacity(hashes.len());
+        for hash in &hashes {
+            let block = rpc_client.get_block(hash, false).await?.block;
+            parents.insert(*hash, block.header.direct_parents().iter().map(|&parent| parent.into()).collect());
+        }
+        Ok(Self::sort_topologically(&hashes, &parents))
+    }
+
+    /// Orders `hashes` so every block comes after its parents among them.
+    /// Blocks that don't depend on each other keep their relative order.
+    fn sort_topologically(hashes: &[BlockHash], parents: &HashMap<BlockHash, Vec<BlockHash>>) -> Vec<BlockHash> {
+        let positions: HashMap<BlockHash, usize> = hashes.iter().enumerate().map(|(i, hash)| (*hash, i)).collect();
+        let mut children: Vec<Vec<usize>> = vec![Vec::new(); hashes.len()];
+        let mut missing_parent_counts = vec![0usize; hashes.len()];
+        for (i, hash) in hashes.iter().enumerate() {
+            for parent in parents.get(hash).into_iter().flatten() {
+                if let Some(&parent_position) = positions.get(parent) {
+                    children[parent_position].push(i);
+                    missing_parent_counts[i] += 1;
+                }
+            }
+        }
+
+        let mut ready: BinaryHeap<Reverse<usize>> = (0..hashes.len())
+            .filter(|&i| missing_parent_counts[i] == 0)
+            .map(Reverse)
+            .collect();
+        let mut sorted = Vec::with_capacity(hashes.len());
+        while let Some(Reverse(i)) = ready.pop() {
+            sorted.push(hashes[i]);
+            for &child in &children[i] {
+                missing_parent_counts[child] -= 1;
+                if missing_parent_counts[child] == 0 {
+                    ready.push(Reverse(child));
+                }
+            }
+        }
+        sorted
     }
 
     async fn process_block_and_dependencies_static(
         database: &Database,
         tx: &tokio_postgres::Transaction<'_>,
         rpc_client: &RpcClient,
-        hash: &str,
+        hash: &BlockHash,
         block: &RpcBlock,
         pruning_block: Option<&RpcBlock>,
+        options: BlockProcessingOptions,
     ) -> Result<()> {
+        // Once the graph reached its cap, the blocks above it are never stored,
+        // so collecting them as missing dependencies would walk the whole
+        // capped-out part of the DAG
+        if let Some(max_height) = options.max_height {
+            if database.height_group_size(tx, max_height).await? > 0 {
+                return Self::process_block_static(database, tx, rpc_client, block, None, options, false, false).await;
+            }
+        }
+
         let mut batch = batch::Batch::new(
             database.clone(),
             rpc_client.clone(),
             pruning_block.cloned(),
+            options.dependency_overflow_policy,
+            options.wait_for_missing_parents,
         );
-        batch.collect_block_and_dependencies(tx, hash, block).await?;
+        match batch.collect_block_and_dependencies(tx, hash, block).await? {
+            batch::Collection::Complete => {}
+            batch::Collection::Skipped => return Ok(()),
+            batch::Collection::ResyncNeeded => {
+                return Self::resync_missing_range_static(database, tx, rpc_client, options).await;
+            }
+        }
         
         while let Some((_hash, block)) = batch.pop() {
             if !batch.empty() {
                 warn!("Handling missing dependency block {}", _hash);
             }
-            Self::process_block_static(database, tx, rpc_client, &block, None).await?;
+            Self::process_block_static(database, tx, rpc_client, &block, None, options, true, false).await?;
         }
         Ok(())
     }
 
-    async fn process_block_static(
+    /// Processes the blocks the node has from the highest stored chain block
+    /// up, filling in whatever the database is missing in that range. Used
+    /// when a block has too many missing dependencies to collect.
+    async fn resync_missing_range_static(
         database: &Database,
         tx: &tokio_postgres::Transaction<'_>,
         rpc_client: &RpcClient,
-        block: &RpcBlock,
-        _pruning_block: Option<&RpcBlock>,
+        options: BlockProcessingOptions,
     ) -> Result<()> {
-        let block_hash = block.header.hash.to_string();
-        debug!("Processing block {}", block_hash);
-        
-        let block_exists = database.does_block_exist(tx, &block_hash).await?;
-        
-        if !block_exists {
-            let parent_hashes = block.header.direct_parents();
-            let mut existing_parent_hashes = Vec::new();
-            for parent_hash in parent_hashes {
-                let parent_hash_str = parent_hash.to_string();
-                let parent_exists = database.does_block_exist(tx, &parent_hash_str).await?;
-                if parent_exists {
-                    existing_parent_hashes.push(parent_hash_str);
-                } else {
-                    warn!("Parent {} for block {} does not exist in the database", parent_hash_str, block_hash);
+        let low_hash = BlockHash::parse(&database.highest_block_in_virtual_selected_parent_chain(tx).await?.block_hash)?;
+        let dag_info = rpc_client.get_block_dag_info().await?;
+        let hashes = Self::get_hashes_to_selected_tip(database, rpc_client, &low_hash, dag_info.virtual_daa_score, 0).await?;
+        let missing_hashes = database.missing_hashes(tx, &hashes).await?;
+        info!("Resyncing {} blocks missing after chain block {}", missing_hashes.len(), low_hash);
+        for hash in &missing_hashes {
+            let rpc_block = match rpc_client.get_block(hash, false).await {
+                Ok(rpc_block_resp) => rpc_block_resp.block,
+                Err(TgiError::BlockNotFound(_)) => {
+                    warn!("Block {} is no longer known to the node; not resynced", hash);
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            Self::process_block_static(database, tx, rpc_client, &rpc_block, None, options, false, false).await?;
+        }
+        Ok(())
+    }
+
+    /// Stores a block that isn't in the database yet, along with its height
+    /// group and the edges to its already-stored parents. The parents that
+    /// aren't stored are recorded, so `reconcile_late_parent` can add the
+    /// edges to them and fix the height once they arrive.
+    pub async fn insert_block_and_edges_static(
+        database: &Database,
+        tx: &tokio_postgres::Transaction<'_>,
+        block_hash: &BlockHash,
+        timestamp: i64,
+        daa_score: u64,
+        parent_hashes: &[BlockHash],
+    ) -> Result<()> {
+        let mut existing_parent_hashes = Vec::new();
+        let mut missing_parent_hashes = Vec::new();
+        for parent_hash in parent_hashes {
+            let parent_exists = database.does_block_exist(tx, parent_hash).await?;
+            if parent_exists {
+                existing_parent_hashes.push(*parent_hash);
+            } else {
+                missing_parent_hashes.push(*parent_hash);
+                log_throttle::warn_throttled("missing_parent", || {
+                    format!("Parent {} for block {} does not exist in the database", parent_hash, block_hash)
+                });
+            }
+        }
+
+        let (parent_ids, parent_heights) = database.block_ids_and_heights_by_hashes(tx, &existing_parent_hashes).await?;
+
+        let block_height = parent_heights.iter().max().map(|&h| h + 1).unwrap_or(0);
+        let height_group_size = database.height_group_size(tx, block_height).await?;
+        let block_height_group_index = height_group_size;
+
+        let database_block = Block {
+            id: 0,
+            block_hash: block_hash.to_string(),
+            timestamp,
+            parent_ids,
+            height: block_height,
+            height_group_index: block_height_group_index as u32,
+            selected_parent_id: None,
+            color: "gray".to_string(),
+            is_in_virtual_selected_parent_chain: false,
+            merge_set_red_ids: vec![],
+            merge_set_blue_ids: vec![],
+            is_header_only: false,
+            miner: None,
+            daa_score,
+        };
+        database.insert_block(tx, block_hash, &database_block).await?;
+
+        let block_id = database.block_id_by_hash(tx, block_hash).await?;
+        let height_group = HeightGroup {
+            height: block_height,
+            size: (block_height_group_index + 1) as u32,
+        };
+        database.insert_or_update_height_group(tx, &height_group).await?;
+
+        for parent_id in &database_block.parent_ids {
+            let parent_height = database.block_height(tx, *parent_id).await?;
+            let parent_height_group_index = database.block_height_group_index(tx, *parent_id).await?;
+            let edge = Edge {
+                from_block_id: block_id,
+                to_block_id: *parent_id,
+                from_height: block_height,
+                to_height: parent_height,
+                from_height_group_index: block_height_group_index as u32,
+                to_height_group_index: parent_height_group_index as u32,
+            };
+            database.insert_edge(tx, &edge).await?;
+        }
+
+        if !missing_parent_hashes.is_empty() {
+            database.add_pending_parent_edges(tx, block_id, &missing_parent_hashes).await?;
+        }
+        Ok(())
+    }
+
+    /// Adds the edges from the blocks stored before their parent `block_hash`
+    /// to it. Those blocks got their height from their other parents only,
+    /// so any of them not above the parent is raised, along with the
+    /// descendants that end up not above it in turn.
+    async fn reconcile_late_parent(
+        database: &Database,
+        tx: &tokio_postgres::Transaction<'_>,
+        block_hash: &BlockHash,
+        block_id: u64,
+    ) -> Result<()> {
+        let child_ids = database.take_pending_parent_edges(tx, block_hash).await?;
+        if child_ids.is_empty() {
+            return Ok(());
+        }
+        database.add_late_parent(tx, &child_ids, block_id).await?;
+
+        let height = database.block_height(tx, block_id).await?;
+        let raises = database.blocks_to_raise(tx, &child_ids, height + 1).await?;
+        database.raise_blocks(tx, &raises).await?;
+        if !raises.is_empty() {
+            debug!("Raised the height of {} blocks stored before their parent {}", raises.len(), block_hash);
+        }
+        Ok(())
+    }
+
+    /// Syncs blocks into an empty database. Every chunk of blocks is stored
+    /// with bulk inserts first, then their selected parents and merge sets
+    /// are filled in block by block.
+    #[allow(clippy::too_many_arguments)]
+    async fn bulk_sync_blocks_static(
+        database: &Database,
+        tx: &tokio_postgres::Transaction<'_>,
+        rpc_client: &RpcClient,
+        hashes: &[BlockHash],
+        chunk_size: usize,
+        vspc_cycle: u64,
+        parallel_fetch: bool,
+        virtual_daa_score: u64,
+        options: BlockProcessingOptions,
+    ) -> Result<()> {
+        let mut added_count = 0;
+        let progress = ResyncProgress::start(hashes.len(), virtual_daa_score);
+        for chunk in hashes.chunks(chunk_size) {
+            let missing_hashes = database.missing_hashes(tx, chunk).await?;
+            let rpc_blocks = if parallel_fetch {
+                Self::fetch_blocks_concurrently(rpc_client, missing_hashes).await?
+            } else {
+                let mut rpc_blocks = Vec::with_capacity(missing_hashes.len());
+                for block_hash in &missing_hashes {
+                    rpc_blocks.push(rpc_client.get_block(block_hash, false).await?.block);
                 }
+                rpc_blocks
+            };
+
+            // Parentless blocks other than the genesis are left for
+            // `process_block_static` to turn down
+            let new_blocks: Vec<NewBlock> = rpc_blocks.iter()
+                .filter(|block| !block.header.direct_parents().is_empty() || Self::is_genesis(block, options.genesis_hash))
+                .map(|block| NewBlock {
+                    hash: block.header.hash.into(),
+                    timestamp: block.header.timestamp as i64,
+                    daa_score: block.header.daa_score,
+                    parent_hashes: block.header.direct_parents().iter().map(|&parent| parent.into()).collect(),
+                })
+                .collect();
+            let inserted = Self::bulk_insert_blocks_and_edges_static(database, tx, &new_blocks).await?;
+            NEW_BLOCK_COUNT.fetch_add(inserted as u64, Ordering::Relaxed);
+            EXISTING_BLOCK_COUNT.fetch_add((chunk.len() - rpc_blocks.len()) as u64, Ordering::Relaxed);
+
+            for rpc_block in &rpc_blocks {
+                Self::process_block_static(database, tx, rpc_client, rpc_block, None, options, false, true).await?;
             }
 
-            let (parent_ids, parent_heights) = database.block_ids_and_heights_by_hashes(tx, &existing_parent_hashes).await?;
+            added_count += chunk.len();
+            // Chunk blocks the database already had aren't fetched, falling
+            // back to the virtual DAA score leaves only the cycle estimate
+            let daa_score = rpc_blocks.iter().map(|block| block.header.daa_score).max().unwrap_or(virtual_daa_score);
+            let eta = progress.eta(added_count, daa_score);
+            eta.record();
+            info!("Cycle {} - Added {}/{} blocks to the database, {}", vspc_cycle, added_count, hashes.len(), eta);
+        }
+        Ok(())
+    }
 
-            let block_height = parent_heights.iter().max().map(|&h| h + 1).unwrap_or(0);
-            let height_group_size = database.height_group_size(tx, block_height).await?;
-            let block_height_group_index = height_group_size;
+    /// Fetches blocks from the node all at once, leaving the RPC client to cap
+    /// the calls in flight. Returns them in the order of `hashes`.
+    async fn fetch_blocks_concurrently(rpc_client: &RpcClient, hashes: Vec<BlockHash>) -> Result<Vec<RpcBlock>> {
+        let mut tasks = JoinSet::new();
+        for (index, block_hash) in hashes.into_iter().enumerate() {
+            let rpc_client = rpc_client.clone();
+            tasks.spawn(async move {
+                let block = rpc_client.get_block(block_hash, false).await?.block;
+                Ok::<_, TgiError>((index, block))
+            });
+        }
+        let mut indexed_blocks = Vec::with_capacity(tasks.len());
+        while let Some(result) = tasks.join_next().await {
+            indexed_blocks.push(result??);
+        }
+        indexed_blocks.sort_unstable_by_key(|(index, _)| *index);
+        Ok(indexed_blocks.into_iter().map(|(_, block)| block).collect())
+    }
 
-            let database_block = Block {
+    /// Bulk counterpart of `insert_block_and_edges_static`. `blocks` must be
+    /// in topological order and not stored yet. Parents in neither the batch
+    /// nor the database are recorded like there. A block none of whose
+    /// parents are is left out instead of getting height 0, for
+    /// `process_block_static` to defer. Returns the number of blocks
+    /// inserted.
+    pub async fn bulk_insert_blocks_and_edges_static(
+        database: &Database,
+        tx: &tokio_postgres::Transaction<'_>,
+        blocks: &[NewBlock],
+    ) -> Result<usize> {
+        // Height and height group index of the blocks of this batch
+        let mut batch_positions: HashMap<BlockHash, (u64, u32)> = HashMap::new();
+        let mut height_group_sizes: HashMap<u64, u32> = HashMap::new();
+        let mut database_blocks = Vec::with_capacity(blocks.len());
+        let mut stored_parent_hashes = Vec::with_capacity(blocks.len());
+        let mut missing_parent_hashes = Vec::with_capacity(blocks.len());
+
+        for block in blocks {
+            let mut existing_parent_hashes = Vec::new();
+            let mut block_missing_parent_hashes = Vec::new();
+            let mut block_height = 0;
+            for parent_hash in &block.parent_hashes {
+                let parent_height = match batch_positions.get(parent_hash) {
+                    Some((height, _)) => *height,
+                    None if database.does_block_exist(tx, parent_hash).await? => {
+                        database.block_height_by_hash(tx, parent_hash).await?
+                    }
+                    None => {
+                        log_throttle::warn_throttled("missing_parent", || {
+                            format!("Parent {} for block {} does not exist in the database", parent_hash, block.hash)
+                        });
+                        block_missing_parent_hashes.push(*parent_hash);
+                        continue;
+                    }
+                };
+                block_height = block_height.max(parent_height + 1);
+                existing_parent_hashes.push(*parent_hash);
+            }
+            if existing_parent_hashes.is_empty() && !block.parent_hashes.is_empty() {
+                continue;
+            }
+
+            let block_height_group_index = match height_group_sizes.get(&block_height) {
+                Some(size) => *size,
+                None => database.height_group_size(tx, block_height).await?,
+            };
+            height_group_sizes.insert(block_height, block_height_group_index + 1);
+            batch_positions.insert(block.hash, (block_height, block_height_group_index));
+
+            database_blocks.push(Block {
                 id: 0,
-                block_hash: block_hash.clone(),
-                timestamp: block.header.timestamp as i64,
-                parent_ids,
+                block_hash: block.hash.to_string(),
+                timestamp: block.timestamp,
+                parent_ids: vec![],
                 height: block_height,
-                height_group_index: block_height_group_index as u32,
+                height_group_index: block_height_group_index,
                 selected_parent_id: None,
                 color: "gray".to_string(),
                 is_in_virtual_selected_parent_chain: false,
                 merge_set_red_ids: vec![],
                 merge_set_blue_ids: vec![],
-                daa_score: block.header.daa_score,
-            };
-            database.insert_block(tx, &block_hash, &database_block).await?;
+                is_header_only: false,
+                miner: None,
+                daa_score: block.daa_score,
+            });
+            stored_parent_hashes.push(existing_parent_hashes);
+            missing_parent_hashes.push(block_missing_parent_hashes);
+        }
 
-            let block_id = database.block_id_by_hash(tx, &block_hash).await?;
-            let height_group = HeightGroup {
-                height: block_height,
-                size: (block_height_group_index + 1) as u32,
-            };
-            database.insert_or_update_height_group(tx, &height_group).await?;
+        // Parent ids are only known once the whole batch has ids
+        let block_ids = database.bulk_insert_blocks(tx, &database_blocks).await?;
 
-            for parent_id in &database_block.parent_ids {
-                let parent_height = database.block_height(tx, *parent_id).await?;
-                let parent_height_group_index = database.block_height_group_index(tx, *parent_id).await?;
-                let edge = Edge {
-                    from_block_id: block_id,
-                    to_block_id: *parent_id,
-                    from_height: block_height,
-                    to_height: parent_height,
-                    from_height_group_index: block_height_group_index as u32,
-                    to_height_group_index: parent_height_group_index as u32,
+        let mut block_ids_to_parent_ids = Vec::with_capacity(blocks.len());
+        let mut edges = Vec::new();
+        for ((database_block, parent_hashes), block_id) in database_blocks.iter().zip(&stored_parent_hashes).zip(&block_ids) {
+            let (parent_ids, parent_heights) = database.block_ids_and_heights_by_hashes(tx, parent_hashes).await?;
+            for ((parent_hash, parent_id), parent_height) in parent_hashes.iter().zip(&parent_ids).zip(&parent_heights) {
+                let parent_height_group_index = match batch_positions.get(parent_hash) {
+                    Some((_, height_group_index)) => *height_group_index,
+                    None => database.block_height_group_index(tx, *parent_id).await?,
                 };
-                database.insert_edge(tx, &edge).await?;
+                edges.push(Edge {
+                    from_block_id: *block_id,
+                    to_block_id: *parent_id,
+                    from_height: database_block.height,
+                    to_height: *parent_height,
+                    from_height_group_index: database_block.height_group_index,
+                    to_height_group_index: parent_height_group_index,
+                });
+            }
+            block_ids_to_parent_ids.push((*block_id, parent_ids));
+        }
+        database.update_blocks_parent_ids(tx, &block_ids_to_parent_ids).await?;
+        database.copy_in_edges(tx, &edges).await?;
+        for (block_id, parent_hashes) in block_ids.iter().zip(&missing_parent_hashes) {
+            if !parent_hashes.is_empty() {
+                database.add_pending_parent_edges(tx, *block_id, parent_hashes).await?;
+            }
+        }
+
+        let height_groups: Vec<HeightGroup> = height_group_sizes.into_iter()
+            .map(|(height, size)| HeightGroup { height, size })
+            .collect();
+        database.bulk_upsert_height_groups(tx, &height_groups).await?;
+        Ok(block_ids.len())
+    }
+
+    /// `with_dependencies` tells whether the block's missing dependencies
+    /// were collected first, which only matters to the latency metrics.
+    /// `bulk_inserted` tells that the bulk sync handled the block right
+    /// before: it inserted it and counted it as new already, or left it out
+    /// for this to defer or turn down.
+    ///
+    /// A block none of whose parents are stored is not given height 0 but
+    /// deferred, and processed once one of its parents is.
+    #[allow(clippy::too_many_arguments)]
+    async fn process_block_static(
+        database: &Database,
+        tx: &tokio_postgres::Transaction<'_>,
+        rpc_client: &RpcClient,
+        block: &RpcBlock,
+        _pruning_block: Option<&RpcBlock>,
+        options: BlockProcessingOptions,
+        with_dependencies: bool,
+        bulk_inserted: bool,
+    ) -> Result<()> {
+        let mut released_blocks = Self::process_single_block_static(
+            database, tx, rpc_client, block, options, with_dependencies, bulk_inserted,
+        ).await?;
+        // Deferred blocks were recorded whole, so storing them doesn't wait
+        // for the node while the transaction is open
+        while let Some((released_hash, released_block)) = released_blocks.pop() {
+            let Some(released_block) = released_block else {
+                warn!("Deferred block {} was recorded without its data; not stored", released_hash);
+                continue;
+            };
+            debug!("Processing block {} now that one of its parents is stored", released_hash);
+            let released_block: RpcBlock = serde_json::from_value(released_block)
+                .with_context(|| format!("Could not read deferred block {}", released_hash))?;
+            released_blocks.extend(
+                Self::process_single_block_static(database, tx, rpc_client, &released_block, options, with_dependencies, false).await?
+            );
+        }
+        Ok(())
+    }
+
+    /// Processes one block and returns the deferred blocks it released, see
+    /// `Database::take_pending_orphan_blocks`.
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    async fn process_single_block_static(
+        database: &Database,
+        tx: &tokio_postgres::Transaction<'_>,
+        rpc_client: &RpcClient,
+        block: &RpcBlock,
+        options: BlockProcessingOptions,
+        with_dependencies: bool,
+        bulk_inserted: bool,
+    ) -> Result<Vec<(BlockHash, Option<serde_json::Value>)>> {
+        #[cfg(feature = "metrics")]
+        let mut timer = crate::metrics::BlockProcessingTimer::start(with_dependencies);
+        let block_hash = BlockHash::from(block.header.hash);
+        debug!("Processing block {}", block_hash);
+        
+        let block_exists = database.does_block_exist(tx, &block_hash).await?;
+        #[cfg(feature = "metrics")]
+        timer.set_new(!block_exists || bulk_inserted);
+        if block_exists && !bulk_inserted {
+            EXISTING_BLOCK_COUNT.fetch_add(1, Ordering::Relaxed);
+        }
+        
+        if !block_exists {
+            let parent_hashes: Vec<BlockHash> = block.header.direct_parents().iter().map(|&parent| parent.into()).collect();
+            let is_genesis = Self::is_genesis(block, options.genesis_hash);
+            // Only the genesis block has no parents at all
+            if !is_genesis && parent_hashes.is_empty() {
+                log_throttle::warn_throttled("parentless_block", || {
+                    format!("Block {} has no parents but isn't the genesis block; not stored", block_hash)
+                });
+                return Ok(vec![]);
+            }
+            if !is_genesis && database.missing_hashes(tx, &parent_hashes).await?.len() == parent_hashes.len() {
+                log_throttle::warn_throttled("orphan_block", || {
+                    format!("None of the parents of block {} are stored; deferring it until one is", block_hash)
+                });
+                let block_data = serde_json::to_value(block)
+                    .with_context(|| format!("Could not serialize block {}", block_hash))?;
+                database.add_pending_orphan_block(tx, &block_hash, &parent_hashes, &block_data).await
+                    .with_context(|| format!("Could not defer block {}", block_hash))?;
+                return Ok(vec![]);
             }
+            if let Some(max_height) = options.max_height {
+                if Self::is_above_max_height(database, tx, &parent_hashes, max_height).await? {
+                    debug!("Block {} is above the maximum height {}; not stored", block_hash, max_height);
+                    return Ok(vec![]);
+                }
+            }
+            Self::insert_block_and_edges_static(
+                database, tx, &block_hash, block.header.timestamp as i64, block.header.daa_score, &parent_hashes
+            ).await?;
+            NEW_BLOCK_COUNT.fetch_add(1, Ordering::Relaxed);
         } else {
             debug!("Block {} already exists in database; not processed", block_hash);
         }
 
-        let rpc_block_resp = rpc_client.get_block(&block_hash, false).await?;
-        let verbose_data = match rpc_block_resp.block.verbose_data {
-            Some(vd) => vd,
-            None => {
-                warn!("Block {} is incomplete so leaving block processing", block_hash);
-                return Ok(());
+        let block_id = database.block_id_by_hash(tx, &block_hash).await
+            .with_context(|| format!("Could not get id of block {}", block_hash))?;
+
+        if options.store_headers {
+            let header_blob = header::encode_header(&block.header)?;
+            database.update_block_header_blob(tx, block_id, &header_blob).await
+                .with_context(|| format!("Could not store header of block {}", block_hash))?;
+        }
+
+        // Selected parents and merge sets never change, so a block that got
+        // them before is done
+        if block_exists && options.skips_complete_blocks() && database.is_block_fully_processed(tx, block_id).await?
+        {
+            debug!("Block {} was fully processed before; skipped", block_hash);
+            return Ok(vec![]);
+        }
+
+        let mut released_blocks = vec![];
+        // Bulk inserted blocks were stored right before, without this
+        if !block_exists || bulk_inserted {
+            let resolved = database.resolve_pending_selected_parents(tx, &block_hash, block_id).await
+                .with_context(|| format!("Could not resolve blocks waiting for selected parent {}", block_hash))?;
+            if resolved > 0 {
+                debug!("Set block {} as the selected parent of {} blocks stored before it", block_hash, resolved);
             }
-        };
+            released_blocks = database.take_pending_orphan_blocks(tx, &block_hash).await
+                .with_context(|| format!("Could not release blocks waiting for parent {}", block_hash))?;
+            Self::reconcile_late_parent(database, tx, &block_hash, block_id).await
+                .with_context(|| format!("Could not reconcile the blocks stored before their parent {}", block_hash))?;
+        }
 
-        if verbose_data.is_header_only {
-            warn!("Block {} is incomplete so leaving block processing", block_hash);
-            return Ok(());
+        // Callers usually fetched the block with its verbose data already, so
+        // only fetch it again when something is missing
+        let mut fetched_block = None;
+        if Self::lacks_requested_data(block, options) {
+            fetched_block = Some(rpc_client.get_block(&block_hash, options.track_miners).await?.block);
         }
+        let full_block = fetched_block.as_ref().unwrap_or(block);
+        // Incomplete blocks were fetched again before the transaction started,
+        // see `fetch_complete_block`. Those still incomplete stay header only
+        // and are processed again by a later resync
+        let is_header_only = Self::is_incomplete(full_block);
+        database.update_block_is_header_only(tx, block_id, is_header_only).await
+            .with_context(|| format!("Could not update header only state of block {}", block_hash))?;
 
-        let selected_parent_hash_str = verbose_data.selected_parent_hash.to_string();
-        let selected_parent_id = database.block_id_by_hash(tx, &selected_parent_hash_str).await
-            .with_context(|| format!("Could not get id of selected parent block {}", selected_parent_hash_str))?;
+        if options.track_miners {
+            match full_block.transactions.first().and_then(|coinbase| coinbase::miner_from_coinbase_payload(&coinbase.payload)) {
+                Some(miner) => database.update_block_miner(tx, block_id, &miner).await
+                    .with_context(|| format!("Could not update miner of block {}", block_hash))?,
+                None => debug!("Block {} has no parsable coinbase; miner not recorded", block_hash),
+            }
+        }
 
-        let block_id = database.block_id_by_hash(tx, &block_hash).await
-            .with_context(|| format!("Could not get id of block {}", block_hash))?;
+        let verbose_data = match &full_block.verbose_data {
+            Some(vd) if !vd.is_header_only => vd,
+            _ => {
+                log_throttle::warn_throttled("incomplete_block", || {
+                    format!("Block {} is incomplete so leaving block processing", block_hash)
+                });
+                return Ok(released_blocks);
+            }
+        };
 
-        database.update_block_selected_parent(tx, block_id, selected_parent_id).await
-            .with_context(|| format!("Could not update selected parent of block {}", block_hash))?;
+        let mut is_complete = true;
+        // The genesis block has no parents, so its selected parent is undefined
+        if Self::is_genesis(block, options.genesis_hash) {
+            debug!("Block {} is the genesis block; it has no selected parent", block_hash);
+        } else {
+            let selected_parent_hash = BlockHash::from(verbose_data.selected_parent_hash);
+            if database.does_block_exist(tx, &selected_parent_hash).await? {
+                let selected_parent_id = database.block_id_by_hash(tx, &selected_parent_hash).await
+                    .with_context(|| format!("Could not get id of selected parent block {}", selected_parent_hash))?;
+
+                database.update_block_selected_parent(tx, block_id, selected_parent_id).await
+                    .with_context(|| format!("Could not update selected parent of block {}", block_hash))?;
+            } else {
+                // During catch-up the selected parent may not be processed
+                // yet. It is filled in once the parent gets stored
+                log_throttle::warn_throttled("deferred_selected_parent", || {
+                    format!("Selected parent {} of block {} is not stored yet; deferring it", selected_parent_hash, block_hash)
+                });
+                is_complete = false;
+                database.add_pending_selected_parent(tx, block_id, &selected_parent_hash).await
+                    .with_context(|| format!("Could not defer selected parent of block {}", block_hash))?;
+            }
+        }
 
-        let merge_set_reds: Vec<String> = verbose_data.merge_set_reds_hashes.iter().map(|h| h.to_string()).collect();
-        let merge_set_red_ids = database.block_ids_by_hashes(tx, &merge_set_reds).await.unwrap_or_default();
+        let merge_set_reds: Vec<BlockHash> = verbose_data.merge_set_reds_hashes.iter().map(|&hash| hash.into()).collect();
+        let merge_set_red_ids = Self::resolve_merge_set_ids(database, tx, &block_hash, "red", &merge_set_reds, options.strict_merge_set).await?;
 
-        let merge_set_blues: Vec<String> = verbose_data.merge_set_blues_hashes.iter().map(|h| h.to_string()).collect();
-        let merge_set_blue_ids = database.block_ids_by_hashes(tx, &merge_set_blues).await.unwrap_or_default();
+        let merge_set_blues: Vec<BlockHash> = verbose_data.merge_set_blues_hashes.iter().map(|&hash| hash.into()).collect();
+        let merge_set_blue_ids = Self::resolve_merge_set_ids(database, tx, &block_hash, "blue", &merge_set_blues, options.strict_merge_set).await?;
 
         database.update_block_merge_set(tx, block_id, &merge_set_red_ids, &merge_set_blue_ids).await
             .with_context(|| format!("Could not update merge sets colors for block {}", block_hash))?;
 
+        // Merge set blocks missing from the database were skipped
+        if is_complete && merge_set_red_ids.len() == merge_set_reds.len() && merge_set_blue_ids.len() == merge_set_blues.len() {
+            database.set_block_fully_processed(tx, block_id).await
+                .with_context(|| format!("Could not mark block {} as fully processed", block_hash))?;
+        }
+
         debug!("Finished processing block {}", block_hash);
-        Ok(())
+        Ok(released_blocks)
+    }
+
+    /// Whether the node returned the block without the verbose data needed
+    /// to process it.
+    fn is_incomplete(block: &RpcBlock) -> bool {
+        block.verbose_data.as_ref().is_none_or(|vd| vd.is_header_only)
+    }
+
+    /// Whether the block was fetched without the verbose data or, when
+    /// tracking miners, the transactions that processing needs.
+    fn lacks_requested_data(block: &RpcBlock, options: BlockProcessingOptions) -> bool {
+        block.verbose_data.is_none() || (options.track_miners && block.transactions.is_empty())
+    }
+
+    /// Returns the block with everything processing needs, fetching it again
+    /// when the node returned it incomplete, up to
+    /// `incomplete_block_retries` times with growing waits. Runs before the
+    /// transaction processing the block starts, so the waits don't hold the
+    /// database connection.
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    async fn fetch_complete_block(
+        rpc_client: &RpcClient,
+        block: RpcBlock,
+        options: BlockProcessingOptions,
+    ) -> Result<RpcBlock, TgiError> {
+        let block_hash = block.header.hash;
+        let mut block = block;
+        if Self::lacks_requested_data(&block, options) {
+            block = rpc_client.get_block(block_hash, options.track_miners).await?.block;
+        }
+        if !Self::is_incomplete(&block) {
+            return Ok(block);
+        }
+        let mut retries = 0;
+        while Self::is_incomplete(&block) && retries < options.incomplete_block_retries {
+            retries += 1;
+            debug!("Block {} is incomplete; fetching it again ({}/{})", block_hash, retries, options.incomplete_block_retries);
+            tokio::time::sleep(INCOMPLETE_BLOCK_RETRY_DELAY * retries).await;
+            block = rpc_client.get_block(block_hash, options.track_miners).await?.block;
+        }
+        #[cfg(feature = "metrics")]
+        crate::metrics::record_incomplete_block(!Self::is_incomplete(&block));
+        Ok(block)
+    }
+
+    /// Whether a block with these parents would be stored above `max_height`.
+    /// Once the graph reached the cap, a parent missing from the database was
+    /// most likely skipped for being above it, which puts the block above it
+    /// as well.
+    async fn is_above_max_height(
+        database: &Database,
+        tx: &tokio_postgres::Transaction<'_>,
+        parent_hashes: &[BlockHash],
+        max_height: u64,
+    ) -> Result<bool> {
+        let mut block_height = 0;
+        let mut has_missing_parent = false;
+        for parent_hash in parent_hashes {
+            if database.does_block_exist(tx, parent_hash).await? {
+                block_height = block_height.max(database.block_height_by_hash(tx, parent_hash).await? + 1);
+            } else {
+                has_missing_parent = true;
+            }
+        }
+        if block_height > max_height {
+            return Ok(true);
+        }
+        Ok(has_missing_parent && database.height_group_size(tx, max_height).await? > 0)
+    }
+
+    /// Resolves the ids of the stored merge set blocks. Missing blocks are
+    /// skipped with a warning, or fail the resolution in strict mode.
+    async fn resolve_merge_set_ids(
+        database: &Database,
+        tx: &tokio_postgres::Transaction<'_>,
+        block_hash: &BlockHash,
+        merge_set_color: &str,
+        merge_set_hashes: &[BlockHash],
+        strict_merge_set: bool,
+    ) -> Result<Vec<u64>> {
+        let mut ids = Vec::with_capacity(merge_set_hashes.len());
+        let mut missing_count = 0;
+        for hash in merge_set_hashes {
+            match database.block_id_by_hash(tx, hash).await {
+                Ok(id) => ids.push(id),
+                Err(TgiError::BlockNotFound(_)) if !strict_merge_set => {
+                    debug!("Merge set {} block {} of block {} is missing from the database", merge_set_color, hash, block_hash);
+                    missing_count += 1;
+                }
+                Err(TgiError::BlockNotFound(_)) => {
+                    anyhow::bail!("Merge set {} block {} of block {} is missing from the database", merge_set_color, hash, block_hash);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if missing_count > 0 {
+            warn!(
+                "{}/{} merge set {} blocks of block {} are missing from the database and were skipped",
+                missing_count, merge_set_hashes.len(), merge_set_color, block_hash
+            );
+        }
+        Ok(ids)
+    }
+
+    /// Resets the merge sets of chain blocks that left the virtual selected
+    /// parent chain to gray. Chain blocks added in the same update re-color
+    /// whatever they merge on top of the returned map.
+    ///
+    /// Besides the stored merge sets, every block recorded as colored by a
+    /// removed chain block turns gray, which covers merge set blocks that
+    /// were missing from the database when the merge set was stored.
+    async fn uncolor_merge_sets(
+        database: &Database,
+        tx: &tokio_postgres::Transaction<'_>,
+        removed_block_ids: &[u64],
+    ) -> Result<HashMap<u64, BlockColorUpdate>> {
+        let mut block_colors: HashMap<u64, BlockColorUpdate> = HashMap::new();
+        for removed_block_id in removed_block_ids {
+            let (merge_set_red_ids, merge_set_blue_ids) = database.block_merge_set_ids(tx, *removed_block_id).await?;
+            for merged_block_id in merge_set_red_ids.into_iter().chain(merge_set_blue_ids) {
+                block_colors.insert(merged_block_id, BlockColorUpdate::gray(merged_block_id));
+            }
+        }
+        if !removed_block_ids.is_empty() {
+            for colored_block_id in database.blocks_colored_by(tx, removed_block_ids).await? {
+                block_colors.insert(colored_block_id, BlockColorUpdate::gray(colored_block_id));
+            }
+        }
+        Ok(block_colors)
+    }
+
+    /// Fetches the merge sets of chain blocks added to the virtual selected
+    /// parent chain from the node.
+    async fn fetch_chain_merge_sets(
+        rpc_client: &RpcClient,
+        added_chain_block_hashes: &[BlockHash],
+    ) -> Result<Vec<ChainMergeSet>> {
+        let mut merge_sets = Vec::with_capacity(added_chain_block_hashes.len());
+        for added_hash in added_chain_block_hashes {
+            let rpc_block_resp = rpc_client.get_block(added_hash, false).await?;
+            if let Some(verbose_data) = rpc_block_resp.block.verbose_data {
+                merge_sets.push(ChainMergeSet {
+                    chain_block_hash: *added_hash,
+                    blue_hashes: verbose_data.merge_set_blues_hashes.iter().map(|&hash| hash.into()).collect(),
+                    red_hashes: verbose_data.merge_set_reds_hashes.iter().map(|&hash| hash.into()).collect(),
+                });
+            }
+        }
+        Ok(merge_sets)
+    }
+
+    /// Computes the colors of the blocks merged by a virtual chain change:
+    /// merge sets of removed chain blocks turn gray, then the merge sets of
+    /// the added chain blocks are colored on top.
+    async fn color_chain_merge_sets(
+        database: &Database,
+        tx: &tokio_postgres::Transaction<'_>,
+        removed_block_ids: &[u64],
+        merge_sets: &[ChainMergeSet],
+    ) -> Result<HashMap<u64, BlockColorUpdate>> {
+        let mut block_colors = Self::uncolor_merge_sets(database, tx, removed_block_ids).await?;
+        for merge_set in merge_sets {
+            let colored_by_block_id = match database.block_id_by_hash(tx, &merge_set.chain_block_hash).await {
+                Ok(block_id) => Some(block_id),
+                Err(TgiError::BlockNotFound(_)) => None,
+                Err(e) => return Err(e.into()),
+            };
+            let colored = [(COLOR_BLUE, &merge_set.blue_hashes), (COLOR_RED, &merge_set.red_hashes)];
+            for (color, hashes) in colored {
+                for hash in hashes {
+                    let block_id = match database.block_id_by_hash(tx, hash).await {
+                        Ok(block_id) => block_id,
+                        Err(TgiError::BlockNotFound(_)) => continue,
+                        Err(e) => return Err(e.into()),
+                    };
+                    block_colors.insert(block_id, BlockColorUpdate {
+                        block_id,
+                        color: color.to_string(),
+                        colored_by_block_id,
+                    });
+                }
+            }
+        }
+        Ok(block_colors)
     }
 
     async fn resync_virtual_selected_parent_chain_static(
@@ -391,55 +2219,71 @@ impl Processing {
         tx: &tokio_postgres::Transaction<'_>,
         rpc_client: &RpcClient,
         _with_dependencies: bool,
+        disable_coloring: bool,
     ) -> Result<()> {
+        let chain = Self::fetch_virtual_selected_parent_chain(rpc_client, disable_coloring).await?;
+        Self::store_virtual_selected_parent_chain(database, tx, &chain, disable_coloring).await
+    }
+
+    /// Fetches the virtual selected parent chain from the node, along with
+    /// the merge sets of its added blocks unless coloring is disabled.
+    async fn fetch_virtual_selected_parent_chain(rpc_client: &RpcClient, disable_coloring: bool) -> Result<VirtualChainResync> {
         let sink_resp = rpc_client.get_sink().await?;
-        let sink_hash_str = sink_resp.sink.to_string();
-        
-        let virtual_chain_resp = rpc_client.get_virtual_chain_from_block(&sink_hash_str, false).await?;
-        
-        let mut block_is_in_virtual_selected_parent_chain: HashMap<u64, bool> = HashMap::new();
-        
-        for removed_hash in &virtual_chain_resp.removed_chain_block_hashes {
-            let removed_hash_str = removed_hash.to_string();
-            if let Ok(removed_block_id) = database.block_id_by_hash(tx, &removed_hash_str).await {
-                block_is_in_virtual_selected_parent_chain.insert(removed_block_id, false);
+        let virtual_chain_resp = rpc_client.get_virtual_chain_from_block(sink_resp.sink, false).await?;
+        let added_hashes: Vec<BlockHash> = virtual_chain_resp.added_chain_block_hashes.iter().map(|&hash| hash.into()).collect();
+        let merge_sets = match disable_coloring {
+            true => vec![],
+            false => Self::fetch_chain_merge_sets(rpc_client, &added_hashes).await?,
+        };
+        Ok(VirtualChainResync {
+            removed_hashes: virtual_chain_resp.removed_chain_block_hashes.iter().map(|&hash| hash.into()).collect(),
+            added_hashes,
+            merge_sets,
+        })
+    }
+
+    /// Stores the virtual selected parent chain fetched by
+    /// `fetch_virtual_selected_parent_chain`.
+    async fn store_virtual_selected_parent_chain(
+        database: &Database,
+        tx: &tokio_postgres::Transaction<'_>,
+        chain: &VirtualChainResync,
+        disable_coloring: bool,
+    ) -> Result<()> {
+        // The resync runs in a single transaction, so chunks only bound the
+        // updates held in memory at once. Removals go first so a block
+        // removed and added back ends up in the chain
+        for removed_chunk in chain.removed_hashes.chunks(VIRTUAL_CHAIN_CHUNK_SIZE) {
+            let mut updates = Vec::with_capacity(removed_chunk.len());
+            for removed_hash in removed_chunk {
+                if let Ok(removed_block_id) = database.block_id_by_hash(tx, removed_hash).await {
+                    updates.push((removed_block_id, false));
+                }
             }
-        }
-        
-        for added_hash in &virtual_chain_resp.added_chain_block_hashes {
-            let added_hash_str = added_hash.to_string();
-            if let Ok(added_block_id) = database.block_id_by_hash(tx, &added_hash_str).await {
-                block_is_in_virtual_selected_parent_chain.insert(added_block_id, true);
+            database.update_block_is_in_virtual_selected_parent_chain(tx, &updates).await?;
+            if !disable_coloring {
+                let removed_block_ids: Vec<u64> = updates.iter().map(|(id, _)| *id).collect();
+                let block_colors = Self::uncolor_merge_sets(database, tx, &removed_block_ids).await?;
+                let color_updates: Vec<BlockColorUpdate> = block_colors.into_values().collect();
+                database.update_block_colors(tx, &color_updates).await?;
             }
         }
-        
-        let updates: Vec<(u64, bool)> = block_is_in_virtual_selected_parent_chain.iter().map(|(k, v)| (*k, *v)).collect();
-        database.update_block_is_in_virtual_selected_parent_chain(tx, &updates).await?;
-        
-        let mut block_colors: HashMap<u64, String> = HashMap::new();
-        
-        for added_hash in &virtual_chain_resp.added_chain_block_hashes {
-            let added_hash_str = added_hash.to_string();
-            let rpc_block_resp = rpc_client.get_block(&added_hash_str, false).await?;
-            if let Some(verbose_data) = rpc_block_resp.block.verbose_data {
-                for blue_hash in &verbose_data.merge_set_blues_hashes {
-                    let blue_hash_str = blue_hash.to_string();
-                    if let Ok(blue_block_id) = database.block_id_by_hash(tx, &blue_hash_str).await {
-                        block_colors.insert(blue_block_id, "blue".to_string());
-                    }
-                }
-                for red_hash in &verbose_data.merge_set_reds_hashes {
-                    let red_hash_str = red_hash.to_string();
-                    if let Ok(red_block_id) = database.block_id_by_hash(tx, &red_hash_str).await {
-                        block_colors.insert(red_block_id, "red".to_string());
-                    }
+
+        for added_chunk in chain.added_hashes.chunks(VIRTUAL_CHAIN_CHUNK_SIZE) {
+            let mut updates = Vec::with_capacity(added_chunk.len());
+            for added_hash in added_chunk {
+                if let Ok(added_block_id) = database.block_id_by_hash(tx, added_hash).await {
+                    updates.push((added_block_id, true));
                 }
             }
+            database.update_block_is_in_virtual_selected_parent_chain(tx, &updates).await?;
         }
-        
-        let color_updates: Vec<(u64, String)> = block_colors.iter().map(|(k, v)| (*k, v.clone())).collect();
-        database.update_block_colors(tx, &color_updates).await?;
-        
+        for merge_set_chunk in chain.merge_sets.chunks(VIRTUAL_CHAIN_CHUNK_SIZE) {
+            let block_colors = Self::color_chain_merge_sets(database, tx, &[], merge_set_chunk).await?;
+            let color_updates: Vec<BlockColorUpdate> = block_colors.into_values().collect();
+            database.update_block_colors(tx, &color_updates).await?;
+        }
+
         info!("Updated the virtual selected parent chain");
         Ok(())
     }
@@ -447,118 +2291,605 @@ impl Processing {
     async fn initialize_consensus_events_handler(&self) -> Result<()> {
         let database1 = self.database.clone();
         let rpc_client1 = self.rpc_client.clone();
+        let block_processing_options = self.config.block_processing_options();
+        let batch_size = self.config.notification_batch_size();
+        let batch_window = self.config.notification_batch_window();
         
-        let database1_clone = database1.clone();
-        let rpc_client1_clone = rpc_client1.clone();
+        let (block_sender, block_receiver) = mpsc::unbounded_channel::<RpcBlock>();
+        tokio::spawn(Self::run_block_notification_worker(
+            database1, rpc_client1.clone(), block_receiver, self.last_progress.clone(), self.event_sink.clone(),
+            block_processing_options, batch_size, batch_window,
+        ));
         rpc_client1.register_for_block_added_notifications(move |notification: BlockAddedNotification| {
-            let database = database1_clone.clone();
-            let rpc_client = rpc_client1_clone.clone();
-            let block = (*notification.block).clone();
-            tokio::spawn(async move {
-                if let Err(e) = Self::process_block_notification(&database, &rpc_client, &block).await {
-                    warn!("Error processing block added notification: {}", e);
-                }
-            });
+            if block_sender.send((*notification.block).clone()).is_err() {
+                warn!("Block added notification worker stopped; dropping block {}", notification.block.header.hash);
+            }
         }).await?;
 
-        let database2 = self.database.clone();
-        let rpc_client2 = self.rpc_client.clone();
-        
-        let database2_clone = database2.clone();
-        let rpc_client2_clone = rpc_client2.clone();
-        rpc_client2.register_for_virtual_chain_changed_notifications(false, move |notification: VirtualChainChangedNotification| {
-            let database = database2_clone.clone();
-            let rpc_client = rpc_client2_clone.clone();
-            let notification = notification.clone();
-            tokio::spawn(async move {
-                if let Err(e) = Self::process_virtual_chain_changed_notification(&database, &rpc_client, notification).await {
-                    warn!("Error processing virtual chain changed notification: {}", e);
+        let (chain_sender, chain_receiver) = mpsc::unbounded_channel::<VirtualChainChangedNotification>();
+        let virtual_chain_options = self.config.virtual_chain_options();
+        let virtual_chain_worker = tokio::spawn(Self::run_virtual_chain_worker(
+            self.database.clone(), self.rpc_client.clone(), chain_receiver, virtual_chain_options, self.shutdown.subscribe(),
+        ));
+        *self.virtual_chain_worker.lock().await = Some(virtual_chain_worker);
+        self.rpc_client.register_for_virtual_chain_changed_notifications(
+            virtual_chain_options.track_accepted_transactions,
+            move |notification: VirtualChainChangedNotification| {
+                if chain_sender.send(notification).is_err() {
+                    warn!("Virtual chain worker stopped; dropping a virtual chain change");
                 }
-            });
-        }).await?;
+            },
+        ).await?;
 
         Ok(())
     }
 
-    async fn process_block_notification(
+    /// Processes added blocks one batch at a time. A batch is flushed once it
+    /// holds `batch_size` blocks or `batch_window` elapsed since its first
+    /// block arrived, whichever comes first. Blocks are published to
+    /// `event_sink` once their transaction committed.
+    async fn run_block_notification_worker(
+        database: Database,
+        rpc_client: Arc<RpcClient>,
+        mut receiver: mpsc::UnboundedReceiver<RpcBlock>,
+        last_progress: Arc<Mutex<Instant>>,
+        event_sink: Arc<dyn BlockEventSink>,
+        options: BlockProcessingOptions,
+        batch_size: usize,
+        batch_window: Duration,
+    ) {
+        while let Some(block) = receiver.recv().await {
+            let mut blocks = vec![block];
+            let deadline = tokio::time::Instant::now() + batch_window;
+            while blocks.len() < batch_size {
+                match tokio::time::timeout_at(deadline, receiver.recv()).await {
+                    Ok(Some(block)) => blocks.push(block),
+                    _ => break,
+                }
+            }
+
+            if blocks.len() > 1 {
+                debug!("Processing {} added blocks in a single transaction", blocks.len());
+                match Self::process_block_notifications(&database, &rpc_client, blocks.clone(), options).await {
+                    Ok(()) => {
+                        record_progress(&last_progress).await;
+                        Self::publish_block_events(event_sink.as_ref(), &blocks).await;
+                        continue;
+                    }
+                    // The failed transaction rolled back the whole batch, so retry
+                    // block by block to keep the failure to the offending block
+                    Err(e) => warn!("Error processing a batch of {} added blocks, retrying one by one: {}", blocks.len(), e),
+                }
+            }
+            for block in blocks {
+                let result = Self::with_notification_retries("block added notification", || {
+                    Self::process_block_notifications(&database, &rpc_client, vec![block.clone()], options)
+                }).await;
+                match result {
+                    Ok(()) => {
+                        record_progress(&last_progress).await;
+                        Self::publish_block_events(event_sink.as_ref(), std::slice::from_ref(&block)).await;
+                    }
+                    Err(e) => {
+                        let block_hash = BlockHash::from(block.header.hash);
+                        Self::dead_letter_notification(&database, BLOCK_ADDED_NOTIFICATION, &block_hash, &e).await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Runs `process` until it succeeds, up to `NOTIFICATION_ATTEMPTS` times,
+    /// waiting longer after every failure so transient database or node
+    /// errors can clear.
+    async fn with_notification_retries<F, Fut, T>(description: &str, mut process: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: std::future::Future<Output = Result<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match process().await {
+                Ok(result) => return Ok(result),
+                Err(e) if attempt < NOTIFICATION_ATTEMPTS => {
+                    warn!("Error processing {} (attempt {}/{}), retrying: {}", description, attempt, NOTIFICATION_ATTEMPTS, e);
+                    tokio::time::sleep(NOTIFICATION_RETRY_DELAY * attempt).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Runs `process` with options that fail on a parent the node doesn't
+    /// know yet, waiting `MISSING_PARENT_RETRY_DELAY` after each such failure.
+    /// `process` runs its own transaction, so the wait holds no database
+    /// connection or lock. The last of `MISSING_PARENT_ATTEMPTS` runs ignores
+    /// the parents still missing as out of the node scope.
+    async fn with_missing_parent_retries<F, Fut, T>(options: BlockProcessingOptions, mut process: F) -> Result<T>
+    where
+        F: FnMut(BlockProcessingOptions) -> Fut,
+        Fut: std::future::Future<Output = Result<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            let wait_for_missing_parents = attempt < MISSING_PARENT_ATTEMPTS;
+            let result = process(BlockProcessingOptions { wait_for_missing_parents, ..options }).await;
+            match result {
+                Err(e) if wait_for_missing_parents && e.downcast_ref::<batch::ParentNotYetFound>().is_some() => {
+                    debug!("{} (attempt {}/{}), retrying", e, attempt, MISSING_PARENT_ATTEMPTS);
+                    tokio::time::sleep(MISSING_PARENT_RETRY_DELAY).await;
+                    attempt += 1;
+                }
+                result => {
+                    #[cfg(feature = "metrics")]
+                    if attempt > 1 {
+                        let outcome = if wait_for_missing_parents {
+                            crate::metrics::RetryOutcome::Recovered
+                        } else {
+                            crate::metrics::RetryOutcome::Exhausted
+                        };
+                        crate::metrics::record_rpc_retry("GetBlock", outcome);
+                    }
+                    return result;
+                }
+            }
+        }
+    }
+
+    /// Records a notification that failed every attempt, so the next start
+    /// retries it instead of the update being lost.
+    async fn dead_letter_notification(database: &Database, kind: &'static str, block_hash: &BlockHash, error: &anyhow::Error) {
+        error!("Giving up on the {} notification of block {}: {}", kind, block_hash, error);
+        #[cfg(feature = "metrics")]
+        crate::metrics::record_failed_notification(kind);
+        let database_for_closure = database.clone();
+        let block_hash_for_closure = *block_hash;
+        let error_message = format!("{:#}", error);
+        let result = database.run_in_transaction(move |tx| {
+            Box::pin(async move {
+                Ok(database_for_closure.add_failed_notification(tx, kind, &block_hash_for_closure, &error_message).await?)
+            })
+        }).await;
+        if let Err(e) = result {
+            error!("Could not record the failed {} notification of block {}: {}", kind, block_hash, e);
+        }
+    }
+
+    /// Delivery is best effort: a failing sink must not stall processing.
+    async fn publish_block_events(event_sink: &dyn BlockEventSink, blocks: &[RpcBlock]) {
+        for block in blocks {
+            if let Err(e) = event_sink.send(BlockEvent::from(block)).await {
+                warn!("Error publishing block {} to the event sink: {}", block.header.hash, e);
+            }
+        }
+    }
+
+    async fn process_block_notifications(
         database: &Database,
         rpc_client: &RpcClient,
-        block: &RpcBlock,
+        blocks: Vec<RpcBlock>,
+        options: BlockProcessingOptions,
     ) -> Result<()> {
-        let block_hash = block.header.hash.to_string();
-        let block = block.clone();
+        let mut complete_blocks = Vec::with_capacity(blocks.len());
+        for block in blocks {
+            complete_blocks.push(Self::fetch_complete_block(rpc_client, block, options).await?);
+        }
+        let blocks = Arc::new(complete_blocks);
         let database = database.clone();
         let rpc_client = rpc_client.clone();
-        let database_for_closure = database.clone();
-        let rpc_client_for_closure = rpc_client.clone();
-        database.run_in_transaction(move |tx| {
-            let block = block.clone();
-            let block_hash = block_hash.clone();
-            let rpc_client = rpc_client_for_closure.clone();
-            let database = database_for_closure.clone();
-            Box::pin(async move {
-                Self::process_block_and_dependencies_static(&database, tx, &rpc_client, &block_hash, &block, None).await
+        Self::with_missing_parent_retries(options, |options| {
+            let database_for_closure = database.clone();
+            let rpc_client_for_closure = rpc_client.clone();
+            let blocks = blocks.clone();
+            database.run_in_transaction(move |tx| {
+                let rpc_client = rpc_client_for_closure.clone();
+                let database = database_for_closure.clone();
+                let blocks = blocks.clone();
+                Box::pin(async move {
+                    for block in blocks.iter() {
+                        let block_hash = BlockHash::from(block.header.hash);
+                        Self::process_block_and_dependencies_static(&database, tx, &rpc_client, &block_hash, block, None, options).await?;
+                    }
+                    Ok(())
+                })
             })
         }).await
     }
 
+    /// Stores the chain membership of a virtual chain change and returns the
+    /// merge set coloring it calls for, which needs a node round trip per
+    /// added chain block and is left to the caller.
     async fn process_virtual_chain_changed_notification(
         database: &Database,
-        rpc_client: &RpcClient,
         notification: VirtualChainChangedNotification,
+        track_accepted_transactions: bool,
+    ) -> Result<Vec<ColorUpdate>> {
+        let removed_hashes: Vec<BlockHash> = notification.removed_chain_block_hashes.iter().map(|&hash| hash.into()).collect();
+        let added_hashes: Vec<BlockHash> = notification.added_chain_block_hashes.iter().map(|&hash| hash.into()).collect();
+        if removed_hashes.len() > DEEP_REORG_ALERT_THRESHOLD {
+            alerts::raise(AlertKind::DeepReorg, format!(
+                "Virtual chain reorg removed {} chain blocks and added {}",
+                removed_hashes.len(), added_hashes.len()
+            ));
+        }
+        if removed_hashes.len() + added_hashes.len() > VIRTUAL_CHAIN_CHUNK_SIZE {
+            info!(
+                "Virtual chain change removes {} and adds {} chain blocks; storing it in chunks of {}",
+                removed_hashes.len(), added_hashes.len(), VIRTUAL_CHAIN_CHUNK_SIZE
+            );
+        }
+
+        // Removals are stored before additions so a block that is removed and
+        // added back by the same change ends up in the chain
+        let mut parts: Vec<VirtualChainPart> = removed_hashes.chunks(VIRTUAL_CHAIN_CHUNK_SIZE)
+            .map(|chunk| VirtualChainPart::Chain { removed_hashes: chunk.to_vec(), added_hashes: vec![] })
+            .chain(added_hashes.chunks(VIRTUAL_CHAIN_CHUNK_SIZE)
+                .map(|chunk| VirtualChainPart::Chain { removed_hashes: vec![], added_hashes: chunk.to_vec() }))
+            .collect();
+        if track_accepted_transactions {
+            let accepted: Vec<(BlockHash, Vec<String>)> = notification.accepted_transaction_ids.iter()
+                .map(|accepted| (
+                    accepted.accepting_block_hash.into(),
+                    accepted.accepted_transaction_ids.iter().map(|id| id.to_string()).collect(),
+                ))
+                .collect();
+            parts.extend(accepted.chunks(VIRTUAL_CHAIN_CHUNK_SIZE).map(|chunk| VirtualChainPart::AcceptedTransactions(chunk.to_vec())));
+        }
+
+        let replay_hash = Self::virtual_chain_replay_hash(&notification);
+        let last_part = parts.len().saturating_sub(1);
+        let mut color_updates = vec![];
+        for (i, part) in parts.into_iter().enumerate() {
+            let resume_marker = match replay_hash {
+                Some(replay_hash) if last_part > 0 && i == 0 => ResumeMarker::Add(replay_hash),
+                Some(replay_hash) if last_part > 0 && i == last_part => ResumeMarker::Remove(replay_hash),
+                _ => ResumeMarker::None,
+            };
+            match part {
+                VirtualChainPart::Chain { removed_hashes, added_hashes } => {
+                    let removed_block_ids = Self::store_virtual_chain_chunk(
+                        database, removed_hashes, added_hashes.clone(), track_accepted_transactions, resume_marker,
+                    ).await?;
+                    color_updates.push(ColorUpdate { removed_block_ids, added_chain_block_hashes: added_hashes });
+                }
+                VirtualChainPart::AcceptedTransactions(accepted) => {
+                    Self::store_accepted_transactions_chunk(database, accepted, resume_marker).await?;
+                }
+            }
+        }
+        Ok(color_updates)
+    }
+
+    /// The block a virtual chain change is replayed from: its highest removed
+    /// chain block, or else its first added one.
+    fn virtual_chain_replay_hash(notification: &VirtualChainChangedNotification) -> Option<BlockHash> {
+        notification.removed_chain_block_hashes.first()
+            .or(notification.added_chain_block_hashes.first())
+            .map(|&hash| BlockHash::from(hash))
+    }
+
+    /// Stores one chunk of a virtual chain change in its own transaction.
+    /// Returns the ids of the stored blocks that left the chain.
+    async fn store_virtual_chain_chunk(
+        database: &Database,
+        removed_hashes: Vec<BlockHash>,
+        added_hashes: Vec<BlockHash>,
+        track_accepted_transactions: bool,
+        resume_marker: ResumeMarker,
+    ) -> Result<Vec<u64>> {
+        let database_for_closure = database.clone();
+        let removed_block_ids = database.run_in_transaction(move |tx| {
+            Box::pin(async move {
+                let mut updates = Vec::with_capacity(removed_hashes.len() + added_hashes.len());
+                let mut removed_block_ids = Vec::new();
+                for removed_hash in &removed_hashes {
+                    if let Ok(removed_block_id) = database_for_closure.block_id_by_hash(tx, removed_hash).await {
+                        updates.push((removed_block_id, false));
+                        removed_block_ids.push(removed_block_id);
+                    }
+                }
+                for added_hash in &added_hashes {
+                    if let Ok(added_block_id) = database_for_closure.block_id_by_hash(tx, added_hash).await {
+                        updates.push((added_block_id, true));
+                    }
+                }
+                database_for_closure.update_block_is_in_virtual_selected_parent_chain(tx, &updates).await?;
+
+                if track_accepted_transactions {
+                    database_for_closure.remove_accepted_transactions(tx, &removed_block_ids).await?;
+                }
+                resume_marker.store(&database_for_closure, tx).await?;
+                Ok(removed_block_ids)
+            })
+        }).await?;
+        Ok(removed_block_ids)
+    }
+
+    /// Stores the transactions accepted by a chunk of accepting blocks in
+    /// its own transaction.
+    async fn store_accepted_transactions_chunk(
+        database: &Database,
+        accepted: Vec<(BlockHash, Vec<String>)>,
+        resume_marker: ResumeMarker,
     ) -> Result<()> {
-        let notification = notification.clone();
-        let database = database.clone();
-        let rpc_client = rpc_client.clone();
         let database_for_closure = database.clone();
-        let rpc_client_for_closure = rpc_client.clone();
         database.run_in_transaction(move |tx| {
-            let notification = notification.clone();
-            let rpc_client = rpc_client_for_closure.clone();
+            Box::pin(async move {
+                for (accepting_block_hash, transaction_ids) in &accepted {
+                    let Ok(accepting_block_id) = database_for_closure.block_id_by_hash(tx, accepting_block_hash).await else {
+                        debug!("Accepting block {} is not stored; its accepted transactions are skipped", accepting_block_hash);
+                        continue;
+                    };
+                    database_for_closure.store_accepted_transactions(tx, accepting_block_id, transaction_ids).await?;
+                }
+                resume_marker.store(&database_for_closure, tx).await?;
+                Ok(())
+            })
+        }).await?;
+        Ok(())
+    }
+
+    /// Stores virtual chain changes one at a time, in the order the node
+    /// sent them, then recomputes the merge set colors they call for up to
+    /// `options.color_batch_size` changes at a time in a single transaction.
+    ///
+    /// Chain membership is stored as soon as a change arrives, but its
+    /// coloring is held back, along with every later one, until the blue
+    /// score of the node sink is `options.coloring_confirmations` above its
+    /// last added chain block, so colors near the tip don't flap during
+    /// small reorgs. Held back colorings are checked again every
+    /// `COLORING_RECHECK_INTERVAL`, and applied regardless once `shutdown`
+    /// fires, before the worker stops.
+    async fn run_virtual_chain_worker(
+        database: Database,
+        rpc_client: Arc<RpcClient>,
+        mut receiver: mpsc::UnboundedReceiver<VirtualChainChangedNotification>,
+        options: VirtualChainOptions,
+        mut shutdown: watch::Receiver<bool>,
+    ) {
+        // Held back colorings, with the blue score of the last chain block
+        // their change added. A change adding none waits as long as the one
+        // before it
+        let mut pending: VecDeque<(u64, ColorUpdate)> = VecDeque::new();
+        let mut last_blue_score = 0;
+        let mut recheck = tokio::time::interval(COLORING_RECHECK_INTERVAL);
+        recheck.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            let mut received = vec![];
+            tokio::select! {
+                notification = receiver.recv() => match notification {
+                    Some(notification) => received.push(notification),
+                    None => break,
+                },
+                _ = recheck.tick() => {}
+                _ = shutdown.changed() => break,
+            }
+            while let Ok(notification) = receiver.try_recv() {
+                received.push(notification);
+            }
+            for notification in received {
+                if options.coloring_confirmations > 0 {
+                    if let Some(hash) = notification.added_chain_block_hashes.last() {
+                        match rpc_client.get_block(*hash, false).await {
+                            Ok(response) => last_blue_score = response.block.header.blue_score,
+                            Err(e) => warn!("Could not get the blue score of chain block {}; holding its coloring back like the previous one: {}", hash, e),
+                        }
+                    }
+                }
+                for update in Self::store_virtual_chain_change(&database, notification, options).await {
+                    pending.push_back((last_blue_score, update));
+                }
+            }
+
+            let released = if options.coloring_confirmations == 0 || pending.is_empty() {
+                pending.len()
+            } else {
+                match Self::sink_blue_score(&rpc_client).await {
+                    Ok(sink_blue_score) => pending.iter()
+                        .take_while(|(blue_score, _)| sink_blue_score.saturating_sub(*blue_score) >= options.coloring_confirmations)
+                        .count(),
+                    Err(e) => {
+                        warn!("Could not get the blue score of the node sink; holding back the coloring of {} virtual chain changes: {}", pending.len(), e);
+                        0
+                    }
+                }
+            };
+            let released: Vec<_> = pending.drain(..released).map(|(_, update)| update).collect();
+            Self::apply_color_update_batches(&database, &rpc_client, released, options).await;
+        }
+
+        while let Ok(notification) = receiver.try_recv() {
+            for update in Self::store_virtual_chain_change(&database, notification, options).await {
+                pending.push_back((last_blue_score, update));
+            }
+        }
+        if !pending.is_empty() {
+            info!("Applying the held back coloring of {} virtual chain changes before stopping", pending.len());
+            let pending: Vec<_> = pending.into_iter().map(|(_, update)| update).collect();
+            Self::apply_color_update_batches(&database, &rpc_client, pending, options).await;
+        }
+    }
+
+    async fn sink_blue_score(rpc_client: &RpcClient) -> Result<u64, TgiError> {
+        let sink = rpc_client.get_sink().await?.sink;
+        Ok(rpc_client.get_block(sink, false).await?.block.header.blue_score)
+    }
+
+    /// Stores `notification`, recording it for the next start when it fails
+    /// every attempt, and returns the merge set colorings it calls for,
+    /// none when coloring is disabled.
+    async fn store_virtual_chain_change(
+        database: &Database,
+        notification: VirtualChainChangedNotification,
+        options: VirtualChainOptions,
+    ) -> Vec<ColorUpdate> {
+        let result = Self::with_notification_retries("virtual chain changed notification", || {
+            Self::process_virtual_chain_changed_notification(
+                database, notification.clone(), options.track_accepted_transactions
+            )
+        }).await;
+        match result {
+            Ok(_) if options.disable_coloring => vec![],
+            Ok(updates) => updates,
+            Err(e) => {
+                if let Some(block_hash) = Self::virtual_chain_replay_hash(&notification) {
+                    Self::dead_letter_notification(database, VIRTUAL_CHAIN_CHANGED_NOTIFICATION, &block_hash, &e).await;
+                }
+                vec![]
+            }
+        }
+    }
+
+    /// Applies `color_updates` in order, `options.color_batch_size` at a time.
+    async fn apply_color_update_batches(
+        database: &Database,
+        rpc_client: &RpcClient,
+        mut color_updates: Vec<ColorUpdate>,
+        options: VirtualChainOptions,
+    ) {
+        while !color_updates.is_empty() {
+            let rest = color_updates.split_off(color_updates.len().min(options.color_batch_size));
+            Self::apply_color_updates_with_retries(database, rpc_client, Arc::new(color_updates)).await;
+            color_updates = rest;
+        }
+    }
+
+    async fn apply_color_updates_with_retries(database: &Database, rpc_client: &RpcClient, updates: Arc<Vec<ColorUpdate>>) {
+        let mut attempt = 1;
+        loop {
+            match Self::apply_color_updates(database, rpc_client, updates.clone()).await {
+                Ok(()) => break,
+                Err(e) if attempt < COLOR_UPDATE_ATTEMPTS => {
+                    warn!("Error recomputing merge set colors (attempt {}/{}), retrying: {}", attempt, COLOR_UPDATE_ATTEMPTS, e);
+                    attempt += 1;
+                    tokio::time::sleep(COLOR_UPDATE_RETRY_DELAY).await;
+                }
+                Err(e) => {
+                    Self::dead_letter_color_updates(database, &updates, &e).await;
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Records the chain blocks of color updates that failed every attempt,
+    /// so the next start colors their merge sets instead of them staying
+    /// stale. A chain block recorded as added is no longer recorded as
+    /// removed and the other way around, as the latest update wins.
+    async fn dead_letter_color_updates(database: &Database, updates: &Arc<Vec<ColorUpdate>>, error: &anyhow::Error) {
+        error!("Giving up recomputing merge set colors of {} virtual chain changes: {}", updates.len(), error);
+        #[cfg(feature = "metrics")]
+        crate::metrics::record_failed_notification(COLOR_UPDATE_ADDED);
+        let database_for_closure = database.clone();
+        let updates_for_closure = updates.clone();
+        let error_message = format!("{:#}", error);
+        let result = database.run_in_transaction(move |tx| {
             let database = database_for_closure.clone();
             Box::pin(async move {
-                let mut block_is_in_virtual_selected_parent_chain: HashMap<u64, bool> = HashMap::new();
-                
-                for removed_hash in notification.removed_chain_block_hashes.iter() {
-                    let removed_hash_str = removed_hash.to_string();
-                    if let Ok(removed_block_id) = database.block_id_by_hash(tx, &removed_hash_str).await {
-                        block_is_in_virtual_selected_parent_chain.insert(removed_block_id, false);
+                for update in updates_for_closure.iter() {
+                    let removed_hashes = database.block_hashes_by_ids(tx, &update.removed_block_ids).await?;
+                    for removed_hash in removed_hashes.values() {
+                        database.remove_failed_notification(tx, COLOR_UPDATE_ADDED, removed_hash).await?;
+                        database.add_failed_notification(tx, COLOR_UPDATE_REMOVED, removed_hash, &error_message).await?;
+                    }
+                    for added_hash in &update.added_chain_block_hashes {
+                        database.remove_failed_notification(tx, COLOR_UPDATE_REMOVED, added_hash).await?;
+                        database.add_failed_notification(tx, COLOR_UPDATE_ADDED, added_hash, &error_message).await?;
                     }
                 }
-                
-                for added_hash in notification.added_chain_block_hashes.iter() {
-                    let added_hash_str = added_hash.to_string();
-                    if let Ok(added_block_id) = database.block_id_by_hash(tx, &added_hash_str).await {
-                        block_is_in_virtual_selected_parent_chain.insert(added_block_id, true);
+                Ok(())
+            })
+        }).await;
+        if let Err(e) = result {
+            error!("Could not record the failed merge set coloring of {} virtual chain changes: {}", updates.len(), e);
+        }
+    }
+
+    /// Colors the merge sets of the chain blocks recorded by
+    /// `dead_letter_color_updates` in an earlier run. Blocks pruned since are
+    /// skipped; the records are kept when coloring fails again.
+    async fn retry_failed_color_updates(database: &Database, rpc_client: &RpcClient) -> Result<()> {
+        let database_for_closure = database.clone();
+        let (update, removed_hashes, added_hashes) = database.run_in_read_transaction(move |tx| {
+            Box::pin(async move {
+                let removed_hashes = database_for_closure.failed_notifications(tx, COLOR_UPDATE_REMOVED).await?;
+                let added_hashes = database_for_closure.failed_notifications(tx, COLOR_UPDATE_ADDED).await?;
+                let mut update = ColorUpdate { removed_block_ids: vec![], added_chain_block_hashes: vec![] };
+                for removed_hash in &removed_hashes {
+                    match database_for_closure.block_id_by_hash(tx, removed_hash).await {
+                        Ok(block_id) => update.removed_block_ids.push(block_id),
+                        Err(TgiError::BlockNotFound(_)) => {}
+                        Err(e) => return Err(e.into()),
                     }
                 }
-                
-                let updates: Vec<(u64, bool)> = block_is_in_virtual_selected_parent_chain.iter().map(|(k, v)| (*k, *v)).collect();
-        database.update_block_is_in_virtual_selected_parent_chain(tx, &updates).await?;
-                
-                let mut block_colors: HashMap<u64, String> = HashMap::new();
-                
-                for added_hash in notification.added_chain_block_hashes.iter() {
-                    let added_hash_str = added_hash.to_string();
-                    let rpc_block_resp = rpc_client.get_block(&added_hash_str, false).await?;
-                    if let Some(verbose_data) = rpc_block_resp.block.verbose_data {
-                        for blue_hash in &verbose_data.merge_set_blues_hashes {
-                            let blue_hash_str = blue_hash.to_string();
-                            if let Ok(blue_block_id) = database.block_id_by_hash(tx, &blue_hash_str).await {
-                                block_colors.insert(blue_block_id, "blue".to_string());
-                            }
-                        }
-                        for red_hash in &verbose_data.merge_set_reds_hashes {
-                            let red_hash_str = red_hash.to_string();
-                            if let Ok(red_block_id) = database.block_id_by_hash(tx, &red_hash_str).await {
-                                block_colors.insert(red_block_id, "red".to_string());
-                            }
-                        }
+                for added_hash in &added_hashes {
+                    match database_for_closure.block_id_by_hash(tx, added_hash).await {
+                        Ok(_) => update.added_chain_block_hashes.push(*added_hash),
+                        Err(TgiError::BlockNotFound(_)) => {}
+                        Err(e) => return Err(e.into()),
                     }
                 }
-                
-                let color_updates: Vec<(u64, String)> = block_colors.iter().map(|(k, v)| (*k, v.clone())).collect();
-        database.update_block_colors(tx, &color_updates).await?;
+                Ok((update, removed_hashes, added_hashes))
+            })
+        }).await?;
+        if removed_hashes.is_empty() && added_hashes.is_empty() {
+            return Ok(());
+        }
+        info!(
+            "Recomputing the merge set colors of {} chain blocks whose coloring failed before",
+            removed_hashes.len() + added_hashes.len()
+        );
+        Self::apply_color_updates(database, rpc_client, Arc::new(vec![update])).await?;
+
+        let database_for_closure = database.clone();
+        database.run_in_transaction(move |tx| {
+            Box::pin(async move {
+                for removed_hash in &removed_hashes {
+                    database_for_closure.remove_failed_notification(tx, COLOR_UPDATE_REMOVED, removed_hash).await?;
+                }
+                for added_hash in &added_hashes {
+                    database_for_closure.remove_failed_notification(tx, COLOR_UPDATE_ADDED, added_hash).await?;
+                }
+                Ok(())
+            })
+        }).await
+    }
+
+    async fn apply_color_updates(
+        database: &Database,
+        rpc_client: &RpcClient,
+        updates: Arc<Vec<ColorUpdate>>,
+    ) -> Result<()> {
+        let mut merge_sets = Vec::with_capacity(updates.len());
+        for update in updates.iter() {
+            merge_sets.push(Self::fetch_chain_merge_sets(rpc_client, &update.added_chain_block_hashes).await?);
+        }
+
+        let database_for_closure = database.clone();
+        database.run_in_transaction(move |tx| {
+            let database = database_for_closure.clone();
+            Box::pin(async move {
+                // A chain block may have left the chain since its coloring
+                // was computed or held back; its merge set is then colored
+                // by the chain blocks that replaced it
+                let added_hashes: Vec<BlockHash> = updates.iter()
+                    .flat_map(|update| update.added_chain_block_hashes.iter().copied())
+                    .collect();
+                let left_chain: HashSet<BlockHash> = database.hashes_not_in_virtual_selected_parent_chain(tx, &added_hashes).await?
+                    .into_iter().collect();
+
+                // Later changes override the colors of earlier ones
+                let mut block_colors: HashMap<u64, BlockColorUpdate> = HashMap::new();
+                for (update, merge_sets) in updates.iter().zip(&merge_sets) {
+                    let merge_sets: Vec<_> = merge_sets.iter()
+                        .filter(|merge_set| !left_chain.contains(&merge_set.chain_block_hash))
+                        .cloned()
+                        .collect();
+                    block_colors.extend(Self::color_chain_merge_sets(&database, tx, &update.removed_block_ids, &merge_sets).await?);
+                }
+                let color_updates: Vec<BlockColorUpdate> = block_colors.into_values().collect();
+                database.update_block_colors(tx, &color_updates).await?;
                 Ok(())
             })
         }).await
@@ -0,0 +1,166 @@
+/// How many blocks a resync cycle processes with their missing dependencies
+/// before it trusts the topological order of the node and stops looking
+const DEPENDENCY_COLLECTION_LIMIT: usize = 6000;
+
+/// Stored blocks re-processed before the latest one found during a resync,
+/// in case the ones right before it were only partially processed
+const STORED_BLOCKS_LOOKBACK: usize = 3000;
+
+use super::BlockProcessingOptions;
+
+/// Policy of a block processing pass, deciding how blocks are stored, which
+/// safety nets run and how often the pass commits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessingMode {
+    /// Filling an empty database: blocks are bulk inserted in chunks
+    ColdSync,
+    /// `--resync`: every block from the starting point is processed again,
+    /// trusting the node order instead of collecting dependencies
+    Resync,
+    /// Catching up with, or following, the node: blocks already stored are
+    /// skipped and missing dependencies are collected
+    Live,
+}
+
+impl ProcessingMode {
+    /// Mode of a resync cycle. A height cap rules out bulk inserts since they
+    /// don't check it.
+    pub fn for_resync_cycle(keep_database: bool, resync: bool, capped: bool) -> Self {
+        if !keep_database && !capped {
+            Self::ColdSync
+        } else if resync {
+            Self::Resync
+        } else {
+            Self::Live
+        }
+    }
+
+    /// Number of blocks per bulk insert, or `None` if blocks are processed
+    /// one by one.
+    pub fn bulk_insert_chunk_size(self) -> Option<usize> {
+        match self {
+            Self::ColdSync => Some(super::BULK_SYNC_CHUNK_SIZE),
+            Self::Resync | Self::Live => None,
+        }
+    }
+
+    /// Whether blocks already stored are skipped rather than processed again
+    pub fn skips_stored_blocks(self) -> bool {
+        matches!(self, Self::Live)
+    }
+
+    /// Whether stored blocks get their selected parent and merge sets
+    /// written again even when an earlier pass completed them. Bulk inserts
+    /// only ever add new blocks
+    pub fn upserts_stored_blocks(self) -> bool {
+        matches!(self, Self::Resync)
+    }
+
+    /// `options` as a pass in this mode processes blocks with
+    pub fn block_processing_options(self, options: BlockProcessingOptions) -> BlockProcessingOptions {
+        BlockProcessingOptions {
+            reprocess_complete_blocks: options.reprocess_complete_blocks || self.upserts_stored_blocks(),
+            ..options
+        }
+    }
+
+    /// Blocks a transaction processes before committing, given the
+    /// configured limit. Bulk inserts commit on chunk boundaries, so a limit
+    /// of at least a chunk is rounded down to whole chunks
+    pub fn transaction_block_limit(self, configured: Option<usize>) -> Option<usize> {
+        match (self.bulk_insert_chunk_size(), configured) {
+            (Some(chunk_size), Some(limit)) if limit >= chunk_size => Some(limit / chunk_size * chunk_size),
+            (_, limit) => limit,
+        }
+    }
+
+    /// Index in the node blocks to resume at, given the index of the latest
+    /// block already stored
+    pub fn resume_index(latest_stored_index: usize) -> usize {
+        latest_stored_index.saturating_sub(STORED_BLOCKS_LOOKBACK)
+    }
+
+    /// Whether the `processed`-th block of a pass gets its missing
+    /// dependencies collected first
+    pub fn collects_dependencies(self, processed: usize) -> bool {
+        match self {
+            Self::Live => processed < DEPENDENCY_COLLECTION_LIMIT,
+            Self::ColdSync | Self::Resync => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processing::BULK_SYNC_CHUNK_SIZE;
+
+    const MODES: [ProcessingMode; 3] = [ProcessingMode::ColdSync, ProcessingMode::Resync, ProcessingMode::Live];
+
+    #[test]
+    fn resync_cycle_mode_follows_the_database_and_the_flags() {
+        assert_eq!(ProcessingMode::for_resync_cycle(false, false, false), ProcessingMode::ColdSync);
+        assert_eq!(ProcessingMode::for_resync_cycle(false, true, false), ProcessingMode::ColdSync);
+        // A height cap rules out bulk inserts
+        assert_eq!(ProcessingMode::for_resync_cycle(false, false, true), ProcessingMode::Live);
+        assert_eq!(ProcessingMode::for_resync_cycle(false, true, true), ProcessingMode::Resync);
+        assert_eq!(ProcessingMode::for_resync_cycle(true, true, false), ProcessingMode::Resync);
+        assert_eq!(ProcessingMode::for_resync_cycle(true, false, false), ProcessingMode::Live);
+    }
+
+    #[test]
+    fn only_cold_sync_bulk_inserts() {
+        let chunk_sizes: Vec<_> = MODES.iter().map(|mode| mode.bulk_insert_chunk_size()).collect();
+        assert_eq!(chunk_sizes, vec![Some(BULK_SYNC_CHUNK_SIZE), None, None]);
+    }
+
+    #[test]
+    fn only_live_skips_stored_blocks() {
+        let skips: Vec<_> = MODES.iter().map(|mode| mode.skips_stored_blocks()).collect();
+        assert_eq!(skips, vec![false, false, true]);
+    }
+
+    #[test]
+    fn only_resync_upserts_stored_blocks() {
+        let upserts: Vec<_> = MODES.iter().map(|mode| mode.upserts_stored_blocks()).collect();
+        assert_eq!(upserts, vec![false, true, false]);
+
+        let options = BlockProcessingOptions::default();
+        let reprocesses: Vec<_> = MODES.iter()
+            .map(|mode| mode.block_processing_options(options).reprocess_complete_blocks)
+            .collect();
+        assert_eq!(reprocesses, vec![false, true, false]);
+        // An explicit request to reprocess holds in every mode
+        let options = BlockProcessingOptions { reprocess_complete_blocks: true, ..Default::default() };
+        assert!(MODES.iter().all(|mode| mode.block_processing_options(options).reprocess_complete_blocks));
+    }
+
+    #[test]
+    fn only_live_collects_dependencies_and_only_at_first() {
+        for mode in MODES {
+            assert_eq!(mode.collects_dependencies(0), mode == ProcessingMode::Live, "{:?}", mode);
+        }
+        assert!(ProcessingMode::Live.collects_dependencies(DEPENDENCY_COLLECTION_LIMIT - 1));
+        assert!(!ProcessingMode::Live.collects_dependencies(DEPENDENCY_COLLECTION_LIMIT));
+    }
+
+    #[test]
+    fn cold_sync_commits_on_chunk_boundaries() {
+        let chunk_size = BULK_SYNC_CHUNK_SIZE;
+        assert_eq!(ProcessingMode::ColdSync.transaction_block_limit(Some(chunk_size + 1)), Some(chunk_size));
+        assert_eq!(ProcessingMode::ColdSync.transaction_block_limit(Some(3 * chunk_size - 1)), Some(2 * chunk_size));
+        // A limit below a chunk still holds
+        assert_eq!(ProcessingMode::ColdSync.transaction_block_limit(Some(2)), Some(2));
+        assert_eq!(ProcessingMode::ColdSync.transaction_block_limit(None), None);
+        for mode in [ProcessingMode::Resync, ProcessingMode::Live] {
+            assert_eq!(mode.transaction_block_limit(Some(1)), Some(1));
+            assert_eq!(mode.transaction_block_limit(None), None);
+        }
+    }
+
+    #[test]
+    fn resume_index_looks_back_over_stored_blocks() {
+        assert_eq!(ProcessingMode::resume_index(STORED_BLOCKS_LOOKBACK + 5), 5);
+        assert_eq!(ProcessingMode::resume_index(10), 0);
+    }
+}
@@ -0,0 +1,1641 @@
+//! Processing against a mock node and a test database. See
+//! `database::testing` for the database these tests need.
+
+use super::*;
+use crate::database::testing::TestDatabase;
+use crate::database::{GraphBounds, COLOR_GRAY};
+use crate::rpc_client::mock::MockRpcApi;
+use crate::rpc_client::GetInfoResponse;
+use tondi_rpc_core::model::{RpcAcceptedTransactionIds, RpcHash, RpcMempoolEntry, RpcTransaction};
+
+fn mock_node() -> (Arc<MockRpcApi>, RpcClient) {
+    let mock = Arc::new(MockRpcApi::new(MockRpcApi::synthetic_genesis()));
+    let rpc_client = RpcClient::with_node(mock.clone(), "mock", 8);
+    (mock, rpc_client)
+}
+
+async fn fetch_block(rpc_client: &RpcClient, hash: RpcHash) -> RpcBlock {
+    rpc_client.get_block(hash, false).await.expect("the mock knows the block").block
+}
+
+/// Processes `hashes` as added block notifications, one transaction each.
+async fn process_blocks(test_database: &TestDatabase, rpc_client: &RpcClient, hashes: &[RpcHash]) {
+    for hash in hashes {
+        let block = fetch_block(rpc_client, *hash).await;
+        Processing::process_block_notifications(&test_database.database, rpc_client, vec![block], BlockProcessingOptions::default())
+            .await.expect("failed to process the block");
+    }
+}
+
+/// Whether the block is stored, asked on a separate connection so the block
+/// cache can't answer.
+async fn is_stored(test_database: &TestDatabase, hash: RpcHash) -> bool {
+    let row = test_database.client().await
+        .query_one("SELECT EXISTS (SELECT 1 FROM blocks WHERE block_hash = $1)", &[&hash.to_string()]).await
+        .expect("failed to look up the block");
+    row.get(0)
+}
+
+/// Fails the first edge insert with a serialization failure, which the
+/// notification retries are meant to get past.
+async fn fail_first_edge_insert(test_database: &TestDatabase) {
+    test_database.client().await.batch_execute(
+        r#"
+        CREATE SEQUENCE edge_inserts;
+        CREATE FUNCTION fail_first_edge_insert() RETURNS trigger AS $$
+        BEGIN
+            -- Sequences aren't rolled back, so only the first insert fails
+            IF nextval('edge_inserts') = 1 THEN
+                RAISE EXCEPTION 'injected failure' USING ERRCODE = '40001';
+            END IF;
+            RETURN NEW;
+        END
+        $$ LANGUAGE plpgsql;
+        CREATE TRIGGER fail_first_edge_insert BEFORE INSERT ON edges
+            FOR EACH ROW EXECUTE FUNCTION fail_first_edge_insert();
+        "#,
+    ).await.expect("failed to inject the failure");
+}
+
+#[tokio::test]
+async fn notification_retry_stores_the_block_after_a_transient_failure() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    process_blocks(&test_database, &rpc_client, &[mock.genesis_hash()]).await;
+    let hash = mock.add_block(&[mock.genesis_hash()]);
+    let block = fetch_block(&rpc_client, hash).await;
+    fail_first_edge_insert(&test_database).await;
+
+    let mut attempts = 0;
+    Processing::with_notification_retries("block added notification", || {
+        attempts += 1;
+        Processing::process_block_notifications(&test_database.database, &rpc_client, vec![block.clone()], BlockProcessingOptions::default())
+    }).await.expect("the retry should succeed");
+
+    assert_eq!(attempts, 2);
+    assert!(is_stored(&test_database, hash).await);
+}
+
+async fn is_chain_block(test_database: &TestDatabase, hash: RpcHash) -> bool {
+    let row = test_database.client().await
+        .query_one("SELECT is_in_virtual_selected_parent_chain FROM blocks WHERE block_hash = $1", &[&hash.to_string()]).await
+        .expect("failed to look up the block");
+    row.get(0)
+}
+
+#[tokio::test]
+async fn failed_virtual_chain_change_is_replayed_from_its_start_block() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let a = mock.add_block(&[mock.genesis_hash()]);
+    let b = mock.add_block(&[a]);
+    process_blocks(&test_database, &rpc_client, &[mock.genesis_hash(), a, b]).await;
+    assert!(!is_chain_block(&test_database, a).await);
+
+    Processing::replay_virtual_chain_change(&test_database.database, &rpc_client, &a.into(), false, false)
+        .await.expect("failed to replay the change");
+
+    assert!(is_chain_block(&test_database, a).await);
+    assert!(is_chain_block(&test_database, b).await);
+}
+
+/// A change moving the chain from `removed` to `added`, stored in two
+/// transactions: removals first, then additions.
+fn reorg(removed: RpcHash, added: RpcHash) -> VirtualChainChangedNotification {
+    VirtualChainChangedNotification {
+        removed_chain_block_hashes: Arc::new(vec![removed]),
+        added_chain_block_hashes: Arc::new(vec![added]),
+        accepted_transaction_ids: Arc::new(vec![]),
+    }
+}
+
+async fn failed_virtual_chain_changes(test_database: &TestDatabase) -> Vec<String> {
+    test_database.client().await
+        .query("SELECT block_hash FROM failed_notifications WHERE kind = $1", &[&VIRTUAL_CHAIN_CHANGED_NOTIFICATION]).await
+        .expect("failed to read the failed notifications")
+        .iter().map(|row| row.get(0)).collect()
+}
+
+#[tokio::test]
+async fn virtual_chain_change_interrupted_between_transactions_is_left_for_replay() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let a = mock.add_block(&[mock.genesis_hash()]);
+    let b = mock.add_block(&[mock.genesis_hash()]);
+    process_blocks(&test_database, &rpc_client, &[mock.genesis_hash(), a, b]).await;
+    // Fails the transaction adding chain blocks, after the removals committed
+    test_database.client().await.batch_execute(
+        r#"
+        CREATE FUNCTION fail_chain_additions() RETURNS trigger AS $$
+        BEGIN
+            RAISE EXCEPTION 'injected failure';
+        END
+        $$ LANGUAGE plpgsql;
+        CREATE TRIGGER fail_chain_additions BEFORE UPDATE ON blocks
+            FOR EACH ROW WHEN (NEW.is_in_virtual_selected_parent_chain) EXECUTE FUNCTION fail_chain_additions();
+        "#,
+    ).await.expect("failed to inject the failure");
+
+    let result = Processing::process_virtual_chain_changed_notification(&test_database.database, reorg(a, b), false).await;
+
+    assert!(result.is_err());
+    assert_eq!(failed_virtual_chain_changes(&test_database).await, vec![a.to_string()]);
+}
+
+#[tokio::test]
+async fn virtual_chain_change_stored_in_several_transactions_leaves_no_resume_marker() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let a = mock.add_block(&[mock.genesis_hash()]);
+    let b = mock.add_block(&[mock.genesis_hash()]);
+    process_blocks(&test_database, &rpc_client, &[mock.genesis_hash(), a, b]).await;
+
+    Processing::process_virtual_chain_changed_notification(&test_database.database, reorg(a, b), false)
+        .await.expect("failed to store the change");
+
+    assert!(!is_chain_block(&test_database, a).await);
+    assert!(is_chain_block(&test_database, b).await);
+    assert!(failed_virtual_chain_changes(&test_database).await.is_empty());
+}
+
+async fn accepted_transaction_ids(test_database: &TestDatabase, hash: RpcHash) -> Vec<String> {
+    let block_id = block_id(test_database, hash).await;
+    let database_for_closure = test_database.database.clone();
+    test_database.database.run_in_read_transaction(move |tx| Box::pin(async move {
+        Ok(database_for_closure.accepted_transaction_ids(tx, block_id).await?)
+    })).await.expect("failed to read the accepted transactions")
+}
+
+#[tokio::test]
+async fn transactions_accepted_by_chain_blocks_are_stored_until_they_leave_the_chain() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let a = mock.add_block(&[mock.genesis_hash()]);
+    let b = mock.add_block(&[a]);
+    let other = mock.add_block(&[a]);
+    process_blocks(&test_database, &rpc_client, &[mock.genesis_hash(), a, b, other]).await;
+    let transaction_ids = [RpcHash::from_bytes([0x11; 32]), RpcHash::from_bytes([0x22; 32])];
+    let notification = VirtualChainChangedNotification {
+        removed_chain_block_hashes: Arc::new(vec![]),
+        added_chain_block_hashes: Arc::new(vec![a, b]),
+        accepted_transaction_ids: Arc::new(vec![RpcAcceptedTransactionIds {
+            accepting_block_hash: b,
+            accepted_transaction_ids: transaction_ids.to_vec(),
+        }]),
+    };
+
+    Processing::process_virtual_chain_changed_notification(&test_database.database, notification, true)
+        .await.expect("failed to store the change");
+
+    let expected: Vec<String> = transaction_ids.iter().map(|id| id.to_string()).collect();
+    assert_eq!(accepted_transaction_ids(&test_database, b).await, expected);
+    assert!(accepted_transaction_ids(&test_database, a).await.is_empty());
+
+    Processing::process_virtual_chain_changed_notification(&test_database.database, reorg(b, other), true)
+        .await.expect("failed to store the reorg");
+
+    assert!(accepted_transaction_ids(&test_database, b).await.is_empty());
+}
+
+fn transaction(payload: Vec<u8>) -> RpcTransaction {
+    RpcTransaction {
+        version: 0,
+        inputs: vec![],
+        outputs: vec![],
+        lock_time: 0,
+        subnetwork_id: Default::default(),
+        gas: 0,
+        payload,
+        mass: 0,
+        verbose_data: None,
+    }
+}
+
+fn mempool_entry(fee: u64, is_orphan: bool) -> RpcMempoolEntry {
+    RpcMempoolEntry { fee, transaction: transaction(vec![]), is_orphan }
+}
+
+#[tokio::test]
+async fn mempool_sample_counts_orphans_apart() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    mock.set_mempool_entries(vec![mempool_entry(10, false), mempool_entry(20, false), mempool_entry(40, true)]);
+
+    let sample = Processing::sample_mempool(&test_database.database, &rpc_client).await
+        .expect("failed to sample the mempool");
+
+    assert_eq!((sample.transaction_count, sample.orphan_count, sample.total_fees), (2, 1, 30));
+    let row = test_database.client().await
+        .query_one("SELECT transaction_count, orphan_count, total_fees FROM mempool_samples", &[]).await
+        .expect("the sample should be stored");
+    assert_eq!((row.get::<_, i64>(0), row.get::<_, i64>(1), row.get::<_, i64>(2)), (2, 1, 30));
+}
+
+/// Coinbase payload paying `script`, laid out as `coinbase` parses it
+fn coinbase_payload(script: &[u8]) -> Vec<u8> {
+    let mut payload = vec![];
+    payload.extend_from_slice(&7u64.to_le_bytes());
+    payload.extend_from_slice(&50u64.to_le_bytes());
+    payload.extend_from_slice(&0u16.to_le_bytes());
+    payload.push(script.len() as u8);
+    payload.extend_from_slice(script);
+    payload.extend_from_slice(b"extra data");
+    payload
+}
+
+#[tokio::test]
+async fn tracked_miners_are_stored_and_queried_by_payout_script() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let a = mock.add_block(&[mock.genesis_hash()]);
+    let b = mock.add_block(&[a]);
+    process_blocks(&test_database, &rpc_client, &[mock.genesis_hash()]).await;
+    let options = BlockProcessingOptions { track_miners: true, ..Default::default() };
+    for (hash, script) in [(a, [0xaa; 4]), (b, [0xbb; 4])] {
+        let mut block = fetch_block(&rpc_client, hash).await;
+        block.transactions = vec![transaction(coinbase_payload(&script))];
+        process_rpc_block_alone(&test_database, &rpc_client, block, options).await;
+    }
+
+    let database_for_closure = test_database.database.clone();
+    let mined = test_database.database.run_in_read_transaction(move |tx| Box::pin(async move {
+        Ok(database_for_closure.blocks_by_miner(tx, "aaaaaaaa", 10).await?)
+    })).await.expect("failed to query the miner");
+
+    let mined: Vec<(String, Option<String>)> = mined.into_iter().map(|block| (block.block_hash, block.miner)).collect();
+    assert_eq!(mined, vec![(a.to_string(), Some("aaaaaaaa".to_string()))]);
+}
+
+async fn is_header_only(test_database: &TestDatabase, hash: RpcHash) -> bool {
+    let row = test_database.client().await
+        .query_one("SELECT is_header_only FROM blocks WHERE block_hash = $1", &[&hash.to_string()]).await
+        .expect("failed to look up the block");
+    row.get(0)
+}
+
+#[tokio::test]
+async fn incomplete_block_is_fetched_again_before_processing() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    process_blocks(&test_database, &rpc_client, &[mock.genesis_hash()]).await;
+    let hash = mock.add_block(&[mock.genesis_hash()]);
+    mock.return_header_only_next(1);
+    let block = fetch_block(&rpc_client, hash).await;
+    assert!(Processing::is_incomplete(&block));
+
+    let options = BlockProcessingOptions { incomplete_block_retries: 1, ..Default::default() };
+    Processing::process_block_notifications(&test_database.database, &rpc_client, vec![block], options)
+        .await.expect("failed to process the block");
+
+    assert!(!is_header_only(&test_database, hash).await);
+}
+
+#[tokio::test]
+async fn block_still_incomplete_after_the_retries_is_stored_header_only() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    process_blocks(&test_database, &rpc_client, &[mock.genesis_hash()]).await;
+    let hash = mock.add_block(&[mock.genesis_hash()]);
+    mock.return_header_only_next(3);
+    let block = fetch_block(&rpc_client, hash).await;
+
+    let options = BlockProcessingOptions { incomplete_block_retries: 1, ..Default::default() };
+    Processing::process_block_notifications(&test_database.database, &rpc_client, vec![block], options)
+        .await.expect("failed to process the block");
+
+    assert!(is_header_only(&test_database, hash).await);
+}
+
+#[test]
+fn out_of_order_hashes_are_sorted_parents_first() {
+    let [genesis, a, b, c, side]: [BlockHash; 5] = std::array::from_fn(|n| BlockHash::parse(&format!("{:064x}", n)).unwrap());
+    let parents = HashMap::from([
+        (a, vec![genesis]),
+        (b, vec![a]),
+        (c, vec![b, side]),
+        (side, vec![a]),
+    ]);
+
+    let sorted = Processing::sort_topologically(&[c, b, genesis, side, a], &parents);
+
+    // Blocks ready at the same time keep the node order
+    assert_eq!(sorted, vec![genesis, a, b, side, c]);
+    // Parents outside of the page don't hold their children back
+    assert_eq!(Processing::sort_topologically(&[c, side, b], &parents), vec![side, b, c]);
+}
+
+#[tokio::test]
+async fn resync_from_a_low_hash_unknown_to_the_node_starts_at_the_pruning_point() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let a = mock.add_block(&[mock.genesis_hash()]);
+    let b = mock.add_block(&[a]);
+    mock.set_block_dag_info(GetBlockDagInfoResponse {
+        network: "mainnet".parse().unwrap(),
+        block_count: 3,
+        header_count: 3,
+        tip_hashes: vec![b],
+        difficulty: 0.0,
+        past_median_time: 0,
+        virtual_parent_hashes: vec![b],
+        pruning_point_hash: mock.genesis_hash(),
+        virtual_daa_score: 2,
+        sink: b,
+    });
+
+    let unknown_hash = BlockHash::parse(&"ff".repeat(32)).unwrap();
+    let hashes = Processing::get_hashes_to_selected_tip(&test_database.database, &rpc_client, &unknown_hash, 2, 0).await
+        .expect("the resync should fall back to the pruning point");
+
+    assert_eq!(hashes, vec![mock.genesis_hash().into(), a.into(), b.into()]);
+}
+
+#[tokio::test]
+async fn oversized_resync_page_is_committed_in_parts() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let mut chain = vec![mock.genesis_hash()];
+    for _ in 0..5 {
+        chain.push(mock.add_block(&[*chain.last().unwrap()]));
+    }
+    let sink = *chain.last().unwrap();
+    mock.set_block_dag_info(GetBlockDagInfoResponse {
+        network: "mainnet".parse().unwrap(),
+        block_count: chain.len() as u64,
+        header_count: chain.len() as u64,
+        tip_hashes: vec![sink],
+        difficulty: 0.0,
+        past_median_time: 0,
+        virtual_parent_hashes: vec![sink],
+        pruning_point_hash: mock.genesis_hash(),
+        virtual_daa_score: chain.len() as u64,
+        sink,
+    });
+    let commits = Arc::new(std::sync::Mutex::new(vec![]));
+    let commits_for_hook = commits.clone();
+    let database = test_database.database.clone().with_commit_hook(Arc::new(move |summary| {
+        commits_for_hook.lock().unwrap().push(summary.rows_inserted);
+    }));
+    let options = ResyncOptions {
+        prefetch_blocks: 8,
+        transaction_blocks: Some(2),
+        ..Default::default()
+    };
+
+    // GetBlocks returns all 6 blocks in a single page
+    Processing::resync_database_static(&database, &Arc::new(rpc_client), options).await
+        .expect("the resync should succeed");
+
+    let commits = commits.lock().unwrap().clone();
+    assert_eq!(commits.len(), 3, "{:?}", commits);
+    assert!(commits.iter().all(|&rows_inserted| rows_inserted > 0), "{:?}", commits);
+    for hash in chain {
+        assert!(is_stored(&test_database, hash).await);
+    }
+}
+
+/// DAG info of a node holding `block_count` blocks up to the single tip
+/// `sink`
+fn block_dag_info(pruning_point_hash: RpcHash, sink: RpcHash, block_count: u64) -> GetBlockDagInfoResponse {
+    GetBlockDagInfoResponse {
+        network: "mainnet".parse().unwrap(),
+        block_count,
+        header_count: block_count,
+        tip_hashes: vec![sink],
+        difficulty: 0.0,
+        past_median_time: 0,
+        virtual_parent_hashes: vec![sink],
+        pruning_point_hash,
+        virtual_daa_score: block_count,
+        sink,
+    }
+}
+
+async fn has_selected_parent(test_database: &TestDatabase, hash: RpcHash) -> bool {
+    let row = test_database.client().await
+        .query_one("SELECT selected_parent_id IS NOT NULL FROM blocks WHERE block_hash = $1", &[&hash.to_string()]).await
+        .expect("failed to look up the block");
+    row.get(0)
+}
+
+#[tokio::test]
+async fn resync_of_a_fresh_network_seeds_the_genesis_pruning_point() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let rpc_client = Arc::new(rpc_client);
+    let options = ResyncOptions { prefetch_blocks: 8, ..Default::default() };
+    let a = mock.add_block(&[mock.genesis_hash()]);
+    let b = mock.add_block(&[a]);
+    mock.set_block_dag_info(block_dag_info(mock.genesis_hash(), b, 3));
+
+    Processing::resync_database_static(&test_database.database, &rpc_client, options.clone()).await
+        .expect("the resync from genesis should succeed");
+
+    assert_eq!(block_position(&test_database, mock.genesis_hash()).await, (0, 0));
+    assert!(!has_selected_parent(&test_database, mock.genesis_hash()).await);
+    assert_eq!(block_position(&test_database, b).await.0, 2);
+    assert!(has_selected_parent(&test_database, b).await);
+
+    // The genesis pruning point is kept, and the resync starts from it
+    let c = mock.add_block(&[b]);
+    mock.set_block_dag_info(block_dag_info(mock.genesis_hash(), c, 4));
+    Processing::resync_database_static(&test_database.database, &rpc_client, options.clone()).await
+        .expect("the second resync from genesis should succeed");
+
+    assert_eq!(block_position(&test_database, mock.genesis_hash()).await, (0, 0));
+    assert_eq!(block_position(&test_database, c).await.0, 3);
+}
+
+#[tokio::test]
+async fn resync_stops_once_it_reaches_the_node_sink() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let a = mock.add_block(&[mock.genesis_hash()]);
+    let b = mock.add_block(&[a]);
+    mock.set_block_dag_info(block_dag_info(mock.genesis_hash(), b, 3));
+    // Thresholds a fast network never gets under
+    let options = ResyncOptions { prefetch_blocks: 8, vspc_threshold: 0, tip_threshold: 0, ..Default::default() };
+
+    Processing::resync_database_static(&test_database.database, &Arc::new(rpc_client), options).await
+        .expect("the resync should succeed");
+
+    assert_eq!(mock.call_count("GetBlocks"), 1);
+    assert!(is_stored(&test_database, b).await);
+}
+
+#[tokio::test]
+async fn resync_short_of_the_node_sink_stops_at_the_tip_threshold() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let a = mock.add_block(&[mock.genesis_hash()]);
+    let b = mock.add_block(&[a]);
+    // The node returns its blocks up to a side block above the sink
+    let side = mock.add_block(&[a]);
+    mock.set_block_dag_info(block_dag_info(mock.genesis_hash(), b, 4));
+    let options = ResyncOptions { prefetch_blocks: 8, vspc_threshold: 100, tip_threshold: 100, ..Default::default() };
+
+    Processing::resync_database_static(&test_database.database, &Arc::new(rpc_client), options).await
+        .expect("the resync should succeed");
+
+    // The chain is resynced every cycle, and the second one ends the resync
+    assert_eq!(mock.call_count("GetBlocks"), 2);
+    assert!(is_stored(&test_database, side).await);
+}
+
+#[tokio::test]
+async fn resync_after_the_node_pruned_past_the_database_clears_it_or_aborts() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let rpc_client = Arc::new(rpc_client);
+    let options = ResyncOptions { prefetch_blocks: 8, ..Default::default() };
+    let a = mock.add_block(&[mock.genesis_hash()]);
+    mock.set_block_dag_info(block_dag_info(mock.genesis_hash(), a, 2));
+    Processing::resync_database_static(&test_database.database, &rpc_client, options.clone()).await
+        .expect("the first resync should succeed");
+    assert!(is_stored(&test_database, a).await);
+
+    // The node moves its pruning point past every stored block
+    let b = mock.add_block(&[a]);
+    let c = mock.add_block(&[b]);
+    let d = mock.add_block(&[c]);
+    mock.set_block_dag_info(block_dag_info(c, d, 5));
+
+    let abort_options = ResyncOptions { abort_if_pruned: true, ..options.clone() };
+    let error = Processing::resync_database_static(&test_database.database, &rpc_client, abort_options).await
+        .expect_err("the resync should abort");
+    assert!(error.to_string().contains("pruned past"), "{}", error);
+    assert!(is_stored(&test_database, a).await, "aborting should leave the database alone");
+
+    Processing::resync_database_static(&test_database.database, &rpc_client, options.clone()).await
+        .expect("the resync should clear the database");
+    assert!(!is_stored(&test_database, mock.genesis_hash()).await);
+    assert!(!is_stored(&test_database, a).await);
+    assert!(!is_stored(&test_database, b).await);
+    assert!(is_stored(&test_database, c).await);
+    assert!(is_stored(&test_database, d).await);
+}
+
+fn chain_change(added: &[RpcHash]) -> VirtualChainChangedNotification {
+    VirtualChainChangedNotification {
+        removed_chain_block_hashes: Arc::new(vec![]),
+        added_chain_block_hashes: Arc::new(added.to_vec()),
+        accepted_transaction_ids: Arc::new(vec![]),
+    }
+}
+
+/// Polls until the block joins the chain, failing after a few seconds.
+async fn wait_for_chain_block(test_database: &TestDatabase, hash: RpcHash) {
+    for _ in 0..50 {
+        if is_chain_block(test_database, hash).await {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("block {} never joined the chain", hash);
+}
+
+/// Polls until the block gets `color`, failing after a few seconds.
+async fn wait_for_color(test_database: &TestDatabase, hash: RpcHash, color: &str) {
+    for _ in 0..50 {
+        if block_color(test_database, hash).await == color {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("block {} never turned {}", hash, color);
+}
+
+#[tokio::test]
+async fn virtual_chain_changes_are_stored_right_away_and_colored_after_confirmations() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let side = mock.add_block(&[mock.genesis_hash()]);
+    let a = mock.add_block(&[mock.genesis_hash()]);
+    mock.reorg(mock.genesis_hash(), &[a]);
+    let b = mock.add_block_with_reds(&[a, side], &[side]);
+    process_blocks(&test_database, &rpc_client, &[mock.genesis_hash(), side, a, b]).await;
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let (shutdown, shutdown_receiver) = watch::channel(false);
+    let options = VirtualChainOptions { color_batch_size: 10, coloring_confirmations: 2, ..Default::default() };
+    let worker = tokio::spawn(Processing::run_virtual_chain_worker(
+        test_database.database.clone(), Arc::new(rpc_client.clone()), receiver, options, shutdown_receiver,
+    ));
+
+    // b is the sink, so its chain membership is stored but its merge set
+    // stays gray
+    sender.send(chain_change(&[a, b])).unwrap();
+    wait_for_chain_block(&test_database, b).await;
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(block_color(&test_database, side).await, COLOR_GRAY);
+
+    // With the sink 2 blue score above b, the next recheck colors its merge
+    // set without waiting for another change
+    let c = mock.add_block(&[b]);
+    let d = mock.add_block(&[c]);
+    process_blocks(&test_database, &rpc_client, &[c, d]).await;
+    wait_for_color(&test_database, side, COLOR_RED).await;
+    assert_eq!(block_color(&test_database, a).await, COLOR_BLUE);
+
+    // The coloring of c waits, and is applied on shutdown
+    sender.send(chain_change(&[c])).unwrap();
+    wait_for_chain_block(&test_database, c).await;
+    assert_eq!(block_color(&test_database, b).await, COLOR_GRAY);
+    shutdown.send_replace(true);
+    worker.await.unwrap();
+    assert_eq!(block_color(&test_database, b).await, COLOR_BLUE);
+    assert!(!is_chain_block(&test_database, d).await);
+}
+
+#[tokio::test]
+async fn disabled_coloring_stores_the_chain_without_fetching_merge_sets() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let side = mock.add_block(&[mock.genesis_hash()]);
+    let a = mock.add_block(&[mock.genesis_hash()]);
+    mock.reorg(mock.genesis_hash(), &[a]);
+    let b = mock.add_block_with_reds(&[a, side], &[side]);
+    process_blocks(&test_database, &rpc_client, &[mock.genesis_hash(), side, a, b]).await;
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let (shutdown, shutdown_receiver) = watch::channel(false);
+    let options = VirtualChainOptions { disable_coloring: true, color_batch_size: 10, ..Default::default() };
+    let worker = tokio::spawn(Processing::run_virtual_chain_worker(
+        test_database.database.clone(), Arc::new(rpc_client.clone()), receiver, options, shutdown_receiver,
+    ));
+    let get_block_calls = mock.call_count("GetBlock");
+
+    sender.send(chain_change(&[a, b])).unwrap();
+    wait_for_chain_block(&test_database, b).await;
+    shutdown.send_replace(true);
+    worker.await.unwrap();
+
+    assert_eq!(mock.call_count("GetBlock"), get_block_calls);
+    for hash in [mock.genesis_hash(), side, a, b] {
+        assert_eq!(block_color(&test_database, hash).await, COLOR_GRAY);
+    }
+}
+
+/// Keeps every event in memory
+#[derive(Default)]
+struct CapturingEventSink {
+    events: std::sync::Mutex<Vec<BlockEvent>>,
+}
+
+impl BlockEventSink for CapturingEventSink {
+    fn send(&self, event: BlockEvent) -> events::SendFuture<'_> {
+        self.events.lock().unwrap().push(event);
+        Box::pin(async { Ok(()) })
+    }
+}
+
+#[tokio::test]
+async fn added_blocks_are_published_once_stored() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let a = mock.add_block(&[mock.genesis_hash()]);
+    let b = mock.add_block(&[a]);
+    let event_sink = Arc::new(CapturingEventSink::default());
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let worker = tokio::spawn(Processing::run_block_notification_worker(
+        test_database.database.clone(), Arc::new(rpc_client.clone()), receiver, Arc::new(Mutex::new(Instant::now())),
+        event_sink.clone(), BlockProcessingOptions::default(), 2, Duration::from_millis(50),
+    ));
+
+    for hash in [mock.genesis_hash(), a, b] {
+        sender.send(fetch_block(&rpc_client, hash).await).unwrap();
+    }
+    drop(sender);
+    worker.await.unwrap();
+
+    let events = event_sink.events.lock().unwrap().clone();
+    let published: Vec<String> = events.iter().map(|event| event.block_hash.clone()).collect();
+    assert_eq!(published, vec![mock.genesis_hash().to_string(), a.to_string(), b.to_string()]);
+    assert_eq!(events[2].parent_hashes, vec![a.to_string()]);
+    assert_eq!(events[2].daa_score, fetch_block(&rpc_client, b).await.header.daa_score);
+    assert!(is_stored(&test_database, b).await);
+}
+
+async fn is_fully_processed(test_database: &TestDatabase, hash: RpcHash) -> bool {
+    let row = test_database.client().await
+        .query_one("SELECT fully_processed FROM blocks WHERE block_hash = $1", &[&hash.to_string()]).await
+        .expect("failed to look up the block");
+    row.get(0)
+}
+
+#[tokio::test]
+async fn second_resync_does_not_fetch_fully_processed_blocks() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let mut chain = vec![mock.genesis_hash()];
+    for _ in 0..5 {
+        chain.push(mock.add_block(&[*chain.last().unwrap()]));
+    }
+    let sink = *chain.last().unwrap();
+    mock.set_block_dag_info(GetBlockDagInfoResponse {
+        network: "mainnet".parse().unwrap(),
+        block_count: chain.len() as u64,
+        header_count: chain.len() as u64,
+        tip_hashes: vec![sink],
+        difficulty: 0.0,
+        past_median_time: 0,
+        virtual_parent_hashes: vec![sink],
+        pruning_point_hash: mock.genesis_hash(),
+        virtual_daa_score: chain.len() as u64,
+        sink,
+    });
+    let rpc_client = Arc::new(rpc_client);
+    let options = ResyncOptions {
+        prefetch_blocks: 8,
+        ..Default::default()
+    };
+
+    // The cold sync bulk inserts the blocks, then completes them
+    Processing::resync_database_static(&test_database.database, &rpc_client, options.clone()).await
+        .expect("the first resync should succeed");
+    for &hash in &chain[1..] {
+        assert!(is_fully_processed(&test_database, hash).await, "block {} is not fully processed", hash);
+    }
+
+    let fetched_before = mock.call_count("GetBlock");
+    Processing::resync_database_static(&test_database.database, &rpc_client, options).await
+        .expect("the second resync should succeed");
+    let fetched = mock.call_count("GetBlock") - fetched_before;
+    // Only the pruning point, which has no selected parent, is fetched again
+    // besides the lookups of the resync setup
+    assert!(fetched < chain.len() - 1, "{} blocks fetched again", fetched);
+}
+
+#[tokio::test]
+async fn bulk_synced_blocks_count_as_new() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let mut chain = vec![mock.genesis_hash()];
+    for _ in 0..100 {
+        chain.push(mock.add_block(&[*chain.last().unwrap()]));
+    }
+    let sink = *chain.last().unwrap();
+    mock.set_block_dag_info(GetBlockDagInfoResponse {
+        network: "mainnet".parse().unwrap(),
+        block_count: chain.len() as u64,
+        header_count: chain.len() as u64,
+        tip_hashes: vec![sink],
+        difficulty: 0.0,
+        past_median_time: 0,
+        virtual_parent_hashes: vec![sink],
+        pruning_point_hash: mock.genesis_hash(),
+        virtual_daa_score: chain.len() as u64,
+        sink,
+    });
+    let options = ResyncOptions {
+        prefetch_blocks: 8,
+        ..Default::default()
+    };
+
+    let counts_before = ProcessedBlockCounts::current();
+    Processing::resync_database_static(&test_database.database, &Arc::new(rpc_client), options).await
+        .expect("the resync should succeed");
+    // The counters are shared with the tests running alongside, which only
+    // ever add to them
+    let counts = ProcessedBlockCounts::current().since(counts_before);
+    assert!(counts.new >= chain.len() as u64 - 1, "only {} of {} blocks counted as new", counts.new, chain.len() - 1);
+}
+
+/// Processes the block without collecting its missing parents first, so it
+/// can be stored before them.
+async fn process_block_alone(test_database: &TestDatabase, rpc_client: &RpcClient, hash: RpcHash) {
+    let block = fetch_block(rpc_client, hash).await;
+    process_rpc_block_alone(test_database, rpc_client, block, BlockProcessingOptions::default()).await;
+}
+
+async fn process_rpc_block_alone(test_database: &TestDatabase, rpc_client: &RpcClient, block: RpcBlock, options: BlockProcessingOptions) {
+    try_process_rpc_block_alone(test_database, rpc_client, block, options).await.expect("failed to process the block");
+}
+
+async fn try_process_rpc_block_alone(test_database: &TestDatabase, rpc_client: &RpcClient, block: RpcBlock, options: BlockProcessingOptions) -> Result<()> {
+    let database_for_closure = test_database.database.clone();
+    let rpc_client_for_closure = rpc_client.clone();
+    test_database.database.run_in_transaction(move |tx| {
+        let database = database_for_closure.clone();
+        let rpc_client = rpc_client_for_closure.clone();
+        let block = block.clone();
+        Box::pin(async move {
+            Processing::process_block_static(&database, tx, &rpc_client, &block, None, options, false, false).await
+        })
+    }).await?;
+    Ok(())
+}
+
+async fn block_id(test_database: &TestDatabase, hash: RpcHash) -> u64 {
+    let row = test_database.client().await
+        .query_one("SELECT id FROM blocks WHERE block_hash = $1", &[&hash.to_string()]).await
+        .expect("failed to look up the block");
+    row.get::<_, i64>(0) as u64
+}
+
+/// Stored merge set blue ids of the block
+async fn merge_set_blue_ids(test_database: &TestDatabase, hash: RpcHash) -> Vec<u64> {
+    let row = test_database.client().await
+        .query_one("SELECT merge_set_blue_ids FROM blocks WHERE block_hash = $1", &[&hash.to_string()]).await
+        .expect("failed to look up the block");
+    serde_json::from_value(row.get(0)).expect("merge set ids are a JSON array")
+}
+
+/// A block merging `a` and `b`, whose merge set also names a block the
+/// database doesn't have
+async fn block_with_an_unknown_merge_set_block(test_database: &TestDatabase, mock: &MockRpcApi, rpc_client: &RpcClient) -> (RpcHash, RpcHash, RpcBlock) {
+    let a = mock.add_block(&[mock.genesis_hash()]);
+    let b = mock.add_block(&[mock.genesis_hash()]);
+    let c = mock.add_block(&[a, b]);
+    process_blocks(test_database, rpc_client, &[mock.genesis_hash(), a, b]).await;
+    let mut block = fetch_block(rpc_client, c).await;
+    let verbose_data = block.verbose_data.as_mut().expect("mock blocks have verbose data");
+    verbose_data.merge_set_blues_hashes.push(RpcHash::from_bytes([9; 32]));
+    (a, b, block)
+}
+
+#[tokio::test]
+async fn merge_set_block_missing_from_the_database_is_skipped() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let (a, b, block) = block_with_an_unknown_merge_set_block(&test_database, &mock, &rpc_client).await;
+    let hash = block.header.hash;
+
+    process_rpc_block_alone(&test_database, &rpc_client, block, BlockProcessingOptions::default()).await;
+
+    let expected = vec![block_id(&test_database, a).await, block_id(&test_database, b).await];
+    assert_eq!(merge_set_blue_ids(&test_database, hash).await, expected);
+}
+
+#[tokio::test]
+async fn merge_set_block_missing_from_the_database_fails_a_strict_merge_set() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let (_, _, block) = block_with_an_unknown_merge_set_block(&test_database, &mock, &rpc_client).await;
+    let hash = block.header.hash;
+    let options = BlockProcessingOptions { strict_merge_set: true, ..Default::default() };
+
+    let error = try_process_rpc_block_alone(&test_database, &rpc_client, block, options).await
+        .expect_err("a strict merge set should fail");
+
+    assert!(error.to_string().contains("missing from the database"), "{}", error);
+    assert!(!is_stored(&test_database, hash).await);
+}
+
+#[tokio::test]
+async fn block_with_verbose_data_is_not_fetched_again() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let a = mock.add_block(&[mock.genesis_hash()]);
+    let b = mock.add_block(&[a]);
+    process_blocks(&test_database, &rpc_client, &[mock.genesis_hash(), a]).await;
+    let block = fetch_block(&rpc_client, b).await;
+    let mut without_verbose_data = fetch_block(&rpc_client, a).await;
+    without_verbose_data.verbose_data = None;
+    let get_block_calls = mock.call_count("GetBlock");
+
+    process_rpc_block_alone(&test_database, &rpc_client, block, BlockProcessingOptions::default()).await;
+    assert_eq!(mock.call_count("GetBlock"), get_block_calls);
+
+    // Only a block lacking its verbose data is fetched
+    let options = BlockProcessingOptions { reprocess_complete_blocks: true, ..Default::default() };
+    process_rpc_block_alone(&test_database, &rpc_client, without_verbose_data, options).await;
+    assert_eq!(mock.call_count("GetBlock"), get_block_calls + 1);
+}
+
+/// Height and height group index of the block
+async fn block_position(test_database: &TestDatabase, hash: RpcHash) -> (i64, i32) {
+    let row = test_database.client().await
+        .query_one("SELECT height, height_group_index FROM blocks WHERE block_hash = $1", &[&hash.to_string()]).await
+        .expect("failed to look up the block");
+    (row.get(0), row.get(1))
+}
+
+#[tokio::test]
+async fn late_parent_raises_its_children_without_leaving_index_gaps() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let genesis = mock.genesis_hash();
+    let a = mock.add_block(&[genesis]);
+    let late = mock.add_block(&[a]);
+    let child = mock.add_block(&[genesis, late]);
+    let grandchild = mock.add_block(&[child]);
+    process_blocks(&test_database, &rpc_client, &[genesis, a]).await;
+
+    // Without the late parent, the child sits next to a at height 1 and the
+    // grandchild at height 2
+    process_block_alone(&test_database, &rpc_client, child).await;
+    process_block_alone(&test_database, &rpc_client, grandchild).await;
+    assert_eq!(block_position(&test_database, child).await, (1, 1));
+    assert_eq!(block_position(&test_database, grandchild).await, (2, 0));
+
+    process_block_alone(&test_database, &rpc_client, late).await;
+    assert_eq!(block_position(&test_database, a).await, (1, 0));
+    assert_eq!(block_position(&test_database, late).await, (2, 0));
+    assert_eq!(block_position(&test_database, child).await, (3, 0));
+    assert_eq!(block_position(&test_database, grandchild).await, (4, 0));
+
+    let client = test_database.client().await;
+    // Every height group is as large as its blocks and numbers them without gaps
+    let mismatched_groups: i64 = client.query_one(
+        r#"
+        SELECT COUNT(*) FROM (
+            SELECT height, COUNT(*) AS size, MAX(height_group_index) AS max_index FROM blocks GROUP BY height
+        ) b
+        FULL JOIN height_groups g ON g.height = b.height
+        WHERE g.size IS DISTINCT FROM b.size OR b.max_index <> b.size - 1
+        "#,
+        &[],
+    ).await.unwrap().get(0);
+    assert_eq!(mismatched_groups, 0);
+    // Every edge carries the positions of its blocks
+    let stale_edges: i64 = client.query_one(
+        r#"
+        SELECT COUNT(*) FROM edges e
+        JOIN blocks f ON f.id = e.from_block_id
+        JOIN blocks t ON t.id = e.to_block_id
+        WHERE (e.from_height, e.from_height_group_index, e.to_height, e.to_height_group_index)
+            IS DISTINCT FROM (f.height, f.height_group_index, t.height, t.height_group_index)
+        "#,
+        &[],
+    ).await.unwrap().get(0);
+    assert_eq!(stale_edges, 0);
+    let late_edges: i64 = client.query_one(
+        "SELECT COUNT(*) FROM edges e JOIN blocks t ON t.id = e.to_block_id WHERE t.block_hash = $1",
+        &[&late.to_string()],
+    ).await.unwrap().get(0);
+    assert_eq!(late_edges, 1);
+}
+
+#[tokio::test]
+async fn selected_parent_stored_after_its_child_is_set_once_it_arrives() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let genesis = mock.genesis_hash();
+    let a = mock.add_block(&[genesis]);
+    let side = mock.add_block(&[genesis]);
+    // side has the higher hash, so it is the selected parent
+    let b = mock.add_block(&[a, side]);
+    process_blocks(&test_database, &rpc_client, &[genesis, a]).await;
+
+    process_block_alone(&test_database, &rpc_client, b).await;
+    assert!(is_stored(&test_database, b).await);
+    assert!(!has_selected_parent(&test_database, b).await);
+
+    process_block_alone(&test_database, &rpc_client, side).await;
+    let client = test_database.client().await;
+    let row = client.query_one(
+        r#"
+        SELECT b.selected_parent_id = s.id, (SELECT COUNT(*) FROM pending_selected_parents)
+        FROM blocks b, blocks s WHERE b.block_hash = $1 AND s.block_hash = $2
+        "#,
+        &[&b.to_string(), &side.to_string()],
+    ).await.unwrap();
+    assert!(row.get::<_, bool>(0));
+    assert_eq!(row.get::<_, i64>(1), 0);
+}
+
+#[tokio::test]
+async fn backfilled_selected_parents_are_no_longer_pending() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let genesis = mock.genesis_hash();
+    let hash = mock.add_block(&[genesis]);
+    process_blocks(&test_database, &rpc_client, &[genesis, hash]).await;
+    let client = test_database.client().await;
+    // As left by a block stored while its selected parent wasn't
+    client.execute(
+        r#"
+        WITH block AS (UPDATE blocks SET selected_parent_id = NULL WHERE block_hash = $1 RETURNING id)
+        INSERT INTO pending_selected_parents (block_id, selected_parent_hash) SELECT id, $2 FROM block
+        "#,
+        &[&hash.to_string(), &genesis.to_string()],
+    ).await.unwrap();
+
+    Processing::backfill_selected_parents(&test_database.database, &rpc_client).await
+        .expect("the backfill should succeed");
+
+    let row = client.query_one(
+        r#"
+        SELECT b.selected_parent_id = g.id, (SELECT COUNT(*) FROM pending_selected_parents)
+        FROM blocks b, blocks g WHERE b.block_hash = $1 AND g.block_hash = $2
+        "#,
+        &[&hash.to_string(), &genesis.to_string()],
+    ).await.unwrap();
+    assert!(row.get::<_, bool>(0));
+    assert_eq!(row.get::<_, i64>(1), 0);
+}
+
+/// Adds a block whose parent isn't stored yet and returns it after having the
+/// node fail the next `failures` lookups of that parent as not found.
+async fn child_of_unknown_parent(test_database: &TestDatabase, mock: &MockRpcApi, rpc_client: &RpcClient, failures: usize) -> (RpcHash, RpcBlock) {
+    process_blocks(test_database, rpc_client, &[mock.genesis_hash()]).await;
+    let parent = mock.add_block(&[mock.genesis_hash()]);
+    let child = fetch_block(rpc_client, mock.add_block(&[parent])).await;
+    for _ in 0..failures {
+        mock.fail_next("GetBlock", &format!("Block {} not found", parent));
+    }
+    (parent, child)
+}
+
+async fn process_block_notification(test_database: &TestDatabase, rpc_client: &RpcClient, block: RpcBlock) -> Instant {
+    Processing::process_block_notifications(&test_database.database, rpc_client, vec![block], BlockProcessingOptions::default())
+        .await.expect("failed to process the block");
+    Instant::now()
+}
+
+#[tokio::test]
+async fn parent_found_on_the_second_attempt_is_stored_with_its_child() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let (parent, child) = child_of_unknown_parent(&test_database, &mock, &rpc_client, 1).await;
+
+    process_block_notification(&test_database, &rpc_client, child.clone()).await;
+
+    assert!(is_stored(&test_database, parent).await);
+    assert!(is_stored(&test_database, child.header.hash).await);
+}
+
+#[tokio::test]
+async fn parent_still_unknown_after_the_attempts_leaves_its_child_orphaned() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let (parent, child) = child_of_unknown_parent(&test_database, &mock, &rpc_client, MISSING_PARENT_ATTEMPTS as usize).await;
+
+    process_block_notification(&test_database, &rpc_client, child.clone()).await;
+
+    assert!(!is_stored(&test_database, parent).await);
+    // The child waits for its parent as an orphan
+    let orphans: i64 = test_database.client().await
+        .query_one("SELECT COUNT(*) FROM pending_orphan_blocks WHERE block_hash = $1", &[&child.header.hash.to_string()]).await
+        .expect("failed to count the orphans").get(0);
+    assert_eq!(orphans, 1);
+}
+
+#[tokio::test]
+async fn waiting_for_a_missing_parent_holds_no_transaction() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let (_, child) = child_of_unknown_parent(&test_database, &mock, &rpc_client, 2).await;
+
+    // Starts while the processing waits for the parent
+    let other_transaction = async {
+        tokio::time::sleep(MISSING_PARENT_RETRY_DELAY / 2).await;
+        test_database.database.run_in_transaction(|tx| Box::pin(async move {
+            tx.execute("SELECT 1", &[]).await?;
+            Ok(())
+        })).await.expect("failed to run the other transaction");
+        Instant::now()
+    };
+    let (processed_at, other_committed_at) = tokio::join!(
+        process_block_notification(&test_database, &rpc_client, child),
+        other_transaction,
+    );
+
+    assert!(other_committed_at < processed_at);
+}
+
+async fn deferred_blocks(test_database: &TestDatabase) -> Vec<String> {
+    test_database.client().await
+        .query("SELECT DISTINCT block_hash FROM pending_orphan_blocks ORDER BY block_hash", &[]).await
+        .expect("failed to read the deferred blocks")
+        .iter().map(|row| row.get(0)).collect()
+}
+
+#[tokio::test]
+async fn only_the_configured_genesis_is_stored_without_parents() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let options = BlockProcessingOptions { genesis_hash: Some(mock.genesis_hash().into()), ..Default::default() };
+    let genesis = fetch_block(&rpc_client, mock.genesis_hash()).await;
+    let mut impostor = genesis.clone();
+    impostor.header.hash = RpcHash::from_bytes([7; 32]);
+
+    process_rpc_block_alone(&test_database, &rpc_client, genesis, options).await;
+    process_rpc_block_alone(&test_database, &rpc_client, impostor.clone(), options).await;
+
+    assert!(is_stored(&test_database, mock.genesis_hash()).await);
+    assert_eq!(block_position(&test_database, mock.genesis_hash()).await.0, 0);
+    assert!(!is_stored(&test_database, impostor.header.hash).await);
+    assert!(deferred_blocks(&test_database).await.is_empty());
+}
+
+#[tokio::test]
+async fn deferred_block_is_stored_without_asking_the_node_once_its_parent_is() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    process_blocks(&test_database, &rpc_client, &[mock.genesis_hash()]).await;
+    let a = mock.add_block(&[mock.genesis_hash()]);
+    let b = mock.add_block(&[a]);
+    process_block_alone(&test_database, &rpc_client, b).await;
+    assert!(!is_stored(&test_database, b).await);
+    assert_eq!(deferred_blocks(&test_database).await, vec![b.to_string()]);
+
+    let a_block = fetch_block(&rpc_client, a).await;
+    let get_block_calls = mock.call_count("GetBlock");
+    process_rpc_block_alone(&test_database, &rpc_client, a_block, BlockProcessingOptions::default()).await;
+
+    assert_eq!(mock.call_count("GetBlock"), get_block_calls);
+    assert!(is_stored(&test_database, b).await);
+    assert_eq!(block_position(&test_database, b).await.0, 2);
+    assert!(deferred_blocks(&test_database).await.is_empty());
+}
+
+#[tokio::test]
+async fn sweep_drops_the_deferred_blocks_whose_parents_never_arrived() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    process_blocks(&test_database, &rpc_client, &[mock.genesis_hash()]).await;
+    let a = mock.add_block(&[mock.genesis_hash()]);
+    let expired = mock.add_block(&[a]);
+    let recent = mock.add_block(&[a]);
+    process_block_alone(&test_database, &rpc_client, expired).await;
+    process_block_alone(&test_database, &rpc_client, recent).await;
+    let legacy = RpcHash::from_bytes([7; 32]);
+    let client = test_database.client().await;
+    client.execute("UPDATE pending_orphan_block_data SET deferred_at = 0 WHERE block_hash = $1", &[&expired.to_string()]).await
+        .expect("failed to age the deferred block");
+    // Deferred before the blocks were recorded along
+    client.execute("INSERT INTO pending_orphan_blocks (block_hash, parent_hash) VALUES ($1, $2)", &[&legacy.to_string(), &a.to_string()]).await
+        .expect("failed to defer the legacy block");
+
+    let swept = Processing::sweep_pending_orphan_blocks(&test_database.database, PENDING_ORPHAN_TTL).await
+        .expect("failed to sweep the deferred blocks");
+
+    let mut swept: Vec<String> = swept.iter().map(|hash| hash.to_string()).collect();
+    swept.sort();
+    let mut expected = vec![expired.to_string(), legacy.to_string()];
+    expected.sort();
+    assert_eq!(swept, expected);
+    assert_eq!(deferred_blocks(&test_database).await, vec![recent.to_string()]);
+}
+
+/// Stores the blocks as one chunk of the bulk sync
+async fn bulk_sync_blocks(test_database: &TestDatabase, rpc_client: &RpcClient, hashes: &[RpcHash]) {
+    let hashes: Vec<BlockHash> = hashes.iter().map(|&hash| hash.into()).collect();
+    let database_for_closure = test_database.database.clone();
+    let rpc_client_for_closure = rpc_client.clone();
+    test_database.database.run_in_transaction(move |tx| {
+        let database = database_for_closure.clone();
+        let rpc_client = rpc_client_for_closure.clone();
+        let hashes = hashes.clone();
+        Box::pin(async move {
+            Processing::bulk_sync_blocks_static(
+                &database, tx, &rpc_client, &hashes, hashes.len(), 0, false, 0, BlockProcessingOptions::default(),
+            ).await
+        })
+    }).await.expect("failed to bulk sync the blocks");
+}
+
+#[tokio::test]
+async fn bulk_inserted_child_gets_the_edge_to_its_parent_stored_after_it() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let genesis = mock.genesis_hash();
+    let late = mock.add_block(&[genesis]);
+    let child = mock.add_block(&[genesis, late]);
+    process_blocks(&test_database, &rpc_client, &[genesis]).await;
+
+    bulk_sync_blocks(&test_database, &rpc_client, &[child]).await;
+    let client = test_database.client().await;
+    let pending: Vec<String> = client.query("SELECT parent_hash FROM pending_parent_edges", &[]).await.unwrap()
+        .iter().map(|row| row.get(0)).collect();
+    assert_eq!(pending, vec![late.to_string()]);
+    assert_eq!(block_position(&test_database, child).await, (1, 0));
+
+    bulk_sync_blocks(&test_database, &rpc_client, &[late]).await;
+    assert_eq!(block_position(&test_database, late).await, (1, 0));
+    assert_eq!(block_position(&test_database, child).await, (2, 0));
+    let late_edges: i64 = client.query_one(
+        "SELECT COUNT(*) FROM edges e JOIN blocks t ON t.id = e.to_block_id WHERE t.block_hash = $1",
+        &[&late.to_string()],
+    ).await.unwrap().get(0);
+    assert_eq!(late_edges, 1);
+    let pending: i64 = client.query_one("SELECT COUNT(*) FROM pending_parent_edges", &[]).await.unwrap().get(0);
+    assert_eq!(pending, 0);
+}
+
+#[tokio::test]
+async fn bulk_synced_block_without_stored_parents_is_deferred() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    process_blocks(&test_database, &rpc_client, &[mock.genesis_hash()]).await;
+    let a = mock.add_block(&[mock.genesis_hash()]);
+    let b = mock.add_block(&[a]);
+
+    bulk_sync_blocks(&test_database, &rpc_client, &[b]).await;
+    assert!(!is_stored(&test_database, b).await);
+    assert_eq!(deferred_blocks(&test_database).await, vec![b.to_string()]);
+
+    bulk_sync_blocks(&test_database, &rpc_client, &[a]).await;
+    assert_eq!(block_position(&test_database, a).await.0, 1);
+    assert_eq!(block_position(&test_database, b).await.0, 2);
+    assert!(deferred_blocks(&test_database).await.is_empty());
+}
+
+#[tokio::test]
+async fn rebuilt_height_groups_match_the_stored_blocks() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let genesis = mock.genesis_hash();
+    let a = mock.add_block(&[genesis]);
+    let side = mock.add_block(&[genesis]);
+    let b = mock.add_block(&[a, side]);
+    process_blocks(&test_database, &rpc_client, &[genesis, a, side, b]).await;
+    let client = test_database.client().await;
+    client.batch_execute(
+        r#"
+        UPDATE height_groups SET size = 7 WHERE height = 1;
+        DELETE FROM height_groups WHERE height = 2;
+        INSERT INTO height_groups (height, size) VALUES (9, 3);
+        UPDATE blocks SET height_group_index = 5 WHERE height = 1;
+        "#,
+    ).await.expect("failed to corrupt the height groups");
+
+    let database_for_closure = test_database.database.clone();
+    test_database.database.run_in_transaction(move |tx| Box::pin(async move {
+        Ok(database_for_closure.rebuild_height_groups(tx).await?)
+    })).await.expect("failed to rebuild the height groups");
+
+    let height_groups: Vec<(i64, i32)> = client.query("SELECT height, size FROM height_groups ORDER BY height", &[]).await.unwrap()
+        .iter().map(|row| (row.get(0), row.get(1))).collect();
+    assert_eq!(height_groups, vec![(0, 1), (1, 2), (2, 1)]);
+    assert_eq!(block_position(&test_database, a).await, (1, 0));
+    assert_eq!(block_position(&test_database, side).await, (1, 1));
+    let stale_edges: i64 = client.query_one(
+        r#"
+        SELECT COUNT(*) FROM edges e
+        JOIN blocks f ON f.id = e.from_block_id
+        JOIN blocks t ON t.id = e.to_block_id
+        WHERE e.from_height_group_index <> f.height_group_index OR e.to_height_group_index <> t.height_group_index
+        "#,
+        &[],
+    ).await.unwrap().get(0);
+    assert_eq!(stale_edges, 0);
+}
+
+#[tokio::test]
+async fn tip_blocks_are_the_blocks_without_children() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let genesis = mock.genesis_hash();
+    let a = mock.add_block(&[genesis]);
+    let side = mock.add_block(&[genesis]);
+    let b = mock.add_block(&[a, side]);
+    let c = mock.add_block(&[a]);
+    let d = mock.add_block(&[b]);
+    process_blocks(&test_database, &rpc_client, &[genesis, a, side, b, c, d]).await;
+
+    let database_for_closure = test_database.database.clone();
+    let tips = test_database.database.run_in_read_transaction(move |tx| Box::pin(async move {
+        Ok(database_for_closure.tip_blocks(tx, 10).await?)
+    })).await.expect("failed to read the tips");
+    let tip_hashes: Vec<String> = tips.iter().map(|block| block.block_hash.clone()).collect();
+    // Highest first
+    assert_eq!(tip_hashes, vec![d.to_string(), c.to_string()]);
+
+    let database_for_closure = test_database.database.clone();
+    let tips = test_database.database.run_in_read_transaction(move |tx| Box::pin(async move {
+        Ok(database_for_closure.tip_blocks(tx, 1).await?)
+    })).await.expect("failed to read the tips");
+    assert_eq!(tips.len(), 1);
+}
+
+#[tokio::test]
+async fn latest_block_is_the_last_inserted_one_whatever_its_height() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let genesis = mock.genesis_hash();
+    let a = mock.add_block(&[genesis]);
+    let b = mock.add_block(&[a]);
+    let side = mock.add_block(&[genesis]);
+    let read_latest_block = || {
+        let database_for_closure = test_database.database.clone();
+        test_database.database.run_in_read_transaction(move |tx| Box::pin(async move {
+            Ok(database_for_closure.latest_block(tx).await?)
+        }))
+    };
+    assert!(read_latest_block().await.expect("failed to read the latest block").is_none());
+
+    process_blocks(&test_database, &rpc_client, &[genesis, a, b, side]).await;
+
+    let latest_block = read_latest_block().await.expect("failed to read the latest block").expect("blocks are stored");
+    assert_eq!(latest_block.block_hash, side.to_string());
+    assert_eq!(latest_block.height, 1);
+}
+
+/// Hashes of the parents and children of the block
+async fn neighbor_hashes(test_database: &TestDatabase, hash: RpcHash) -> (Vec<String>, Vec<String>) {
+    let database_for_closure = test_database.database.clone();
+    let (parents, children) = test_database.database.run_in_read_transaction(move |tx| Box::pin(async move {
+        Ok(database_for_closure.block_neighbors(tx, &hash.into()).await?)
+    })).await.expect("failed to read the neighbors");
+    let hashes = |blocks: Vec<Block>| blocks.into_iter().map(|block| block.block_hash).collect();
+    (hashes(parents), hashes(children))
+}
+
+#[tokio::test]
+async fn block_neighbors_are_its_parents_and_children() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let genesis = mock.genesis_hash();
+    let a = mock.add_block(&[genesis]);
+    let side = mock.add_block(&[genesis]);
+    let b = mock.add_block(&[a, side]);
+    let c = mock.add_block(&[a]);
+    process_blocks(&test_database, &rpc_client, &[genesis, a, side, b, c]).await;
+
+    assert_eq!(neighbor_hashes(&test_database, a).await, (vec![genesis.to_string()], vec![b.to_string(), c.to_string()]));
+    assert_eq!(neighbor_hashes(&test_database, b).await, (vec![a.to_string(), side.to_string()], vec![]));
+    assert_eq!(neighbor_hashes(&test_database, genesis).await, (vec![], vec![a.to_string(), side.to_string()]));
+}
+
+async fn children_ids(test_database: &TestDatabase, hash: RpcHash) -> Vec<u64> {
+    let block_id = block_id(test_database, hash).await;
+    let database_for_closure = test_database.database.clone();
+    test_database.database.run_in_read_transaction(move |tx| Box::pin(async move {
+        Ok(database_for_closure.block_children_ids(tx, block_id).await?)
+    })).await.expect("failed to read the children")
+}
+
+#[tokio::test]
+async fn children_are_looked_up_through_the_reverse_edges() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let genesis = mock.genesis_hash();
+    let a = mock.add_block(&[genesis]);
+    let side = mock.add_block(&[genesis]);
+    let b = mock.add_block(&[a, side]);
+    process_blocks(&test_database, &rpc_client, &[genesis, a, side, b]).await;
+
+    let (a_id, side_id, b_id) = (block_id(&test_database, a).await, block_id(&test_database, side).await, block_id(&test_database, b).await);
+    assert_eq!(children_ids(&test_database, genesis).await, vec![a_id, side_id]);
+    assert_eq!(children_ids(&test_database, a).await, vec![b_id]);
+    assert_eq!(children_ids(&test_database, side).await, vec![b_id]);
+    assert!(children_ids(&test_database, b).await.is_empty());
+    let row = test_database.client().await
+        .query_one("SELECT EXISTS (SELECT 1 FROM pg_indexes WHERE schemaname = current_schema() AND indexname = 'edges_to_block_id_idx')", &[])
+        .await.expect("failed to look up the index");
+    assert!(row.get::<_, bool>(0));
+}
+
+async fn graph_bounds(test_database: &TestDatabase) -> GraphBounds {
+    let database_for_closure = test_database.database.clone();
+    test_database.database.run_in_read_transaction(move |tx| Box::pin(async move {
+        Ok(database_for_closure.graph_bounds(tx).await?)
+    })).await.expect("failed to read the graph bounds")
+}
+
+#[tokio::test]
+async fn graph_bounds_cover_every_stored_block() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let bounds = graph_bounds(&test_database).await;
+    assert_eq!((bounds.min_height, bounds.max_height, bounds.block_count, bounds.chain_tip_hash), (None, None, 0, None));
+
+    let (mock, rpc_client) = mock_node();
+    let genesis = mock.genesis_hash();
+    let a = mock.add_block(&[genesis]);
+    let side = mock.add_block(&[genesis]);
+    let b = mock.add_block(&[a, side]);
+    let c = mock.add_block(&[b]);
+    process_blocks(&test_database, &rpc_client, &[genesis, a, side, b, c]).await;
+    Processing::process_virtual_chain_changed_notification(&test_database.database, chain_change(&[a, b]), false)
+        .await.expect("failed to store the chain");
+
+    let bounds = graph_bounds(&test_database).await;
+    let daa_score = |block: RpcBlock| Some(block.header.daa_score);
+    assert_eq!((bounds.min_height, bounds.max_height), (Some(0), Some(3)));
+    assert_eq!(bounds.min_daa_score, daa_score(fetch_block(&rpc_client, genesis).await));
+    assert_eq!(bounds.max_daa_score, daa_score(fetch_block(&rpc_client, c).await));
+    assert_eq!(bounds.block_count, 5);
+    // c is above the chain tip
+    assert_eq!(bounds.chain_tip_hash, Some(b.to_string()));
+}
+
+#[tokio::test]
+async fn pruning_point_advance_leaves_nothing_pointing_at_pruned_blocks() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let genesis = mock.genesis_hash();
+    let a = mock.add_block(&[genesis]);
+    let side = mock.add_block(&[genesis]);
+    let b = mock.add_block(&[a, side]);
+    let c = mock.add_block(&[b]);
+    process_blocks(&test_database, &rpc_client, &[genesis, a, side, b, c]).await;
+    let client = test_database.client().await;
+    let chain: Vec<String> = [genesis, a, b, c].iter().map(|hash| hash.to_string()).collect();
+    client.execute("UPDATE blocks SET is_in_virtual_selected_parent_chain = block_hash = ANY($1)", &[&chain]).await.unwrap();
+    // Rows left about the side block by its processing, and a block deferred
+    // on it
+    let (deferred, unknown) = (RpcHash::from_bytes([7; 32]), RpcHash::from_bytes([8; 32]));
+    client.batch_execute(&format!(
+        r#"
+        UPDATE blocks SET colored_by_block_id = (SELECT id FROM blocks WHERE block_hash = '{side}') WHERE block_hash = '{c}';
+        INSERT INTO pending_parent_edges (block_id, parent_hash) SELECT id, '{unknown}' FROM blocks WHERE block_hash = '{side}';
+        INSERT INTO pending_selected_parents (block_id, selected_parent_hash) SELECT id, '{unknown}' FROM blocks WHERE block_hash = '{side}';
+        INSERT INTO pending_orphan_blocks (block_hash, parent_hash) VALUES ('{deferred}', '{side}');
+        INSERT INTO pending_orphan_block_data (block_hash, block, deferred_at) VALUES ('{deferred}', '{{}}', 0);
+        "#,
+    )).await.unwrap();
+    let b_position = block_position(&test_database, b).await;
+    let b_daa_score: i64 = client.query_one("SELECT daa_score FROM blocks WHERE block_hash = $1", &[&b.to_string()]).await.unwrap().get(0);
+
+    let database_for_closure = test_database.database.clone();
+    let pruned = test_database.database.run_in_transaction(move |tx| Box::pin(async move {
+        Ok(database_for_closure.prune_below_daa_score(tx, b_daa_score as u64).await?)
+    })).await.unwrap();
+
+    assert_eq!(pruned, 1);
+    assert!(!is_stored(&test_database, side).await);
+    assert!(is_stored(&test_database, a).await);
+    assert_eq!(block_position(&test_database, b).await, b_position);
+    let row = client.query_one(
+        r#"
+        SELECT
+            (SELECT COUNT(*) FROM pending_parent_edges),
+            (SELECT COUNT(*) FROM pending_selected_parents),
+            (SELECT COUNT(*) FROM pending_orphan_blocks),
+            (SELECT COUNT(*) FROM pending_orphan_block_data),
+            (SELECT COUNT(*) FROM blocks WHERE colored_by_block_id IS NOT NULL AND colored_by_block_id NOT IN (SELECT id FROM blocks)),
+            (SELECT COUNT(*) FROM blocks b, jsonb_array_elements_text(b.parent_ids) AS p(id) WHERE p.id::BIGINT NOT IN (SELECT id FROM blocks))
+        "#,
+        &[],
+    ).await.unwrap();
+    for column in 0..6 {
+        assert_eq!(row.get::<_, i64>(column), 0, "column {}", column);
+    }
+    // The cache no longer resolves the pruned block
+    let database_for_closure = test_database.database.clone();
+    let side_exists = test_database.database.run_in_transaction(move |tx| Box::pin(async move {
+        Ok(database_for_closure.does_block_exist(tx, &side.into()).await?)
+    })).await.unwrap();
+    assert!(!side_exists);
+}
+
+#[test]
+fn first_stall_check_only_records_where_the_node_is() {
+    let mut stall_check = StallCheck::default();
+    assert!(!stall_check.node_advanced(100));
+    assert!(!stall_check.node_advanced(100));
+    assert!(stall_check.node_advanced(101));
+    assert!(!stall_check.node_advanced(101));
+}
+
+#[tokio::test]
+async fn node_stuck_in_ibd_is_reported_once_its_counts_stop_moving() {
+    let (mock, rpc_client) = mock_node();
+    mock.set_info(GetInfoResponse {
+        p2p_id: String::new(),
+        mempool_size: 0,
+        server_version: String::new(),
+        is_utxo_indexed: false,
+        is_synced: false,
+        has_notify_command: true,
+        has_message_id: true,
+    });
+    mock.set_block_dag_info(block_dag_info(mock.genesis_hash(), mock.genesis_hash(), 1));
+
+    let error = Processing::wait_for_synced_node_static(&rpc_client, 1, true).await
+        .expect_err("the node never progresses");
+
+    assert!(error.to_string().contains("no peers"), "{}", error);
+    assert_eq!(mock.call_count("GetConnectedPeerInfo"), 1);
+}
+
+async fn block_color(test_database: &TestDatabase, hash: RpcHash) -> String {
+    let row = test_database.client().await
+        .query_one("SELECT color FROM blocks WHERE block_hash = $1", &[&hash.to_string()]).await
+        .expect("failed to look up the block");
+    row.get(0)
+}
+
+async fn failed_color_updates(test_database: &TestDatabase) -> Vec<String> {
+    test_database.client().await
+        .query("SELECT block_hash FROM failed_notifications WHERE kind = $1", &[&COLOR_UPDATE_ADDED]).await
+        .expect("failed to read the failed notifications")
+        .iter().map(|row| row.get(0)).collect()
+}
+
+#[tokio::test]
+async fn color_update_failing_every_attempt_is_recorded_and_applied_on_the_next_start() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let a = mock.add_block(&[mock.genesis_hash()]);
+    let side = mock.add_block(&[mock.genesis_hash()]);
+    let b = mock.add_block_with_reds(&[a, side], &[side]);
+    process_blocks(&test_database, &rpc_client, &[mock.genesis_hash(), a, side, b]).await;
+    Processing::process_virtual_chain_changed_notification(&test_database.database, chain_change(&[a, b]), false)
+        .await.expect("failed to store the chain");
+    for _ in 0..COLOR_UPDATE_ATTEMPTS {
+        mock.fail_next("GetBlock", "injected failure");
+    }
+    let update = ColorUpdate { removed_block_ids: vec![], added_chain_block_hashes: vec![b.into()] };
+
+    Processing::apply_color_updates_with_retries(&test_database.database, &rpc_client, Arc::new(vec![update])).await;
+
+    assert_eq!(failed_color_updates(&test_database).await, vec![b.to_string()]);
+    assert_eq!(block_color(&test_database, side).await, COLOR_GRAY);
+
+    Processing::retry_failed_color_updates(&test_database.database, &rpc_client)
+        .await.expect("failed to retry the color update");
+
+    assert_eq!(block_color(&test_database, side).await, COLOR_RED);
+    assert_eq!(block_color(&test_database, a).await, COLOR_BLUE);
+    assert!(failed_color_updates(&test_database).await.is_empty());
+}
+
+/// Stores the change and applies its coloring right away
+async fn store_and_color(test_database: &TestDatabase, rpc_client: &RpcClient, notification: VirtualChainChangedNotification) {
+    let updates = Processing::process_virtual_chain_changed_notification(&test_database.database, notification, false)
+        .await.expect("failed to store the change");
+    Processing::apply_color_updates(&test_database.database, rpc_client, Arc::new(updates))
+        .await.expect("failed to color the change");
+}
+
+#[tokio::test]
+async fn reorg_turns_the_merge_set_of_removed_chain_blocks_gray() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let a = mock.add_block(&[mock.genesis_hash()]);
+    let side = mock.add_block(&[mock.genesis_hash()]);
+    let b = mock.add_block_with_reds(&[a, side], &[side]);
+    let other = mock.add_block(&[a]);
+    process_blocks(&test_database, &rpc_client, &[mock.genesis_hash(), a, side, b, other]).await;
+    store_and_color(&test_database, &rpc_client, chain_change(&[a, b])).await;
+    assert_eq!(block_color(&test_database, side).await, COLOR_RED);
+
+    store_and_color(&test_database, &rpc_client, reorg(b, other)).await;
+
+    // Only b merged side, while a is also in the merge set of other
+    assert_eq!(block_color(&test_database, side).await, COLOR_GRAY);
+    assert_eq!(block_color(&test_database, a).await, COLOR_BLUE);
+    assert_eq!(block_color(&test_database, b).await, COLOR_GRAY);
+}
+
+#[tokio::test]
+async fn reprocess_recolors_every_block_in_the_range() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let side = mock.add_block(&[mock.genesis_hash()]);
+    let a = mock.add_block(&[mock.genesis_hash()]);
+    mock.reorg(mock.genesis_hash(), &[a]);
+    let b = mock.add_block_with_reds(&[a, side], &[side]);
+    let lone = mock.add_block(&[mock.genesis_hash()]);
+    let c = mock.add_block(&[b]);
+    process_blocks(&test_database, &rpc_client, &[mock.genesis_hash(), side, a, b, lone, c]).await;
+    Processing::process_virtual_chain_changed_notification(&test_database.database, chain_change(&[a, b, c]), false)
+        .await.expect("failed to store the chain");
+    // Wrong colors, as an older coloring logic might have left them. side
+    // and a are only merged by b, above the reprocessed range, and no chain
+    // block merges lone
+    test_database.client().await.execute(
+        "UPDATE blocks SET color = CASE WHEN block_hash = $1 THEN 'blue' ELSE 'red' END WHERE block_hash IN ($1, $2, $3, $4)",
+        &[&side.to_string(), &a.to_string(), &lone.to_string(), &mock.genesis_hash().to_string()],
+    ).await.expect("failed to set the wrong colors");
+
+    Processing::reprocess(&test_database.database, &rpc_client, 0, 1, BlockProcessingOptions::default())
+        .await.expect("failed to reprocess");
+
+    assert_eq!(block_color(&test_database, mock.genesis_hash()).await, COLOR_BLUE);
+    assert_eq!(block_color(&test_database, a).await, COLOR_BLUE);
+    assert_eq!(block_color(&test_database, side).await, COLOR_RED);
+    assert_eq!(block_color(&test_database, lone).await, COLOR_GRAY);
+    // Blocks above the range keep their color
+    assert_eq!(block_color(&test_database, b).await, COLOR_GRAY);
+}
+
+/// Stores the genesis as the chain, then processes the tip of a chain with
+/// more missing dependencies than a batch supports
+async fn process_overflowing_block(test_database: &TestDatabase, mock: &MockRpcApi, rpc_client: &RpcClient, policy: DependencyOverflowPolicy) -> (RpcHash, Result<()>) {
+    process_blocks(test_database, rpc_client, &[mock.genesis_hash()]).await;
+    Processing::process_virtual_chain_changed_notification(&test_database.database, chain_change(&[mock.genesis_hash()]), false)
+        .await.expect("failed to store the chain");
+    let mut tip = mock.genesis_hash();
+    for _ in 0..=batch::MAX_SUPPORTED_MISSING_DEPENDENCIES {
+        tip = mock.add_block(&[tip]);
+    }
+    mock.set_block_dag_info(block_dag_info(mock.genesis_hash(), tip, mock.chain().len() as u64));
+    let block = fetch_block(rpc_client, tip).await;
+    let options = BlockProcessingOptions { dependency_overflow_policy: policy, ..Default::default() };
+    let database_for_closure = test_database.database.clone();
+    let rpc_client_for_closure = rpc_client.clone();
+    let result = test_database.database.run_in_transaction(move |tx| {
+        let database = database_for_closure.clone();
+        let rpc_client = rpc_client_for_closure.clone();
+        let block = block.clone();
+        Box::pin(async move {
+            Processing::process_block_and_dependencies_static(&database, tx, &rpc_client, &BlockHash::from(tip), &block, None, options).await
+        })
+    }).await;
+    (tip, result.map(|_| ()))
+}
+
+#[tokio::test]
+async fn dependency_overflow_aborts_by_default() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let (tip, result) = process_overflowing_block(&test_database, &mock, &rpc_client, DependencyOverflowPolicy::Abort).await;
+
+    assert!(result.is_err());
+    assert!(!is_stored(&test_database, tip).await);
+}
+
+#[tokio::test]
+async fn dependency_overflow_skips_the_block_when_asked_to() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let (tip, result) = process_overflowing_block(&test_database, &mock, &rpc_client, DependencyOverflowPolicy::Skip).await;
+
+    result.expect("skipping doesn't fail");
+    assert!(!is_stored(&test_database, tip).await);
+    assert!(!is_stored(&test_database, mock.chain()[1]).await);
+}
+
+#[tokio::test]
+async fn dependency_overflow_resyncs_the_missing_range_when_asked_to() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let (tip, result) = process_overflowing_block(&test_database, &mock, &rpc_client, DependencyOverflowPolicy::Resync).await;
+
+    result.expect("the resync doesn't fail");
+    assert!(is_stored(&test_database, tip).await);
+    assert!(is_stored(&test_database, mock.chain()[1]).await);
+}
+
+#[tokio::test]
+async fn diff_block_flags_a_stored_color_the_node_disagrees_with() {
+    let Some(test_database) = TestDatabase::create().await else { return };
+    let (mock, rpc_client) = mock_node();
+    let side = mock.add_block(&[mock.genesis_hash()]);
+    let a = mock.add_block(&[mock.genesis_hash()]);
+    mock.reorg(mock.genesis_hash(), &[a]);
+    let b = mock.add_block_with_reds(&[a, side], &[side]);
+    process_blocks(&test_database, &rpc_client, &[mock.genesis_hash(), side, a, b]).await;
+    store_and_color(&test_database, &rpc_client, chain_change(&[a, b])).await;
+    assert!(crate::diff::diff_block(&test_database.database, &rpc_client, &side.to_string()).await.unwrap());
+
+    test_database.client().await
+        .execute("UPDATE blocks SET color = 'blue' WHERE block_hash = $1", &[&side.to_string()])
+        .await.expect("failed to set the wrong color");
+
+    assert!(!crate::diff::diff_block(&test_database.database, &rpc_client, &side.to_string()).await.unwrap());
+}
+
+#[tokio::test]
+async fn prefetcher_waits_for_a_slow_consumer() {
+    let (mock, rpc_client) = mock_node();
+    let mut hashes = vec![];
+    let mut tip = mock.genesis_hash();
+    for _ in 0..20 {
+        tip = mock.add_block(&[tip]);
+        hashes.push(BlockHash::from(tip));
+    }
+
+    let mut prefetched_blocks = Processing::spawn_block_prefetcher(Arc::new(rpc_client), hashes.clone(), 2, BlockProcessingOptions::default());
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    // Two blocks wait in the channel and a third one to be sent
+    assert_eq!(mock.call_count("GetBlock"), 3);
+
+    let first = prefetched_blocks.recv().await.expect("the prefetcher is running").expect("the mock knows the block");
+    assert_eq!(BlockHash::from(first.header.hash), hashes[0]);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(mock.call_count("GetBlock"), 4);
+}
@@ -0,0 +1,62 @@
+//! One-shot measurement of the node `GetBlock` latency, to help choose the
+//! RPC concurrency and retry settings. Never touches the database.
+
+use crate::rpc_client::RpcClient;
+use anyhow::Result;
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+
+/// Latency below which `quantile` of the samples fall. `samples` must be
+/// sorted and not empty.
+fn percentile(samples: &[Duration], quantile: f64) -> Duration {
+    let index = ((samples.len() as f64 * quantile).ceil() as usize).clamp(1, samples.len()) - 1;
+    samples[index]
+}
+
+/// Issues `calls` `GetBlock` calls at once, cycling through the block hashes
+/// the node returns from its pruning point, and prints a latency summary.
+/// The RPC client caps how many calls are in flight.
+pub async fn benchmark_rpc(rpc_client: &RpcClient, calls: usize, max_concurrency: usize) -> Result<()> {
+    let pruning_point_hash = rpc_client.get_block_dag_info().await?.pruning_point_hash.to_string();
+    let hashes: Vec<String> = rpc_client.get_blocks(&pruning_point_hash, false, false).await?
+        .block_hashes.iter().map(|hash| hash.to_string()).collect();
+    if hashes.is_empty() {
+        anyhow::bail!("The node returned no block hashes to benchmark with");
+    }
+    println!("Benchmarking {} GetBlock calls over {} blocks, at most {} in flight", calls, hashes.len(), max_concurrency);
+
+    let started = Instant::now();
+    let mut tasks = JoinSet::new();
+    for hash in hashes.iter().cycle().take(calls).cloned() {
+        let rpc_client = rpc_client.clone();
+        tasks.spawn(async move {
+            let call_started = Instant::now();
+            let result = rpc_client.get_block(&hash, false).await;
+            (call_started.elapsed(), result.is_ok())
+        });
+    }
+    let mut latencies = Vec::with_capacity(calls);
+    let mut errors = 0;
+    while let Some(result) = tasks.join_next().await {
+        let (elapsed, succeeded) = result?;
+        if succeeded {
+            latencies.push(elapsed);
+        } else {
+            errors += 1;
+        }
+    }
+    let total = started.elapsed();
+
+    println!("{:<12} {}", "calls", calls);
+    println!("{:<12} {}", "errors", errors);
+    println!("{:<12} {:.1} calls/s", "throughput", calls as f64 / total.as_secs_f64());
+    if latencies.is_empty() {
+        anyhow::bail!("Every GetBlock call failed");
+    }
+    latencies.sort_unstable();
+    for (name, quantile) in [("p50", 0.50), ("p95", 0.95), ("p99", 0.99)] {
+        println!("{:<12} {:.1} ms", name, percentile(&latencies, quantile).as_secs_f64() * 1000.0);
+    }
+    println!("{:<12} {:.1} ms", "max", latencies[latencies.len() - 1].as_secs_f64() * 1000.0);
+    Ok(())
+}
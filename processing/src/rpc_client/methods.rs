@@ -1,86 +1,150 @@
-use crate::rpc_client::{RpcClient, BlockAddedNotification, VirtualChainChangedNotification};
-use tondi_rpc_core::api::rpc::RpcApi;
+use crate::rpc_client::{RpcClient, BlockAddedNotification, BlockHash, IntoBlockHash, VirtualChainChangedNotification};
 use tondi_rpc_core::model::*;
 use tondi_rpc_core::Notification;
-use tondi_hashes::Hash;
-use anyhow::Result;
+use crate::alerts::{self, AlertKind};
+use crate::error::{Result, TgiError};
+use std::future::Future;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, SemaphorePermit};
+
+/// Awaits an RPC call, recording its latency and outcome when metrics are
+/// enabled.
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+async fn instrumented<T>(method: &'static str, call: impl Future<Output = Result<T>>) -> Result<T> {
+    #[cfg(feature = "metrics")]
+    let started = std::time::Instant::now();
+    let result = call.await;
+    if let Err(e @ TgiError::ConnectionLost(_)) = &result {
+        alerts::raise(AlertKind::RpcDisconnected, format!("{} failed: {}", method, e));
+    }
+    #[cfg(feature = "metrics")]
+    crate::metrics::record_rpc_call(method, started.elapsed(), result.as_ref().err());
+    result
+}
 
 impl RpcClient {
+    /// Waits until fewer than the configured maximum of calls are in flight.
+    /// The returned permit must be held for the duration of the call.
+    async fn acquire_call_permit(&self) -> Result<SemaphorePermit<'_>> {
+        self.call_permits.acquire().await
+            .map_err(|e| TgiError::rpc("AcquireCallPermit", e))
+    }
+
+    /// Checks that every hash parses before a batch of calls is made, and
+    /// reports all malformed hashes at once rather than only the first one.
+    pub fn validate_hashes(hashes: &[String]) -> Result<()> {
+        let invalid_hashes: Vec<String> = hashes.iter()
+            .filter_map(|hash| match BlockHash::parse(hash) {
+                Err(TgiError::InvalidHash { message, .. }) => Some(format!("{} ({})", hash, message)),
+                _ => None,
+            })
+            .collect();
+        if invalid_hashes.is_empty() {
+            Ok(())
+        } else {
+            Err(TgiError::InvalidHashes(invalid_hashes))
+        }
+    }
+
     pub async fn get_info(&self) -> Result<GetInfoResponse> {
-        let response = self.client.get_info().await
-            .map_err(|e| anyhow::anyhow!("GetInfo RPC call failed: {}", e))?;
+        let _permit = self.acquire_call_permit().await?;
+        let response = instrumented("GetInfo", async {
+            self.node.get_info().await.map_err(|e| TgiError::rpc("GetInfo", e))
+        }).await?;
         Ok(response)
     }
 
     pub async fn get_block_dag_info(&self) -> Result<GetBlockDagInfoResponse> {
-        let response = self.client.get_block_dag_info().await
-            .map_err(|e| anyhow::anyhow!("GetBlockDAGInfo RPC call failed: {}", e))?;
+        let _permit = self.acquire_call_permit().await?;
+        let response = instrumented("GetBlockDAGInfo", async {
+            self.node.get_block_dag_info().await.map_err(|e| TgiError::rpc("GetBlockDAGInfo", e))
+        }).await?;
         Ok(response)
     }
 
-    pub async fn get_block(&self, hash: &str, include_transactions: bool) -> Result<GetBlockResponse> {
-        let rpc_hash: RpcHash = hash.parse::<Hash>()
-            .map_err(|e| anyhow::anyhow!("Invalid hash format {}: {}", hash, e))?;
-        let block = self.client.get_block(rpc_hash, include_transactions).await
-            .map_err(|e| anyhow::anyhow!("GetBlock RPC call failed: {}", e))?;
+    pub async fn get_connected_peer_info(&self) -> Result<GetConnectedPeerInfoResponse> {
+        let _permit = self.acquire_call_permit().await?;
+        let response = instrumented("GetConnectedPeerInfo", async {
+            self.node.get_connected_peer_info().await.map_err(|e| TgiError::rpc("GetConnectedPeerInfo", e))
+        }).await?;
+        Ok(response)
+    }
+
+    pub async fn get_block(&self, hash: impl IntoBlockHash, include_transactions: bool) -> Result<GetBlockResponse> {
+        let hash = hash.into_block_hash()?;
+        let _permit = self.acquire_call_permit().await?;
+        let block = instrumented("GetBlock", async {
+            self.node.get_block(hash.into(), include_transactions).await
+                .map_err(|e| TgiError::get_block(&hash.to_string(), e))
+        }).await?;
         Ok(GetBlockResponse { block })
     }
 
     pub async fn get_blocks(
         &self,
-        low_hash: &str,
+        low_hash: impl IntoBlockHash,
         include_blocks: bool,
         include_transactions: bool,
     ) -> Result<GetBlocksResponse> {
-        let rpc_hash: Option<RpcHash> = if low_hash.is_empty() {
-            None
-        } else {
-            Some(low_hash.parse::<Hash>()
-                .map_err(|e| anyhow::anyhow!("Invalid hash format {}: {}", low_hash, e))?)
-        };
-        let response = self.client.get_blocks(rpc_hash, include_blocks, include_transactions).await
-            .map_err(|e| anyhow::anyhow!("GetBlocks RPC call failed: {}", e))?;
+        let low_hash = low_hash.into_block_hash()?;
+        let _permit = self.acquire_call_permit().await?;
+        let response = instrumented("GetBlocks", async {
+            self.node.get_blocks(Some(low_hash.into()), include_blocks, include_transactions).await
+                .map_err(|e| TgiError::get_blocks(&low_hash.to_string(), e))
+        }).await?;
         Ok(response)
     }
 
     pub async fn get_sink(&self) -> Result<GetSinkResponse> {
-        let response = self.client.get_sink().await
-            .map_err(|e| anyhow::anyhow!("GetSink RPC call failed: {}", e))?;
+        let _permit = self.acquire_call_permit().await?;
+        let response = instrumented("GetSink", async {
+            self.node.get_sink().await.map_err(|e| TgiError::rpc("GetSink", e))
+        }).await?;
         Ok(response)
     }
 
     pub async fn get_virtual_chain_from_block(
         &self,
-        start_hash: &str,
+        start_hash: impl IntoBlockHash,
         include_accepted_transaction_ids: bool,
     ) -> Result<GetVirtualChainFromBlockResponse> {
-        let rpc_hash: RpcHash = start_hash.parse::<Hash>()
-            .map_err(|e| anyhow::anyhow!("Invalid hash format {}: {}", start_hash, e))?;
-        let response = self.client.get_virtual_chain_from_block(rpc_hash, include_accepted_transaction_ids).await
-            .map_err(|e| anyhow::anyhow!("GetVirtualChainFromBlock RPC call failed: {}", e))?;
+        let start_hash = start_hash.into_block_hash()?;
+        let _permit = self.acquire_call_permit().await?;
+        let response = instrumented("GetVirtualChainFromBlock", async {
+            self.node.get_virtual_chain_from_block(start_hash.into(), include_accepted_transaction_ids).await
+                .map_err(|e| TgiError::rpc("GetVirtualChainFromBlock", e))
+        }).await?;
         Ok(response)
     }
 
+    /// Transactions in the node mempool. Orphans, whose inputs are unknown
+    /// to the node, are only included with `include_orphan_pool`;
+    /// `filter_transaction_pool` leaves out the other transactions.
+    pub async fn get_mempool_entries(
+        &self,
+        include_orphan_pool: bool,
+        filter_transaction_pool: bool,
+    ) -> Result<Vec<RpcMempoolEntry>> {
+        let _permit = self.acquire_call_permit().await?;
+        let entries = instrumented("GetMempoolEntries", async {
+            self.node.get_mempool_entries(include_orphan_pool, filter_transaction_pool).await
+                .map_err(|e| TgiError::rpc("GetMempoolEntries", e))
+        }).await?;
+        Ok(entries)
+    }
+
     pub async fn register_for_block_added_notifications<F>(&self, handler: F) -> Result<()>
     where
         F: Fn(BlockAddedNotification) + Send + Sync + 'static,
     {
-        // Get the notification channel receiver from the client (Direct mode)
-        let receiver = self.client.notification_channel_receiver();
-        
-        // Start notification for BlockAdded events using Direct mode listener ID
-        let listener_id = tondi_grpc_client::GrpcClient::DIRECT_MODE_LISTENER_ID;
         let scope = tondi_notify::scope::Scope::BlockAdded(tondi_notify::scope::BlockAddedScope {});
-        
-        self.client.start_notify(listener_id, scope).await
-            .map_err(|e| anyhow::anyhow!("Failed to start block added notifications: {}", e))?;
+        let mut receiver = self.node.subscribe(scope).await
+            .map_err(|e| TgiError::rpc("StartNotify(BlockAdded)", e))?;
 
         // Spawn a task to handle notifications
         let handler = Arc::new(Mutex::new(handler));
         tokio::spawn(async move {
-            while let Ok(notification) = receiver.recv().await {
+            while let Some(notification) = receiver.recv().await {
                 match notification {
                     Notification::BlockAdded(notif) => {
                         let h = handler.lock().await;
@@ -103,22 +167,16 @@ impl RpcClient {
     where
         F: Fn(VirtualChainChangedNotification) + Send + Sync + 'static,
     {
-        // Get the notification channel receiver from the client (Direct mode)
-        let receiver = self.client.notification_channel_receiver();
-        
-        // Start notification for VirtualChainChanged events using Direct mode listener ID
-        let listener_id = tondi_grpc_client::GrpcClient::DIRECT_MODE_LISTENER_ID;
         let scope = tondi_notify::scope::Scope::VirtualChainChanged(
             tondi_notify::scope::VirtualChainChangedScope::new(include_accepted_transaction_ids)
         );
-        
-        self.client.start_notify(listener_id, scope).await
-            .map_err(|e| anyhow::anyhow!("Failed to start virtual chain changed notifications: {}", e))?;
+        let mut receiver = self.node.subscribe(scope).await
+            .map_err(|e| TgiError::rpc("StartNotify(VirtualChainChanged)", e))?;
 
         // Spawn a task to handle notifications
         let handler = Arc::new(Mutex::new(handler));
         tokio::spawn(async move {
-            while let Ok(notification) = receiver.recv().await {
+            while let Some(notification) = receiver.recv().await {
                 match notification {
                     Notification::VirtualChainChanged(notif) => {
                         let h = handler.lock().await;
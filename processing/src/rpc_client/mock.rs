@@ -0,0 +1,397 @@
+//! In-memory node backed by a synthetic DAG, to exercise the processing
+//! logic without a real node. Enabled by the `mock-rpc` feature.
+//!
+//! Blocks are derived from a template block (e.g. a real genesis), so only
+//! the fields TGI reads are synthesized: hash, parents, DAA and blue scores,
+//! timestamp and the verbose data. Coloring is simplified: the selected
+//! parent is the parent with the highest DAA score, every parent is in the
+//! merge set and all of them are blue unless `add_block_with_reds` says
+//! otherwise.
+//!
+//! ```ignore
+//! let mock = Arc::new(MockRpcApi::new(genesis));
+//! let a = mock.add_block(&[mock.genesis_hash()]);
+//! let b = mock.add_block(&[a]);
+//! let rpc_client = RpcClient::with_node(mock.clone(), "mock", 8);
+//! ```
+
+use super::node::{NodeApi, NodeFuture, NodeResult};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+use tondi_hashes::Hash;
+use tondi_notify::scope::Scope;
+use tondi_rpc_core::model::*;
+use tondi_rpc_core::Notification;
+
+/// Milliseconds between the timestamps of a block and its selected parent
+const BLOCK_INTERVAL_MS: u64 = 1000;
+
+#[derive(Default)]
+struct MockDag {
+    blocks: HashMap<RpcHash, RpcBlock>,
+    /// Every block in insertion order, which is a topological order
+    order: Vec<RpcHash>,
+    /// Virtual selected parent chain, from the genesis to the sink
+    chain: Vec<RpcHash>,
+    next_hash: u64,
+    /// Errors returned by the next calls of a method, by method name
+    failures: HashMap<&'static str, Vec<String>>,
+    subscribers: Vec<mpsc::UnboundedSender<Notification>>,
+    info: Option<GetInfoResponse>,
+    block_dag_info: Option<GetBlockDagInfoResponse>,
+    mempool_entries: Vec<RpcMempoolEntry>,
+    /// How many of the next `get_block` calls return header only blocks
+    header_only_blocks: usize,
+    /// Calls made so far, by method name
+    calls: HashMap<&'static str, usize>,
+}
+
+pub struct MockRpcApi {
+    genesis_hash: RpcHash,
+    template: RpcBlock,
+    dag: Mutex<MockDag>,
+}
+
+impl MockRpcApi {
+    /// Starts a DAG holding only `genesis`, which must have verbose data.
+    /// Later blocks are copies of it with the synthesized fields replaced.
+    pub fn new(genesis: RpcBlock) -> Self {
+        assert!(genesis.verbose_data.is_some(), "the mock genesis needs verbose data");
+        let genesis_hash = genesis.header.hash;
+        let mut dag = MockDag::default();
+        dag.blocks.insert(genesis_hash, genesis.clone());
+        dag.order.push(genesis_hash);
+        dag.chain.push(genesis_hash);
+        Self { genesis_hash, template: genesis, dag: Mutex::new(dag) }
+    }
+
+    /// A genesis block with every field but the hash zeroed, for tests that
+    /// have no real genesis at hand.
+    pub fn synthetic_genesis() -> RpcBlock {
+        let hash = Self::synthetic_hash(u64::MAX);
+        RpcBlock {
+            header: RpcHeader {
+                hash,
+                version: 0,
+                parents_by_level: vec![],
+                hash_merkle_root: Default::default(),
+                accepted_id_merkle_root: Default::default(),
+                utxo_commitment: Default::default(),
+                timestamp: 1_700_000_000_000,
+                bits: 0,
+                nonce: 0,
+                daa_score: 0,
+                blue_work: Default::default(),
+                blue_score: 0,
+                pruning_point: Default::default(),
+            },
+            transactions: vec![],
+            verbose_data: Some(RpcBlockVerboseData {
+                hash,
+                difficulty: 0.0,
+                selected_parent_hash: Default::default(),
+                transaction_ids: vec![],
+                is_header_only: false,
+                blue_score: 0,
+                children_hashes: vec![],
+                merge_set_blues_hashes: vec![],
+                merge_set_reds_hashes: vec![],
+                is_chain_block: true,
+            }),
+        }
+    }
+
+    pub fn genesis_hash(&self) -> RpcHash {
+        self.genesis_hash
+    }
+
+    /// Current virtual selected parent chain, genesis first.
+    pub fn chain(&self) -> Vec<RpcHash> {
+        self.lock().chain.clone()
+    }
+
+    pub fn sink(&self) -> RpcHash {
+        *self.lock().chain.last().expect("the chain holds at least the genesis")
+    }
+
+    /// Answers `get_info` with `info`. Unset, the call fails.
+    pub fn set_info(&self, info: GetInfoResponse) {
+        self.lock().info = Some(info);
+    }
+
+    /// Answers `get_block_dag_info` with `block_dag_info`. Unset, the call
+    /// fails.
+    pub fn set_block_dag_info(&self, block_dag_info: GetBlockDagInfoResponse) {
+        self.lock().block_dag_info = Some(block_dag_info);
+    }
+
+    /// Answers `get_mempool_entries` with `entries`, orphans included.
+    /// Unset, the mempool is empty.
+    pub fn set_mempool_entries(&self, entries: Vec<RpcMempoolEntry>) {
+        self.lock().mempool_entries = entries;
+    }
+
+    /// Adds a block with all `parents` blue. See `add_block_with_reds`.
+    pub fn add_block(&self, parents: &[RpcHash]) -> RpcHash {
+        self.add_block_with_reds(parents, &[])
+    }
+
+    /// Adds a block on top of `parents`, coloring the `reds` among them red,
+    /// and notifies subscribers with `BlockAdded`. A block whose selected
+    /// parent is the sink extends the virtual chain, which is notified with
+    /// `VirtualChainChanged`; use `reorg` to move the chain elsewhere.
+    pub fn add_block_with_reds(&self, parents: &[RpcHash], reds: &[RpcHash]) -> RpcHash {
+        assert!(!parents.is_empty(), "only the genesis has no parents");
+        let mut guard = self.lock();
+        let dag = &mut *guard;
+        dag.next_hash += 1;
+        let hash = Self::synthetic_hash(dag.next_hash);
+
+        let selected_parent = *parents.iter()
+            .max_by_key(|parent| (dag.block(parent).header.daa_score, **parent))
+            .expect("parents is not empty");
+        let selected_parent_block = dag.block(&selected_parent).clone();
+
+        let mut block = self.template.clone();
+        block.header.hash = hash;
+        block.header.parents_by_level = vec![parents.to_vec()];
+        block.header.daa_score = selected_parent_block.header.daa_score + parents.len() as u64;
+        block.header.blue_score = selected_parent_block.header.blue_score + (parents.len() - reds.len()) as u64;
+        block.header.timestamp = selected_parent_block.header.timestamp + BLOCK_INTERVAL_MS;
+        let verbose_data = block.verbose_data.as_mut().expect("the template has verbose data");
+        verbose_data.hash = hash;
+        verbose_data.selected_parent_hash = selected_parent;
+        verbose_data.merge_set_blues_hashes = parents.iter().filter(|parent| !reds.contains(parent)).copied().collect();
+        verbose_data.merge_set_reds_hashes = reds.to_vec();
+        verbose_data.children_hashes = vec![];
+        verbose_data.is_chain_block = false;
+        verbose_data.is_header_only = false;
+
+        for parent in parents {
+            let parent_block = dag.blocks.get_mut(parent).expect("parents are added before their children");
+            if let Some(parent_verbose_data) = parent_block.verbose_data.as_mut() {
+                parent_verbose_data.children_hashes.push(hash);
+            }
+        }
+        dag.blocks.insert(hash, block.clone());
+        dag.order.push(hash);
+        dag.broadcast(Notification::BlockAdded(BlockAddedNotification { block: block.into() }));
+
+        if dag.chain.last() == Some(&selected_parent) {
+            let keep = dag.chain.len();
+            dag.set_chain(keep, vec![hash]);
+        }
+        hash
+    }
+
+    /// Replaces the chain blocks above `fork_point` with `new_chain`, lowest
+    /// first, and notifies subscribers with `VirtualChainChanged`. Every
+    /// block of `new_chain` must already be in the DAG.
+    pub fn reorg(&self, fork_point: RpcHash, new_chain: &[RpcHash]) {
+        let mut guard = self.lock();
+        let dag = &mut *guard;
+        let fork_position = dag.chain.iter().position(|hash| *hash == fork_point)
+            .expect("the fork point is a chain block");
+        for hash in new_chain {
+            assert!(dag.blocks.contains_key(hash), "reorged in blocks are added first");
+        }
+        dag.set_chain(fork_position + 1, new_chain.to_vec());
+    }
+
+    /// Fails the next call of `method` (e.g. "GetBlock") with `message`.
+    /// Messages containing "not found" or "disconnected" are classified like
+    /// the matching node errors. Queued failures are returned in order.
+    pub fn fail_next(&self, method: &'static str, message: &str) {
+        self.lock().failures.entry(method).or_default().push(message.to_string());
+    }
+
+    /// Returns the next `count` blocks fetched with `get_block` as header
+    /// only, like a node that hasn't got their bodies yet.
+    pub fn return_header_only_next(&self, count: usize) {
+        self.lock().header_only_blocks = count;
+    }
+
+    /// Number of calls of `method` (e.g. "GetBlock") made so far, failed
+    /// ones included.
+    pub fn call_count(&self, method: &'static str) -> usize {
+        self.lock().calls.get(method).copied().unwrap_or_default()
+    }
+
+    fn synthetic_hash(n: u64) -> RpcHash {
+        let mut bytes = [0u8; 32];
+        bytes[24..].copy_from_slice(&n.to_be_bytes());
+        Hash::from_bytes(bytes)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MockDag> {
+        self.dag.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Returns the queued failure of `method`, if any, or else runs `call`.
+    fn respond<T: Send + 'static>(
+        &self,
+        method: &'static str,
+        call: impl FnOnce(&MockDag) -> NodeResult<T>,
+    ) -> NodeFuture<'_, T> {
+        let result = {
+            let mut dag = self.lock();
+            *dag.calls.entry(method).or_default() += 1;
+            let failure = dag.failures.get_mut(method)
+                .and_then(|failures| (!failures.is_empty()).then(|| failures.remove(0)));
+            match failure {
+                Some(message) => Err(message),
+                None => call(&dag),
+            }
+        };
+        Box::pin(async move { result })
+    }
+}
+
+impl MockDag {
+    fn block(&self, hash: &RpcHash) -> &RpcBlock {
+        self.blocks.get(hash).expect("the block is in the mock DAG")
+    }
+
+    fn find_block(&self, hash: &RpcHash) -> NodeResult<&RpcBlock> {
+        self.blocks.get(hash).ok_or_else(|| format!("Block {} not found", hash))
+    }
+
+    /// Truncates the chain to `keep` blocks, appends `added` and notifies the
+    /// change.
+    fn set_chain(&mut self, keep: usize, added: Vec<RpcHash>) {
+        let removed: Vec<RpcHash> = self.chain.drain(keep..).rev().collect();
+        for hash in &removed {
+            self.set_chain_block(hash, false);
+        }
+        for hash in &added {
+            self.set_chain_block(hash, true);
+        }
+        self.chain.extend(added.iter().copied());
+        self.broadcast(Notification::VirtualChainChanged(VirtualChainChangedNotification {
+            removed_chain_block_hashes: removed.into(),
+            added_chain_block_hashes: added.into(),
+            accepted_transaction_ids: Vec::new().into(),
+        }));
+    }
+
+    fn set_chain_block(&mut self, hash: &RpcHash, is_chain_block: bool) {
+        if let Some(verbose_data) = self.blocks.get_mut(hash).and_then(|block| block.verbose_data.as_mut()) {
+            verbose_data.is_chain_block = is_chain_block;
+        }
+    }
+
+    fn broadcast(&mut self, notification: Notification) {
+        self.subscribers.retain(|subscriber| subscriber.send(notification.clone()).is_ok());
+    }
+
+    /// Chain changes from `start_hash` to the sink. A start block that left
+    /// the chain is walked down its selected parents to the chain, like the
+    /// node does.
+    fn virtual_chain_from_block(&self, start_hash: &RpcHash) -> NodeResult<GetVirtualChainFromBlockResponse> {
+        let mut removed = vec![];
+        let mut current = *start_hash;
+        let position = loop {
+            if let Some(position) = self.chain.iter().position(|hash| *hash == current) {
+                break position;
+            }
+            let block = self.find_block(&current)?;
+            removed.push(current);
+            current = block.verbose_data.as_ref()
+                .map(|verbose_data| verbose_data.selected_parent_hash)
+                .ok_or_else(|| format!("Block {} has no selected parent", current))?;
+        };
+        Ok(GetVirtualChainFromBlockResponse {
+            removed_chain_block_hashes: removed,
+            added_chain_block_hashes: self.chain[position + 1..].to_vec(),
+            accepted_transaction_ids: vec![],
+        })
+    }
+}
+
+impl NodeApi for MockRpcApi {
+    fn get_info(&self) -> NodeFuture<'_, GetInfoResponse> {
+        self.respond("GetInfo", |dag| dag.info.clone().ok_or_else(|| "GetInfo is not set up in the mock".to_string()))
+    }
+
+    fn get_block_dag_info(&self) -> NodeFuture<'_, GetBlockDagInfoResponse> {
+        self.respond("GetBlockDAGInfo", |dag| {
+            dag.block_dag_info.clone().ok_or_else(|| "GetBlockDAGInfo is not set up in the mock".to_string())
+        })
+    }
+
+    fn get_connected_peer_info(&self) -> NodeFuture<'_, GetConnectedPeerInfoResponse> {
+        self.respond("GetConnectedPeerInfo", |_| Ok(GetConnectedPeerInfoResponse { peer_info: vec![] }))
+    }
+
+    fn get_block(&self, hash: RpcHash, _include_transactions: bool) -> NodeFuture<'_, RpcBlock> {
+        let header_only = {
+            let mut dag = self.lock();
+            let header_only = dag.header_only_blocks > 0;
+            dag.header_only_blocks = dag.header_only_blocks.saturating_sub(1);
+            header_only
+        };
+        self.respond("GetBlock", move |dag| {
+            let mut block = dag.find_block(&hash).cloned()?;
+            if header_only {
+                if let Some(verbose_data) = block.verbose_data.as_mut() {
+                    verbose_data.is_header_only = true;
+                }
+            }
+            Ok(block)
+        })
+    }
+
+    fn get_blocks(
+        &self,
+        low_hash: Option<RpcHash>,
+        include_blocks: bool,
+        _include_transactions: bool,
+    ) -> NodeFuture<'_, GetBlocksResponse> {
+        self.respond("GetBlocks", move |dag| {
+            let start = match low_hash {
+                Some(low_hash) => dag.order.iter().position(|hash| *hash == low_hash)
+                    .ok_or_else(|| format!("Block {} not found", low_hash))?,
+                None => 0,
+            };
+            let block_hashes = dag.order[start..].to_vec();
+            let blocks = if include_blocks {
+                block_hashes.iter().map(|hash| dag.block(hash).clone()).collect()
+            } else {
+                vec![]
+            };
+            Ok(GetBlocksResponse { block_hashes, blocks })
+        })
+    }
+
+    fn get_sink(&self) -> NodeFuture<'_, GetSinkResponse> {
+        self.respond("GetSink", |dag| Ok(GetSinkResponse { sink: *dag.chain.last().expect("the chain holds at least the genesis") }))
+    }
+
+    fn get_virtual_chain_from_block(
+        &self,
+        start_hash: RpcHash,
+        _include_accepted_transaction_ids: bool,
+    ) -> NodeFuture<'_, GetVirtualChainFromBlockResponse> {
+        self.respond("GetVirtualChainFromBlock", move |dag| dag.virtual_chain_from_block(&start_hash))
+    }
+
+    fn get_mempool_entries(
+        &self,
+        include_orphan_pool: bool,
+        filter_transaction_pool: bool,
+    ) -> NodeFuture<'_, Vec<RpcMempoolEntry>> {
+        self.respond("GetMempoolEntries", move |dag| {
+            Ok(dag.mempool_entries.iter()
+                .filter(|entry| if entry.is_orphan { include_orphan_pool } else { !filter_transaction_pool })
+                .cloned()
+                .collect())
+        })
+    }
+
+    fn subscribe(&self, _scope: Scope) -> NodeFuture<'_, mpsc::UnboundedReceiver<Notification>> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.lock().subscribers.push(sender);
+        Box::pin(async move { Ok(receiver) })
+    }
+}
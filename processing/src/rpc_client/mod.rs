@@ -1,49 +1,108 @@
 mod methods;
+#[cfg(feature = "mock-rpc")]
+pub mod mock;
+mod node;
 pub mod types;
 
 pub use methods::*;
+pub use node::*;
 pub use types::*;
 
-use anyhow::Result;
+use crate::error::{Result, TgiError};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Semaphore};
 use tracing::{info, warn};
 use tondi_grpc_client::GrpcClient;
-use tondi_rpc_core::api::rpc::RpcApi;
-use tondi_rpc_core::model::*;
-use tondi_rpc_core::notify::connection::ChannelConnection;
-use tondi_rpc_core::Notification;
 use tondi_utils_tower::counters::TowerConnectionCounters;
 
 #[derive(Clone)]
 pub struct RpcClient {
-    client: Arc<GrpcClient>,
+    node: Arc<dyn NodeApi>,
     address: String,
+    call_permits: Arc<Semaphore>,
     on_reconnected_handler: Arc<Mutex<Option<Box<dyn Fn() + Send + Sync>>>>,
 }
 
+pub const DEFAULT_RPC_MAX_CONCURRENCY: usize = 32;
+
+/// Options of the RPC connection.
+#[derive(Debug, Clone)]
+pub struct RpcConnectOptions {
+    /// Maximum number of RPC calls in flight at the same time
+    pub max_concurrency: usize,
+}
+
+/// RPC server address, as given by --rpcserver: `host:port` with an optional
+/// `grpc://` scheme
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RpcAddress {
+    /// Host name or IP address, IPv6 addresses in brackets
+    pub host: String,
+    pub port: u16,
+}
+
+impl RpcAddress {
+    /// Parses an address, so malformed ones fail before connecting rather
+    /// than deep in the gRPC client.
+    pub fn parse(address: &str) -> Result<Self> {
+        let invalid = |reason: &str| TgiError::InvalidRpcAddress {
+            address: address.to_string(),
+            reason: reason.to_string(),
+        };
+        let host_and_port = match address.split_once("://") {
+            Some(("grpc", rest)) => rest,
+            Some(("ws" | "wss" | "wrpc", _)) => return Err(invalid("wRPC is not supported, use the gRPC port of the node")),
+            Some((scheme, _)) => return Err(invalid(&format!("unknown scheme {}", scheme))),
+            None => address,
+        };
+        let (host, port) = host_and_port.rsplit_once(':').ok_or_else(|| invalid("missing port"))?;
+        let port = match port.parse::<u16>() {
+            Ok(port) if port > 0 => port,
+            _ => return Err(invalid(&format!("invalid port {:?}", port))),
+        };
+        let is_valid_host = match host.strip_prefix('[').and_then(|host| host.strip_suffix(']')) {
+            Some(ipv6) => ipv6.parse::<std::net::Ipv6Addr>().is_ok(),
+            None => !host.is_empty() && host.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-'),
+        };
+        if !is_valid_host {
+            return Err(invalid(&format!("invalid host {:?}", host)));
+        }
+        Ok(Self { host: host.to_string(), port })
+    }
+}
+
+impl Default for RpcConnectOptions {
+    fn default() -> Self {
+        Self {
+            max_concurrency: DEFAULT_RPC_MAX_CONCURRENCY,
+        }
+    }
+}
+
 impl RpcClient {
-    pub async fn new(address: &str, _route_capacity: usize) -> Result<Self> {
+    pub async fn new(address: &str, _route_capacity: usize, options: &RpcConnectOptions) -> Result<Self> {
         info!("Connecting to RPC server at {}", address);
         
-        // Ensure address has grpc:// prefix
-        let url = if address.starts_with("grpc://") {
-            address.to_string()
-        } else {
-            format!("grpc://{}", address)
-        };
+        let rpc_address = RpcAddress::parse(address)?;
+        let url = format!("grpc://{}:{}", rpc_address.host, rpc_address.port);
 
         let counters = Arc::new(TowerConnectionCounters::default());
         let client = GrpcClient::connect(url.clone()).await
-            .map_err(|e| anyhow::anyhow!("Failed to connect to Tondi RPC server: {}", e))?;
+            .map_err(|e| TgiError::RpcConnect(e.to_string()))?;
 
         info!("Connected to Tondi RPC server at {}", address);
 
-        Ok(Self {
-            client: Arc::new(client),
+        Ok(Self::with_node(Arc::new(client), address, options.max_concurrency))
+    }
+
+    /// Wraps an already connected node, e.g. `mock::MockRpcApi` in tests.
+    pub fn with_node(node: Arc<dyn NodeApi>, address: &str, max_concurrency: usize) -> Self {
+        Self {
+            node,
             address: address.to_string(),
+            call_permits: Arc::new(Semaphore::new(max_concurrency.max(1))),
             on_reconnected_handler: Arc::new(Mutex::new(None)),
-        })
+        }
     }
 
     pub fn address(&self) -> &str {
@@ -57,8 +116,126 @@ impl RpcClient {
         // Handler will be called on reconnection automatically by the client
         // For now, we don't need to store it since the client handles reconnection
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tondi_notify::scope::Scope;
+    use tondi_rpc_core::model::*;
+    use tondi_rpc_core::Notification;
+    use tokio::sync::mpsc;
+
+    /// Node whose `get_block` calls take a while, counting how many of them
+    /// are in flight at once
+    #[derive(Default)]
+    struct CountingNode {
+        in_flight: AtomicUsize,
+        max_in_flight: AtomicUsize,
+    }
+
+    impl NodeApi for CountingNode {
+        fn get_info(&self) -> NodeFuture<'_, GetInfoResponse> {
+            unimplemented!()
+        }
+
+        fn get_block_dag_info(&self) -> NodeFuture<'_, GetBlockDagInfoResponse> {
+            unimplemented!()
+        }
+
+        fn get_connected_peer_info(&self) -> NodeFuture<'_, GetConnectedPeerInfoResponse> {
+            unimplemented!()
+        }
+
+        fn get_block(&self, hash: RpcHash, _include_transactions: bool) -> NodeFuture<'_, RpcBlock> {
+            Box::pin(async move {
+                let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                self.in_flight.fetch_sub(1, Ordering::SeqCst);
+                Err(format!("Block {} not found", hash))
+            })
+        }
+
+        fn get_blocks(&self, _low_hash: Option<RpcHash>, _include_blocks: bool, _include_transactions: bool) -> NodeFuture<'_, GetBlocksResponse> {
+            unimplemented!()
+        }
+
+        fn get_sink(&self) -> NodeFuture<'_, GetSinkResponse> {
+            unimplemented!()
+        }
+
+        fn get_virtual_chain_from_block(&self, _start_hash: RpcHash, _include_accepted_transaction_ids: bool) -> NodeFuture<'_, GetVirtualChainFromBlockResponse> {
+            unimplemented!()
+        }
+
+        fn get_mempool_entries(&self, _include_orphan_pool: bool, _filter_transaction_pool: bool) -> NodeFuture<'_, Vec<RpcMempoolEntry>> {
+            unimplemented!()
+        }
+
+        fn subscribe(&self, _scope: Scope) -> NodeFuture<'_, mpsc::UnboundedReceiver<Notification>> {
+            unimplemented!()
+        }
+    }
+
+    #[test]
+    fn every_malformed_hash_is_reported_at_once() {
+        let valid = "ab".repeat(32);
+        let hashes = [valid.clone(), "xyz".to_string(), valid, "ab".repeat(31)];
+        match RpcClient::validate_hashes(&hashes) {
+            Err(TgiError::InvalidHashes(invalid)) => {
+                assert_eq!(invalid.len(), 2, "{:?}", invalid);
+                assert!(invalid[0].starts_with("xyz "), "{:?}", invalid);
+                assert!(invalid[1].starts_with(&"ab".repeat(31)), "{:?}", invalid);
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert!(RpcClient::validate_hashes(&["cd".repeat(32)]).is_ok());
+        assert!(RpcClient::validate_hashes(&[]).is_ok());
+    }
+
+    #[tokio::test]
+    async fn calls_in_flight_never_exceed_the_max_concurrency() {
+        let node = Arc::new(CountingNode::default());
+        let rpc_client = RpcClient::with_node(node.clone(), "counting", 3);
+
+        let mut calls = tokio::task::JoinSet::new();
+        for _ in 0..12 {
+            let rpc_client = rpc_client.clone();
+            calls.spawn(async move {
+                let _ = rpc_client.get_block(RpcHash::from_bytes([1; 32]), false).await;
+            });
+        }
+        while calls.join_next().await.is_some() {}
+
+        assert_eq!(node.max_in_flight.load(Ordering::SeqCst), 3);
+    }
+
+    fn address(host: &str, port: u16) -> RpcAddress {
+        RpcAddress { host: host.to_string(), port }
+    }
+
+    #[test]
+    fn parses_addresses_with_and_without_scheme() {
+        assert_eq!(RpcAddress::parse("localhost:16110").unwrap(), address("localhost", 16110));
+        assert_eq!(RpcAddress::parse("grpc://node-1.example.com:16110").unwrap(), address("node-1.example.com", 16110));
+        assert_eq!(RpcAddress::parse("10.0.0.1:443").unwrap(), address("10.0.0.1", 443));
+        assert_eq!(RpcAddress::parse("[::1]:16110").unwrap(), address("[::1]", 16110));
+    }
 
-    pub fn client(&self) -> &GrpcClient {
-        &self.client
+    #[test]
+    fn malformed_addresses_are_invalid_and_not_transient() {
+        let malformed = [
+            "localhost", "localhost:", "localhost:0", "localhost:65536", ":16110", "wss://localhost:17110", "grpcs://localhost:443",
+            "http://localhost:16110", "[::g]:16110", "local_host:16110",
+        ];
+        for malformed in malformed {
+            match RpcAddress::parse(malformed) {
+                Err(e @ TgiError::InvalidRpcAddress { .. }) => assert!(!e.is_transient()),
+                result => panic!("{:?} should be an invalid address, got {:?}", malformed, result),
+            }
+        }
     }
 }
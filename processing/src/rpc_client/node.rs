@@ -0,0 +1,128 @@
+//! The subset of the node RPC interface TGI uses.
+//!
+//! `RpcClient` talks to the node through `NodeApi` rather than the gRPC
+//! client directly, so another implementation (e.g. the in-memory mock of
+//! the `mock-rpc` feature) can be injected with `RpcClient::with_node`.
+
+use std::future::Future;
+use std::pin::Pin;
+use tokio::sync::mpsc;
+use tondi_grpc_client::GrpcClient;
+use tondi_notify::scope::Scope;
+use tondi_rpc_core::api::rpc::RpcApi;
+use tondi_rpc_core::model::*;
+use tondi_rpc_core::Notification;
+
+/// Errors are kept as the node message, which `TgiError::rpc` classifies.
+pub type NodeResult<T> = std::result::Result<T, String>;
+
+pub type NodeFuture<'a, T> = Pin<Box<dyn Future<Output = NodeResult<T>> + Send + 'a>>;
+
+pub trait NodeApi: Send + Sync {
+    fn get_info(&self) -> NodeFuture<'_, GetInfoResponse>;
+
+    fn get_block_dag_info(&self) -> NodeFuture<'_, GetBlockDagInfoResponse>;
+
+    fn get_connected_peer_info(&self) -> NodeFuture<'_, GetConnectedPeerInfoResponse>;
+
+    fn get_block(&self, hash: RpcHash, include_transactions: bool) -> NodeFuture<'_, RpcBlock>;
+
+    fn get_blocks(
+        &self,
+        low_hash: Option<RpcHash>,
+        include_blocks: bool,
+        include_transactions: bool,
+    ) -> NodeFuture<'_, GetBlocksResponse>;
+
+    fn get_sink(&self) -> NodeFuture<'_, GetSinkResponse>;
+
+    fn get_virtual_chain_from_block(
+        &self,
+        start_hash: RpcHash,
+        include_accepted_transaction_ids: bool,
+    ) -> NodeFuture<'_, GetVirtualChainFromBlockResponse>;
+
+    fn get_mempool_entries(
+        &self,
+        include_orphan_pool: bool,
+        filter_transaction_pool: bool,
+    ) -> NodeFuture<'_, Vec<RpcMempoolEntry>>;
+
+    /// Starts the notifications of `scope` and returns the channel they, and
+    /// possibly notifications of other scopes, are received on.
+    fn subscribe(&self, scope: Scope) -> NodeFuture<'_, mpsc::UnboundedReceiver<Notification>>;
+}
+
+impl NodeApi for GrpcClient {
+    fn get_info(&self) -> NodeFuture<'_, GetInfoResponse> {
+        Box::pin(async move { RpcApi::get_info(self).await.map_err(|e| e.to_string()) })
+    }
+
+    fn get_block_dag_info(&self) -> NodeFuture<'_, GetBlockDagInfoResponse> {
+        Box::pin(async move { RpcApi::get_block_dag_info(self).await.map_err(|e| e.to_string()) })
+    }
+
+    fn get_connected_peer_info(&self) -> NodeFuture<'_, GetConnectedPeerInfoResponse> {
+        Box::pin(async move { RpcApi::get_connected_peer_info(self).await.map_err(|e| e.to_string()) })
+    }
+
+    fn get_block(&self, hash: RpcHash, include_transactions: bool) -> NodeFuture<'_, RpcBlock> {
+        Box::pin(async move { RpcApi::get_block(self, hash, include_transactions).await.map_err(|e| e.to_string()) })
+    }
+
+    fn get_blocks(
+        &self,
+        low_hash: Option<RpcHash>,
+        include_blocks: bool,
+        include_transactions: bool,
+    ) -> NodeFuture<'_, GetBlocksResponse> {
+        Box::pin(async move {
+            RpcApi::get_blocks(self, low_hash, include_blocks, include_transactions).await.map_err(|e| e.to_string())
+        })
+    }
+
+    fn get_sink(&self) -> NodeFuture<'_, GetSinkResponse> {
+        Box::pin(async move { RpcApi::get_sink(self).await.map_err(|e| e.to_string()) })
+    }
+
+    fn get_virtual_chain_from_block(
+        &self,
+        start_hash: RpcHash,
+        include_accepted_transaction_ids: bool,
+    ) -> NodeFuture<'_, GetVirtualChainFromBlockResponse> {
+        Box::pin(async move {
+            RpcApi::get_virtual_chain_from_block(self, start_hash, include_accepted_transaction_ids).await
+                .map_err(|e| e.to_string())
+        })
+    }
+
+    fn get_mempool_entries(
+        &self,
+        include_orphan_pool: bool,
+        filter_transaction_pool: bool,
+    ) -> NodeFuture<'_, Vec<RpcMempoolEntry>> {
+        Box::pin(async move {
+            RpcApi::get_mempool_entries(self, include_orphan_pool, filter_transaction_pool).await
+                .map_err(|e| e.to_string())
+        })
+    }
+
+    fn subscribe(&self, scope: Scope) -> NodeFuture<'_, mpsc::UnboundedReceiver<Notification>> {
+        Box::pin(async move {
+            // Direct mode delivers the notifications of every scope on the
+            // client notification channel
+            let receiver = self.notification_channel_receiver();
+            self.start_notify(GrpcClient::DIRECT_MODE_LISTENER_ID, scope).await.map_err(|e| e.to_string())?;
+
+            let (sender, forwarded) = mpsc::unbounded_channel();
+            tokio::spawn(async move {
+                while let Ok(notification) = receiver.recv().await {
+                    if sender.send(notification).is_err() {
+                        break;
+                    }
+                }
+            });
+            Ok(forwarded)
+        })
+    }
+}
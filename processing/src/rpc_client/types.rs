@@ -5,3 +5,169 @@ pub use tondi_rpc_core::model::{
     GetSinkResponse, GetVirtualChainFromBlockResponse, GetInfoResponse,
     BlockAddedNotification, VirtualChainChangedNotification,
 };
+
+use crate::error::{Result, TgiError};
+use bytes::BytesMut;
+use postgres_types::{to_sql_checked, FromSql, IsNull, ToSql, Type};
+use std::fmt;
+use std::str::FromStr;
+use tondi_hashes::Hash;
+
+/// A block hash, parsed once from its hex encoding at the boundary where it
+/// enters TGI, and carried parsed through processing, RPC calls and database
+/// queries. It binds to and reads from SQL as its hex encoding; the query
+/// API and the stored `Block` rows keep hex strings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BlockHash(RpcHash);
+
+impl BlockHash {
+    pub fn parse(hash: &str) -> Result<Self> {
+        hash.parse::<Hash>().map(Self).map_err(|e| TgiError::invalid_hash(hash, e))
+    }
+
+    pub fn as_rpc_hash(&self) -> RpcHash {
+        self.0
+    }
+}
+
+impl FromStr for BlockHash {
+    type Err = TgiError;
+
+    fn from_str(hash: &str) -> Result<Self> {
+        Self::parse(hash)
+    }
+}
+
+impl fmt::Display for BlockHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl From<RpcHash> for BlockHash {
+    fn from(hash: RpcHash) -> Self {
+        Self(hash)
+    }
+}
+
+impl From<BlockHash> for RpcHash {
+    fn from(hash: BlockHash) -> Self {
+        hash.0
+    }
+}
+
+impl ToSql for BlockHash {
+    fn to_sql(&self, ty: &Type, out: &mut BytesMut) -> std::result::Result<IsNull, Box<dyn std::error::Error + Sync + Send>> {
+        self.to_string().as_str().to_sql(ty, out)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        <&str as ToSql>::accepts(ty)
+    }
+
+    to_sql_checked!();
+}
+
+impl<'a> FromSql<'a> for BlockHash {
+    fn from_sql(ty: &Type, raw: &'a [u8]) -> std::result::Result<Self, Box<dyn std::error::Error + Sync + Send>> {
+        Ok(Self::parse(<&str as FromSql>::from_sql(ty, raw)?)?)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        <&str as FromSql>::accepts(ty)
+    }
+}
+
+/// A block hash argument of the RPC client methods: hashes received from
+/// the node pass through as they are, hex strings are parsed.
+pub trait IntoBlockHash {
+    fn into_block_hash(self) -> Result<BlockHash>;
+}
+
+impl IntoBlockHash for BlockHash {
+    fn into_block_hash(self) -> Result<BlockHash> {
+        Ok(self)
+    }
+}
+
+impl IntoBlockHash for &BlockHash {
+    fn into_block_hash(self) -> Result<BlockHash> {
+        Ok(*self)
+    }
+}
+
+impl IntoBlockHash for RpcHash {
+    fn into_block_hash(self) -> Result<BlockHash> {
+        Ok(BlockHash(self))
+    }
+}
+
+impl IntoBlockHash for &str {
+    fn into_block_hash(self) -> Result<BlockHash> {
+        BlockHash::parse(self)
+    }
+}
+
+impl IntoBlockHash for &String {
+    fn into_block_hash(self) -> Result<BlockHash> {
+        BlockHash::parse(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::testing::TestDatabase;
+
+    const HEX: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn parsing_round_trips_through_display() {
+        let hash = BlockHash::parse(HEX).unwrap();
+        assert_eq!(hash.to_string(), HEX);
+        assert_eq!(HEX.parse::<BlockHash>().unwrap(), hash);
+    }
+
+    #[test]
+    fn malformed_hashes_are_rejected() {
+        for hash in ["", "abc", &HEX[1..], &format!("{}00", HEX), &HEX.replace('a', "g")] {
+            assert!(matches!(BlockHash::parse(hash), Err(TgiError::InvalidHash { hash: h, .. }) if h == hash), "{}", hash);
+        }
+    }
+
+    #[test]
+    fn converts_to_and_from_rpc_hashes() {
+        let hash = BlockHash::parse(HEX).unwrap();
+        let rpc_hash: RpcHash = hash.into();
+        assert_eq!(rpc_hash, hash.as_rpc_hash());
+        assert_eq!(rpc_hash.to_string(), HEX);
+        assert_eq!(BlockHash::from(rpc_hash), hash);
+    }
+
+    #[test]
+    fn every_argument_kind_converts_to_the_same_hash() {
+        let hash = BlockHash::parse(HEX).unwrap();
+        assert_eq!(HEX.into_block_hash().unwrap(), hash);
+        assert_eq!((&HEX.to_string()).into_block_hash().unwrap(), hash);
+        assert_eq!(hash.into_block_hash().unwrap(), hash);
+        assert_eq!((&hash).into_block_hash().unwrap(), hash);
+        assert_eq!(hash.as_rpc_hash().into_block_hash().unwrap(), hash);
+        assert!("not a hash".into_block_hash().is_err());
+    }
+
+    #[tokio::test]
+    async fn binds_to_and_reads_from_sql_as_hex() {
+        let Some(test_database) = TestDatabase::create_empty().await else {
+            return;
+        };
+        let client = test_database.client().await;
+        let hash = BlockHash::parse(HEX).unwrap();
+        for cast in ["TEXT", "CHAR(64)", "VARCHAR"] {
+            let row = client.query_one(&format!("SELECT $1::{cast}, $1::{cast}::TEXT"), &[&hash]).await.unwrap();
+            assert_eq!(row.get::<_, BlockHash>(0), hash, "{}", cast);
+            assert_eq!(row.get::<_, String>(1), HEX, "{}", cast);
+        }
+        let error = client.query_one("SELECT 'not a hash'::TEXT", &[]).await.unwrap().try_get::<_, BlockHash>(0);
+        assert!(error.is_err());
+    }
+}
@@ -0,0 +1,388 @@
+use crate::database::{Database, COLOR_BLUE, COLOR_RED};
+use crate::rpc_client::{BlockHash, RpcClient};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tracing::{info, warn};
+
+const ORPHAN_BLOCKS_SAMPLE_SIZE: u64 = 20;
+
+/// Chain blocks checked per database round trip
+const CHAIN_CHUNK_SIZE: usize = 1000;
+
+/// Discrepancies reported in full; the rest are only counted
+const DISCREPANCIES_SAMPLE_SIZE: usize = 100;
+
+pub const DEFAULT_VERIFY_CONCURRENCY: usize = 8;
+
+/// A difference between the stored virtual selected parent chain and the
+/// node one. Ordered by position in the chain, so reports don't depend on
+/// the order concurrent checks complete in.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Discrepancy {
+    chain_index: usize,
+    block_hash: BlockHash,
+    description: String,
+}
+
+pub async fn verify_database(database: &Database) -> Result<()> {
+    info!("Verifying database integrity");
+
+    let database_for_closure = database.clone();
+    database.run_in_read_transaction(move |tx| {
+        let database = database_for_closure.clone();
+        Box::pin(async move {
+            let header_only_count = database.count_header_only(tx).await?;
+            info!("{} stored blocks are header only", header_only_count);
+
+            let orphan_count = database.orphan_block_count(tx).await?;
+            if orphan_count == 0 {
+                info!("No orphan blocks found");
+                return Ok(());
+            }
+
+            warn!("Found {} orphan blocks", orphan_count);
+            for block in database.orphan_blocks(tx, ORPHAN_BLOCKS_SAMPLE_SIZE).await? {
+                warn!(
+                    "Orphan block {} (id {}, height {}, selected parent {:?}, parents {:?})",
+                    block.block_hash, block.id, block.height, block.selected_parent_id, block.parent_ids
+                );
+            }
+            Ok(())
+        })
+    }).await?;
+
+    info!("Finished verifying database integrity");
+    Ok(())
+}
+
+/// Merge set and selected parent of a chain block, as reported by the node
+struct NodeMergeSet {
+    chain_index: usize,
+    block_hash: BlockHash,
+    /// `None` for the genesis block
+    selected_parent_hash: Option<BlockHash>,
+    blue_hashes: Vec<BlockHash>,
+    red_hashes: Vec<BlockHash>,
+}
+
+/// Fetches the merge sets of chain blocks from the node, at most
+/// `concurrency` at a time, in chain order.
+async fn fetch_merge_sets(
+    rpc_client: &RpcClient,
+    chain: &[(usize, BlockHash)],
+    concurrency: usize,
+) -> Result<Vec<NodeMergeSet>> {
+    let permits = Arc::new(Semaphore::new(concurrency.max(1)));
+    let mut tasks = JoinSet::new();
+    for (chain_index, block_hash) in chain.iter().cloned() {
+        let rpc_client = rpc_client.clone();
+        let permits = permits.clone();
+        tasks.spawn(async move {
+            let _permit = permits.acquire_owned().await?;
+            let block = rpc_client.get_block(block_hash, false).await?.block;
+            let (selected_parent_hash, blue_hashes, red_hashes) = match block.verbose_data {
+                Some(verbose_data) => (
+                    (!block.header.direct_parents().is_empty()).then(|| verbose_data.selected_parent_hash.into()),
+                    verbose_data.merge_set_blues_hashes.iter().map(|&hash| hash.into()).collect(),
+                    verbose_data.merge_set_reds_hashes.iter().map(|&hash| hash.into()).collect(),
+                ),
+                None => (None, vec![], vec![]),
+            };
+            anyhow::Ok(NodeMergeSet { chain_index, block_hash, selected_parent_hash, blue_hashes, red_hashes })
+        });
+    }
+
+    let mut merge_sets = Vec::with_capacity(chain.len());
+    while let Some(result) = tasks.join_next().await {
+        merge_sets.push(result??);
+    }
+    merge_sets.sort_by_key(|merge_set| merge_set.chain_index);
+    Ok(merge_sets)
+}
+
+/// Checks that every block of the node virtual selected parent chain, from
+/// the pruning point up, is stored, marked as a chain block, points at the
+/// selected parent the node reports, and that the blocks it merged carry the
+/// color the node gives them. Merge sets are fetched with up to
+/// `concurrency` concurrent RPC calls. With `repair`, wrong selected parents
+/// are fixed.
+pub async fn verify_virtual_chain(database: &Database, rpc_client: &RpcClient, concurrency: usize, repair: bool) -> Result<()> {
+    let (discrepancies, selected_parent_repairs) = chain_discrepancies(database, rpc_client, concurrency).await?;
+    if discrepancies.is_empty() {
+        info!("The stored virtual selected parent chain matches the node");
+        return Ok(());
+    }
+    warn!("Found {} discrepancies with the node virtual selected parent chain", discrepancies.len());
+    for discrepancy in discrepancies.iter().take(DISCREPANCIES_SAMPLE_SIZE) {
+        warn!(
+            "Chain position {}: block {}: {}",
+            discrepancy.chain_index, discrepancy.block_hash, discrepancy.description
+        );
+    }
+    if discrepancies.len() > DISCREPANCIES_SAMPLE_SIZE {
+        warn!("{} more discrepancies not shown", discrepancies.len() - DISCREPANCIES_SAMPLE_SIZE);
+    }
+    if repair && !selected_parent_repairs.is_empty() {
+        repair_selected_parents(database, selected_parent_repairs).await?;
+    }
+    Ok(())
+}
+
+/// Compares the stored chain with the node one. Returns the discrepancies,
+/// sorted by chain position, and the chain blocks paired with the selected
+/// parent the node reports for them where the stored one differs.
+async fn chain_discrepancies(
+    database: &Database,
+    rpc_client: &RpcClient,
+    concurrency: usize,
+) -> Result<(Vec<Discrepancy>, Vec<(BlockHash, BlockHash)>)> {
+    let pruning_point_hash = rpc_client.get_block_dag_info().await?.pruning_point_hash;
+    let chain: Vec<BlockHash> = rpc_client.get_virtual_chain_from_block(pruning_point_hash, false).await?
+        .added_chain_block_hashes.iter().map(|&hash| hash.into()).collect();
+    info!("Verifying {} chain blocks from the pruning point {}", chain.len(), pruning_point_hash);
+
+    let mut discrepancies = Vec::new();
+    // Chain blocks with the selected parent the node reports for them
+    let mut selected_parent_repairs = Vec::new();
+    for (chunk_index, chunk) in chain.chunks(CHAIN_CHUNK_SIZE).enumerate() {
+        let chunk_start = chunk_index * CHAIN_CHUNK_SIZE;
+        let chain_indexes: HashMap<BlockHash, usize> = chunk.iter().enumerate()
+            .map(|(i, hash)| (*hash, chunk_start + i))
+            .collect();
+
+        let database_for_closure = database.clone();
+        let hashes = chunk.to_vec();
+        let (missing, not_in_chain) = database.run_in_read_transaction(move |tx| {
+            let database = database_for_closure.clone();
+            Box::pin(async move {
+                let missing = database.missing_hashes(tx, &hashes).await?;
+                let not_in_chain = database.hashes_not_in_virtual_selected_parent_chain(tx, &hashes).await?;
+                Ok((missing, not_in_chain))
+            })
+        }).await?;
+        for hash in missing.iter() {
+            discrepancies.push(Discrepancy {
+                chain_index: chain_indexes[hash],
+                block_hash: *hash,
+                description: "chain block is not stored".to_string(),
+            });
+        }
+        for hash in not_in_chain {
+            discrepancies.push(Discrepancy {
+                chain_index: chain_indexes[&hash],
+                block_hash: hash,
+                description: "stored block is not marked as in the virtual selected parent chain".to_string(),
+            });
+        }
+
+        let stored_chain: Vec<(usize, BlockHash)> = chunk.iter().enumerate()
+            .filter(|(_, hash)| !missing.contains(hash))
+            .map(|(i, hash)| (chunk_start + i, *hash))
+            .collect();
+        let merge_sets = fetch_merge_sets(rpc_client, &stored_chain, concurrency).await?;
+
+        let database_for_closure = database.clone();
+        let stored_hashes: Vec<BlockHash> = stored_chain.iter().map(|(_, hash)| *hash).collect();
+        let stored_selected_parents = database.run_in_read_transaction(move |tx| {
+            let database = database_for_closure.clone();
+            Box::pin(async move { Ok(database.selected_parent_hashes_by_hashes(tx, &stored_hashes).await?) })
+        }).await?;
+        for merge_set in &merge_sets {
+            let Some(stored_selected_parent) = stored_selected_parents.get(&merge_set.block_hash) else { continue };
+            let Some(node_selected_parent) = &merge_set.selected_parent_hash else { continue };
+            if stored_selected_parent.as_ref() != Some(node_selected_parent) {
+                discrepancies.push(Discrepancy {
+                    chain_index: merge_set.chain_index,
+                    block_hash: merge_set.block_hash,
+                    description: format!(
+                        "selected parent is {} but the node reports {}",
+                        stored_selected_parent.map_or("unset".to_string(), |hash| hash.to_string()), node_selected_parent
+                    ),
+                });
+                selected_parent_repairs.push((merge_set.block_hash, *node_selected_parent));
+            }
+        }
+
+        let expected_colors: Vec<(usize, BlockHash, &str)> = merge_sets.iter()
+            .flat_map(|merge_set| {
+                merge_set.blue_hashes.iter().map(|hash| (merge_set.chain_index, *hash, COLOR_BLUE))
+                    .chain(merge_set.red_hashes.iter().map(|hash| (merge_set.chain_index, *hash, COLOR_RED)))
+            })
+            .collect();
+        let database_for_closure = database.clone();
+        let merged_hashes: Vec<BlockHash> = expected_colors.iter().map(|(_, hash, _)| *hash).collect();
+        let stored_colors = database.run_in_read_transaction(move |tx| {
+            let database = database_for_closure.clone();
+            Box::pin(async move { Ok(database.block_colors_by_hashes(tx, &merged_hashes).await?) })
+        }).await?;
+        for (chain_index, hash, expected_color) in expected_colors {
+            // Merged blocks that aren't stored are out of the database scope
+            if let Some(stored_color) = stored_colors.get(&hash) {
+                if stored_color != expected_color {
+                    discrepancies.push(Discrepancy {
+                        chain_index,
+                        description: format!("merged block is {} but the node colors it {}", stored_color, expected_color),
+                        block_hash: hash,
+                    });
+                }
+            }
+        }
+    }
+
+    discrepancies.sort();
+    discrepancies.dedup();
+    Ok((discrepancies, selected_parent_repairs))
+}
+
+/// Points every block of `repairs` at the selected parent paired with it.
+/// Selected parents that aren't stored are left for block processing to
+/// resolve.
+async fn repair_selected_parents(database: &Database, repairs: Vec<(BlockHash, BlockHash)>) -> Result<()> {
+    info!("Repairing the selected parents of {} chain blocks", repairs.len());
+    let database_for_closure = database.clone();
+    let (repaired, unresolvable) = database.run_in_transaction(move |tx| {
+        let database = database_for_closure.clone();
+        Box::pin(async move {
+            let (mut repaired, mut unresolvable) = (0, 0);
+            for (block_hash, selected_parent_hash) in &repairs {
+                let Some(selected_parent) = database.block_by_hash(tx, selected_parent_hash).await? else {
+                    warn!("Selected parent {} of block {} is not stored; not repaired", selected_parent_hash, block_hash);
+                    unresolvable += 1;
+                    continue;
+                };
+                let block_id = database.block_id_by_hash(tx, block_hash).await?;
+                database.update_block_selected_parent(tx, block_id, selected_parent.id).await?;
+                repaired += 1;
+            }
+            Ok((repaired, unresolvable))
+        })
+    }).await?;
+    info!("Repaired {} selected parents, {} left unresolved", repaired, unresolvable);
+    Ok(())
+}
+
+#[cfg(all(test, feature = "mock-rpc"))]
+mod tests {
+    use super::*;
+    use crate::database::testing::TestDatabase;
+    use crate::database::Block;
+    use crate::rpc_client::mock::MockRpcApi;
+    use crate::rpc_client::GetBlockDagInfoResponse;
+
+    /// Stores the node chain, skipping the blocks at `missing` chain
+    /// positions, flagging those at `off_chain` as off the chain and coloring
+    /// those at `red` red
+    async fn store_chain(database: &Database, chain: &[BlockHash], missing: &[usize], off_chain: &[usize], red: &[usize]) {
+        let chain = chain.to_vec();
+        let (missing, off_chain, red) = (missing.to_vec(), off_chain.to_vec(), red.to_vec());
+        let database_for_closure = database.clone();
+        database.run_in_transaction(move |tx| {
+            let database = database_for_closure.clone();
+            let (chain, missing, off_chain, red) = (chain.clone(), missing.clone(), off_chain.clone(), red.clone());
+            Box::pin(async move {
+                let mut selected_parent_id = None;
+                for (position, hash) in chain.iter().enumerate() {
+                    if missing.contains(&position) {
+                        selected_parent_id = None;
+                        continue;
+                    }
+                    let block = Block {
+                        id: 0,
+                        block_hash: hash.to_string(),
+                        timestamp: 0,
+                        parent_ids: selected_parent_id.into_iter().collect(),
+                        daa_score: position as u64,
+                        height: position as u64,
+                        height_group_index: 0,
+                        selected_parent_id,
+                        color: if red.contains(&position) { COLOR_RED } else { COLOR_BLUE }.to_string(),
+                        is_in_virtual_selected_parent_chain: !off_chain.contains(&position),
+                        merge_set_red_ids: vec![],
+                        merge_set_blue_ids: vec![],
+                        is_header_only: false,
+                        miner: None,
+                    };
+                    database.insert_block(tx, hash, &block).await?;
+                    selected_parent_id = Some(database.block_id_by_hash(tx, hash).await?);
+                }
+                Ok(())
+            })
+        }).await.unwrap();
+    }
+
+    /// A node whose chain is the genesis and `length` blocks above it, with
+    /// the genesis as its pruning point
+    fn mock_chain(length: u64) -> (RpcClient, Vec<BlockHash>) {
+        let mock = Arc::new(MockRpcApi::new(MockRpcApi::synthetic_genesis()));
+        let rpc_client = RpcClient::with_node(mock.clone(), "mock", 8);
+        let mut tip = mock.genesis_hash();
+        for _ in 0..length {
+            tip = mock.add_block(&[tip]);
+        }
+        mock.set_block_dag_info(GetBlockDagInfoResponse {
+            network: "mainnet".parse().unwrap(),
+            block_count: length + 1,
+            header_count: length + 1,
+            tip_hashes: vec![tip],
+            difficulty: 0.0,
+            past_median_time: 0,
+            virtual_parent_hashes: vec![tip],
+            pruning_point_hash: mock.genesis_hash(),
+            virtual_daa_score: length + 1,
+            sink: tip,
+        });
+        let chain = mock.chain().into_iter().map(BlockHash::from).collect();
+        (rpc_client, chain)
+    }
+
+    #[tokio::test]
+    async fn concurrent_checks_report_each_discrepancy_once_in_chain_order() {
+        let Some(test_database) = TestDatabase::create().await else { return };
+        let (rpc_client, chain) = mock_chain(20);
+        store_chain(&test_database.database, &chain, &[3, 11], &[5, 17], &[7, 13]).await;
+
+        let (sequential, _) = chain_discrepancies(&test_database.database, &rpc_client, 1).await.unwrap();
+        let (concurrent, _) = chain_discrepancies(&test_database.database, &rpc_client, 8).await.unwrap();
+
+        assert!(!sequential.is_empty());
+        assert_eq!(concurrent, sequential);
+        assert!(concurrent.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", concurrent);
+        // Each broken block is reported exactly once
+        for (position, description) in [
+            (3, "chain block is not stored"),
+            (11, "chain block is not stored"),
+            (5, "stored block is not marked as in the virtual selected parent chain"),
+            (17, "stored block is not marked as in the virtual selected parent chain"),
+            (7, "merged block is red but the node colors it blue"),
+            (13, "merged block is red but the node colors it blue"),
+        ] {
+            assert_eq!(
+                concurrent.iter().filter(|discrepancy| discrepancy.block_hash == chain[position] && discrepancy.description == description).count(),
+                1,
+                "{} at position {}", description, position,
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn wrong_selected_parent_is_repaired() {
+        let Some(test_database) = TestDatabase::create().await else { return };
+        let (rpc_client, chain) = mock_chain(8);
+        store_chain(&test_database.database, &chain, &[], &[], &[]).await;
+        test_database.client().await.execute(
+            "UPDATE blocks SET selected_parent_id = (SELECT id FROM blocks WHERE block_hash = $1) WHERE block_hash = $2",
+            &[&chain[2].to_string(), &chain[5].to_string()],
+        ).await.unwrap();
+        let (discrepancies, repairs) = chain_discrepancies(&test_database.database, &rpc_client, 4).await.unwrap();
+        assert_eq!(discrepancies.iter().map(|discrepancy| discrepancy.block_hash).collect::<Vec<_>>(), vec![chain[5]]);
+        assert_eq!(repairs, vec![(chain[5], chain[4])]);
+
+        verify_virtual_chain(&test_database.database, &rpc_client, 4, true).await.unwrap();
+
+        let (discrepancies, _) = chain_discrepancies(&test_database.database, &rpc_client, 4).await.unwrap();
+        assert!(discrepancies.is_empty(), "{:?}", discrepancies);
+    }
+}